@@ -0,0 +1,23 @@
+use std::process::Command;
+
+/// Embed the git commit PgCat was built from, shown by `SHOW VERSION`.
+/// Builds without a git checkout (e.g. Docker) can set `PGCAT_GIT_HASH`.
+fn main() {
+    println!("cargo:rerun-if-env-changed=PGCAT_GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let git_hash = std::env::var("PGCAT_GIT_HASH")
+        .ok()
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=PGCAT_GIT_HASH={}", git_hash);
+}
@@ -5,17 +5,17 @@ use bytes::{Buf, BufMut, BytesMut};
 use log::{error, info, trace};
 use nix::sys::signal::{self, Signal};
 use nix::unistd::Pid;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 /// Admin database.
 use std::sync::atomic::Ordering;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::Instant;
 
-use crate::config::{get_config, reload_config, VERSION};
+use crate::config::{get_config, get_reload_status, reload_config, GIT_HASH, VERSION};
 use crate::errors::Error;
 use crate::messages::*;
-use crate::pool::ClientServerMap;
 use crate::pool::{get_all_pools, get_pool};
+use crate::pool::{ClientServerMap, ConnectionPool};
 use crate::stats::{get_client_stats, get_server_stats, ClientState, ServerState};
 
 pub fn generate_server_parameters_for_admin() -> ServerParameters {
@@ -89,6 +89,18 @@ where
             trace!("SHUTDOWN");
             shutdown(stream).await
         }
+        "RESET" => match query_parts
+            .get(1)
+            .unwrap_or(&"")
+            .to_ascii_uppercase()
+            .as_str()
+        {
+            "STATS" => {
+                trace!("RESET STATS");
+                reset_stats(stream).await
+            }
+            _ => error_response(stream, "Unsupported RESET query against the admin database").await,
+        },
         "SHOW" => match query_parts
             .get(1)
             .unwrap_or(&"")
@@ -111,14 +123,26 @@ where
                 trace!("SHOW DATABASES");
                 show_databases(stream).await
             }
+            "LATENCY" => {
+                trace!("SHOW LATENCY");
+                show_latency(stream).await
+            }
             "LISTS" => {
                 trace!("SHOW LISTS");
                 show_lists(stream).await
             }
+            "RELOAD" => {
+                trace!("SHOW RELOAD");
+                show_reload(stream).await
+            }
             "POOLS" => {
                 trace!("SHOW POOLS");
                 show_pools(stream).await
             }
+            "APPLICATIONS" => {
+                trace!("SHOW APPLICATIONS");
+                show_applications(stream).await
+            }
             "CLIENTS" => {
                 trace!("SHOW CLIENTS");
                 show_clients(stream).await
@@ -247,6 +271,46 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Show the config file in use and the outcome of the last reload.
+async fn show_reload<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let status = get_reload_status();
+    let mut res = BytesMut::new();
+
+    res.put(row_description(&vec![
+        ("config_path", DataType::Text),
+        ("last_reload", DataType::Text),
+        ("last_reload_attempt", DataType::Text),
+        ("status", DataType::Text),
+        ("error", DataType::Text),
+    ]));
+    res.put(data_row(&vec![
+        get_config().path,
+        status
+            .last_success
+            .map(|time| time.to_string())
+            .unwrap_or_default(),
+        status
+            .last_attempt
+            .map(|time| time.to_string())
+            .unwrap_or_default(),
+        match status.error {
+            Some(_) => "failed".to_string(),
+            None => "ok".to_string(),
+        },
+        status.error.unwrap_or_default(),
+    ]));
+    res.put(command_complete("SHOW"));
+
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Show PgCat version.
 async fn show_version<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -254,8 +318,14 @@ where
 {
     let mut res = BytesMut::new();
 
-    res.put(row_description(&vec![("version", DataType::Text)]));
-    res.put(data_row(&vec![format!("PgCat {}", VERSION)]));
+    res.put(row_description(&vec![
+        ("version", DataType::Text),
+        ("git_hash", DataType::Text),
+    ]));
+    res.put(data_row(&vec![
+        format!("PgCat {}", VERSION),
+        GIT_HASH.to_string(),
+    ]));
     res.put(command_complete("SHOW"));
 
     res.put_u8(b'Z');
@@ -298,13 +368,17 @@ where
         "SHOW HELP|CONFIG|DATABASES|POOLS|CLIENTS|SERVERS|USERS|VERSION",
         // "SHOW PEERS|PEER_POOLS", // missing PEERS|PEER_POOLS
         // "SHOW FDS|SOCKETS|ACTIVE_SOCKETS|LISTS|MEM|STATE", // missing FDS|SOCKETS|ACTIVE_SOCKETS|MEM|STATE
+        "SHOW APPLICATIONS",
+        "SHOW LATENCY",
         "SHOW LISTS",
+        "SHOW RELOAD",
         // "SHOW DNS_HOSTS|DNS_ZONES", // missing DNS_HOSTS|DNS_ZONES
         "SHOW STATS", // missing STATS_TOTALS|STATS_AVERAGES|TOTALS
         "SET key = arg",
         "RELOAD",
-        "PAUSE [<db>, <user>]",
-        "RESUME [<db>, <user>]",
+        "PAUSE [<db>[, <user>]]",
+        "RESUME [<db>[, <user>]]",
+        "RESET STATS",
         // "DISABLE <db>", // missing
         // "ENABLE <db>", // missing
         // "RECONNECT [<db>]", missing
@@ -342,8 +416,10 @@ where
         ("pool_mode", DataType::Text),
         ("max_connections", DataType::Int4),
         ("current_connections", DataType::Int4),
+        ("in_flight", DataType::Int4),
         ("paused", DataType::Int4),
         ("disabled", DataType::Int4),
+        ("ban_remaining_seconds", DataType::Int4),
     ];
 
     let mut res = BytesMut::new();
@@ -357,31 +433,33 @@ where
             for server in 0..pool.servers(shard) {
                 let address = pool.address(shard, server);
                 let pool_state = pool.pool_state(shard, server);
-                let banned = pool.is_banned(address);
+                let ban_remaining = pool.ban_remaining(address);
                 let paused = pool.paused();
 
                 res.put(data_row(&vec![
-                    address.name(),                                          // name
-                    address.host.to_string(),                                // host
-                    address.port.to_string(),                                // port
-                    database_name.to_string(),                               // database
-                    pool_config.user.username.to_string(),                   // force_user
-                    pool_config.user.pool_size.to_string(),                  // pool_size
-                    pool_config.user.min_pool_size.unwrap_or(0).to_string(), // min_pool_size
-                    "0".to_string(),                                         // reserve_pool
-                    pool_config.pool_mode.to_string(),                       // pool_mode
-                    pool_config.user.pool_size.to_string(),                  // max_connections
-                    pool_state.connections.to_string(),                      // current_connections
+                    address.name(),                                  // name
+                    address.host.to_string(),                        // host
+                    address.port.to_string(),                        // port
+                    database_name.to_string(),                       // database
+                    pool_config.user.username.to_string(),           // force_user
+                    pool.pool_size(shard).to_string(),               // pool_size
+                    pool_config.min_pool_size.to_string(),           // min_pool_size
+                    "0".to_string(),                                 // reserve_pool
+                    pool_config.pool_mode.to_string(),               // pool_mode
+                    pool.pool_size(shard).to_string(),               // max_connections
+                    pool_state.connections.to_string(),              // current_connections
+                    pool.busy_connection_count(address).to_string(), // in_flight
                     match paused {
                         // paused
                         true => "1".to_string(),
                         false => "0".to_string(),
                     },
-                    match banned {
+                    match ban_remaining {
                         // disabled
-                        true => "1".to_string(),
-                        false => "0".to_string(),
+                        Some(_) => "1".to_string(),
+                        None => "0".to_string(),
                     },
+                    ban_remaining.unwrap_or(0).to_string(), // ban_remaining_seconds
                 ]));
             }
         }
@@ -412,7 +490,9 @@ where
 {
     let host = match tokens.get(1) {
         Some(host) => host,
-        None => return error_response(stream, "usage: BAN hostname duration_seconds").await,
+        None => {
+            return error_response(stream, "usage: BAN host|server_name duration_seconds").await
+        }
     };
 
     let duration_seconds = match tokens.get(2) {
@@ -422,11 +502,13 @@ where
                 return error_response(stream, "duration_seconds must be an integer").await;
             }
         },
-        None => return error_response(stream, "usage: BAN hostname duration_seconds").await,
+        None => {
+            return error_response(stream, "usage: BAN host|server_name duration_seconds").await
+        }
     };
 
     if duration_seconds <= 0 {
-        return error_response(stream, "duration_seconds must be >= 0").await;
+        return error_response(stream, "duration_seconds must be > 0").await;
     }
 
     let columns = vec![
@@ -434,6 +516,7 @@ where
         ("user", DataType::Text),
         ("role", DataType::Text),
         ("host", DataType::Text),
+        ("name", DataType::Text),
     ];
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
@@ -446,7 +529,8 @@ where
                     id.db.clone(),
                     id.user.clone(),
                     address.role.to_string(),
-                    address.host,
+                    address.host.clone(),
+                    address.name(),
                 ]));
             }
         }
@@ -469,7 +553,13 @@ where
 {
     let host = match tokens.get(1) {
         Some(host) => host,
-        None => return error_response(stream, "UNBAN command requires a hostname to unban").await,
+        None => {
+            return error_response(
+                stream,
+                "UNBAN command requires a hostname or server name to unban",
+            )
+            .await
+        }
     };
 
     let columns = vec![
@@ -477,6 +567,7 @@ where
         ("user", DataType::Text),
         ("role", DataType::Text),
         ("host", DataType::Text),
+        ("name", DataType::Text),
     ];
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
@@ -489,7 +580,8 @@ where
                     id.db.clone(),
                     id.user.clone(),
                     address.role.to_string(),
-                    address.host,
+                    address.host.clone(),
+                    address.name(),
                 ]));
             }
         }
@@ -515,6 +607,7 @@ where
         ("user", DataType::Text),
         ("role", DataType::Text),
         ("host", DataType::Text),
+        ("name", DataType::Text),
         ("reason", DataType::Text),
         ("ban_time", DataType::Text),
         ("ban_duration_seconds", DataType::Text),
@@ -533,7 +626,7 @@ where
         for (address, (ban_reason, ban_time)) in pool.get_bans().iter() {
             let ban_duration = match ban_reason {
                 BanReason::AdminBan(duration) => *duration,
-                _ => pool.settings.ban_time,
+                _ => pool.ban_time(address),
             };
             let remaining = ban_duration - (now - ban_time.timestamp());
             if remaining <= 0 {
@@ -544,6 +637,7 @@ where
                 id.user.clone(),
                 address.role.to_string(),
                 address.host.clone(),
+                address.name(),
                 format!("{:?}", ban_reason),
                 ban_time.to_string(),
                 ban_duration.to_string(),
@@ -569,12 +663,27 @@ where
 {
     info!("Reloading config");
 
-    reload_config(client_server_map).await?;
+    // Keep the current config and tell the client why.
+    let changed = match reload_config(client_server_map).await {
+        Ok(changed) => changed,
+        Err(Error::ConfigParseError(err)) => {
+            return error_response(stream, &format!("Config reload failed, {}", err)).await
+        }
+        Err(_) => {
+            return error_response(
+                stream,
+                "Config reload failed, invalid configuration, see the logs for details",
+            )
+            .await
+        }
+    };
 
     get_config().show();
 
     let mut res = BytesMut::new();
 
+    res.put(row_description(&vec![("changed", DataType::Bool)]));
+    res.put(data_row(&vec![if changed { "t" } else { "f" }.to_string()]));
     res.put(command_complete("RELOAD"));
 
     // ReadyForQuery
@@ -585,6 +694,11 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Config keys whose values are not shown by SHOW CONFIG.
+fn is_secret(key: &str) -> bool {
+    key.ends_with("password") || key.ends_with("private_key")
+}
+
 /// Shows current configuration.
 async fn show_config<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -608,8 +722,18 @@ where
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
 
+    let mut config: Vec<(String, String)> = config.into_iter().collect();
+    config.sort();
+
     // DataRow rows
     for (key, value) in config {
+        // Never show secrets, only whether they are set.
+        let value = if is_secret(&key) && !value.is_empty() {
+            "<redacted>".to_string()
+        } else {
+            value
+        };
+
         let changeable = if immutables.iter().filter(|col| *col == &key).count() == 1 {
             "no".to_string()
         } else {
@@ -656,6 +780,10 @@ where
         ("avg_xact_time", DataType::Numeric),
         ("avg_query_time", DataType::Numeric),
         ("avg_wait_time", DataType::Numeric),
+        ("total_server_connect_errors", DataType::Numeric),
+        ("total_query_errors", DataType::Numeric),
+        ("total_pool_timeouts", DataType::Numeric),
+        ("total_client_auth_failures", DataType::Numeric),
     ];
 
     let mut res = BytesMut::new();
@@ -669,6 +797,7 @@ where
                 let mut row = vec![address.name(), user_pool.db.clone(), user_pool.user.clone()];
                 let stats = address.stats.clone();
                 stats.populate_row(&mut row);
+                row.push(pool.auth_failures().to_string());
 
                 res.put(data_row(&row));
             }
@@ -685,6 +814,59 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Show query latency percentiles per pool, over the last stats period.
+async fn show_latency<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("query_count", DataType::Numeric),
+        ("server_p50_us", DataType::Numeric),
+        ("server_p95_us", DataType::Numeric),
+        ("server_p99_us", DataType::Numeric),
+        ("server_max_us", DataType::Numeric),
+        ("client_p50_us", DataType::Numeric),
+        ("client_p95_us", DataType::Numeric),
+        ("client_p99_us", DataType::Numeric),
+        ("client_max_us", DataType::Numeric),
+    ];
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (user_pool, pool) in get_all_pools() {
+        let server = pool.latency.server();
+        let client = pool.latency.client();
+
+        let row = vec![
+            user_pool.db.clone(),
+            user_pool.user.clone(),
+            server.count().to_string(),
+            server.percentile(50.0).to_string(),
+            server.percentile(95.0).to_string(),
+            server.percentile(99.0).to_string(),
+            server.max().to_string(),
+            client.percentile(50.0).to_string(),
+            client.percentile(95.0).to_string(),
+            client.percentile(99.0).to_string(),
+            client.max().to_string(),
+        ];
+
+        res.put(data_row(&row));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Show currently connected clients
 async fn show_clients<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -702,6 +884,12 @@ where
         ("age_seconds", DataType::Numeric),
         ("maxwait", DataType::Numeric),
         ("maxwait_us", DataType::Numeric),
+        ("addr", DataType::Text),
+        ("shard", DataType::Text),
+        ("role", DataType::Text),
+        ("rate_limit", DataType::Numeric),
+        ("rate_limit_available", DataType::Numeric),
+        ("rate_limited_count", DataType::Numeric),
     ];
 
     let new_map = get_client_stats();
@@ -710,6 +898,17 @@ where
 
     for (_, client) in new_map {
         let max_wait = client.max_wait_time.load(Ordering::Relaxed);
+        let (shard, role) = match *client.server.lock() {
+            Some((shard, role)) => (shard.to_string(), role.to_string()),
+            None => (String::new(), String::new()),
+        };
+        let (rate_limit, rate_limit_available) =
+            match get_pool(&client.pool_name(), &client.username())
+                .and_then(|pool| pool.rate_limiter)
+            {
+                Some(rate_limiter) => (rate_limiter.queries_per_second(), rate_limiter.available()),
+                None => (0, 0),
+            };
         let row = vec![
             format!("{:#010X}", client.client_id()),
             client.pool_name(),
@@ -725,6 +924,15 @@ where
                 .to_string(),
             (max_wait / 1_000_000).to_string(),
             (max_wait % 1_000_000).to_string(),
+            client.addr(),
+            shard,
+            role,
+            rate_limit.to_string(),
+            rate_limit_available.to_string(),
+            client
+                .rate_limited_count
+                .load(Ordering::Relaxed)
+                .to_string(),
         ];
 
         res.put(data_row(&row));
@@ -740,6 +948,76 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Show connected clients and the servers they use, grouped by application_name.
+async fn show_applications<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("application_name", DataType::Text),
+        ("cl_idle", DataType::Numeric),
+        ("cl_waiting", DataType::Numeric),
+        ("cl_active", DataType::Numeric),
+        ("sv_active", DataType::Numeric),
+        ("transaction_count", DataType::Numeric),
+        ("query_count", DataType::Numeric),
+        ("error_count", DataType::Numeric),
+    ];
+
+    // cl_idle, cl_waiting, cl_active, sv_active, transaction_count, query_count, error_count
+    let mut applications: BTreeMap<(String, String, String), [u64; 7]> = BTreeMap::new();
+
+    for (_, client) in get_client_stats() {
+        let counts = applications
+            .entry((
+                client.pool_name(),
+                client.username(),
+                client.application_name(),
+            ))
+            .or_default();
+
+        match client.state.load(Ordering::Relaxed) {
+            ClientState::Idle => counts[0] += 1,
+            ClientState::Waiting => counts[1] += 1,
+            ClientState::Active => counts[2] += 1,
+        }
+        counts[4] += client.transaction_count.load(Ordering::Relaxed);
+        counts[5] += client.query_count.load(Ordering::Relaxed);
+        counts[6] += client.error_count.load(Ordering::Relaxed);
+    }
+
+    for (_, server) in get_server_stats() {
+        if server.state.load(Ordering::Relaxed) != ServerState::Active {
+            continue;
+        }
+
+        let application_name = server.application_name.read().clone();
+        applications
+            .entry((server.pool_name(), server.username(), application_name))
+            .or_default()[3] += 1;
+    }
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for ((database, user, application_name), counts) in applications {
+        let mut row = vec![database, user, application_name];
+        row.extend(counts.iter().map(|count| count.to_string()));
+        res.put(data_row(&row));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Show currently connected servers
 async fn show_servers<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -750,8 +1028,14 @@ where
         ("database_name", DataType::Text),
         ("user", DataType::Text),
         ("address_id", DataType::Text),
+        ("host", DataType::Text),
+        ("port", DataType::Numeric),
+        ("role", DataType::Text),
+        ("shard", DataType::Numeric),
         ("application_name", DataType::Text),
         ("state", DataType::Text),
+        ("client_id", DataType::Text),
+        ("backend_pid", DataType::Numeric),
         ("transaction_count", DataType::Numeric),
         ("query_count", DataType::Numeric),
         ("bytes_sent", DataType::Numeric),
@@ -761,6 +1045,10 @@ where
         ("prepare_cache_miss", DataType::Numeric),
         ("prepare_cache_eviction", DataType::Numeric),
         ("prepare_cache_size", DataType::Numeric),
+        ("ban_remaining_seconds", DataType::Numeric),
+        ("ban_time_seconds", DataType::Numeric),
+        ("replica_lag_ms", DataType::Numeric),
+        ("zone", DataType::Text),
     ];
 
     let new_map = get_server_stats();
@@ -768,14 +1056,39 @@ where
     res.put(row_description(&columns));
 
     for (_, server) in new_map {
+        let (ban_remaining, ban_time, replica_lag) =
+            get_pool(&server.pool_name(), &server.username())
+                .and_then(|pool| {
+                    pool.get_addresses_from_host(&server.address_name())
+                        .first()
+                        .map(|address| {
+                            (
+                                pool.ban_remaining(address),
+                                pool.ban_time(address),
+                                address.replica_lag(),
+                            )
+                        })
+                })
+                .unwrap_or_default();
         let application_name = server.application_name.read();
+        let address = server.address();
+        let client_id = match server.client_id.load(Ordering::Relaxed) {
+            0 => String::new(),
+            client_id => format!("{:#010X}", client_id),
+        };
         let row = vec![
             format!("{:#010X}", server.server_id()),
             server.pool_name(),
             server.username(),
             server.address_name(),
+            address.host.clone(),
+            address.port.to_string(),
+            address.role.to_string(),
+            address.shard.to_string(),
             application_name.clone(),
             server.state.load(Ordering::Relaxed).to_string(),
+            client_id,
+            server.backend_pid.load(Ordering::Relaxed).to_string(),
             server.transaction_count.load(Ordering::Relaxed).to_string(),
             server.query_count.load(Ordering::Relaxed).to_string(),
             server.bytes_sent.load(Ordering::Relaxed).to_string(),
@@ -800,6 +1113,10 @@ where
                 .prepared_cache_size
                 .load(Ordering::Relaxed)
                 .to_string(),
+            ban_remaining.unwrap_or(0).to_string(),
+            ban_time.to_string(),
+            replica_lag.to_string(),
+            address.zone.clone().unwrap_or_default(),
         ];
 
         res.put(data_row(&row));
@@ -815,67 +1132,97 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Pools targeted by PAUSE and RESUME: all of them, all pools of a database,
+/// or a single database and user pair.
+fn pools_for_command(tokens: &[&str]) -> Result<Vec<ConnectionPool>, String> {
+    let args = tokens[1..].join(" ");
+    let parts: Vec<&str> = args
+        .split(',')
+        .map(|part| part.trim())
+        .filter(|part| !part.is_empty())
+        .collect();
+
+    match parts.len() {
+        0 => Ok(get_all_pools().into_values().collect()),
+        1 => {
+            let database = parts[0];
+            let pools: Vec<ConnectionPool> = get_all_pools()
+                .into_iter()
+                .filter(|(identifier, _)| identifier.db == database)
+                .map(|(_, pool)| pool)
+                .collect();
+
+            match pools.is_empty() {
+                true => Err(format!("No pool configured for database: {}", database)),
+                false => Ok(pools),
+            }
+        }
+        2 => {
+            let database = parts[0];
+            let user = parts[1];
+
+            match get_pool(database, user) {
+                Some(pool) => Ok(vec![pool]),
+                None => Err(format!(
+                    "No pool configured for database: {}, user: {}",
+                    database, user
+                )),
+            }
+        }
+        _ => Err(format!("usage: {} [db[, user]]", tokens[0].to_uppercase())),
+    }
+}
+
 /// Pause a pool. It won't pass any more queries to the backends.
 async fn pause<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    let parts: Vec<&str> = match tokens.len() == 2 {
-        true => tokens[1].split(',').map(|part| part.trim()).collect(),
-        false => Vec::new(),
+    let pools = match pools_for_command(&tokens) {
+        Ok(pools) => pools,
+        Err(err) => return error_response(stream, &err).await,
     };
 
-    match parts.len() {
-        0 => {
-            for (_, pool) in get_all_pools() {
-                pool.pause();
-            }
+    for pool in pools {
+        pool.pause();
+    }
 
-            let mut res = BytesMut::new();
+    let mut res = BytesMut::new();
 
-            res.put(command_complete("PAUSE"));
+    res.put(command_complete(
+        format!("PAUSE {}", tokens[1..].join(" ")).trim_end(),
+    ));
 
-            // ReadyForQuery
-            res.put_u8(b'Z');
-            res.put_i32(5);
-            res.put_u8(b'I');
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
 
-            write_all_half(stream, &res).await
-        }
-        2 => {
-            let database = parts[0];
-            let user = parts[1];
+    write_all_half(stream, &res).await
+}
 
-            match get_pool(database, user) {
-                Some(pool) => {
-                    pool.pause();
-
-                    let mut res = BytesMut::new();
-
-                    res.put(command_complete(&format!("PAUSE {},{}", database, user)));
-
-                    // ReadyForQuery
-                    res.put_u8(b'Z');
-                    res.put_i32(5);
-                    res.put_u8(b'I');
-
-                    write_all_half(stream, &res).await
-                }
-
-                None => {
-                    error_response(
-                        stream,
-                        &format!(
-                            "No pool configured for database: {}, user: {}",
-                            database, user
-                        ),
-                    )
-                    .await
-                }
-            }
-        }
-        _ => error_response(stream, "usage: PAUSE [db, user]").await,
+/// Zero the cumulative counters of SHOW STATS, e.g. to compute rates between scrapes.
+/// Gauges like the number of active connections are not affected.
+async fn reset_stats<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    for (_, pool) in get_all_pools() {
+        pool.reset_stats();
     }
+
+    info!("Stats reset");
+
+    let mut res = BytesMut::new();
+
+    res.put(command_complete("RESET STATS"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
 }
 
 /// Resume a pool. Queries are allowed again.
@@ -883,62 +1230,27 @@ async fn resume<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    let parts: Vec<&str> = match tokens.len() == 2 {
-        true => tokens[1].split(',').map(|part| part.trim()).collect(),
-        false => Vec::new(),
+    let pools = match pools_for_command(&tokens) {
+        Ok(pools) => pools,
+        Err(err) => return error_response(stream, &err).await,
     };
 
-    match parts.len() {
-        0 => {
-            for (_, pool) in get_all_pools() {
-                pool.resume();
-            }
-
-            let mut res = BytesMut::new();
+    for pool in pools {
+        pool.resume();
+    }
 
-            res.put(command_complete("RESUME"));
+    let mut res = BytesMut::new();
 
-            // ReadyForQuery
-            res.put_u8(b'Z');
-            res.put_i32(5);
-            res.put_u8(b'I');
+    res.put(command_complete(
+        format!("RESUME {}", tokens[1..].join(" ")).trim_end(),
+    ));
 
-            write_all_half(stream, &res).await
-        }
-        2 => {
-            let database = parts[0];
-            let user = parts[1];
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
 
-            match get_pool(database, user) {
-                Some(pool) => {
-                    pool.resume();
-
-                    let mut res = BytesMut::new();
-
-                    res.put(command_complete(&format!("RESUME {},{}", database, user)));
-
-                    // ReadyForQuery
-                    res.put_u8(b'Z');
-                    res.put_i32(5);
-                    res.put_u8(b'I');
-
-                    write_all_half(stream, &res).await
-                }
-
-                None => {
-                    error_response(
-                        stream,
-                        &format!(
-                            "No pool configured for database: {}, user: {}",
-                            database, user
-                        ),
-                    )
-                    .await
-                }
-            }
-        }
-        _ => error_response(stream, "usage: RESUME [db, user]").await,
-    }
+    write_all_half(stream, &res).await
 }
 
 /// Send response packets for shutdown.
@@ -1,6 +1,7 @@
 use crate::config::AuthType;
 use crate::errors::Error;
 use crate::pool::ConnectionPool;
+use crate::scram::ScramVerifier;
 use crate::server::Server;
 use log::debug;
 
@@ -76,13 +77,18 @@ impl AuthPassthrough {
             password: Some(self.password.clone()),
             server_username: None,
             server_password: None,
-            pool_size: 1,
+            pool_size: Some(1),
             statement_timeout: 0,
             pool_mode: None,
             server_lifetime: None,
             min_pool_size: None,
             connect_timeout: None,
             idle_timeout: None,
+            max_queries_per_second: None,
+            rate_limit_wait_timeout: 0,
+            max_client_connections: None,
+            default_role: None,
+            allowed_shards: None,
         };
 
         let user = &address.username;
@@ -92,18 +98,26 @@ impl AuthPassthrough {
         let auth_query = self.query.replace("$1", user);
 
         match Server::exec_simple_query(address, &auth_user, &auth_query).await {
+            // The role doesn't exist on the server.
+            Ok(password_data) if password_data.is_empty() => {
+                Err(Error::AuthPassthroughError(
+                    format!("User '{}' not found using auth_query", user)
+                ))
+            }
             Ok(password_data) => {
                 if password_data.len() == 2 && password_data.first().unwrap() == user {
-                    if let Some(stripped_hash) = password_data
-                        .last()
-                        .unwrap()
-                        .to_string()
-                        .strip_prefix("md5") {
+                    let hash = password_data.last().unwrap().to_string();
+
+                    if let Some(stripped_hash) = hash.strip_prefix("md5") {
                             Ok(stripped_hash.to_string())
                         }
+                    // SCRAM verifiers can be used to authenticate clients with SCRAM-SHA-256.
+                    else if ScramVerifier::is_verifier(&hash) {
+                        Ok(hash)
+                    }
                     else {
                         Err(Error::AuthPassthroughError(
-                            "Obtained hash from auth_query does not seem to be in md5 format.".to_string(),
+                            "Obtained hash from auth_query does not seem to be in md5 or SCRAM-SHA-256 format.".to_string(),
                         ))
                     }
                 } else {
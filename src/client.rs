@@ -1,28 +1,42 @@
 use crate::errors::{ClientIdentifier, Error};
 use crate::pool::BanReason;
 /// Handle clients by pretending to be a PostgreSQL server.
+use bb8::PooledConnection;
 use bytes::{Buf, BufMut, BytesMut};
 use log::{debug, error, info, trace, warn};
+use md5::{Digest, Md5};
 use once_cell::sync::Lazy;
+use std::borrow::Cow;
 use std::collections::{HashMap, VecDeque};
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 use tokio::io::{split, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UnixStream};
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 
 use crate::admin::{generate_server_parameters_for_admin, handle_admin};
 use crate::auth_passthrough::refetch_auth_hash;
 use crate::config::{
-    get_config, get_idle_client_in_transaction_timeout, Address, AuthType, PoolMode,
+    get_backend_application_name, get_config, get_error_verbosity,
+    get_idle_client_in_transaction_timeout, get_log_min_duration, get_log_query_text,
+    get_pause_timeout, get_set_backend_client_addr, get_statement_timeout, Address, AuthType,
+    CrossShardTransactions, ErrorVerbosity, HbaMethod, PoolMode, Role,
 };
 use crate::constants::*;
 use crate::messages::*;
 use crate::plugins::PluginOutput;
-use crate::pool::{get_pool, ClientServerMap, ConnectionPool};
-use crate::query_router::{Command, QueryRouter};
-use crate::server::{Server, ServerParameters};
+use crate::pool::{
+    get_all_pools, get_pool, ClientServerMap, ClientSlot, ConnectionPool, ServerPool,
+};
+use crate::query_router::{Command, QueryRouter, SetStatement};
+use crate::query_tracing::QuerySpan;
+use crate::scatter_gather::{self, ShardError, ShardResponse};
+use crate::scram::{ScramServer, ScramVerifier};
+use crate::server::{parse_startup_options, Server, ServerParameters, BACKEND_CLIENT_ADDR};
 use crate::stats::{ClientStats, ServerStats};
 use crate::tls::Tls;
 
@@ -33,6 +47,19 @@ use tokio_rustls::server::TlsStream;
 pub static PREPARED_STATEMENT_COUNTER: Lazy<Arc<AtomicUsize>> =
     Lazy::new(|| Arc::new(AtomicUsize::new(0)));
 
+/// Listener the client connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listener {
+    /// `port`, serving the pools, and the admin database unless `admin_port` is set.
+    Main,
+
+    /// `admin_port`, serving only the admin database.
+    Admin,
+
+    /// The Unix socket, serving the same databases as `port`.
+    Unix,
+}
+
 /// Type of connection received from client.
 enum ClientConnectionType {
     Startup,
@@ -40,6 +67,87 @@ enum ClientConnectionType {
     CancelQuery,
 }
 
+/// A scatter-gather query running on a server of a shard.
+struct ShardQuery<'a> {
+    connection: PooledConnection<'a, ServerPool>,
+    address: Address,
+    statement_timeout: Option<StatementTimeout>,
+    start: Instant,
+}
+
+impl ShardQuery<'_> {
+    fn server(&mut self) -> &mut Server {
+        &mut self.connection
+    }
+
+    /// The server answered the whole query.
+    fn finish(&mut self, server_parameters: &ServerParameters) {
+        if let Some(statement_timeout) = self.statement_timeout.take() {
+            statement_timeout.disarm(&mut self.connection);
+        }
+
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.connection
+            .stats()
+            .query(elapsed, server_parameters.get_application_name());
+    }
+}
+
+/// Cancels the query running on a server once `statement_timeout` expires.
+/// Dropping it disarms the timeout.
+struct StatementTimeout {
+    task: JoinHandle<()>,
+
+    /// Set by whichever comes first: the timeout firing, or the query completing.
+    done: Arc<AtomicBool>,
+}
+
+impl StatementTimeout {
+    fn new(server: &Server, timeout: u64) -> StatementTimeout {
+        let address = server.address();
+        let (process_id, secret_key) = server.cancel_key();
+        let done = Arc::new(AtomicBool::new(false));
+        let fired = done.clone();
+
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(tokio::time::Duration::from_millis(timeout)).await;
+
+            // The query completed in the meantime.
+            if fired.swap(true, Ordering::AcqRel) {
+                return;
+            }
+
+            warn!(
+                "Statement timeout of {}ms reached on {:?}, cancelling the query",
+                timeout, address
+            );
+
+            if let Err(err) =
+                Server::cancel(&address.host, address.port, process_id, secret_key).await
+            {
+                error!("Could not cancel the query on {:?}: {:?}", address, err);
+            }
+        });
+
+        StatementTimeout { task, done }
+    }
+
+    /// Disarm the timeout once the query completed. If it fired already, the cancel request
+    /// can reach the server after the query, and cancel the next one running on the connection,
+    /// so the connection can't be reused.
+    fn disarm(self, server: &mut Server) {
+        if self.done.swap(true, Ordering::AcqRel) {
+            server.mark_bad("statement timeout fired, a cancel request may be in flight");
+        }
+    }
+}
+
+impl Drop for StatementTimeout {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
 /// The client state. One of these is created per client.
 pub struct Client<S, T> {
     /// The reads are buffered (8K by default).
@@ -87,9 +195,19 @@ pub struct Client<S, T> {
     /// Last address the client talked to.
     last_address_id: Option<usize>,
 
+    /// When the last message from the client was received.
+    last_message_time: Instant,
+
     /// Last server process stats we talked to.
     last_server_stats: Option<Arc<ServerStats>>,
 
+    /// Tracing span of the query currently running, if tracing is enabled.
+    query_span: QuerySpan,
+
+    /// Session-level SET statements replayed on every server we check out,
+    /// when `track_set_statements` is enabled.
+    set_statements: Vec<(String, String)>,
+
     /// Connected to server
     connected_to_server: bool,
 
@@ -113,11 +231,23 @@ pub struct Client<S, T> {
 
     /// Buffered extended protocol data
     extended_protocol_data_buffer: VecDeque<ExtendedProtocolData>,
+
+    /// Counts the client towards the user's max_client_connections until it disconnects.
+    _client_slot: Option<ClientSlot>,
+}
+
+/// Socket of a client, accepted on one of the TCP listeners or on the Unix socket.
+pub enum ClientSocket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
 }
 
 /// Client entrypoint.
+#[allow(clippy::too_many_arguments)]
 pub async fn client_entrypoint(
-    mut stream: TcpStream,
+    socket: ClientSocket,
+    addr: std::net::SocketAddr,
+    listener: Listener,
     client_server_map: ClientServerMap,
     shutdown: Receiver<()>,
     drain: Sender<i32>,
@@ -125,126 +255,136 @@ pub async fn client_entrypoint(
     tls_certificate: Option<String>,
     log_client_connections: bool,
 ) -> Result<(), Error> {
-    // Figure out if the client wants TLS or not.
-    let addr = match stream.peer_addr() {
-        Ok(addr) => addr,
-        Err(err) => {
-            return Err(Error::SocketError(format!(
-                "Failed to get peer address: {:?}",
-                err
-            )));
+    match socket {
+        ClientSocket::Tcp(stream) => {
+            serve_client(
+                stream,
+                addr,
+                listener,
+                client_server_map,
+                shutdown,
+                drain,
+                admin_only,
+                tls_certificate,
+                log_client_connections,
+            )
+            .await
         }
-    };
 
-    match get_startup::<TcpStream>(&mut stream).await {
-        // Client requested a TLS connection.
-        Ok((ClientConnectionType::Tls, _)) => {
-            // TLS settings are configured, will setup TLS now.
-            if tls_certificate.is_some() {
-                debug!("Accepting TLS request");
-
-                let mut yes = BytesMut::new();
-                yes.put_u8(b'S');
-                write_all(&mut stream, yes).await?;
-
-                // Negotiate TLS.
-                match startup_tls(stream, client_server_map, shutdown, admin_only).await {
-                    Ok(mut client) => {
-                        if log_client_connections {
-                            info!("Client {:?} connected (TLS)", addr);
-                        } else {
-                            debug!("Client {:?} connected (TLS)", addr);
-                        }
+        // TLS isn't offered over Unix sockets, like Postgres.
+        ClientSocket::Unix(stream) => {
+            serve_client(
+                stream,
+                addr,
+                listener,
+                client_server_map,
+                shutdown,
+                drain,
+                admin_only,
+                None,
+                log_client_connections,
+            )
+            .await
+        }
+    }
+}
 
-                        if !client.is_admin() {
-                            let _ = drain.send(1).await;
-                        }
+/// Negotiate TLS if the client asks for it and it's configured,
+/// then run the client until it disconnects.
+#[allow(clippy::too_many_arguments)]
+async fn serve_client<S>(
+    mut stream: S,
+    addr: std::net::SocketAddr,
+    listener: Listener,
+    client_server_map: ClientServerMap,
+    shutdown: Receiver<()>,
+    drain: Sender<i32>,
+    admin_only: bool,
+    tls_certificate: Option<String>,
+    log_client_connections: bool,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    // Unix socket clients don't have an address, they're local.
+    let peer = match listener {
+        Listener::Unix => String::from("unix socket"),
+        _ => format!("{:?}", addr),
+    };
 
-                        let result = client.handle().await;
+    let (connection_type, bytes) = match get_startup::<S>(&mut stream).await? {
+        // Client requested a TLS connection and TLS settings are configured, will setup TLS now.
+        (ClientConnectionType::Tls, _) if tls_certificate.is_some() => {
+            debug!("Accepting TLS request");
 
-                        if !client.is_admin() {
-                            let _ = drain.send(-1).await;
-                        }
+            let mut yes = BytesMut::new();
+            yes.put_u8(b'S');
+            write_all(&mut stream, yes).await?;
 
-                        if result.is_err() {
-                            client.stats.disconnect();
-                        }
+            // Negotiate TLS.
+            let client = startup_tls(
+                stream,
+                addr,
+                client_server_map,
+                shutdown,
+                admin_only,
+                listener,
+            )
+            .await?;
 
-                        result
-                    }
-                    Err(err) => Err(err),
-                }
+            if log_client_connections {
+                info!("Client {} connected (TLS)", peer);
+            } else {
+                debug!("Client {} connected (TLS)", peer);
             }
-            // TLS is not configured, we cannot offer it.
-            else {
-                // Rejecting client request for TLS.
-                let mut no = BytesMut::new();
-                no.put_u8(b'N');
-                write_all(&mut stream, no).await?;
-
-                // Attempting regular startup. Client can disconnect now
-                // if they choose.
-                match get_startup::<TcpStream>(&mut stream).await {
-                    // Client accepted unencrypted connection.
-                    Ok((ClientConnectionType::Startup, bytes)) => {
-                        let (read, write) = split(stream);
-
-                        // Continue with regular startup.
-                        match Client::startup(
-                            read,
-                            write,
-                            addr,
-                            bytes,
-                            client_server_map,
-                            shutdown,
-                            admin_only,
-                        )
-                        .await
-                        {
-                            Ok(mut client) => {
-                                if log_client_connections {
-                                    info!("Client {:?} connected (plain)", addr);
-                                } else {
-                                    debug!("Client {:?} connected (plain)", addr);
-                                }
-
-                                if !client.is_admin() {
-                                    let _ = drain.send(1).await;
-                                }
-
-                                let result = client.handle().await;
-
-                                if !client.is_admin() {
-                                    let _ = drain.send(-1).await;
-                                }
-
-                                if result.is_err() {
-                                    client.stats.disconnect();
-                                }
 
-                                result
-                            }
-                            Err(err) => Err(err),
-                        }
-                    }
+            return run_client(client, drain).await;
+        }
 
-                    // Client probably disconnected rejecting our plain text connection.
-                    Ok((ClientConnectionType::Tls, _))
-                    | Ok((ClientConnectionType::CancelQuery, _)) => Err(Error::ProtocolSyncError(
+        // TLS is not configured, we cannot offer it.
+        // Client can disconnect now if they choose, or go on with a regular startup.
+        (ClientConnectionType::Tls, _) => {
+            let mut no = BytesMut::new();
+            no.put_u8(b'N');
+            write_all(&mut stream, no).await?;
+
+            match get_startup::<S>(&mut stream).await? {
+                // Client probably disconnected rejecting our plain text connection.
+                (ClientConnectionType::Tls, _) => {
+                    return Err(Error::ProtocolSyncError(
                         "Bad postgres client (plain)".into(),
-                    )),
-
-                    Err(err) => Err(err),
+                    ))
                 }
+
+                startup => startup,
             }
         }
 
+        startup => startup,
+    };
+
+    match connection_type {
         // Client wants to use plain connection without encryption.
-        Ok((ClientConnectionType::Startup, bytes)) => {
+        ClientConnectionType::Startup => {
+            // Clients must authenticate with a certificate, which requires TLS.
+            if get_config().general.require_client_certificate {
+                error_response_terminal_with_code(
+                    &mut stream,
+                    "SSL connection with a client certificate is required",
+                    INVALID_AUTHORIZATION_SPECIFICATION,
+                )
+                .await?;
+
+                return Err(Error::ClientError(format!(
+                    "Client {} attempted a plain connection, but a client certificate is required",
+                    peer
+                )));
+            }
+
             let (read, write) = split(stream);
 
             // Continue with regular startup.
-            match Client::startup(
+            let client = Client::startup(
                 read,
                 write,
                 addr,
@@ -252,129 +392,228 @@ pub async fn client_entrypoint(
                 client_server_map,
                 shutdown,
                 admin_only,
+                listener,
             )
-            .await
-            {
-                Ok(mut client) => {
-                    if log_client_connections {
-                        info!("Client {:?} connected (plain)", addr);
-                    } else {
-                        debug!("Client {:?} connected (plain)", addr);
-                    }
-
-                    if !client.is_admin() {
-                        let _ = drain.send(1).await;
-                    }
-
-                    let result = client.handle().await;
-
-                    if !client.is_admin() {
-                        let _ = drain.send(-1).await;
-                    }
-
-                    if result.is_err() {
-                        client.stats.disconnect();
-                    }
+            .await?;
 
-                    result
-                }
-                Err(err) => Err(err),
+            if log_client_connections {
+                info!("Client {} connected (plain)", peer);
+            } else {
+                debug!("Client {} connected (plain)", peer);
             }
+
+            run_client(client, drain).await
         }
 
         // Client wants to cancel a query.
-        Ok((ClientConnectionType::CancelQuery, bytes)) => {
+        ClientConnectionType::CancelQuery => {
             let (read, write) = split(stream);
 
             // Continue with cancel query request.
-            match Client::cancel(read, write, addr, bytes, client_server_map, shutdown).await {
-                Ok(mut client) => {
-                    info!("Client {:?} issued a cancel query request", addr);
+            let client =
+                Client::cancel(read, write, addr, bytes, client_server_map, shutdown).await?;
 
-                    if !client.is_admin() {
-                        let _ = drain.send(1).await;
-                    }
+            info!("Client {} issued a cancel query request", peer);
+
+            run_client(client, drain).await
+        }
 
-                    let result = client.handle().await;
+        ClientConnectionType::Tls => unreachable!("TLS requests are answered above"),
+    }
+}
 
-                    if !client.is_admin() {
-                        let _ = drain.send(-1).await;
-                    }
+/// Handle the client's queries until it disconnects. Clients of the pools
+/// are counted, so a graceful shutdown can wait for them to finish.
+async fn run_client<S, T>(mut client: Client<S, T>, drain: Sender<i32>) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    if !client.is_admin() {
+        let _ = drain.send(1).await;
+    }
 
-                    if result.is_err() {
-                        client.stats.disconnect();
-                    }
+    let result = client.handle().await;
+
+    if !client.is_admin() {
+        let _ = drain.send(-1).await;
+    }
+
+    if result.is_err() {
+        client.stats.disconnect();
+    }
+
+    result
+}
 
-                    result
+/// Handle the first message the client sends.
+async fn get_startup<S>(stream: &mut S) -> Result<(ClientConnectionType, BytesMut), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin + tokio::io::AsyncWrite,
+{
+    loop {
+        // Get startup message length.
+        let len = match stream.read_i32().await {
+            Ok(len) => len,
+            Err(_) => return Err(Error::ClientBadStartup),
+        };
+
+        // Not a Postgres client, or a garbled startup packet.
+        if !(8..=MAX_STARTUP_PACKET_LENGTH).contains(&len) {
+            error_response_terminal_with_code(
+                stream,
+                "invalid length of startup packet",
+                PROTOCOL_VIOLATION,
+            )
+            .await?;
+            return Err(Error::ClientBadStartup);
+        }
+
+        // Get the rest of the message.
+        let mut startup = vec![0u8; len as usize - 4];
+        match stream.read_exact(&mut startup).await {
+            Ok(_) => (),
+            Err(_) => return Err(Error::ClientBadStartup),
+        };
+
+        let mut bytes = BytesMut::from(&startup[..]);
+        let code = bytes.get_i32();
+
+        match code {
+            // Client is requesting SSL (TLS).
+            SSL_REQUEST_CODE => return Ok((ClientConnectionType::Tls, bytes)),
+
+            // Client is requesting GSSAPI encryption, which we don't support.
+            // It can go on without it and send the startup message or an SSLRequest.
+            GSSENC_REQUEST_CODE => {
+                if get_config().general.reject_gss_encryption {
+                    error_response_terminal_with_code(
+                        stream,
+                        "GSSAPI encryption is not supported, connect with gssencmode=disable",
+                        FEATURE_NOT_SUPPORTED,
+                    )
+                    .await?;
+                    return Err(Error::ClientError("GSSAPI encryption requested".into()));
                 }
 
-                Err(err) => Err(err),
+                debug!("Rejecting GSSAPI encryption request");
+
+                let mut no = BytesMut::new();
+                no.put_u8(b'N');
+                write_all(stream, no).await?;
+            }
+
+            // Client wants to use plain text, requesting regular startup.
+            PROTOCOL_VERSION_NUMBER => return Ok((ClientConnectionType::Startup, bytes)),
+
+            // Client is requesting to cancel a running query (plain text connection).
+            CANCEL_REQUEST_CODE => return Ok((ClientConnectionType::CancelQuery, bytes)),
+
+            // Another protocol version, e.g. a protocol 2.0 client,
+            // or something that's not a Postgres client at all.
+            _ => {
+                unsupported_protocol_version(stream, code).await?;
+
+                return Err(Error::ProtocolSyncError(format!(
+                    "Unsupported protocol version: {}.{}",
+                    code >> 16,
+                    code & 0xffff
+                )));
             }
         }
+    }
+}
 
-        // Something failed, probably the socket.
-        Err(err) => Err(err),
+/// The `replication` startup parameter of a replication client, e.g. `true` or `database`.
+fn replication_mode(parameters: &HashMap<String, String>) -> Option<&str> {
+    match parameters.get("replication") {
+        Some(value) if !matches!(value.to_lowercase().as_str(), "false" | "off" | "no" | "0") => {
+            Some(value.as_str())
+        }
+        _ => None,
     }
 }
 
-/// Handle the first message the client sends.
-async fn get_startup<S>(stream: &mut S) -> Result<(ClientConnectionType, BytesMut), Error>
+/// The `target_session_attrs` the client sent as a startup parameter or in `options`,
+/// e.g. `options=-c target_session_attrs=read-only` with libpq.
+fn target_session_attrs(parameters: &HashMap<String, String>) -> Option<String> {
+    parameters.get("target_session_attrs").cloned().or_else(|| {
+        parse_startup_options(parameters.get("options")?)
+            .into_iter()
+            .find(|(key, _)| key == "target_session_attrs")
+            .map(|(_, value)| value)
+    })
+}
+
+/// Authenticate the client using SCRAM-SHA-256 against the configured
+/// password, which can be either in cleartext or a SCRAM verifier.
+async fn scram_authentication<S, T>(
+    read: &mut S,
+    write: &mut T,
+    password: &str,
+    username: &str,
+    client_identifier: &ClientIdentifier,
+) -> Result<(), Error>
 where
-    S: tokio::io::AsyncRead + std::marker::Unpin + tokio::io::AsyncWrite,
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    // Get startup message length.
-    let len = match stream.read_i32().await {
-        Ok(len) => len,
-        Err(_) => return Err(Error::ClientBadStartup),
-    };
+    let mut scram = ScramServer::new(password)?;
+
+    sasl_challenge(write).await?;
+
+    // SASLInitialResponse
+    let (mechanism, client_first) = parse_sasl_initial_response(read_message(read).await?)?;
+
+    if mechanism != SCRAM_SHA_256 {
+        wrong_password(write, username).await?;
+        return Err(Error::ClientGeneralError(
+            format!("Unsupported SASL mechanism: {}", mechanism),
+            client_identifier.clone(),
+        ));
+    }
 
-    // Get the rest of the message.
-    let mut startup = vec![0u8; len as usize - 4];
-    match stream.read_exact(&mut startup).await {
-        Ok(_) => (),
-        Err(_) => return Err(Error::ClientBadStartup),
+    let server_first = match scram.server_first(&client_first) {
+        Ok(server_first) => server_first,
+        Err(err) => {
+            wrong_password(write, username).await?;
+            return Err(err);
+        }
     };
 
-    let mut bytes = BytesMut::from(&startup[..]);
-    let code = bytes.get_i32();
+    sasl_continue(write, &server_first).await?;
 
-    match code {
-        // Client is requesting SSL (TLS).
-        SSL_REQUEST_CODE => Ok((ClientConnectionType::Tls, bytes)),
+    // SASLResponse
+    let client_final = parse_sasl_response(read_message(read).await?)?;
 
-        // Client wants to use plain text, requesting regular startup.
-        PROTOCOL_VERSION_NUMBER => Ok((ClientConnectionType::Startup, bytes)),
+    match scram.server_final(&client_final) {
+        Ok(server_final) => sasl_final(write, &server_final).await,
+        Err(_) => {
+            let error =
+                Error::ClientGeneralError("Invalid password".into(), client_identifier.clone());
 
-        // Client is requesting to cancel a running query (plain text connection).
-        CANCEL_REQUEST_CODE => Ok((ClientConnectionType::CancelQuery, bytes)),
+            warn!("{}", error);
+            wrong_password(write, username).await?;
 
-        // Something else, probably something is wrong and it's not our fault,
-        // e.g. badly implemented Postgres client.
-        _ => Err(Error::ProtocolSyncError(format!(
-            "Unexpected startup code: {}",
-            code
-        ))),
+            Err(error)
+        }
     }
 }
 
 /// Handle TLS connection negotiation.
-pub async fn startup_tls(
-    stream: TcpStream,
+pub async fn startup_tls<S>(
+    stream: S,
+    addr: std::net::SocketAddr,
     client_server_map: ClientServerMap,
     shutdown: Receiver<()>,
     admin_only: bool,
-) -> Result<Client<ReadHalf<TlsStream<TcpStream>>, WriteHalf<TlsStream<TcpStream>>>, Error> {
+    listener: Listener,
+) -> Result<Client<ReadHalf<TlsStream<S>>, WriteHalf<TlsStream<S>>>, Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+{
     // Negotiate TLS.
     let tls = Tls::new()?;
-    let addr = match stream.peer_addr() {
-        Ok(addr) => addr,
-        Err(err) => {
-            return Err(Error::SocketError(format!(
-                "Failed to get peer address: {:?}",
-                err
-            )));
-        }
-    };
 
     let mut stream = match tls.acceptor.accept(stream).await {
         Ok(stream) => stream,
@@ -387,8 +626,20 @@ pub async fn startup_tls(
     };
 
     // TLS negotiation successful.
+    {
+        let (_, connection) = stream.get_ref();
+        debug!(
+            "Client {:?} negotiated {:?} with {:?}",
+            addr,
+            connection.protocol_version(),
+            connection
+                .negotiated_cipher_suite()
+                .map(|suite| suite.suite()),
+        );
+    }
+
     // Continue with regular startup using encrypted connection.
-    match get_startup::<TlsStream<TcpStream>>(&mut stream).await {
+    match get_startup::<TlsStream<S>>(&mut stream).await {
         // Got good startup message, proceeding like normal except we
         // are encrypted now.
         Ok((ClientConnectionType::Startup, bytes)) => {
@@ -402,6 +653,7 @@ pub async fn startup_tls(
                 client_server_map,
                 shutdown,
                 admin_only,
+                listener,
             )
             .await
         }
@@ -426,6 +678,7 @@ where
 
     /// Handle Postgres client startup after TLS negotiation is complete
     /// or over plain text.
+    #[allow(clippy::too_many_arguments)]
     pub async fn startup(
         mut read: S,
         mut write: T,
@@ -434,6 +687,7 @@ where
         client_server_map: ClientServerMap,
         shutdown: Receiver<()>,
         admin_only: bool,
+        listener: Listener,
     ) -> Result<Client<S, T>, Error> {
         let parameters = parse_startup(bytes.clone())?;
 
@@ -454,7 +708,7 @@ where
 
         let application_name = match parameters.get("application_name") {
             Some(application_name) => application_name,
-            None => "pgcat",
+            None => "",
         };
 
         let client_identifier = ClientIdentifier::new(application_name, username, pool_name);
@@ -471,14 +725,96 @@ where
                 "Rejecting non-admin connection to {} when in admin only mode",
                 pool_name
             );
-            error_response_terminal(
+            error_response_terminal_with_code(
                 &mut write,
                 "terminating connection due to administrator command",
+                ADMIN_SHUTDOWN,
             )
             .await?;
             return Err(Error::ShuttingDown);
         }
 
+        // With admin_port, the admin database and the pools are served on separate ports.
+        let wrong_listener = match listener {
+            Listener::Admin if !admin => Some("only the admin database is served on admin_port"),
+            Listener::Main | Listener::Unix
+                if admin && get_config().general.admin_port.is_some() =>
+            {
+                Some("the admin database is only served on admin_port")
+            }
+            _ => None,
+        };
+
+        if let Some(message) = wrong_listener {
+            error_response_terminal_with_code(
+                &mut write,
+                message,
+                INVALID_AUTHORIZATION_SPECIFICATION,
+            )
+            .await?;
+
+            return Err(Error::ClientGeneralError(
+                format!("Rejected: {}", message),
+                client_identifier,
+            ));
+        }
+
+        // Checked before authentication, like pg_hba.conf.
+        // Unix socket clients don't have an address, they only match `local` rules.
+        let peer_ip = match listener {
+            Listener::Unix => None,
+            _ => Some(addr.ip()),
+        };
+        let hba_method = get_config().hba_method(username, pool_name, peer_ip);
+
+        if matches!(hba_method, None | Some(HbaMethod::Reject)) {
+            let reason = if hba_method.is_none() {
+                "no hba entry"
+            } else {
+                "hba rules reject connection"
+            };
+
+            error_response_terminal_with_code(
+                &mut write,
+                &format!(
+                    "{} for host \"{}\", user \"{}\", database \"{}\"",
+                    reason,
+                    peer_ip.map_or(String::from("[local]"), |ip| ip.to_string()),
+                    username,
+                    pool_name
+                ),
+                INVALID_AUTHORIZATION_SPECIFICATION,
+            )
+            .await?;
+
+            return Err(Error::ClientGeneralError(
+                format!("Rejected by hba rules ({})", reason),
+                client_identifier,
+            ));
+        }
+
+        let trusted = hba_method == Some(HbaMethod::Trust);
+
+        // Replication clients can only be connected directly to the primary, if the pool allows it.
+        if replication_mode(&parameters).is_some()
+            && (admin
+                || !get_pool(pool_name, username)
+                    .is_some_and(|pool| pool.settings.replication_passthrough))
+        {
+            error_response_terminal_with_code(
+                &mut write,
+                "replication connections are not supported by the pooler, \
+                connect to the server directly",
+                FEATURE_NOT_SUPPORTED,
+            )
+            .await?;
+
+            return Err(Error::ClientGeneralError(
+                "Replication connection rejected".into(),
+                client_identifier,
+            ));
+        }
+
         // Generate random backend ID and secret key
         let process_id: i32 = rand::random();
         let secret_key: i32 = rand::random();
@@ -486,11 +822,17 @@ where
         let mut prepared_statements_enabled = false;
 
         // Authenticate admin user.
-        let (transaction_mode, mut server_parameters) = if admin {
+        let (transaction_mode, mut server_parameters, client_slot) = if admin {
             let config = get_config();
             // TODO: Add SASL support.
             // Perform MD5 authentication.
-            match config.general.admin_auth_type {
+            let auth_type = if trusted {
+                AuthType::Trust
+            } else {
+                config.general.admin_auth_type
+            };
+
+            match auth_type {
                 AuthType::Trust => (),
                 AuthType::MD5 => {
                     let salt = md5_challenge(&mut write).await?;
@@ -552,20 +894,42 @@ where
                         return Err(error);
                     }
                 }
+                AuthType::ScramSha256 => {
+                    scram_authentication(
+                        &mut read,
+                        &mut write,
+                        &config.general.admin_password,
+                        username,
+                        &client_identifier,
+                    )
+                    .await?;
+                }
             }
-            (false, generate_server_parameters_for_admin())
+            (false, generate_server_parameters_for_admin(), None)
         }
         // Authenticate normal user.
         else {
             let pool = match get_pool(pool_name, username) {
                 Some(pool) => pool,
+
+                // Each pool is a database with its own shards, users connect to one by its name.
+                None if !get_all_pools().keys().any(|id| &id.db == pool_name) => {
+                    database_does_not_exist(&mut write, pool_name).await?;
+
+                    return Err(Error::ClientGeneralError(
+                        "Invalid database name".into(),
+                        client_identifier,
+                    ));
+                }
+
                 None => {
-                    error_response(
+                    error_response_with_code(
                         &mut write,
                         &format!(
                             "No pool configured for database: {:?}, user: {:?}",
                             pool_name, username
                         ),
+                        INVALID_CATALOG_NAME,
                     )
                     .await?;
 
@@ -579,7 +943,13 @@ where
             // Obtain the hash to compare, we give preference to that written in cleartext in config
             // if there is nothing set in cleartext and auth passthrough (auth_query) is configured, we use the hash obtained
             // when the pool was created. If there is no hash there, we try to fetch it one more time.
-            match pool.settings.user.auth_type {
+            let auth_type = if trusted {
+                AuthType::Trust
+            } else {
+                pool.settings.user.auth_type
+            };
+
+            match auth_type {
                 AuthType::Trust => (),
                 AuthType::MD5 => {
                     // Perform MD5 authentication.
@@ -630,17 +1000,18 @@ where
                         Some(md5_hash_password(username, password, &salt))
                     } else {
                         if !get_config().is_auth_query_configured() {
+                            pool.auth_failure();
                             wrong_password(&mut write, username).await?;
                             return Err(Error::ClientAuthImpossible(username.into()));
                         }
 
-                        let mut hash = (*pool.auth_hash.read()).clone();
+                        let mut hash = pool.cached_auth_hash();
 
                         if hash.is_none() {
                             warn!(
                                 "Query auth configured \
                                   but no hash password found \
-                                  for pool {}. Will try to refetch it.",
+                                  or it expired for pool {}. Will try to refetch it.",
                                 pool_name
                             );
 
@@ -651,15 +1022,13 @@ where
                                         client_identifier
                                     );
 
-                                    {
-                                        let mut pool_auth_hash = pool.auth_hash.write();
-                                        *pool_auth_hash = Some(fetched_hash.clone());
-                                    }
+                                    pool.set_auth_hash(fetched_hash.clone());
 
                                     hash = Some(fetched_hash);
                                 }
 
                                 Err(err) => {
+                                    pool.auth_failure();
                                     wrong_password(&mut write, username).await?;
 
                                     return Err(Error::ClientAuthPassthroughError(
@@ -687,6 +1056,7 @@ where
                         let fetched_hash = match refetch_auth_hash(&pool).await {
                             Ok(fetched_hash) => fetched_hash,
                             Err(err) => {
+                                pool.auth_failure();
                                 wrong_password(&mut write, username).await?;
 
                                 return Err(err);
@@ -702,11 +1072,9 @@ where
                                 client_identifier
                             );
 
-                            {
-                                let mut pool_auth_hash = pool.auth_hash.write();
-                                *pool_auth_hash = Some(fetched_hash);
-                            }
+                            pool.set_auth_hash(fetched_hash);
                         } else {
+                            pool.auth_failure();
                             wrong_password(&mut write, username).await?;
                             return Err(Error::ClientGeneralError(
                                 "Invalid password".into(),
@@ -715,23 +1083,90 @@ where
                         }
                     }
                 }
-            }
-            let transaction_mode = pool.settings.pool_mode == PoolMode::Transaction;
-            prepared_statements_enabled =
-                transaction_mode && pool.prepared_statement_cache.is_some();
-
-            // If the pool hasn't been validated yet,
+                AuthType::ScramSha256 => {
+                    // SCRAM needs either the cleartext password or a SCRAM verifier,
+                    // which auth passthrough can give us if the server stores passwords that way.
+                    let password = match pool.settings.user.password {
+                        Some(ref password) => password.clone(),
+                        None => {
+                            let hash = match pool.cached_auth_hash() {
+                                Some(hash) => Some(hash),
+                                None => match refetch_auth_hash(&pool).await {
+                                    Ok(fetched_hash) => {
+                                        pool.set_auth_hash(fetched_hash.clone());
+                                        Some(fetched_hash)
+                                    }
+                                    Err(err) => {
+                                        debug!("Could not obtain password hash: {}", err);
+                                        None
+                                    }
+                                },
+                            };
+
+                            match hash {
+                                Some(hash) if ScramVerifier::is_verifier(&hash) => hash,
+                                _ => {
+                                    pool.auth_failure();
+                                    wrong_password(&mut write, username).await?;
+                                    return Err(Error::ClientAuthImpossible(username.into()));
+                                }
+                            }
+                        }
+                    };
+
+                    if let Err(err) = scram_authentication(
+                        &mut read,
+                        &mut write,
+                        &password,
+                        username,
+                        &client_identifier,
+                    )
+                    .await
+                    {
+                        pool.auth_failure();
+                        return Err(err);
+                    }
+                }
+            }
+            // Checked once authenticated, so the limit can't be used to probe for users.
+            let client_slot = match pool.register_client() {
+                Some(client_slot) => client_slot,
+                None => {
+                    too_many_connections(&mut write, username).await?;
+
+                    return Err(Error::ClientGeneralError(
+                        "Too many connections".into(),
+                        client_identifier,
+                    ));
+                }
+            };
+
+            // Statement mode releases the server after each statement, so it works like transaction mode
+            // as long as the client doesn't open a transaction.
+            let transaction_mode = pool.settings.pool_mode != PoolMode::Session;
+            prepared_statements_enabled =
+                transaction_mode && pool.prepared_statement_cache.is_some();
+
+            // If the pool hasn't been validated yet,
             // connect to the servers and figure out what's what.
             if !pool.validated() {
                 match pool.validate().await {
                     Ok(_) => (),
                     Err(err) => {
-                        error_response(
+                        let reason = match err {
+                            Error::ServerAuthError(..) | Error::ServerConnectionLimit(..) => {
+                                format!(" - {}", err)
+                            }
+                            _ => String::new(),
+                        };
+
+                        error_response_with_code(
                             &mut write,
                             &format!(
-                                "Pool down for database: {:?}, user: {:?}",
-                                pool_name, username
+                                "Pool down for database: {:?}, user: {:?}{}",
+                                pool_name, username, reason
                             ),
+                            CONNECTION_FAILURE,
                         )
                         .await?;
                         return Err(Error::ClientError(format!("Pool down: {:?}", err)));
@@ -739,16 +1174,49 @@ where
                 }
             }
 
-            (transaction_mode, pool.server_parameters())
+            (
+                transaction_mode,
+                pool.server_parameters(),
+                Some(client_slot),
+            )
         };
 
         // Update the parameters to merge what the application sent and what's originally on the server
-        server_parameters.set_from_hashmap(&parameters, false);
+        server_parameters.set_from_startup_parameters(&parameters);
+
+        // Clients that didn't send it shouldn't inherit the application_name of the previous
+        // client that used the server.
+        server_parameters.set_param(
+            "application_name".to_string(),
+            application_name.to_string(),
+            false,
+        );
 
         debug!("Password authentication successful");
 
         auth_ok(&mut write).await?;
         write_all(&mut write, (&server_parameters).into()).await?;
+
+        // Drivers checking target_session_attrs themselves, like pgjdbc, look at the reported
+        // state of the server, which has to match the role the session will be routed to.
+        if !admin {
+            if let Some((Some(role), _)) = target_session_attrs(&parameters)
+                .and_then(|value| QueryRouter::target_session_role(&value).ok())
+            {
+                let read_only = match role {
+                    Role::Replica => "on",
+                    _ => "off",
+                };
+
+                let mut status = server_parameter_message("in_hot_standby", read_only);
+                status.extend(server_parameter_message(
+                    "default_transaction_read_only",
+                    read_only,
+                ));
+                write_all(&mut write, status).await?;
+            }
+        }
+
         backend_key_data(&mut write, process_id, secret_key).await?;
         send_ready_for_query(&mut write).await?;
 
@@ -758,6 +1226,7 @@ where
             application_name,
             username,
             pool_name,
+            &addr.to_string(),
             tokio::time::Instant::now(),
         ));
 
@@ -776,7 +1245,10 @@ where
             stats,
             admin,
             last_address_id: None,
+            last_message_time: Instant::now(),
             last_server_stats: None,
+            query_span: QuerySpan::disabled(),
+            set_statements: Vec::new(),
             connected_to_server: false,
             pool_name: pool_name.clone(),
             username: username.clone(),
@@ -785,6 +1257,7 @@ where
             prepared_statements_enabled,
             prepared_statements: HashMap::new(),
             extended_protocol_data_buffer: VecDeque::new(),
+            _client_slot: client_slot,
         })
     }
 
@@ -814,7 +1287,10 @@ where
             stats: Arc::new(ClientStats::default()),
             admin: false,
             last_address_id: None,
+            last_message_time: Instant::now(),
             last_server_stats: None,
+            query_span: QuerySpan::disabled(),
+            set_statements: Vec::new(),
             connected_to_server: false,
             pool_name: String::from("undefined"),
             username: String::from("undefined"),
@@ -823,11 +1299,120 @@ where
             prepared_statements_enabled: false,
             prepared_statements: HashMap::new(),
             extended_protocol_data_buffer: VecDeque::new(),
+            _client_slot: None,
         })
     }
 
     /// Handle a connected and authenticated client.
     pub async fn handle(&mut self) -> Result<(), Error> {
+        if let Some(replication) = replication_mode(&self.parameters) {
+            let replication = replication.to_string();
+            return self.handle_replication(&replication).await;
+        }
+
+        match self.handle_messages().await {
+            // We didn't read the message, the rest of the stream can't be parsed.
+            Err(Error::MessageTooLarge(len)) => {
+                warn!(
+                    "Client {} sent a message of {} bytes, larger than max_message_size, closing the connection",
+                    self.addr, len
+                );
+                let err = Error::MessageTooLarge(len);
+                error_response_terminal_with_code(
+                    &mut self.write,
+                    &err.to_string(),
+                    PROTOCOL_VIOLATION,
+                )
+                .await?;
+                Err(err)
+            }
+            result => result,
+        }
+    }
+
+    /// Connect a replication client to the primary of the pool for the rest of its session,
+    /// bypassing the pool: replication commands and their COPY streams are relayed as they are.
+    async fn handle_replication(&mut self, replication: &str) -> Result<(), Error> {
+        let pool = match get_pool(&self.pool_name, &self.username) {
+            Some(pool) => pool,
+            None => {
+                error_response_terminal_with_code(
+                    &mut self.write,
+                    &format!(
+                        "No pool configured for database: {:?}, user: {:?}",
+                        self.pool_name, self.username
+                    ),
+                    INVALID_CATALOG_NAME,
+                )
+                .await?;
+                return Err(Error::ClientError("Invalid pool name".into()));
+            }
+        };
+
+        // Replication clients always go to shard 0, which the user must be allowed on.
+        if !pool.settings.user.shard_allowed(0) {
+            error_response_terminal_with_code(
+                &mut self.write,
+                &Error::ShardNotAllowed(0).to_string(),
+                INSUFFICIENT_PRIVILEGE,
+            )
+            .await?;
+            return Err(Error::ShardNotAllowed(0));
+        }
+
+        let primary = (0..pool.servers(0))
+            .map(|index| pool.address(0, index))
+            .find(|address| address.role == Role::Primary);
+
+        let server = match primary {
+            Some(address) => {
+                Server::startup(
+                    address,
+                    &pool.settings.user,
+                    &address.database,
+                    self.client_server_map.clone(),
+                    Arc::new(ServerStats::default()),
+                    pool.auth_hash.clone(),
+                    false,
+                    false,
+                    0,
+                    Some(replication),
+                )
+                .await
+            }
+            None => Err(Error::ClientError(format!(
+                "Pool {} has no primary",
+                self.pool_name
+            ))),
+        };
+
+        let mut server = match server {
+            Ok(server) => server,
+            Err(err) => {
+                error!(
+                    "Could not connect replication client {} to the primary: {:?}",
+                    self.addr, err
+                );
+                error_response_terminal_with_code(
+                    &mut self.write,
+                    "could not connect to the primary for replication",
+                    CONNECTION_FAILURE,
+                )
+                .await?;
+                return Err(err);
+            }
+        };
+
+        info!(
+            "Replication client {} connected to {:?}",
+            self.addr,
+            server.address()
+        );
+
+        server.proxy(&mut self.read, &mut self.write).await
+    }
+
+    async fn handle_messages(&mut self) -> Result<(), Error> {
         // The client wants to cancel a query it has issued previously.
         if self.cancel_mode {
             trace!("Sending CancelRequest");
@@ -866,6 +1451,9 @@ where
         // Result returned by one of the plugins.
         let mut plugin_output = None;
 
+        // The extended protocol statement starting a transaction, until the client syncs.
+        let mut parsed_transaction_start: Option<String> = None;
+
         let client_identifier = ClientIdentifier::new(
             self.server_parameters.get_application_name(),
             &self.username,
@@ -885,6 +1473,21 @@ where
         query_router.update_pool_settings(&pool.settings);
         query_router.set_default_role();
 
+        if let Some(target_session_attrs) = target_session_attrs(&self.parameters) {
+            if let Err(err) = query_router.set_target_session_attrs(&target_session_attrs) {
+                error_response_terminal_with_code(
+                    &mut self.write,
+                    &format!(
+                        "invalid value for parameter \"target_session_attrs\": \"{}\"",
+                        target_session_attrs
+                    ),
+                    INVALID_PARAMETER_VALUE,
+                )
+                .await?;
+                return Err(err);
+            }
+        }
+
         // Our custom protocol loop.
         // We expect the client to either start a transaction with regular queries
         // or issue commands for our sharding and server selection protocol.
@@ -903,9 +1506,10 @@ where
             let message = tokio::select! {
                 _ = self.shutdown.recv() => {
                     if !self.admin {
-                        error_response_terminal(
+                        error_response_terminal_with_code(
                             &mut self.write,
-                            "terminating connection due to administrator command"
+                            "terminating connection due to administrator command",
+                            ADMIN_SHUTDOWN,
                         ).await?;
 
                         self.stats.disconnect();
@@ -920,6 +1524,8 @@ where
                 message_result = read_message(&mut self.read) => message_result?
             };
 
+            self.last_message_time = Instant::now();
+
             if message[0] as char == 'X' {
                 debug!("Client disconnecting");
 
@@ -935,6 +1541,8 @@ where
                 continue;
             }
 
+            query_router.parse_routing_hints(&message);
+
             // Handle all custom protocol commands, if any.
             if self
                 .handle_custom_protocol(&mut query_router, &message, &pool)
@@ -944,10 +1552,23 @@ where
             }
 
             let mut initial_parsed_ast = None;
+            let mut scatter_gather = false;
 
             match message[0] as char {
                 // Query
                 'Q' => {
+                    // The server connection is returned to the pool after the transaction,
+                    // notifications would be lost or sent to another client.
+                    if self.transaction_mode && QueryRouter::is_listen(&message) {
+                        error_response_with_code(
+                            &mut self.write,
+                            "LISTEN is not supported in transaction mode, notifications require pool_mode = \"session\"",
+                            FEATURE_NOT_SUPPORTED,
+                        )
+                        .await?;
+                        continue;
+                    }
+
                     if query_router.query_parser_enabled() {
                         match query_router.parse(&message) {
                             Ok(ast) => {
@@ -967,7 +1588,16 @@ where
                                     _ => (),
                                 };
 
-                                let _ = query_router.infer(&ast);
+                                if let Err(Error::QueryRouterError(error)) =
+                                    query_router.infer(&ast)
+                                {
+                                    error_response(&mut self.write, &error).await?;
+                                    continue;
+                                }
+
+                                // A server connection is held for the whole session in session mode.
+                                scatter_gather =
+                                    self.transaction_mode && query_router.is_scatter_gather(&ast);
 
                                 initial_parsed_ast = Some(ast);
                             }
@@ -976,6 +1606,13 @@ where
                                     "Query parsing error: {} (client: {})",
                                     error, client_identifier
                                 );
+
+                                if let Err(Error::QueryRouterError(error)) =
+                                    query_router.infer_unparsed(&message)
+                                {
+                                    error_response(&mut self.write, &error).await?;
+                                    continue;
+                                }
                             }
                         }
                     }
@@ -997,13 +1634,26 @@ where
                                     plugin_output = Some(output);
                                 }
 
-                                let _ = query_router.infer(&ast);
+                                parsed_transaction_start = QueryRouter::started_transaction(&ast);
+
+                                // Reported once the client syncs, like plugin errors.
+                                if let Err(Error::QueryRouterError(error)) =
+                                    query_router.infer(&ast)
+                                {
+                                    plugin_output = Some(PluginOutput::Deny(error));
+                                }
                             }
                             Err(error) => {
                                 warn!(
                                     "Query parsing error: {} (client: {})",
                                     error, client_identifier
                                 );
+
+                                if let Err(Error::QueryRouterError(error)) =
+                                    query_router.infer_unparsed(&message)
+                                {
+                                    plugin_output = Some(PluginOutput::Deny(error));
+                                }
                             }
                         };
                     }
@@ -1056,23 +1706,85 @@ where
                 continue;
             };
 
+            if matches!(message[0] as char, 'Q' | 'S') && !self.rate_limit(&pool).await {
+                self.reset_buffered_state();
+                error_response_with_code(
+                    &mut self.write,
+                    &rate_limit_error(&pool),
+                    CONFIGURATION_LIMIT_EXCEEDED,
+                )
+                .await?;
+                continue;
+            }
+
             // Check if the pool is paused and wait until it's resumed.
-            pool.wait_paused().await;
+            let pause_timeout = match get_pause_timeout() {
+                0 => tokio::time::Duration::MAX,
+                timeout => tokio::time::Duration::from_millis(timeout),
+            };
+
+            if tokio::time::timeout(pause_timeout, pool.wait_paused())
+                .await
+                .is_err()
+            {
+                self.reset_buffered_state();
+                error_response_with_code(
+                    &mut self.write,
+                    "timed out waiting for the paused pool to resume",
+                    QUERY_CANCELED,
+                )
+                .await?;
+                continue;
+            }
 
             // Refresh pool information, something might have changed.
             pool = self.get_pool().await?;
             query_router.update_pool_settings(&pool.settings);
 
+            if scatter_gather {
+                self.scatter_gather(&pool, query_router.role(), &message)
+                    .await?;
+                continue;
+            }
+
+            // Users can be restricted to some shards with allowed_shards.
+            if matches!(message[0] as char, 'Q' | 'S') {
+                if let Err(err) = query_router.check_shard_allowed() {
+                    self.reset_buffered_state();
+                    error_response_with_code(
+                        &mut self.write,
+                        &err.to_string(),
+                        INSUFFICIENT_PRIVILEGE,
+                    )
+                    .await?;
+                    continue;
+                }
+            }
+
             debug!("Waiting for connection from pool");
             if !self.admin {
                 self.stats.waiting();
             }
 
+            self.query_span = self.new_query_span(&message);
+
             // Grab a server from the pool.
-            let connection = match pool
+            let mut connection = pool
                 .get(query_router.shard(), query_router.role(), &self.stats)
-                .await
-            {
+                .instrument(self.query_span.wait_for_connection())
+                .await;
+
+            // target_session_attrs=prefer-standby uses the primary when no replica is available,
+            // but not when the replicas are only busy.
+            if matches!(connection, Err(Error::AllServersDown)) && query_router.prefer_standby() {
+                debug!("No replica available, using the primary");
+                connection = pool
+                    .get(query_router.shard(), Some(Role::Primary), &self.stats)
+                    .instrument(self.query_span.wait_for_connection())
+                    .await;
+            }
+
+            let connection = match connection {
                 Ok(conn) => {
                     debug!("Got connection from pool");
                     conn
@@ -1089,9 +1801,10 @@ where
                         self.reset_buffered_state();
                     }
 
-                    error_response(
+                    error_response_with_code(
                         &mut self.write,
                         format!("could not get connection from the pool - {}", err).as_str(),
+                        CONNECTION_FAILURE,
                     )
                     .await?;
 
@@ -1117,12 +1830,13 @@ where
                                 "Checkout failure limit reached ({} / {}) - disconnecting client",
                                 checkout_failure_count, limit
                             );
-                            error_response_terminal(
+                            error_response_terminal_with_code(
                                 &mut self.write,
                                 &format!(
                                     "checkout failure limit reached ({} / {})",
                                     checkout_failure_count, limit
                                 ),
+                                CONNECTION_FAILURE,
                             )
                             .await?;
                             self.stats.disconnect();
@@ -1134,8 +1848,10 @@ where
             };
 
             let mut reference = connection.0;
-            let address = connection.1;
-            let server = &mut *reference;
+            let mut address = connection.1;
+            let mut server = &mut *reference;
+
+            self.stats.server_assigned(address.shard, address.role);
 
             // Server is assigned to the client in case the client wants to
             // cancel a query later.
@@ -1147,6 +1863,7 @@ where
 
             self.last_address_id = Some(address.id);
             self.last_server_stats = Some(server.stats());
+            self.query_span.record_server(&address);
 
             debug!(
                 "Client {:?} talking to server {:?}",
@@ -1154,10 +1871,23 @@ where
                 server.address()
             );
 
-            server.sync_parameters(&self.server_parameters).await?;
+            self.sync_parameters(server).await?;
+            self.replay_set_statements(server).await?;
 
             let mut initial_message = Some(message);
 
+            // Only the first query after checkout can be retried on another replica,
+            // the following ones could depend on the state of the server.
+            let mut first_message = true;
+
+            // The shard a statement of the extended protocol goes to, if it's not
+            // the one of the transaction. Handled when the client syncs.
+            let mut cross_shard_statement = None;
+
+            // The transaction only ran the statement that started it, so it can still
+            // move to the shard of the statement that follows, see `cross_shard_transactions`.
+            let mut unpinned_transaction: Option<String> = None;
+
             let idle_client_timeout_duration = match get_idle_client_in_transaction_timeout() {
                 0 => tokio::time::Duration::MAX,
                 timeout => tokio::time::Duration::from_millis(timeout),
@@ -1174,27 +1904,57 @@ where
                     None => {
                         trace!("Waiting for message inside transaction or in session mode");
 
+                        first_message = false;
+
                         // This is not an initial message so discard the initial_parsed_ast
                         initial_parsed_ast.take();
 
-                        match tokio::time::timeout(
-                            idle_client_timeout_duration,
-                            read_message(&mut self.read),
-                        )
-                        .await
-                        {
-                            Ok(Ok(message)) => message,
+                        // Session mode clients can stay idle as long as they want outside of transactions.
+                        let idle_client_timeout_duration =
+                            if self.transaction_mode || server.in_transaction() {
+                                idle_client_timeout_duration
+                            } else {
+                                tokio::time::Duration::MAX
+                            };
+
+                        // In session mode, deliver notifications to listening clients while they are idle.
+                        let message = if !self.transaction_mode && server.is_listening() {
+                            tokio::time::timeout(
+                                idle_client_timeout_duration,
+                                self.read_message_forwarding_notifications(server),
+                            )
+                            .await
+                        } else {
+                            tokio::time::timeout(
+                                idle_client_timeout_duration,
+                                read_message(&mut self.read),
+                            )
+                            .await
+                        };
+
+                        match message {
+                            Ok(Ok(message)) => {
+                                self.last_message_time = Instant::now();
+                                message
+                            }
                             Ok(Err(err)) => {
                                 // Client disconnected inside a transaction.
                                 // Clean up the server and re-use it.
                                 self.stats.disconnect();
-                                server.checkin_cleanup().await?;
+                                server
+                                    .checkin_cleanup(pool.settings.server_reset_query.as_deref())
+                                    .await?;
 
                                 return Err(err);
                             }
                             Err(_) => {
                                 // Client idle in transaction timeout
-                                error_response(&mut self.write, "idle transaction timeout").await?;
+                                error_response_with_code(
+                                    &mut self.write,
+                                    "idle transaction timeout",
+                                    IDLE_IN_TRANSACTION_SESSION_TIMEOUT,
+                                )
+                                .await?;
                                 error!(
                                     "Client idle in transaction timeout: \
                                     {{ \
@@ -1232,9 +1992,11 @@ where
                 match code {
                     // Query
                     'Q' => {
+                        let mut ast = None;
+
                         if query_router.query_parser_enabled() {
                             // We don't want to parse again if we already parsed it as the initial message
-                            let ast = match initial_parsed_ast {
+                            ast = match initial_parsed_ast {
                                 Some(_) => Some(initial_parsed_ast.take().unwrap()),
                                 None => match query_router.parse(&message) {
                                     Ok(ast) => Some(ast),
@@ -1248,8 +2010,8 @@ where
                                 },
                             };
 
-                            if let Some(ast) = ast {
-                                let plugin_result = query_router.execute_plugins(&ast).await;
+                            if let Some(ast) = &ast {
+                                let plugin_result = query_router.execute_plugins(ast).await;
 
                                 match plugin_result {
                                     Ok(PluginOutput::Deny(error)) => {
@@ -1267,17 +2029,136 @@ where
                             }
                         }
 
+                        // A transaction can't move to another shard.
+                        if server.in_transaction() && pool.settings.shards > 1 {
+                            query_router.parse_routing_hints(&message);
+
+                            if let Some(shard) = query_router.statement_shard(ast.as_ref()) {
+                                if shard != address.shard {
+                                    match unpinned_transaction.take() {
+                                        Some(transaction_start) => {
+                                            let connection = self
+                                                .move_transaction_to_shard(
+                                                    shard,
+                                                    &transaction_start,
+                                                    server,
+                                                    &address,
+                                                    &pool,
+                                                )
+                                                .await?;
+
+                                            reference = connection.0;
+                                            address = connection.1;
+                                            server = &mut *reference;
+                                        }
+
+                                        None => {
+                                            self.cross_shard_statement(
+                                                shard, server, &address, &pool,
+                                            )
+                                            .await?;
+                                            continue;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+
+                        unpinned_transaction =
+                            ast.as_deref().and_then(QueryRouter::started_transaction);
+
+                        // The first message was already counted before checkout.
+                        if !first_message && !self.rate_limit(&pool).await {
+                            error_response_with_code(
+                                &mut self.write,
+                                &rate_limit_error(&pool),
+                                CONFIGURATION_LIMIT_EXCEEDED,
+                            )
+                            .await?;
+                            continue;
+                        }
+
                         debug!("Sending query to server");
 
-                        self.send_and_receive_loop(
-                            code,
-                            Some(&message),
-                            server,
-                            &address,
-                            &pool,
-                            &self.stats.clone(),
-                        )
-                        .await?;
+                        if !first_message {
+                            self.query_span = self.new_query_span(&message);
+                            self.query_span.record_server(&address);
+                        }
+
+                        let mut read_retries = 0;
+
+                        loop {
+                            let retryable = first_message
+                                && address.role == Role::Replica
+                                && read_retries < pool.settings.read_retry_count;
+
+                            let error = match self
+                                .send_and_receive_loop(
+                                    code,
+                                    Some(&message),
+                                    server,
+                                    &address,
+                                    &pool,
+                                    &self.stats.clone(),
+                                    retryable,
+                                )
+                                .await
+                            {
+                                Err(Error::RetryableReadError(error)) => error,
+                                result => break result?,
+                            };
+
+                            read_retries += 1;
+                            warn!(
+                                "Read failed on {:?} ({}), retrying on another replica ({} / {})",
+                                address, error, read_retries, pool.settings.read_retry_count
+                            );
+
+                            // The failed replica is banned, so we'll get another one.
+                            let connection = match pool
+                                .get(query_router.shard(), query_router.role(), &self.stats)
+                                .await
+                            {
+                                Ok(connection) => connection,
+                                Err(err) => {
+                                    error_response_terminal_with_code(
+                                        &mut self.write,
+                                        &format!("error receiving data from server: {}", error),
+                                        CONNECTION_FAILURE,
+                                    )
+                                    .await?;
+                                    return Err(err);
+                                }
+                            };
+
+                            reference = connection.0;
+                            address = connection.1;
+                            server = &mut *reference;
+
+                            self.stats.server_assigned(address.shard, address.role);
+                            server.claim(self.process_id, self.secret_key);
+                            self.last_address_id = Some(address.id);
+                            self.last_server_stats = Some(server.stats());
+                            self.query_span.record_server(&address);
+
+                            self.sync_parameters(server).await?;
+                            self.replay_set_statements(server).await?;
+                        }
+
+                        if self.transaction_mode
+                            && pool.settings.track_set_statements
+                            && !server.in_transaction()
+                            && !server.query_failed()
+                        {
+                            if let Some(statement) = QueryRouter::set_statement(&message) {
+                                self.track_set_statement(statement);
+                            }
+                        }
+
+                        if pool.settings.pool_mode == PoolMode::Statement && server.in_transaction()
+                        {
+                            return self.reject_statement_mode_transaction(server, &pool).await;
+                        }
 
                         if !server.in_transaction() {
                             // Report transaction executed statistics.
@@ -1298,7 +2179,9 @@ where
 
                     // Terminate
                     'X' => {
-                        server.checkin_cleanup().await?;
+                        server
+                            .checkin_cleanup(pool.settings.server_reset_query.as_deref())
+                            .await?;
                         self.stats.disconnect();
                         self.release();
 
@@ -1308,20 +2191,45 @@ where
                     // Parse
                     // The query with placeholders is here, e.g. `SELECT * FROM users WHERE email = $1 AND active = $2`.
                     'P' => {
+                        let mut ast = None;
+
                         if query_router.query_parser_enabled() {
-                            if let Ok(ast) = query_router.parse(&message) {
-                                if let Ok(output) = query_router.execute_plugins(&ast).await {
+                            ast = query_router.parse(&message).ok();
+
+                            if let Some(ast) = &ast {
+                                if let Ok(output) = query_router.execute_plugins(ast).await {
                                     plugin_output = Some(output);
                                 }
                             }
                         }
 
+                        if server.in_transaction() && pool.settings.shards > 1 {
+                            query_router.parse_routing_hints(&message);
+
+                            if let Some(shard) = query_router.statement_shard(ast.as_ref()) {
+                                if shard != address.shard {
+                                    cross_shard_statement = Some(shard);
+                                }
+                            }
+                        }
+
+                        parsed_transaction_start =
+                            ast.as_deref().and_then(QueryRouter::started_transaction);
+
                         self.buffer_parse(message, &pool)?;
                     }
 
                     // Bind
                     // The placeholder's replacements are here, e.g. 'user@email.com' and 'true'
                     'B' => {
+                        if server.in_transaction() && pool.settings.shards > 1 {
+                            if let Some(shard) = query_router.bind_shard(&message) {
+                                if shard != address.shard {
+                                    cross_shard_statement = Some(shard);
+                                }
+                            }
+                        }
+
                         self.buffer_bind(message).await?;
                     }
 
@@ -1352,6 +2260,36 @@ where
                     'S' => {
                         debug!("Sending query to server");
 
+                        if let Some(shard) = cross_shard_statement.take() {
+                            match unpinned_transaction.take() {
+                                Some(transaction_start) => {
+                                    let connection = self
+                                        .move_transaction_to_shard(
+                                            shard,
+                                            &transaction_start,
+                                            server,
+                                            &address,
+                                            &pool,
+                                        )
+                                        .await?;
+
+                                    reference = connection.0;
+                                    address = connection.1;
+                                    server = &mut *reference;
+                                }
+
+                                None => {
+                                    plugin_output = None;
+                                    parsed_transaction_start = None;
+                                    self.cross_shard_statement(shard, server, &address, &pool)
+                                        .await?;
+                                    continue;
+                                }
+                            }
+                        }
+
+                        unpinned_transaction = parsed_transaction_start.take();
+
                         match plugin_output {
                             Some(PluginOutput::Deny(error)) => {
                                 error_response(&mut self.write, &error).await?;
@@ -1370,6 +2308,17 @@ where
                             _ => (),
                         };
 
+                        if !first_message && !self.rate_limit(&pool).await {
+                            error_response_with_code(
+                                &mut self.write,
+                                &rate_limit_error(&pool),
+                                CONFIGURATION_LIMIT_EXCEEDED,
+                            )
+                            .await?;
+                            self.reset_buffered_state();
+                            continue;
+                        }
+
                         // Prepared statements can arrive like this
                         // 1. Without named describe
                         //      Client: Parse, with name, query and params
@@ -1523,6 +2472,11 @@ where
                         }
 
                         if should_send_to_server {
+                            if !first_message {
+                                self.query_span = self.new_query_span(&self.buffer);
+                                self.query_span.record_server(&address);
+                            }
+
                             self.send_and_receive_loop(
                                 code,
                                 None,
@@ -1530,12 +2484,18 @@ where
                                 &address,
                                 &pool,
                                 &self.stats.clone(),
+                                false,
                             )
                             .await?;
                         }
 
                         self.buffer.clear();
 
+                        if pool.settings.pool_mode == PoolMode::Statement && server.in_transaction()
+                        {
+                            return self.reject_statement_mode_transaction(server, &pool).await;
+                        }
+
                         if !server.in_transaction() {
                             self.stats.transaction();
                             server
@@ -1575,9 +2535,20 @@ where
                         // Clear the buffer
                         self.buffer.clear();
 
-                        let response = self
-                            .receive_server_message(server, &address, &pool, &self.stats.clone())
-                            .await?;
+                        let response = match self
+                            .receive_server_message(
+                                server,
+                                &address,
+                                &pool,
+                                &self.stats.clone(),
+                                false,
+                            )
+                            .await
+                        {
+                            Ok(response) => response,
+                            Err(err @ Error::StatementTimeout) => return Err(err),
+                            Err(err) => return self.server_lost(server, err, true).await,
+                        };
 
                         match write_all_flush(&mut self.write, &response).await {
                             Ok(_) => (),
@@ -1612,13 +2583,18 @@ where
             // The server is no longer bound to us, we can't cancel it's queries anymore.
             debug!("Releasing server back into the pool");
 
-            server.checkin_cleanup().await?;
+            server
+                .checkin_cleanup(pool.settings.server_reset_query.as_deref())
+                .await?;
 
             server.stats().idle();
             self.connected_to_server = false;
 
             self.release();
             self.stats.idle();
+            self.query_span = QuerySpan::disabled();
+
+            query_router.clear_routing_hints();
         }
     }
 
@@ -1628,12 +2604,13 @@ where
         match get_pool(&self.pool_name, &self.username) {
             Some(pool) => Ok(pool),
             None => {
-                error_response(
+                error_response_with_code(
                     &mut self.write,
                     &format!(
                         "No pool configured for database: {}, user: {}",
                         self.pool_name, self.username
                     ),
+                    INVALID_CATALOG_NAME,
                 )
                 .await?;
 
@@ -1672,7 +2649,7 @@ where
                                     // Bad shard number, send error message to client.
                                     query_router.set_shard(current_shard);
 
-                                    error_response(
+                                    error_response_with_code(
                                                     &mut self.write,
                                                     &format!(
                                                         "shard {} is not configured {}, staying on shard {:?} (shard numbers start at 0)",
@@ -1680,9 +2657,14 @@ where
                                                         pool.shards(),
                                                         current_shard,
                                                     ),
+                                                    INVALID_PARAMETER_VALUE,
                                                 )
                                                     .await?;
-                                } else {
+                                } else if !pool.settings.user.shard_allowed(selected_shard) {
+                                    query_router.set_shard(current_shard);
+                                    self.shard_not_allowed(selected_shard, current_shard)
+                                        .await?;
+                                } else {
                                     custom_protocol_response_ok(&mut self.write, "SET SHARD")
                                         .await?;
                                 }
@@ -1696,9 +2678,21 @@ where
                     }
 
                     // SET SHARDING KEY TO
-                    (Command::SetShardingKey, _) => {
-                        custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
-                    }
+                    // The key's shard is checked like the one of SET SHARD.
+                    (Command::SetShardingKey, _) => match query_router.shard() {
+                        Some(selected_shard)
+                            if !pool.settings.user.shard_allowed(selected_shard) =>
+                        {
+                            query_router.set_shard(current_shard);
+                            self.shard_not_allowed(selected_shard, current_shard)
+                                .await?;
+                        }
+
+                        _ => {
+                            custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY")
+                                .await?;
+                        }
+                    },
 
                     // SET SERVER ROLE TO
                     (Command::SetServerRole, _) => {
@@ -1882,12 +2876,13 @@ where
                     client_given_name
                 );
 
-                error_response(
+                error_response_with_code(
                     &mut self.write,
                     &format!(
                         "prepared statement \"{}\" does not exist",
                         client_given_name
                     ),
+                    INVALID_SQL_STATEMENT_NAME,
                 )
                 .await?;
 
@@ -1944,12 +2939,13 @@ where
             None => {
                 debug!("Got describe for unknown prepared statement {:?}", describe);
 
-                error_response(
+                error_response_with_code(
                     &mut self.write,
                     &format!(
                         "prepared statement \"{}\" does not exist",
                         client_given_name
                     ),
+                    INVALID_SQL_STATEMENT_NAME,
                 )
                 .await?;
 
@@ -1961,18 +2957,312 @@ where
         }
     }
 
+    /// Take a token from the user's rate limiter, waiting for one
+    /// up to rate_limit_wait_timeout. Returns false if the query must be rejected.
+    async fn rate_limit(&self, pool: &ConnectionPool) -> bool {
+        let rate_limiter = match pool.rate_limiter {
+            Some(ref rate_limiter) => rate_limiter,
+            None => return true,
+        };
+
+        if rate_limiter.try_acquire().is_ok() {
+            return true;
+        }
+
+        self.stats.rate_limited();
+
+        match pool.settings.user.rate_limit_wait_timeout {
+            0 => false,
+            timeout => {
+                rate_limiter
+                    .acquire(tokio::time::Duration::from_millis(timeout))
+                    .await
+            }
+        }
+    }
+
     fn reset_buffered_state(&mut self) {
         self.buffer.clear();
         self.extended_protocol_data_buffer.clear();
         self.response_message_queue_buffer.clear();
     }
 
+    /// Wait for the next client message and forward messages the server sends
+    /// in the meantime, e.g. notifications for channels the client is listening to.
+    async fn read_message_forwarding_notifications(
+        &mut self,
+        server: &mut Server,
+    ) -> Result<BytesMut, Error> {
+        loop {
+            tokio::select! {
+                // Reading one byte is cancel safe, the rest of the message is read
+                // once we know the client sent something.
+                code = self.read.read_u8() => {
+                    let code = match code {
+                        Ok(code) => code,
+                        Err(err) => {
+                            return Err(Error::SocketError(format!(
+                                "Error reading message code from socket - Error {:?}",
+                                err
+                            )))
+                        }
+                    };
+
+                    return read_message_data(&mut self.read, code).await;
+                }
+
+                result = server.wait_for_data() => {
+                    result?;
+
+                    let message = server.recv_async_message().await?;
+                    write_all_flush(&mut self.write, &message).await?;
+                }
+            }
+        }
+    }
+
+    /// In statement mode the server is released after each statement,
+    /// so a client that opens a transaction is disconnected and the transaction rolled back.
+    async fn reject_statement_mode_transaction(
+        &mut self,
+        server: &mut Server,
+        pool: &ConnectionPool,
+    ) -> Result<(), Error> {
+        warn!(
+            "Client {} started a transaction in statement mode, disconnecting",
+            self.addr
+        );
+
+        server
+            .checkin_cleanup(pool.settings.server_reset_query.as_deref())
+            .await?;
+
+        error_response_terminal_with_code(
+            &mut self.write,
+            "transaction blocks are not allowed in statement pool mode",
+            FEATURE_NOT_SUPPORTED,
+        )
+        .await?;
+
+        self.stats.disconnect();
+        self.release();
+
+        Ok(())
+    }
+
     /// Release the server from the client: it can't cancel its queries anymore.
     pub fn release(&self) {
         let mut guard = self.client_server_map.lock();
         guard.remove(&(self.process_id, self.secret_key));
     }
 
+    /// Remember a SET the client ran outside of a transaction, to replay it
+    /// on the next servers we check out.
+    fn track_set_statement(&mut self, statement: SetStatement) {
+        match statement {
+            SetStatement::Set(name, value) => {
+                self.set_statements.retain(|(tracked, _)| *tracked != name);
+                self.set_statements.push((name, value));
+            }
+            SetStatement::Reset(name) => {
+                self.set_statements.retain(|(tracked, _)| *tracked != name);
+            }
+            SetStatement::ResetAll => self.set_statements.clear(),
+        }
+    }
+
+    /// The parameters to set on the servers this client uses: the client's own, and the tags
+    /// identifying the client in pg_stat_activity, if enabled. The tags are set on every checkout,
+    /// so a server shared in transaction mode always shows the client currently using it.
+    fn backend_parameters(&self) -> Cow<'_, ServerParameters> {
+        let client_addr = self.addr.ip().to_string();
+        let application_name = get_backend_application_name(
+            self.server_parameters.get_application_name(),
+            &client_addr,
+            &self.username,
+            &self.pool_name,
+        );
+        let set_client_addr = get_set_backend_client_addr();
+
+        if application_name.is_none() && !set_client_addr {
+            return Cow::Borrowed(&self.server_parameters);
+        }
+
+        let mut parameters = self.server_parameters.clone();
+
+        if let Some(application_name) = application_name {
+            parameters.set_param("application_name".to_string(), application_name, false);
+        }
+
+        if set_client_addr {
+            parameters.set_param(BACKEND_CLIENT_ADDR.to_string(), client_addr, true);
+        }
+
+        Cow::Owned(parameters)
+    }
+
+    /// Set the client's parameters on the server. If the server rejects one, e.g. an unsupported
+    /// `client_encoding`, the client gets the server's error, like Postgres would send it on login.
+    async fn sync_parameters(&mut self, server: &mut Server) -> Result<(), Error> {
+        match server.sync_parameters(&self.backend_parameters()).await {
+            Err(Error::ServerParameterError(code, message)) => {
+                // Only the message and SQLSTATE are sent, which `terse` keeps too.
+                let client_message = match get_error_verbosity() {
+                    ErrorVerbosity::Sanitized => sqlstate_class_message(code.as_bytes()),
+                    _ => &message,
+                };
+
+                error_response_terminal_with_code(&mut self.write, client_message, &code).await?;
+
+                Err(Error::ServerParameterError(code, message))
+            }
+            res => res,
+        }
+    }
+
+    /// SET SHARD or SET SHARDING KEY picked a shard the user isn't allowed on, see `allowed_shards`.
+    async fn shard_not_allowed(
+        &mut self,
+        shard: usize,
+        current_shard: Option<usize>,
+    ) -> Result<(), Error> {
+        error_response_with_code(
+            &mut self.write,
+            &format!(
+                "{}, staying on shard {:?}",
+                Error::ShardNotAllowed(shard),
+                current_shard,
+            ),
+            INSUFFICIENT_PRIVILEGE,
+        )
+        .await
+    }
+
+    /// A statement of the transaction running on `address` goes to another shard,
+    /// which a transaction can't do. Depending on `cross_shard_transactions`, the transaction
+    /// is aborted on the server, or only the statement fails.
+    async fn cross_shard_statement(
+        &mut self,
+        shard: usize,
+        server: &mut Server,
+        address: &Address,
+        pool: &ConnectionPool,
+    ) -> Result<(), Error> {
+        let error = format!(
+            "cross-shard transaction: the statement goes to shard {}, the transaction runs on shard {}",
+            shard, address.shard
+        );
+
+        warn!("{} (client: {})", error, self.addr);
+
+        self.reset_buffered_state();
+
+        match pool.settings.cross_shard_transactions {
+            CrossShardTransactions::Reject => {
+                // Fail the transaction on the server, so it can't be committed.
+                let abort = simple_query(&format!(
+                    "DO $$BEGIN RAISE EXCEPTION '{}' USING ERRCODE = 'feature_not_supported'; END$$",
+                    error
+                ));
+
+                self.send_and_receive_loop(
+                    'Q',
+                    Some(&abort),
+                    server,
+                    address,
+                    pool,
+                    &self.stats.clone(),
+                    false,
+                )
+                .await
+            }
+
+            CrossShardTransactions::PinFirst => {
+                error_response_in_transaction(&mut self.write, &error, FEATURE_NOT_SUPPORTED).await
+            }
+        }
+    }
+
+    /// The first statement of a transaction after `BEGIN` goes to another shard than
+    /// the one `BEGIN` went to. The transaction is started again on that shard,
+    /// which it's pinned to from now on. Returns the connection to that shard.
+    async fn move_transaction_to_shard<'a>(
+        &mut self,
+        shard: usize,
+        transaction_start: &str,
+        server: &mut Server,
+        address: &Address,
+        pool: &'a ConnectionPool,
+    ) -> Result<(PooledConnection<'a, ServerPool>, Address), Error> {
+        debug!(
+            "First statement of the transaction goes to shard {}, moving it from {:?}",
+            shard, address
+        );
+
+        server.query("ROLLBACK").await?;
+        server
+            .checkin_cleanup(pool.settings.server_reset_query.as_deref())
+            .await?;
+        server.stats().idle();
+
+        let (mut connection, address) =
+            match pool.get(Some(shard), Some(address.role), &self.stats).await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    error_response_terminal_with_code(
+                        &mut self.write,
+                        &format!("could not move the transaction to shard {}: {}", shard, err),
+                        CONNECTION_FAILURE,
+                    )
+                    .await?;
+                    return Err(err);
+                }
+            };
+
+        let server = &mut *connection;
+
+        self.stats.server_assigned(address.shard, address.role);
+        server.claim(self.process_id, self.secret_key);
+        self.last_address_id = Some(address.id);
+        self.last_server_stats = Some(server.stats());
+        self.query_span.record_server(&address);
+
+        self.sync_parameters(server).await?;
+        self.replay_set_statements(server).await?;
+        server.query(transaction_start).await?;
+
+        Ok((connection, address))
+    }
+
+    /// Apply the SET statements tracked for this client on the server we just checked out.
+    async fn replay_set_statements(&self, server: &mut Server) -> Result<(), Error> {
+        if self.set_statements.is_empty() {
+            return Ok(());
+        }
+
+        let query: String = self
+            .set_statements
+            .iter()
+            .map(|(name, value)| format!("SET {} TO {};", name, value))
+            .collect();
+
+        server.query(&query).await
+    }
+
+    /// Start the tracing span of a query sent by the client.
+    fn new_query_span(&self, message: &BytesMut) -> QuerySpan {
+        QuerySpan::new(
+            &self.username,
+            &self.pool_name,
+            message,
+            self.server_parameters.get_application_name(),
+        )
+    }
+
+    /// Send the message to the server and forward the response to the client.
+    /// If `retryable`, connection errors before anything was sent to the client
+    /// are returned as `Error::RetryableReadError`, so the query can be sent to another server.
+    #[allow(clippy::too_many_arguments)]
     async fn send_and_receive_loop(
         &mut self,
         code: char,
@@ -1981,24 +3271,93 @@ where
         address: &Address,
         pool: &ConnectionPool,
         client_stats: &ClientStats,
+        retryable: bool,
+    ) -> Result<(), Error> {
+        let span = self.query_span.server_execution();
+
+        self.send_and_receive(
+            code,
+            message,
+            server,
+            address,
+            pool,
+            client_stats,
+            retryable,
+        )
+        .instrument(span)
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn send_and_receive(
+        &mut self,
+        code: char,
+        message: Option<&BytesMut>,
+        server: &mut Server,
+        address: &Address,
+        pool: &ConnectionPool,
+        client_stats: &ClientStats,
+        mut retryable: bool,
     ) -> Result<(), Error> {
         debug!("Sending {} to server", code);
 
+        let sent_message = message;
         let message = match message {
             Some(message) => message,
             None => &self.buffer,
         };
 
-        self.send_server_message(server, message, address, pool)
-            .await?;
+        if let Err(err) = self
+            .send_server_message(server, message, address, pool)
+            .await
+        {
+            if retryable {
+                return Err(Error::RetryableReadError(err.to_string()));
+            }
+
+            return Err(err);
+        }
 
         let query_start = Instant::now();
+        let in_transaction = server.in_transaction();
+        let mut replied = false;
+
+        // COPY can legitimately take a long time, so it's exempt.
+        let mut statement_timeout = match get_statement_timeout() {
+            0 => None,
+            _ if server.in_copy_mode() => None,
+            timeout => Some(StatementTimeout::new(server, timeout)),
+        };
+
         // Read all data the server has to offer, which can be multiple messages
-        // buffered in 8196 bytes chunks.
+        // buffered in max_buffer_size chunks.
         loop {
-            let response = self
-                .receive_server_message(server, address, pool, client_stats)
-                .await?;
+            let response = match self
+                .receive_server_message(server, address, pool, client_stats, retryable)
+                .await
+            {
+                Ok(response) => response,
+                Err(err @ (Error::RetryableReadError(_) | Error::StatementTimeout)) => {
+                    return Err(err)
+                }
+                Err(err) => {
+                    // The server's transaction is lost with it, so the client can only carry on
+                    // if it wasn't in one and has not seen any of the reply.
+                    return self
+                        .server_lost(server, err, replied || in_transaction)
+                        .await;
+                }
+            };
+
+            // We're about to send data to the client, it's too late to retry.
+            retryable = false;
+            replied = true;
+
+            if server.in_copy_mode() {
+                if let Some(statement_timeout) = statement_timeout.take() {
+                    statement_timeout.disarm(server);
+                }
+            }
 
             match write_all_flush(&mut self.write, &response).await {
                 Ok(_) => (),
@@ -2014,16 +3373,312 @@ where
             }
         }
 
+        if let Some(statement_timeout) = statement_timeout {
+            statement_timeout.disarm(server);
+        }
+
         // Report query executed statistics.
+        let query_time = query_start.elapsed();
         client_stats.query();
         server.stats().query(
-            Instant::now().duration_since(query_start).as_millis() as u64,
+            query_time.as_millis() as u64,
             self.server_parameters.get_application_name(),
         );
+        pool.latency
+            .query(query_time, self.last_message_time.elapsed());
+
+        self.log_slow_query(sent_message, address);
 
         Ok(())
     }
 
+    /// Log the query if it took longer than `log_min_duration_ms`, since the client sent it.
+    fn log_slow_query(&self, message: Option<&BytesMut>, address: &Address) {
+        let duration = self.last_message_time.elapsed();
+
+        match get_log_min_duration() {
+            Some(min_duration) if duration.as_millis() >= min_duration as u128 => (),
+            _ => return,
+        };
+
+        let message = message.unwrap_or(&self.buffer);
+
+        let query = match self.logged_query_text(message) {
+            Some(query) if get_log_query_text() => truncate_query(query).to_string(),
+            Some(query) => format!(
+                "fingerprint {:x}",
+                Md5::digest(normalize_query(query).as_bytes())
+            ),
+            // A Bind of a statement we don't have the query of.
+            None if message.first() == Some(&b'B') => match Bind::get_name(message) {
+                Ok(name) if name.is_empty() => "<unnamed prepared statement>".to_string(),
+                Ok(name) => format!("<prepared statement {:?}>", name),
+                Err(_) => "<unknown query>".to_string(),
+            },
+            None => "<unknown query>".to_string(),
+        };
+
+        warn!(
+            "Slow query: \
+            {{ \
+                duration: {}ms, \
+                pool_name: {:?}, \
+                username: {:?}, \
+                shard: {}, \
+                role: \"{:?}\", \
+                query: {:?} \
+            }}",
+            duration.as_millis(),
+            self.pool_name,
+            self.username,
+            address.shard,
+            address.role,
+            query
+        );
+    }
+
+    /// The query of a message, or of the cached prepared statement a Bind executes.
+    fn logged_query_text<'a>(&'a self, message: &'a BytesMut) -> Option<&'a str> {
+        if message.first() != Some(&b'B') {
+            return query_text(message);
+        }
+
+        // Binds are renamed to the statement's name on the server, see `buffer_bind`.
+        let name = Bind::get_name(message).ok()?;
+
+        self.prepared_statements
+            .values()
+            .find(|(parse, _)| parse.name == name)
+            .and_then(|(parse, _)| parse.query())
+    }
+
+    /// The connection to the server was lost while waiting for its reply.
+    /// In transaction mode, the query fails and the client can go on with another server,
+    /// unless the client's session was broken with it: then, the client is disconnected.
+    async fn server_lost(
+        &mut self,
+        server: &Server,
+        err: Error,
+        session_broken: bool,
+    ) -> Result<(), Error> {
+        let message = format!("error receiving data from server: {:?}", err);
+        let code = server.connection_lost_code();
+
+        if self.transaction_mode && !session_broken {
+            warn!(
+                "Lost connection to server {:?}, failing the query: {:?}",
+                server.address(),
+                err
+            );
+            return error_response_with_code(&mut self.write, &message, code).await;
+        }
+
+        error_response_terminal_with_code(&mut self.write, &message, code).await?;
+        Err(err)
+    }
+
+    /// Send a read-only query to all shards and return the rows of all of them to the client.
+    /// The rows are forwarded as they arrive, shard after shard, see `scatter_gather`.
+    async fn scatter_gather(
+        &mut self,
+        pool: &ConnectionPool,
+        role: Option<Role>,
+        message: &BytesMut,
+    ) -> Result<(), Error> {
+        debug!("Sending query to all {} shards", pool.shards());
+
+        self.stats.waiting();
+
+        let backend_parameters = self.backend_parameters().into_owned();
+        let shards: Vec<usize> = (0..pool.shards())
+            .filter(|shard| pool.settings.user.shard_allowed(*shard))
+            .collect();
+        let started = futures::future::join_all(shards.iter().map(|shard| {
+            Self::start_shard_query(
+                pool,
+                *shard,
+                role,
+                message,
+                &self.stats,
+                &backend_parameters,
+            )
+        }))
+        .await;
+
+        self.stats.active();
+        self.stats.transaction();
+        self.stats.query();
+
+        let verbosity = get_error_verbosity();
+        let best_effort = pool.settings.scatter_gather_best_effort;
+        let mut responses = Vec::with_capacity(shards.len());
+        let mut queries = Vec::with_capacity(shards.len());
+
+        for (shard, started) in shards.into_iter().zip(started) {
+            match started {
+                Ok((query, response)) => {
+                    responses.push((shard, response));
+                    queries.push(Some(query));
+                }
+                Err(err) => {
+                    let error = ShardError::from_error(&err, verbosity);
+                    responses.push((shard, ShardResponse::error(error)));
+                    queries.push(None);
+                }
+            }
+        }
+
+        let result = match scatter_gather::start(&mut responses, best_effort) {
+            Ok(result) => result,
+            Err(error) => {
+                Self::abandon_shard_queries(&mut queries);
+                self.stats.idle();
+                return error_response_with_code(&mut self.write, &error.message, &error.code)
+                    .await;
+            }
+        };
+
+        write_all_flush(&mut self.write, &result).await?;
+
+        let mut row_count = 0;
+
+        for i in 0..queries.len() {
+            let mut query = match queries[i].take() {
+                Some(query) => query,
+                None => continue,
+            };
+
+            let shard = responses[i].0;
+            let mut messages = responses[i].1.take_rest();
+
+            let error = loop {
+                let (rows, error) = scatter_gather::rows(messages, &mut row_count)?;
+                write_all_flush(&mut self.write, &rows).await?;
+
+                if error.is_some() || !query.server().is_data_available() {
+                    break error;
+                }
+
+                messages = match query.server().recv(None).await {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        pool.ban(
+                            &query.address,
+                            BanReason::MessageReceiveFailed,
+                            Some(&self.stats),
+                        );
+                        break Some(ShardError::from_error(&err, verbosity));
+                    }
+                };
+            };
+
+            // The rest of the response of a failed shard is only its ReadyForQuery.
+            while error.is_some() && query.server().is_data_available() {
+                if query.server().recv(None).await.is_err() {
+                    break;
+                }
+            }
+
+            query.finish(&backend_parameters);
+
+            if let Some(error) = error {
+                warn!(
+                    "Scatter-gather query failed on shard {}: {}",
+                    shard, error.message
+                );
+
+                if !best_effort {
+                    Self::abandon_shard_queries(&mut queries);
+                    self.stats.idle();
+                    let error = scatter_gather::failed_on_shard(shard, &error);
+                    return error_response_with_code(&mut self.write, &error.message, &error.code)
+                        .await;
+                }
+
+                write_all_flush(
+                    &mut self.write,
+                    &scatter_gather::failed_shard_notice(shard, &error),
+                )
+                .await?;
+            }
+        }
+
+        self.stats.idle();
+
+        write_all_flush(&mut self.write, &scatter_gather::end(row_count)).await
+    }
+
+    /// The rows of these shards won't be read, so their connections can't be reused.
+    fn abandon_shard_queries(queries: &mut [Option<ShardQuery<'_>>]) {
+        for query in queries.iter_mut().filter_map(Option::take) {
+            let mut query = query;
+
+            if query.server().is_data_available() {
+                query
+                    .server()
+                    .mark_bad("scatter-gather query abandoned before its end");
+            }
+
+            if let Some(statement_timeout) = query.statement_timeout.take() {
+                statement_timeout.disarm(&mut query.connection);
+            }
+        }
+    }
+
+    /// Send the query to one of the servers of a shard, and read its response up to the rows.
+    async fn start_shard_query<'a>(
+        pool: &'a ConnectionPool,
+        shard: usize,
+        role: Option<Role>,
+        message: &BytesMut,
+        client_stats: &Arc<ClientStats>,
+        server_parameters: &ServerParameters,
+    ) -> Result<(ShardQuery<'a>, ShardResponse), Error> {
+        let (mut connection, address) = pool.get(Some(shard), role, client_stats).await?;
+        let server = &mut *connection;
+
+        server.sync_parameters(server_parameters).await?;
+
+        if let Err(err) = server.send(message).await {
+            pool.ban(&address, BanReason::MessageSendFailed, Some(client_stats));
+            return Err(err);
+        }
+
+        let statement_timeout = match get_statement_timeout() {
+            0 => None,
+            timeout => Some(StatementTimeout::new(server, timeout)),
+        };
+
+        let mut query = ShardQuery {
+            connection,
+            address,
+            statement_timeout,
+            start: Instant::now(),
+        };
+
+        let mut response = ShardResponse::default();
+
+        loop {
+            let messages = match query.server().recv(None).await {
+                Ok(messages) => messages,
+                Err(err) => {
+                    pool.ban(
+                        &query.address,
+                        BanReason::MessageReceiveFailed,
+                        Some(client_stats),
+                    );
+                    return Err(err);
+                }
+            };
+
+            if response.read(messages)? || !query.server().is_data_available() {
+                break;
+            }
+        }
+
+        Ok((query, response))
+    }
+
     async fn send_server_message(
         &self,
         server: &mut Server,
@@ -2046,6 +3701,7 @@ where
         address: &Address,
         pool: &ConnectionPool,
         client_stats: &ClientStats,
+        retryable: bool,
     ) -> Result<BytesMut, Error> {
         let statement_timeout_duration = match pool.settings.user.statement_timeout {
             0 => tokio::time::Duration::MAX,
@@ -2062,11 +3718,11 @@ where
                 Ok(message) => Ok(message),
                 Err(err) => {
                     pool.ban(address, BanReason::MessageReceiveFailed, Some(client_stats));
-                    error_response_terminal(
-                        &mut self.write,
-                        &format!("error receiving data from server: {:?}", err),
-                    )
-                    .await?;
+
+                    if retryable {
+                        return Err(Error::RetryableReadError(err.to_string()));
+                    }
+
                     Err(err)
                 }
             },
@@ -2079,13 +3735,194 @@ where
                     .as_str(),
                 );
                 pool.ban(address, BanReason::StatementTimeout, Some(client_stats));
-                error_response_terminal(&mut self.write, "pool statement timeout").await?;
+                error_response_terminal_with_code(
+                    &mut self.write,
+                    "pool statement timeout",
+                    QUERY_CANCELED,
+                )
+                .await?;
                 Err(Error::StatementTimeout)
             }
         }
     }
 }
 
+/// Longest query text written in the logs.
+const MAX_LOGGED_QUERY_LENGTH: usize = 1024;
+
+fn truncate_query(query: &str) -> &str {
+    match query.char_indices().nth(MAX_LOGGED_QUERY_LENGTH) {
+        Some((end, _)) => &query[..end],
+        None => query,
+    }
+}
+
+/// The query without its literals and comments, like the queries of `pg_stat_statements`:
+/// queries differing only by their values are the same, and values don't end up in the logs.
+fn normalize_query(query: &str) -> String {
+    let chars: Vec<char> = query.chars().collect();
+    let mut normalized = String::with_capacity(query.len());
+    let mut i = 0;
+
+    let is_identifier = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+
+    // Whitespace and comments separate tokens with one space.
+    let space = |normalized: &mut String| {
+        if !normalized.is_empty() && !normalized.ends_with(' ') {
+            normalized.push(' ');
+        }
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let after_identifier = normalized.ends_with(is_identifier);
+
+        match c {
+            c if c.is_whitespace() => {
+                space(&mut normalized);
+                i += 1;
+            }
+
+            '-' if next == Some('-') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                space(&mut normalized);
+            }
+
+            // Block comments nest in Postgres.
+            '/' if next == Some('*') => {
+                let mut depth = 0;
+
+                while i < chars.len() {
+                    if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+                        depth += 1;
+                        i += 2;
+                    } else if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+                        depth -= 1;
+                        i += 2;
+
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+
+                space(&mut normalized);
+            }
+
+            // Strings, with their prefix, e.g. E'it\'s' or B'101'.
+            '\'' => {
+                let prefix = normalized
+                    .chars()
+                    .last()
+                    .filter(|c| "EeBbXxNn".contains(*c))
+                    .filter(|_| !normalized[..normalized.len() - 1].ends_with(is_identifier));
+
+                if prefix.is_some() {
+                    normalized.pop();
+                }
+
+                let escapes = matches!(prefix, Some('E') | Some('e'));
+                i += 1;
+
+                while i < chars.len() {
+                    match chars[i] {
+                        '\\' if escapes => i += 2,
+                        '\'' if chars.get(i + 1) == Some(&'\'') => i += 2,
+                        '\'' => break,
+                        _ => i += 1,
+                    }
+                }
+
+                normalized.push('?');
+                i += 1;
+            }
+
+            // Quoted identifiers are kept.
+            '"' => {
+                normalized.push(c);
+                i += 1;
+
+                while i < chars.len() {
+                    normalized.push(chars[i]);
+                    i += 1;
+
+                    if chars[i - 1] == '"' {
+                        if chars.get(i) != Some(&'"') {
+                            break;
+                        }
+
+                        normalized.push('"');
+                        i += 1;
+                    }
+                }
+            }
+
+            // Dollar-quoted strings, e.g. $$text$$ or $tag$text$tag$. Parameters like $1 are kept.
+            '$' if !after_identifier && !next.is_some_and(|c| c.is_ascii_digit()) => {
+                let tag_end = chars[i + 1..]
+                    .iter()
+                    .position(|c| !(c.is_alphanumeric() || *c == '_'))
+                    .map(|end| i + 1 + end)
+                    .filter(|end| chars[*end] == '$');
+
+                match tag_end {
+                    Some(end) => {
+                        let tag = &chars[i..=end];
+                        i = end + 1;
+
+                        while i < chars.len() && !chars[i..].starts_with(tag) {
+                            i += 1;
+                        }
+
+                        normalized.push('?');
+                        i += tag.len();
+                    }
+
+                    None => {
+                        normalized.push(c);
+                        i += 1;
+                    }
+                }
+            }
+
+            // Numbers, e.g. 42, 1.5e-3 or .5
+            c if (c.is_ascii_digit() || (c == '.' && next.is_some_and(|c| c.is_ascii_digit())))
+                && !after_identifier =>
+            {
+                while i < chars.len() {
+                    match chars[i] {
+                        'e' | 'E' if matches!(chars.get(i + 1), Some('+') | Some('-')) => i += 2,
+                        c if c.is_ascii_alphanumeric() || c == '.' || c == '_' => i += 1,
+                        _ => break,
+                    }
+                }
+
+                normalized.push('?');
+            }
+
+            c => {
+                normalized.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    normalized.trim_end().to_string()
+}
+
+fn rate_limit_error(pool: &ConnectionPool) -> String {
+    format!(
+        "rate limit of {} queries per second exceeded for user {}",
+        pool.settings.user.max_queries_per_second.unwrap_or(0),
+        pool.settings.user.username
+    )
+}
+
 impl<S, T> Drop for Client<S, T> {
     fn drop(&mut self) {
         let mut guard = self.client_server_map.lock();
@@ -2098,3 +3935,126 @@ impl<S, T> Drop for Client<S, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    fn startup_message(version: i32) -> BytesMut {
+        let parameters = b"user\0postgres\0\0";
+        let mut startup = BytesMut::new();
+        startup.put_i32(8 + parameters.len() as i32);
+        startup.put_i32(version);
+        startup.put_slice(parameters);
+        startup
+    }
+
+    #[tokio::test]
+    async fn test_get_startup_unsupported_protocol() {
+        let (mut client, mut server) = duplex(1024);
+
+        // Protocol 2.0
+        client.write_all(&startup_message(2 << 16)).await.unwrap();
+        assert!(get_startup(&mut server).await.is_err());
+
+        let mut response = vec![0u8; 1024];
+        let n = client.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]);
+        assert!(response.starts_with('E'));
+        assert!(response.contains("0A000"));
+        assert!(response.contains("unsupported frontend protocol 2.0: server supports 3.0 to 3.0"));
+    }
+
+    #[tokio::test]
+    async fn test_get_startup_garbage() {
+        for _ in 0..100 {
+            let (mut client, mut server) = duplex(16384);
+            let garbage: Vec<u8> = (0..64).map(|_| rand::random::<u8>()).collect();
+            client.write_all(&garbage).await.unwrap();
+            drop(client);
+
+            assert!(get_startup(&mut server).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_startup_gssenc_request() {
+        let (mut client, mut server) = duplex(1024);
+
+        let mut gssenc_request = BytesMut::new();
+        gssenc_request.put_i32(8);
+        gssenc_request.put_i32(GSSENC_REQUEST_CODE);
+        client.write_all(&gssenc_request).await.unwrap();
+        client
+            .write_all(&startup_message(PROTOCOL_VERSION_NUMBER))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            get_startup(&mut server).await,
+            Ok((ClientConnectionType::Startup, _))
+        ));
+        assert_eq!(client.read_u8().await.unwrap(), b'N');
+    }
+
+    #[tokio::test]
+    async fn test_get_startup_ssl_request() {
+        let (mut client, mut server) = duplex(1024);
+
+        let mut ssl_request = BytesMut::new();
+        ssl_request.put_i32(8);
+        ssl_request.put_i32(SSL_REQUEST_CODE);
+        client.write_all(&ssl_request).await.unwrap();
+        client
+            .write_all(&startup_message(PROTOCOL_VERSION_NUMBER))
+            .await
+            .unwrap();
+
+        // The caller answers the SSLRequest, the startup message is read after that.
+        assert!(matches!(
+            get_startup(&mut server).await,
+            Ok((ClientConnectionType::Tls, _))
+        ));
+        assert!(matches!(
+            get_startup(&mut server).await,
+            Ok((ClientConnectionType::Startup, _))
+        ));
+    }
+
+    #[test]
+    fn test_replication_mode() {
+        let parameters =
+            |value: &str| HashMap::from([("replication".to_string(), value.to_string())]);
+
+        assert_eq!(replication_mode(&HashMap::new()), None);
+        assert_eq!(replication_mode(&parameters("database")), Some("database"));
+        assert_eq!(replication_mode(&parameters("true")), Some("true"));
+        assert_eq!(replication_mode(&parameters("off")), None);
+        assert_eq!(replication_mode(&parameters("False")), None);
+    }
+
+    #[test]
+    fn test_normalize_query() {
+        assert_eq!(
+            normalize_query("SELECT * FROM users WHERE id = 5 AND email = 'o''brien@example.com'"),
+            "SELECT * FROM users WHERE id = ? AND email = ?"
+        );
+        assert_eq!(
+            normalize_query(
+                "select  1.5e-3, -2, E'it\\'s', B'101', $$a 'b'$$, $tag$x$tag$, $1, t1.c2 -- id 5\n"
+            ),
+            "select ?, -?, ?, ?, ?, ?, $1, t1.c2"
+        );
+        assert_eq!(
+            normalize_query("/* user 42 */ SELECT \"col \"\"1\" FROM t\n\tWHERE x IN (1, .5)"),
+            "SELECT \"col \"\"1\" FROM t WHERE x IN (?, ?)"
+        );
+
+        // Queries differing only by their values have the same fingerprint.
+        assert_eq!(
+            normalize_query("UPDATE t SET a = 'secret' WHERE id = 1"),
+            normalize_query("UPDATE t  SET a = 'other'  WHERE id = 22")
+        );
+    }
+}
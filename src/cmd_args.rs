@@ -22,6 +22,13 @@ pub struct Args {
         help = "disable colors in the log output"
     )]
     pub no_color: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "validate the config file and exit without starting the pooler"
+    )]
+    pub check_config: bool,
 }
 
 pub fn parse() -> Args {
@@ -1,32 +1,52 @@
 /// Parse the configuration file.
 use arc_swap::ArcSwap;
-use log::{error, info};
+use chrono::NaiveDateTime;
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::Rng;
 use regex::Regex;
 use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
 
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
 use std::path::Path;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
+use crate::constants::MAX_STARTUP_PACKET_LENGTH;
 use crate::dns_cache::CachedResolver;
 use crate::errors::Error;
 use crate::pool::{ClientServerMap, ConnectionPool};
-use crate::sharding::ShardingFunction;
+use crate::scram::ScramVerifier;
+use crate::sharding::{ShardingFunction, KETAMA_DEFAULT_VNODES};
 use crate::stats::AddressStats;
-use crate::tls::{load_certs, load_keys};
+use crate::tls::{load_certs, load_keys, server_config_builder};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("PGCAT_GIT_HASH");
 
 /// Globally available configuration.
 static CONFIG: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::default()));
 
+/// Outcome of the last attempt to load the configuration.
+static RELOAD_STATUS: Lazy<ArcSwap<ReloadStatus>> =
+    Lazy::new(|| ArcSwap::from_pointee(ReloadStatus::default()));
+
+/// When the configuration was last loaded, and if the last attempt failed, why.
+#[derive(Clone, Debug, Default)]
+pub struct ReloadStatus {
+    pub last_success: Option<NaiveDateTime>,
+    pub last_attempt: Option<NaiveDateTime>,
+    pub error: Option<String>,
+}
+
 /// Server role: primary or replica.
 #[derive(Clone, PartialEq, Serialize, Deserialize, Hash, std::cmp::Eq, Debug, Copy)]
 pub enum Role {
@@ -107,6 +127,22 @@ pub struct Address {
 
     /// Number of errors encountered since last successful checkout
     pub error_count: Arc<AtomicU64>,
+
+    /// Replication lag in milliseconds, last measured by the replica lag checker.
+    pub replica_lag: Arc<AtomicU64>,
+
+    /// Why the server refused our last login, cleared by the next successful one.
+    pub login_error: Arc<Mutex<Option<(Error, Instant)>>>,
+
+    /// Share of the writes sent to this primary, see `primary_selection`.
+    pub weight: u32,
+
+    /// Availability zone of the server, see `local_zone`.
+    pub zone: Option<String>,
+
+    /// Timeouts (ms) of this server: the most specific of the server, shard, user, pool and general settings.
+    pub connect_timeout: u64,
+    pub healthcheck_timeout: u64,
 }
 
 impl Default for Address {
@@ -125,6 +161,12 @@ impl Default for Address {
             mirrors: Vec::new(),
             stats: Arc::new(AddressStats::default()),
             error_count: Arc::new(AtomicU64::new(0)),
+            replica_lag: Arc::new(AtomicU64::new(0)),
+            login_error: Arc::new(Mutex::new(None)),
+            weight: 1,
+            zone: None,
+            connect_timeout: General::default_connect_timeout(),
+            healthcheck_timeout: General::default_healthcheck_timeout(),
         }
     }
 }
@@ -194,6 +236,14 @@ impl Address {
         self.error_count.load(Ordering::Relaxed)
     }
 
+    pub fn replica_lag(&self) -> u64 {
+        self.replica_lag.load(Ordering::Relaxed)
+    }
+
+    pub fn set_replica_lag(&self, lag: u64) {
+        self.replica_lag.store(lag, Ordering::Relaxed);
+    }
+
     pub fn increment_error_count(&self) {
         self.error_count.fetch_add(1, Ordering::Relaxed);
     }
@@ -201,6 +251,19 @@ impl Address {
     pub fn reset_error_count(&self) {
         self.error_count.store(0, Ordering::Relaxed);
     }
+
+    /// Record the outcome of logging into the server.
+    pub fn set_login_error(&self, error: Option<Error>) {
+        *self.login_error.lock() = error.map(|error| (error, Instant::now()));
+    }
+
+    /// Why the server refused our last login, if it happened within `within`.
+    pub fn login_error(&self, within: Duration) -> Option<Error> {
+        match &*self.login_error.lock() {
+            Some((error, at)) if at.elapsed() < within => Some(error.clone()),
+            _ => None,
+        }
+    }
 }
 
 /// PostgreSQL user.
@@ -213,7 +276,8 @@ pub struct User {
     pub auth_type: AuthType,
     pub server_username: Option<String>,
     pub server_password: Option<String>,
-    pub pool_size: u32,
+    /// Overrides the pool_size of the shards and the general one.
+    pub pool_size: Option<u32>,
     pub min_pool_size: Option<u32>,
     pub pool_mode: Option<PoolMode>,
     pub server_lifetime: Option<u64>,
@@ -221,6 +285,14 @@ pub struct User {
     pub statement_timeout: u64,
     pub connect_timeout: Option<u64>,
     pub idle_timeout: Option<u64>,
+    pub max_queries_per_second: Option<u64>,
+    #[serde(default)] // 0
+    pub rate_limit_wait_timeout: u64,
+    pub max_client_connections: Option<u32>,
+    pub default_role: Option<String>,
+
+    /// Shards the user can query, all of them if not set.
+    pub allowed_shards: Option<Vec<usize>>,
 }
 
 impl Default for User {
@@ -231,13 +303,18 @@ impl Default for User {
             auth_type: AuthType::MD5,
             server_username: None,
             server_password: None,
-            pool_size: 15,
+            pool_size: None,
             min_pool_size: None,
             statement_timeout: 0,
             pool_mode: None,
             server_lifetime: None,
             connect_timeout: None,
             idle_timeout: None,
+            max_queries_per_second: None,
+            rate_limit_wait_timeout: 0,
+            max_client_connections: None,
+            default_role: None,
+            allowed_shards: None,
         }
     }
 }
@@ -247,17 +324,78 @@ impl User {
         AuthType::MD5
     }
 
+    /// Whether the user can query the shard, see `allowed_shards`.
+    pub fn shard_allowed(&self, shard: usize) -> bool {
+        match self.allowed_shards {
+            Some(ref allowed_shards) => allowed_shards.contains(&shard),
+            None => true,
+        }
+    }
+
     fn validate(&self) -> Result<(), Error> {
-        if let Some(min_pool_size) = self.min_pool_size {
-            if min_pool_size > self.pool_size {
+        if let Some(ref password) = self.password {
+            if password.is_empty() {
+                error!("User {} has an empty password", self.username);
+                return Err(Error::BadConfig);
+            }
+
+            if ScramVerifier::is_verifier(password) {
+                if let Err(err) = ScramVerifier::parse(password) {
+                    error!("User {} password is not valid: {}", self.username, err);
+                    return Err(Error::BadConfig);
+                }
+
+                if self.auth_type != AuthType::ScramSha256 {
+                    error!(
+                        "User {} password is a SCRAM-SHA-256 verifier, auth_type must be scram-sha-256",
+                        self.username
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                if self.server_password.is_none() {
+                    warn!(
+                        "User {} password is a SCRAM-SHA-256 verifier and server_password is not set, \
+                        connections to servers that require a password will fail",
+                        self.username
+                    );
+                }
+            }
+        }
+
+        if self.pool_size == Some(0) {
+            error!("User {} pool_size must be greater than 0", self.username);
+            return Err(Error::BadConfig);
+        }
+
+        if self.max_queries_per_second == Some(0) {
+            error!(
+                "User {} max_queries_per_second must be greater than 0",
+                self.username
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if let (Some(min_pool_size), Some(pool_size)) = (self.min_pool_size, self.pool_size) {
+            if min_pool_size > pool_size {
                 error!(
                     "min_pool_size of {} cannot be larger than pool_size of {}",
-                    min_pool_size, self.pool_size
+                    min_pool_size, pool_size
                 );
                 return Err(Error::BadConfig);
             }
         };
 
+        if let Some(ref default_role) = self.default_role {
+            if !["any", "primary", "replica"].contains(&default_role.as_str()) {
+                error!(
+                    "User {} default_role must be 'primary', 'replica', or 'any', got: '{}'",
+                    self.username, default_role
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
         Ok(())
     }
 }
@@ -271,14 +409,31 @@ pub struct General {
     #[serde(default = "General::default_port")]
     pub port: u16,
 
+    #[serde(default)] // None
+    pub unix_socket_dir: Option<String>,
+
     pub enable_prometheus_exporter: Option<bool>,
 
     #[serde(default = "General::default_prometheus_exporter_port")]
     pub prometheus_exporter_port: i16,
 
+    #[serde(default)] // None
+    pub healthcheck_port: Option<u16>,
+
+    /// Serve the admin database only on this port, and only the admin database.
+    #[serde(default)] // None
+    pub admin_port: Option<u16>,
+
     #[serde(default = "General::default_connect_timeout")]
     pub connect_timeout: u64,
 
+    #[serde(default)] // 0
+    pub connect_retries: u32,
+    #[serde(default = "General::default_connect_retry_backoff")]
+    pub connect_retry_backoff: u64,
+    #[serde(default = "General::default_connect_retry_max_backoff")]
+    pub connect_retry_max_backoff: u64,
+
     #[serde(default = "General::default_idle_timeout")]
     pub idle_timeout: u64,
 
@@ -290,6 +445,8 @@ pub struct General {
     pub tcp_keepalives_interval: u64,
     #[serde(default = "General::default_tcp_user_timeout")]
     pub tcp_user_timeout: u64,
+    #[serde(default = "General::default_tcp_no_delay")]
+    pub tcp_no_delay: bool,
 
     #[serde(default)] // False
     pub log_client_connections: bool,
@@ -297,30 +454,134 @@ pub struct General {
     #[serde(default)] // False
     pub log_client_disconnections: bool,
 
+    #[serde(default)] // False
+    pub tracing_enabled: bool,
+
+    /// OTLP/HTTP endpoint of an OpenTelemetry collector to export the query spans to.
+    #[serde(default)] // None
+    pub otlp_endpoint: Option<String>,
+
+    /// Client and server messages larger than this (bytes) close the connection.
+    #[serde(default = "General::default_max_message_size")]
+    pub max_message_size: usize,
+
+    /// Rows and COPY data (bytes) read from a server before forwarding them to the client.
+    #[serde(default = "General::default_max_buffer_size")]
+    pub max_buffer_size: usize,
+
+    /// How much of the servers' error messages clients get.
+    #[serde(default)] // Verbose
+    pub error_verbosity: ErrorVerbosity,
+
+    /// Set application_name on the server to `backend_application_name_template`,
+    /// so pg_stat_activity shows which client is behind each backend.
+    #[serde(default)] // False
+    pub set_backend_application_name: bool,
+
+    #[serde(default = "General::default_backend_application_name_template")]
+    pub backend_application_name_template: String,
+
+    /// Set the `pgcat.client_addr` setting on the server to the client's IP address.
+    #[serde(default)] // False
+    pub set_backend_client_addr: bool,
+
+    #[serde(default)] // False
+    pub allow_multiple_primaries: bool,
+
+    #[serde(default = "General::default_primary_selection")]
+    pub primary_selection: PrimarySelection,
+
+    /// Availability zone pgcat runs in, replicas in this zone are preferred.
+    #[serde(default)] // None
+    pub local_zone: Option<String>,
+
+    /// Sharding function of the pools that don't set their own.
+    #[serde(default = "General::default_sharding_function")]
+    pub sharding_function: ShardingFunction,
+
+    /// Log queries taking longer than this (ms).
+    #[serde(default)] // None
+    pub log_min_duration_ms: Option<u64>,
+
+    #[serde(default = "General::default_log_query_text")]
+    pub log_query_text: bool,
+
     #[serde(default)] // False
     pub dns_cache_enabled: bool,
 
-    #[serde(default = "General::default_dns_max_ttl")]
+    #[serde(default = "General::default_dns_max_ttl", alias = "dns_refresh")]
     pub dns_max_ttl: u64,
 
     #[serde(default = "General::default_shutdown_timeout")]
     pub shutdown_timeout: u64,
 
+    #[serde(default = "General::default_replica_lag_check_interval")]
+    pub replica_lag_check_interval: u64,
+
     #[serde(default = "General::default_healthcheck_timeout")]
     pub healthcheck_timeout: u64,
 
-    #[serde(default = "General::default_healthcheck_delay")]
+    #[serde(
+        default = "General::default_healthcheck_delay",
+        alias = "server_check_delay"
+    )]
     pub healthcheck_delay: u64,
 
+    #[serde(
+        default = "General::default_healthcheck_query",
+        alias = "server_check_query"
+    )]
+    pub healthcheck_query: String,
+
+    #[serde(default)] // false
+    pub healthcheck_verify_role: bool,
+
+    /// Health check the servers in the background this often (ms), 0 to only check them on checkout.
+    #[serde(default)] // 0
+    pub healthcheck_interval: u64,
+
+    /// Randomize the intervals of periodic tasks by up to this fraction of them.
+    #[serde(default = "General::default_interval_jitter")]
+    pub interval_jitter: f64,
+
     #[serde(default = "General::default_ban_time")]
     pub ban_time: i64,
 
-    #[serde(default = "General::default_idle_client_in_transaction_timeout")]
+    #[serde(default)] // false
+    pub ban_backoff: bool,
+
+    #[serde(default = "General::default_max_ban_time")]
+    pub max_ban_time: i64,
+
+    #[serde(
+        default = "General::default_idle_client_in_transaction_timeout",
+        alias = "idle_in_transaction_timeout"
+    )]
     pub idle_client_in_transaction_timeout: u64,
 
+    #[serde(default = "General::default_pause_timeout")]
+    pub pause_timeout: u64,
+
+    #[serde(default = "General::default_statement_timeout")]
+    pub statement_timeout: u64,
+
+    #[serde(default = "General::default_server_reset_query")]
+    pub server_reset_query: String,
+
+    #[serde(default)] // None
+    pub query_wait_timeout: Option<u64>,
+
     #[serde(default = "General::default_server_lifetime")]
     pub server_lifetime: u64,
 
+    /// Server connections of each user to each server, unless set for the user or the shard.
+    #[serde(default = "General::default_pool_size")]
+    pub pool_size: u32,
+
+    /// Idle server connections kept open to each server, for users without their own `min_pool_size`.
+    #[serde(default)] // 0
+    pub min_pool_size: u32,
+
     #[serde(default = "General::default_server_round_robin")] // False
     pub server_round_robin: bool,
 
@@ -333,12 +594,31 @@ pub struct General {
     pub tls_certificate: Option<String>,
     pub tls_private_key: Option<String>,
 
+    #[serde(default)] // None
+    pub tls_min_version: Option<String>,
+
+    #[serde(default)] // None
+    pub tls_ciphers: Option<Vec<String>>,
+
+    #[serde(default)] // None
+    pub client_ca_certificate: Option<String>,
+
+    #[serde(default)] // false
+    pub require_client_certificate: bool,
+
+    /// Disconnect clients asking for GSSAPI encryption instead of telling them to go on without it.
+    #[serde(default)] // false
+    pub reject_gss_encryption: bool,
+
     #[serde(default)] // false
     pub server_tls: bool,
 
     #[serde(default)] // false
     pub verify_server_certificate: bool,
 
+    #[serde(default)] // None
+    pub server_tls_ca_certificate: Option<String>,
+
     pub admin_username: String,
     pub admin_password: String,
 
@@ -348,10 +628,22 @@ pub struct General {
     #[serde(default = "General::default_validate_config")]
     pub validate_config: bool,
 
+    /// Exit at startup if no pool can connect to any of its servers.
+    #[serde(default)] // false
+    pub require_healthy_startup: bool,
+
     // Support for auth query
     pub auth_query: Option<String>,
     pub auth_query_user: Option<String>,
     pub auth_query_password: Option<String>,
+
+    /// How long a password hash obtained with auth_query is used before fetching it again.
+    #[serde(default = "General::default_auth_query_cache_ttl")]
+    pub auth_query_cache_ttl: u64,
+
+    /// Fraction of client traffic sent to mirrors.
+    #[serde(default = "General::default_mirror_sample_rate")]
+    pub mirror_sample_rate: f64,
 }
 
 impl General {
@@ -375,6 +667,14 @@ impl General {
         1000
     }
 
+    pub fn default_connect_retry_backoff() -> u64 {
+        100 // 100 milliseconds
+    }
+
+    pub fn default_connect_retry_max_backoff() -> u64 {
+        2000 // 2 seconds
+    }
+
     // These keepalive defaults should detect a dead connection within 30 seconds.
     // Tokio defaults to disabling keepalives which keeps dead connections around indefinitely.
     // This can lead to permanent server pool exhaustion
@@ -394,6 +694,35 @@ impl General {
         10000 // 10000 milliseconds
     }
 
+    // Queries are small messages, don't wait to batch them with Nagle's algorithm.
+    pub fn default_tcp_no_delay() -> bool {
+        true
+    }
+
+    pub fn default_log_query_text() -> bool {
+        true
+    }
+
+    pub fn default_primary_selection() -> PrimarySelection {
+        PrimarySelection::RoundRobin
+    }
+
+    pub fn default_sharding_function() -> ShardingFunction {
+        ShardingFunction::PgBigintHash
+    }
+
+    pub fn default_max_message_size() -> usize {
+        1024 * 1024 * 1024 // Same as Postgres
+    }
+
+    pub fn default_max_buffer_size() -> usize {
+        8196
+    }
+
+    pub fn default_backend_application_name_template() -> String {
+        "pgcat ({application_name})".to_string()
+    }
+
     pub fn default_idle_timeout() -> u64 {
         600000 // 10 minutes
     }
@@ -406,6 +735,10 @@ impl General {
         30
     }
 
+    pub fn default_replica_lag_check_interval() -> u64 {
+        1000
+    }
+
     pub fn default_healthcheck_timeout() -> u64 {
         1000
     }
@@ -414,10 +747,30 @@ impl General {
         30000
     }
 
+    pub fn default_healthcheck_query() -> String {
+        String::from(";")
+    }
+
+    pub fn default_interval_jitter() -> f64 {
+        0.2
+    }
+
     pub fn default_ban_time() -> i64 {
         60
     }
 
+    pub fn default_max_ban_time() -> i64 {
+        3600
+    }
+
+    pub fn default_auth_query_cache_ttl() -> u64 {
+        0
+    }
+
+    pub fn default_mirror_sample_rate() -> f64 {
+        1.0
+    }
+
     pub fn default_worker_threads() -> usize {
         4
     }
@@ -426,6 +779,29 @@ impl General {
         0
     }
 
+    /// Path of the Unix socket, named like Postgres names it so libpq finds it.
+    pub fn unix_socket_path(&self) -> Option<String> {
+        self.unix_socket_dir
+            .as_ref()
+            .map(|dir| format!("{}/.s.PGSQL.{}", dir.trim_end_matches('/'), self.port))
+    }
+
+    pub fn default_statement_timeout() -> u64 {
+        0
+    }
+
+    pub fn default_server_reset_query() -> String {
+        String::from("DISCARD ALL")
+    }
+
+    pub fn default_pause_timeout() -> u64 {
+        0
+    }
+
+    pub fn default_pool_size() -> u32 {
+        15
+    }
+
     pub fn default_validate_config() -> bool {
         true
     }
@@ -444,45 +820,89 @@ impl Default for General {
         General {
             host: Self::default_host(),
             port: Self::default_port(),
+            unix_socket_dir: None,
             enable_prometheus_exporter: Some(false),
             prometheus_exporter_port: 9930,
+            healthcheck_port: None,
+            admin_port: None,
             connect_timeout: General::default_connect_timeout(),
+            connect_retries: 0,
+            connect_retry_backoff: General::default_connect_retry_backoff(),
+            connect_retry_max_backoff: General::default_connect_retry_max_backoff(),
             idle_timeout: General::default_idle_timeout(),
             tcp_keepalives_idle: Self::default_tcp_keepalives_idle(),
             tcp_keepalives_count: Self::default_tcp_keepalives_count(),
             tcp_keepalives_interval: Self::default_tcp_keepalives_interval(),
             tcp_user_timeout: Self::default_tcp_user_timeout(),
+            tcp_no_delay: Self::default_tcp_no_delay(),
             log_client_connections: false,
             log_client_disconnections: false,
+            tracing_enabled: false,
+            otlp_endpoint: None,
+            max_message_size: Self::default_max_message_size(),
+            max_buffer_size: Self::default_max_buffer_size(),
+            error_verbosity: ErrorVerbosity::default(),
+            set_backend_application_name: false,
+            backend_application_name_template: Self::default_backend_application_name_template(),
+            set_backend_client_addr: false,
+            allow_multiple_primaries: false,
+            primary_selection: Self::default_primary_selection(),
+            local_zone: None,
+            sharding_function: Self::default_sharding_function(),
+            log_min_duration_ms: None,
+            log_query_text: Self::default_log_query_text(),
             dns_cache_enabled: false,
             dns_max_ttl: Self::default_dns_max_ttl(),
             shutdown_timeout: Self::default_shutdown_timeout(),
+            replica_lag_check_interval: Self::default_replica_lag_check_interval(),
             healthcheck_timeout: Self::default_healthcheck_timeout(),
             healthcheck_delay: Self::default_healthcheck_delay(),
+            healthcheck_interval: 0,
+            interval_jitter: Self::default_interval_jitter(),
+            healthcheck_query: Self::default_healthcheck_query(),
+            healthcheck_verify_role: false,
             ban_time: Self::default_ban_time(),
+            ban_backoff: false,
+            max_ban_time: Self::default_max_ban_time(),
             idle_client_in_transaction_timeout: Self::default_idle_client_in_transaction_timeout(),
+            statement_timeout: Self::default_statement_timeout(),
+            server_reset_query: Self::default_server_reset_query(),
+            pause_timeout: Self::default_pause_timeout(),
+            query_wait_timeout: None,
             server_lifetime: Self::default_server_lifetime(),
+            pool_size: Self::default_pool_size(),
+            min_pool_size: 0,
             server_round_robin: Self::default_server_round_robin(),
             worker_threads: Self::default_worker_threads(),
             autoreload: None,
             tls_certificate: None,
             tls_private_key: None,
+            tls_min_version: None,
+            tls_ciphers: None,
+            client_ca_certificate: None,
+            require_client_certificate: false,
+            reject_gss_encryption: false,
             server_tls: false,
             verify_server_certificate: false,
+            server_tls_ca_certificate: None,
             admin_username: String::from("admin"),
             admin_password: String::from("admin"),
             admin_auth_type: AuthType::MD5,
             validate_config: true,
+            require_healthy_startup: false,
             auth_query: None,
             auth_query_user: None,
             auth_query_password: None,
+            auth_query_cache_ttl: Self::default_auth_query_cache_ttl(),
+            mirror_sample_rate: Self::default_mirror_sample_rate(),
         }
     }
 }
 
 /// Pool mode:
 /// - transaction: server serves one transaction,
-/// - session: server is attached to the client.
+/// - session: server is attached to the client,
+/// - statement: server serves one statement, transactions are not allowed.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
 pub enum PoolMode {
     #[serde(alias = "transaction", alias = "Transaction")]
@@ -490,6 +910,9 @@ pub enum PoolMode {
 
     #[serde(alias = "session", alias = "Session")]
     Session,
+
+    #[serde(alias = "statement", alias = "Statement")]
+    Statement,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
@@ -499,6 +922,13 @@ pub enum AuthType {
 
     #[serde(alias = "md5", alias = "MD5")]
     MD5,
+
+    #[serde(
+        alias = "scram-sha-256",
+        alias = "SCRAM-SHA-256",
+        alias = "scram_sha_256"
+    )]
+    ScramSha256,
 }
 
 impl std::fmt::Display for PoolMode {
@@ -506,6 +936,7 @@ impl std::fmt::Display for PoolMode {
         match self {
             PoolMode::Transaction => write!(f, "transaction"),
             PoolMode::Session => write!(f, "session"),
+            PoolMode::Statement => write!(f, "statement"),
         }
     }
 }
@@ -515,10 +946,85 @@ pub enum LoadBalancingMode {
     #[serde(alias = "random", alias = "Random")]
     Random,
 
-    #[serde(alias = "loc", alias = "LOC", alias = "least_outstanding_connections")]
+    #[serde(
+        alias = "loc",
+        alias = "LOC",
+        alias = "least_outstanding_connections",
+        alias = "least_connections"
+    )]
     LeastOutstandingConnections,
 }
 
+/// How much of the servers' error messages are forwarded to clients.
+/// Clients may not be trusted with the names and values errors can contain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash, Default)]
+pub enum ErrorVerbosity {
+    /// Errors are forwarded as they are.
+    #[default]
+    #[serde(alias = "verbose", alias = "Verbose")]
+    Verbose,
+
+    /// Only the severity, SQLSTATE, message and position are kept.
+    #[serde(alias = "terse", alias = "Terse")]
+    Terse,
+
+    /// Like `terse`, with the message replaced by the generic one of the SQLSTATE class.
+    #[serde(alias = "sanitized", alias = "Sanitized")]
+    Sanitized,
+}
+
+impl std::fmt::Display for ErrorVerbosity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorVerbosity::Verbose => write!(f, "verbose"),
+            ErrorVerbosity::Terse => write!(f, "terse"),
+            ErrorVerbosity::Sanitized => write!(f, "sanitized"),
+        }
+    }
+}
+
+/// What to do when a statement of a transaction is routed to another shard than the one
+/// the transaction runs on. The pooler can't commit a transaction atomically on several shards.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash, Default)]
+pub enum CrossShardTransactions {
+    /// Abort the transaction, nothing it did is committed.
+    #[default]
+    #[serde(alias = "reject", alias = "Reject")]
+    Reject,
+
+    /// Fail the statement, the transaction stays open on its shard.
+    #[serde(alias = "pin_first", alias = "PinFirst")]
+    PinFirst,
+}
+
+impl std::fmt::Display for CrossShardTransactions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CrossShardTransactions::Reject => write!(f, "reject"),
+            CrossShardTransactions::PinFirst => write!(f, "pin_first"),
+        }
+    }
+}
+
+/// How writes are spread over the primaries of a shard, with `allow_multiple_primaries`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum PrimarySelection {
+    #[serde(alias = "round_robin", alias = "RoundRobin")]
+    RoundRobin,
+
+    #[serde(alias = "weighted", alias = "Weighted")]
+    Weighted,
+}
+
+impl std::fmt::Display for PrimarySelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrimarySelection::RoundRobin => write!(f, "round_robin"),
+            PrimarySelection::Weighted => write!(f, "weighted"),
+        }
+    }
+}
+
 impl std::fmt::Display for LoadBalancingMode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -549,9 +1055,22 @@ pub struct Pool {
     #[serde(default)] // False
     pub query_parser_read_write_splitting: bool,
 
+    #[serde(default)] // False
+    pub block_ddl: bool,
+
+    #[serde(default = "Pool::default_sticky_primary_in_txn")]
+    pub sticky_primary_in_txn: bool,
+
+    #[serde(default)] // False
+    pub track_set_statements: bool,
+
     #[serde(default)] // False
     pub primary_reads_enabled: bool,
 
+    /// Replicas lagging behind the primary by more than this (ms) don't serve reads.
+    #[serde(default)] // None
+    pub max_replica_lag: Option<u64>,
+
     /// Maximum time to allow for establishing a new server connection.
     pub connect_timeout: Option<u64>,
 
@@ -571,12 +1090,36 @@ pub struct Pool {
     /// longer than this period, the pool will not interrupt it.
     pub server_lifetime: Option<u64>,
 
-    #[serde(default = "Pool::default_sharding_function")]
-    pub sharding_function: ShardingFunction,
+    /// Falls back to `general.sharding_function` when not set.
+    #[serde(default)] // None
+    pub sharding_function: Option<ShardingFunction>,
+
+    /// Number of points per shard on the ring used by the ketama sharding function.
+    #[serde(default = "Pool::default_ketama_vnodes")]
+    pub ketama_vnodes: usize,
+
+    /// Number of recently seen sharding keys to remember the shard of, 0 disables the cache.
+    #[serde(default)] // 0
+    pub shard_cache_size: usize,
 
     #[serde(default = "Pool::default_automatic_sharding_key")]
     pub automatic_sharding_key: Option<String>,
 
+    #[serde(default)] // false
+    pub reject_cross_shard_queries: bool,
+
+    #[serde(default)] // Reject
+    pub cross_shard_transactions: CrossShardTransactions,
+
+    #[serde(default)] // false
+    pub enable_scatter_gather: bool,
+
+    #[serde(default)] // false
+    pub scatter_gather_best_effort: bool,
+
+    #[serde(default)] // 0
+    pub read_retry_count: usize,
+
     pub sharding_key_regex: Option<String>,
     pub shard_id_regex: Option<String>,
     pub regex_search_limit: Option<usize>,
@@ -584,6 +1127,19 @@ pub struct Pool {
     #[serde(default = "Pool::default_default_shard")]
     pub default_shard: DefaultShard,
 
+    /// Shard answering catalog queries, i.e. reading only `pg_catalog` and `information_schema`.
+    #[serde(default)] // None
+    pub catalog_shard: Option<usize>,
+
+    /// Role of the server answering catalog queries, inferred like other queries if not set.
+    #[serde(default)] // None
+    pub catalog_role: Option<Role>,
+
+    /// Connect replication clients (`replication` startup parameter) directly to the primary
+    /// instead of rejecting them.
+    #[serde(default)] // False
+    pub replication_passthrough: bool,
+
     pub auth_query: Option<String>,
     pub auth_query_user: Option<String>,
     pub auth_query_password: Option<String>,
@@ -591,6 +1147,9 @@ pub struct Pool {
     #[serde(default = "Pool::default_cleanup_server_connections")]
     pub cleanup_server_connections: bool,
 
+    #[serde(default)] // None
+    pub server_reset_query: Option<String>,
+
     #[serde(default)] // False
     pub log_client_parameter_status_changes: bool,
 
@@ -625,35 +1184,122 @@ impl Pool {
         s.finish()
     }
 
+    /// Hash of everything but the shards, so shards can be compared separately.
+    pub fn settings_hash_value(&self) -> u64 {
+        Pool {
+            shards: BTreeMap::new(),
+            ..self.clone()
+        }
+        .hash_value()
+    }
+
     pub fn is_auth_query_configured(&self) -> bool {
         self.auth_query_password.is_some()
             && self.auth_query_user.is_some()
             && self.auth_query_password.is_some()
     }
 
-    pub fn default_pool_mode() -> PoolMode {
-        PoolMode::Transaction
-    }
+    /// First key of each shard's range, sorted by shard number.
+    pub fn shard_range_starts(&self) -> Vec<i64> {
+        let mut shards = self.shards.iter().collect::<Vec<_>>();
+        shards.sort_by_key(|(shard_idx, _)| shard_idx.parse::<usize>().unwrap_or(usize::MAX));
 
-    pub fn default_default_shard() -> DefaultShard {
-        DefaultShard::default()
+        shards
+            .iter()
+            .map(|(_, shard)| shard.range_start.unwrap_or(i64::MIN))
+            .collect()
     }
 
-    pub fn default_load_balancing_mode() -> LoadBalancingMode {
-        LoadBalancingMode::Random
-    }
+    /// Range sharding requires every key to be covered by exactly one shard:
+    /// ranges must follow shard numbers, be contiguous, and only the first
+    /// and last shards can be unbounded.
+    fn validate_shard_ranges(&self) -> Result<(), Error> {
+        let mut previous_end = None;
 
-    pub fn default_automatic_sharding_key() -> Option<String> {
-        None
-    }
+        for shard_idx in 0..self.shards.len() {
+            let shard = match self.shards.get(&shard_idx.to_string()) {
+                Some(shard) => shard,
+                None => {
+                    error!("Shard {} is missing, range sharding requires shards numbered starting at 0", shard_idx);
+                    return Err(Error::BadConfig);
+                }
+            };
 
-    pub fn default_default_role() -> String {
-        "any".into()
-    }
+            let first = shard_idx == 0;
+            let last = shard_idx == self.shards.len() - 1;
 
-    pub fn default_sharding_function() -> ShardingFunction {
-        ShardingFunction::PgBigintHash
-    }
+            if first && shard.range_start.is_some() {
+                error!("Shard 0 range_start must not be set, the first shard covers all keys below its range_end");
+                return Err(Error::BadConfig);
+            }
+
+            if last && shard.range_end.is_some() {
+                error!("Shard {} range_end must not be set, the last shard covers all keys from its range_start", shard_idx);
+                return Err(Error::BadConfig);
+            }
+
+            if !first && shard.range_start != previous_end {
+                error!(
+                    "Shard {} range_start must be equal to the range_end of shard {} for ranges to be contiguous",
+                    shard_idx,
+                    shard_idx - 1
+                );
+                return Err(Error::BadConfig);
+            }
+
+            if !last && shard.range_end.is_none() {
+                error!("Shard {} range_end must be set", shard_idx);
+                return Err(Error::BadConfig);
+            }
+
+            if let (Some(range_start), Some(range_end)) = (shard.range_start, shard.range_end) {
+                if range_start >= range_end {
+                    error!(
+                        "Shard {} range_start {} must be smaller than range_end {}",
+                        shard_idx, range_start, range_end
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+
+            previous_end = shard.range_end;
+        }
+
+        Ok(())
+    }
+
+    pub fn default_pool_mode() -> PoolMode {
+        PoolMode::Transaction
+    }
+
+    pub fn default_default_shard() -> DefaultShard {
+        DefaultShard::default()
+    }
+
+    pub fn default_load_balancing_mode() -> LoadBalancingMode {
+        LoadBalancingMode::Random
+    }
+
+    pub fn default_automatic_sharding_key() -> Option<String> {
+        None
+    }
+
+    pub fn default_sticky_primary_in_txn() -> bool {
+        true
+    }
+
+    pub fn default_default_role() -> String {
+        "any".into()
+    }
+
+    pub fn default_ketama_vnodes() -> usize {
+        KETAMA_DEFAULT_VNODES
+    }
+
+    /// The pool's sharding function, or the general one if it doesn't set one.
+    pub fn sharding_function(&self, general: &General) -> ShardingFunction {
+        self.sharding_function.unwrap_or(general.sharding_function)
+    }
 
     pub fn default_cleanup_server_connections() -> bool {
         true
@@ -682,7 +1328,7 @@ impl Pool {
         50
     }
 
-    pub fn validate(&mut self) -> Result<(), Error> {
+    pub fn validate(&mut self, general: &General) -> Result<(), Error> {
         match self.default_role.as_ref() {
             "any" => (),
             "primary" => (),
@@ -696,9 +1342,19 @@ impl Pool {
             }
         };
 
+        let mut shard_numbers = BTreeSet::new();
+
         for (shard_idx, shard) in &self.shards {
             match shard_idx.parse::<usize>() {
-                Ok(_) => (),
+                Ok(shard_number) => {
+                    if !shard_numbers.insert(shard_number) {
+                        error!(
+                            "Shard {} is defined more than once (as '{}')",
+                            shard_number, shard_idx
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
                 Err(_) => {
                     error!(
                         "Shard '{}' is not a valid number, shards must be numbered starting at 0",
@@ -710,6 +1366,17 @@ impl Pool {
             shard.validate()?;
         }
 
+        // Sharding functions map keys to shards 0..N-1.
+        if let Some(missing) = (0..self.shards.len()).find(|n| !shard_numbers.contains(n)) {
+            error!(
+                "Shard {} is missing, shards must be numbered from 0 to {} without gaps, found shard {}",
+                missing,
+                self.shards.len() - 1,
+                shard_numbers.last().unwrap()
+            );
+            return Err(Error::BadConfig);
+        }
+
         for (option, name) in [
             (&self.shard_id_regex, "shard_id_regex"),
             (&self.sharding_key_regex, "sharding_key_regex"),
@@ -729,6 +1396,11 @@ impl Pool {
             return Err(Error::BadConfig);
         }
 
+        if self.block_ddl && !self.query_parser_enabled {
+            error!("block_ddl is only valid when query_parser_enabled is true");
+            return Err(Error::BadConfig);
+        }
+
         if self.plugins.is_some() && !self.query_parser_enabled {
             error!("plugins are only valid when query_parser_enabled is true");
             return Err(Error::BadConfig);
@@ -760,8 +1432,69 @@ impl Pool {
             }
         }
 
+        if let Some(catalog_shard) = self.catalog_shard {
+            if catalog_shard >= self.shards.len() {
+                error!("Invalid catalog_shard {}", catalog_shard);
+                return Err(Error::BadConfig);
+            }
+        }
+
+        // Replication clients are relayed to a single primary, which only makes sense unsharded.
+        if self.replication_passthrough && self.shards.len() > 1 {
+            error!("replication_passthrough is only supported on pools with one shard");
+            return Err(Error::BadConfig);
+        }
+
+        if self.sharding_function(general) == ShardingFunction::Range {
+            self.validate_shard_ranges()?;
+        }
+
+        if self.sharding_function(general) == ShardingFunction::Ketama && self.ketama_vnodes == 0 {
+            error!("ketama_vnodes must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
+        let mut usernames = HashSet::new();
         for user in self.users.values() {
+            if !usernames.insert(&user.username) {
+                error!(
+                    "User {} is configured more than once in the same pool",
+                    user.username
+                );
+                return Err(Error::BadConfig);
+            }
+
             user.validate()?;
+
+            if let Some(ref allowed_shards) = user.allowed_shards {
+                if allowed_shards.is_empty() {
+                    error!(
+                        "User {} allowed_shards can't be empty, remove it to allow all shards",
+                        user.username
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                if let Some(shard) = allowed_shards
+                    .iter()
+                    .find(|shard| !shard_numbers.contains(shard))
+                {
+                    error!(
+                        "User {} allowed_shards contains shard {}, which is not configured",
+                        user.username, shard
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                if let DefaultShard::Shard(shard) = self.default_shard {
+                    if !user.shard_allowed(shard) {
+                        warn!(
+                            "User {} is not allowed on the default shard {}, its queries without a shard will be rejected",
+                            user.username, shard
+                        );
+                    }
+                }
+            }
         }
 
         if self.db_activity_based_routing {
@@ -795,20 +1528,35 @@ impl Default for Pool {
             query_parser_enabled: false,
             query_parser_max_length: None,
             query_parser_read_write_splitting: false,
+            block_ddl: false,
+            sticky_primary_in_txn: true,
+            track_set_statements: false,
             primary_reads_enabled: false,
+            max_replica_lag: None,
             connect_timeout: None,
             idle_timeout: None,
             server_lifetime: None,
-            sharding_function: ShardingFunction::PgBigintHash,
+            sharding_function: None,
+            ketama_vnodes: Self::default_ketama_vnodes(),
+            shard_cache_size: 0,
             automatic_sharding_key: None,
+            reject_cross_shard_queries: false,
+            cross_shard_transactions: CrossShardTransactions::default(),
+            enable_scatter_gather: false,
+            scatter_gather_best_effort: false,
+            read_retry_count: 0,
             sharding_key_regex: None,
             shard_id_regex: None,
             regex_search_limit: Some(1000),
             default_shard: Self::default_default_shard(),
+            catalog_shard: None,
+            catalog_role: None,
+            replication_passthrough: false,
             auth_query: None,
             auth_query_user: None,
             auth_query_password: None,
             cleanup_server_connections: true,
+            server_reset_query: None,
             log_client_parameter_status_changes: false,
             prepared_statements_cache_size: Self::default_prepared_statements_cache_size(),
             db_activity_based_routing: Self::default_db_activity_based_routing(),
@@ -827,6 +1575,39 @@ pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub role: Role,
+
+    /// Disabled servers are kept in the config but don't get a pool.
+    #[serde(default = "ServerConfig::default_enabled")]
+    pub enabled: bool,
+
+    /// Share of the writes sent to this primary, when there are several.
+    #[serde(default = "ServerConfig::default_weight")]
+    pub weight: u32,
+
+    /// Availability zone, replicas in `general.local_zone` are preferred.
+    #[serde(default)] // None
+    pub zone: Option<String>,
+
+    /// Override the shard's, pool's and general timeouts (ms) for this server.
+    #[serde(default)] // None
+    pub connect_timeout: Option<u64>,
+    #[serde(default)] // None
+    pub healthcheck_timeout: Option<u64>,
+}
+
+impl ServerConfig {
+    pub fn default_enabled() -> bool {
+        true
+    }
+
+    pub fn default_weight() -> u32 {
+        1
+    }
+
+    /// Clients can use the server. Mirror and disabled servers don't get a pool.
+    pub fn pooled(&self) -> bool {
+        self.enabled && self.role != Role::Mirror
+    }
 }
 
 // No Shard Specified handling.
@@ -880,15 +1661,142 @@ pub struct MirrorServerConfig {
     pub mirroring_target_index: usize,
 }
 
+/// A struct written either as a table or as an array of its fields, e.g. servers as
+/// `[host, port, role]`. The TOML deserializer reads both, the YAML one only tables.
+struct TableOrArray<T>(T);
+
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for TableOrArray<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: serde::Deserialize<'de>> serde::de::Visitor<'de> for Visitor<T> {
+            type Value = T;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a table or an array")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<T, A::Error> {
+                T::deserialize(serde::de::value::SeqAccessDeserializer::new(seq))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+                T::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer
+            .deserialize_any(Visitor(std::marker::PhantomData))
+            .map(TableOrArray)
+    }
+}
+
+fn deserialize_servers<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    let servers: Vec<TableOrArray<T>> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(servers.into_iter().map(|server| server.0).collect())
+}
+
+fn deserialize_optional_servers<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    let servers: Option<Vec<TableOrArray<T>>> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(servers.map(|servers| servers.into_iter().map(|server| server.0).collect()))
+}
+
 /// Shard configuration.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash, Eq)]
 pub struct Shard {
     pub database: String,
+    pub pool_size: Option<u32>,
+    pub range_start: Option<i64>,
+    pub range_end: Option<i64>,
+
+    /// Send writes to a replica while the primary is banned.
+    #[serde(default)] // False
+    pub auto_failover: bool,
+
+    /// Indices in `servers` of the replicas to fail over to, by priority.
+    pub failover_priority: Option<Vec<usize>>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_servers")]
     pub mirrors: Option<Vec<MirrorServerConfig>>,
+    #[serde(deserialize_with = "deserialize_servers")]
     pub servers: Vec<ServerConfig>,
+
+    /// Override the pool's and general timeouts (ms) for the servers of this shard.
+    #[serde(default)] // None
+    pub connect_timeout: Option<u64>,
+    #[serde(default)] // None
+    pub healthcheck_timeout: Option<u64>,
+
+    /// Override the pool's `primary_reads_enabled` for this shard.
+    #[serde(default)] // None
+    pub primary_reads_enabled: Option<bool>,
 }
 
 impl Shard {
+    pub fn hash_value(&self) -> u64 {
+        let mut s = DefaultHasher::new();
+        self.hash(&mut s);
+        s.finish()
+    }
+
+    /// Maximum number of server connections for the user to each server of this shard:
+    /// the user's pool_size, else the shard's, else the general one.
+    pub fn pool_size(&self, user: &User, general: &General) -> u32 {
+        user.pool_size
+            .or(self.pool_size)
+            .unwrap_or(general.pool_size)
+    }
+
+    /// Whether the primary of this shard serves reads along with the replicas.
+    pub fn primary_reads_enabled(&self, pool: &Pool) -> bool {
+        self.primary_reads_enabled
+            .unwrap_or(pool.primary_reads_enabled)
+    }
+
+    /// Position of the server among the servers of the shard that get a pool,
+    /// None for mirror and disabled servers.
+    pub fn address_index(&self, server_index: usize) -> Option<usize> {
+        match self.servers.get(server_index) {
+            Some(server) if server.pooled() => Some(
+                self.servers[..server_index]
+                    .iter()
+                    .filter(|server| server.pooled())
+                    .count(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Replicas to send writes to when the primary is banned, by priority,
+    /// as indices in `servers`. Empty if automatic failover is disabled.
+    pub fn failover_priority(&self) -> Vec<usize> {
+        if !self.auto_failover {
+            return Vec::new();
+        }
+
+        match self.failover_priority {
+            Some(ref failover_priority) => failover_priority.clone(),
+            None => self
+                .servers
+                .iter()
+                .enumerate()
+                .filter(|(_, server)| server.role == Role::Replica && server.enabled)
+                .map(|(index, _)| index)
+                .collect(),
+        }
+    }
+
     pub fn validate(&self) -> Result<(), Error> {
         // We use addresses as unique identifiers,
         // let's make sure they are unique in the config as well.
@@ -900,18 +1808,58 @@ impl Shard {
             return Err(Error::BadConfig);
         }
 
+        if self.pool_size == Some(0) {
+            error!("Shard {} pool_size must be greater than 0", self.database);
+            return Err(Error::BadConfig);
+        }
+
+        let timeouts = [
+            ("connect_timeout", self.connect_timeout),
+            ("healthcheck_timeout", self.healthcheck_timeout),
+        ]
+        .into_iter()
+        .chain(self.servers.iter().flat_map(|server| {
+            [
+                ("connect_timeout", server.connect_timeout),
+                ("healthcheck_timeout", server.healthcheck_timeout),
+            ]
+        }));
+
+        for (name, timeout) in timeouts {
+            if timeout == Some(0) {
+                error!("Shard {} {} must be greater than 0", self.database, name);
+                return Err(Error::BadConfig);
+            }
+        }
+
         for server in &self.servers {
             dup_check.insert(server);
 
-            // Check that we define only zero or one primary.
-            if server.role == Role::Primary {
+            // Disabled primaries don't count, several are only allowed with allow_multiple_primaries.
+            if server.role == Role::Primary && server.enabled {
                 primary_count += 1
             }
+
+            if server.weight == 0 {
+                error!(
+                    "Shard {} server {}:{} weight must be greater than 0",
+                    self.database, server.host, server.port
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        if !self.servers.iter().any(|server| server.pooled()) {
+            error!(
+                "Shard {} has no enabled primary or replica, at least one is required",
+                self.database
+            );
+            return Err(Error::BadConfig);
         }
 
-        if primary_count > 1 {
+        if self.auto_failover && primary_count > 1 {
             error!(
-                "Shard {} has more than one primary configured",
+                "Shard {} has auto_failover enabled and more than one primary configured",
                 self.database
             );
             return Err(Error::BadConfig);
@@ -922,6 +1870,39 @@ impl Shard {
             return Err(Error::BadConfig);
         }
 
+        if self.auto_failover && primary_count == 0 {
+            error!(
+                "Shard {} has auto_failover enabled but no primary configured",
+                self.database
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if let Some(ref failover_priority) = self.failover_priority {
+            let mut seen = HashSet::new();
+
+            for index in failover_priority {
+                match self.servers.get(*index) {
+                    Some(server) if server.role == Role::Replica => (),
+                    _ => {
+                        error!(
+                            "Shard {} failover_priority entry {} is not the index of a replica in servers",
+                            self.database, index
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
+
+                if !seen.insert(index) {
+                    error!(
+                        "Shard {} failover_priority contains server {} more than once",
+                        self.database, index
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -930,16 +1911,127 @@ impl Default for Shard {
     fn default() -> Shard {
         Shard {
             database: String::from("postgres"),
+            pool_size: None,
+            range_start: None,
+            range_end: None,
+            auto_failover: false,
+            failover_priority: None,
             mirrors: None,
+            connect_timeout: None,
+            healthcheck_timeout: None,
+            primary_reads_enabled: None,
             servers: vec![ServerConfig {
                 host: String::from("localhost"),
                 port: 5432,
                 role: Role::Primary,
+                enabled: true,
+                weight: 1,
+                zone: None,
+                connect_timeout: None,
+                healthcheck_timeout: None,
             }],
         }
     }
 }
 
+/// How a client matching an `[[hba]]` rule is authenticated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum HbaMethod {
+    /// Allowed without a password.
+    #[serde(alias = "trust", alias = "Trust")]
+    Trust,
+
+    /// Authenticated with the user's `auth_type`.
+    #[serde(alias = "password", alias = "Password")]
+    Password,
+
+    /// Refused.
+    #[serde(alias = "reject", alias = "Reject")]
+    Reject,
+}
+
+/// Client access rule, like a line of pg_hba.conf.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash, Eq)]
+pub struct HbaRule {
+    /// User name, or "all".
+    #[serde(default = "HbaRule::default_all")]
+    pub user: String,
+
+    /// Pool name, or "all".
+    #[serde(default = "HbaRule::default_all")]
+    pub database: String,
+
+    /// Client network, e.g. "10.0.0.0/8". An address without a prefix length matches only itself.
+    /// "local" matches the clients of the Unix socket.
+    pub cidr: String,
+
+    pub method: HbaMethod,
+}
+
+impl HbaRule {
+    pub fn default_all() -> String {
+        String::from("all")
+    }
+
+    /// Network address and prefix length of `cidr`.
+    pub fn network(&self) -> Option<(IpAddr, u8)> {
+        let (address, prefix) = match self.cidr.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (self.cidr.as_str(), None),
+        };
+
+        let address: IpAddr = address.trim().parse().ok()?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok()?,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            return None;
+        }
+
+        Some((address, prefix))
+    }
+
+    /// Whether the rule applies to the client, `ip` is None for Unix socket clients.
+    pub fn matches(&self, user: &str, database: &str, ip: Option<IpAddr>) -> bool {
+        (self.user == "all" || self.user == user)
+            && (self.database == "all" || self.database == database)
+            && match ip {
+                Some(ip) => self
+                    .network()
+                    .is_some_and(|(network, prefix)| cidr_contains(network, prefix, ip)),
+                None => self.is_local(),
+            }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.cidr == "local"
+    }
+}
+
+fn cidr_contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    // IPv4 clients of a dual-stack listener have IPv4-mapped IPv6 addresses.
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, Hash, Eq)]
 pub struct Plugins {
     pub intercept: Option<Intercept>,
@@ -1069,6 +2161,10 @@ pub struct Config {
     // Plugins that should run in all pools.
     pub plugins: Option<Plugins>,
 
+    // Client access rules, checked in order before authentication.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hba: Vec<HbaRule>,
+
     // Connection pools.
     pub pools: HashMap<String, Pool>,
 }
@@ -1084,6 +2180,42 @@ impl Config {
         String::from("pgcat.toml")
     }
 
+    /// How a client connecting from `ip` authenticates, from the first matching `[[hba]]` rule,
+    /// `ip` being None for Unix socket clients. Without rules, every client authenticates with
+    /// its password; with rules, clients that match none of them are rejected (None).
+    pub fn hba_method(&self, user: &str, database: &str, ip: Option<IpAddr>) -> Option<HbaMethod> {
+        if self.hba.is_empty() {
+            return Some(HbaMethod::Password);
+        }
+
+        let rule = self
+            .hba
+            .iter()
+            .find(|rule| rule.matches(user, database, ip))?;
+
+        // The admin database is only trusted by a rule naming it, for the admin user.
+        // A rule for "all" databases still requires the admin password.
+        let admin = ["pgcat", "pgbouncer"].contains(&database);
+
+        if admin
+            && rule.method == HbaMethod::Trust
+            && (rule.database != database || user != self.general.admin_username)
+        {
+            return Some(HbaMethod::Password);
+        }
+
+        Some(rule.method)
+    }
+
+    /// Pools without a sharding function use the global one.
+    pub fn fill_up_sharding_function(&mut self) {
+        for pool in self.pools.values_mut() {
+            if pool.sharding_function.is_none() {
+                pool.sharding_function = Some(self.general.sharding_function);
+            }
+        }
+    }
+
     pub fn fill_up_auth_query_config(&mut self) {
         for (_name, pool) in self.pools.iter_mut() {
             if pool.auth_query.is_none() {
@@ -1109,6 +2241,7 @@ impl Default for Config {
             path: Self::default_path(),
             general: General::default(),
             plugins: None,
+            hba: Vec::new(),
             pools: HashMap::default(),
         }
     }
@@ -1152,16 +2285,58 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                         format!("pools.{}.default_role", pool_name),
                         pool.default_role.clone(),
                     ),
+                    (
+                        format!("pools.{}.block_ddl", pool_name),
+                        pool.block_ddl.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.sticky_primary_in_txn", pool_name),
+                        pool.sticky_primary_in_txn.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.track_set_statements", pool_name),
+                        pool.track_set_statements.to_string(),
+                    ),
                     (
                         format!("pools.{}.sharding_function", pool_name),
-                        pool.sharding_function.to_string(),
+                        pool.sharding_function(&config.general).to_string(),
                     ),
                     (
-                        format!("pools.{:?}.shard_count", pool_name),
-                        pool.shards.len().to_string(),
+                        format!("pools.{}.cross_shard_transactions", pool_name),
+                        pool.cross_shard_transactions.to_string(),
                     ),
                     (
-                        format!("pools.{:?}.users", pool_name),
+                        format!("pools.{}.ketama_vnodes", pool_name),
+                        pool.ketama_vnodes.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.catalog_shard", pool_name),
+                        match pool.catalog_shard {
+                            Some(shard) => shard.to_string(),
+                            None => "-".to_string(),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.catalog_role", pool_name),
+                        match pool.catalog_role {
+                            Some(role) => role.to_string(),
+                            None => "-".to_string(),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.replication_passthrough", pool_name),
+                        pool.replication_passthrough.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.shard_cache_size", pool_name),
+                        pool.shard_cache_size.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.shard_count", pool_name),
+                        pool.shards.len().to_string(),
+                    ),
+                    (
+                        format!("pools.{}.users", pool_name),
                         pool.users
                             .values()
                             .map(|user| &user.username)
@@ -1180,10 +2355,36 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                 "prometheus_exporter_port".to_string(),
                 config.general.prometheus_exporter_port.to_string(),
             ),
+            (
+                "healthcheck_port".to_string(),
+                match config.general.healthcheck_port {
+                    Some(port) => port.to_string(),
+                    None => "-".to_string(),
+                },
+            ),
+            (
+                "admin_port".to_string(),
+                match config.general.admin_port {
+                    Some(port) => port.to_string(),
+                    None => "-".to_string(),
+                },
+            ),
             (
                 "connect_timeout".to_string(),
                 config.general.connect_timeout.to_string(),
             ),
+            (
+                "connect_retries".to_string(),
+                config.general.connect_retries.to_string(),
+            ),
+            (
+                "connect_retry_backoff".to_string(),
+                config.general.connect_retry_backoff.to_string(),
+            ),
+            (
+                "connect_retry_max_backoff".to_string(),
+                config.general.connect_retry_max_backoff.to_string(),
+            ),
             (
                 "idle_timeout".to_string(),
                 config.general.idle_timeout.to_string(),
@@ -1196,11 +2397,125 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                 "shutdown_timeout".to_string(),
                 config.general.shutdown_timeout.to_string(),
             ),
+            (
+                "tracing_enabled".to_string(),
+                config.general.tracing_enabled.to_string(),
+            ),
+            (
+                "otlp_endpoint".to_string(),
+                config
+                    .general
+                    .otlp_endpoint
+                    .clone()
+                    .unwrap_or_else(|| "-".to_string()),
+            ),
+            (
+                "max_message_size".to_string(),
+                config.general.max_message_size.to_string(),
+            ),
+            (
+                "max_buffer_size".to_string(),
+                config.general.max_buffer_size.to_string(),
+            ),
+            (
+                "require_healthy_startup".to_string(),
+                config.general.require_healthy_startup.to_string(),
+            ),
+            (
+                "error_verbosity".to_string(),
+                config.general.error_verbosity.to_string(),
+            ),
+            (
+                "set_backend_application_name".to_string(),
+                config.general.set_backend_application_name.to_string(),
+            ),
+            (
+                "backend_application_name_template".to_string(),
+                config.general.backend_application_name_template.clone(),
+            ),
+            (
+                "set_backend_client_addr".to_string(),
+                config.general.set_backend_client_addr.to_string(),
+            ),
+            (
+                "log_min_duration_ms".to_string(),
+                match config.general.log_min_duration_ms {
+                    Some(duration) => duration.to_string(),
+                    None => "-".to_string(),
+                },
+            ),
+            (
+                "log_query_text".to_string(),
+                config.general.log_query_text.to_string(),
+            ),
+            (
+                "pool_size".to_string(),
+                config.general.pool_size.to_string(),
+            ),
+            (
+                "min_pool_size".to_string(),
+                config.general.min_pool_size.to_string(),
+            ),
+            (
+                "replica_lag_check_interval".to_string(),
+                config.general.replica_lag_check_interval.to_string(),
+            ),
             (
                 "healthcheck_delay".to_string(),
                 config.general.healthcheck_delay.to_string(),
             ),
+            (
+                "healthcheck_query".to_string(),
+                config.general.healthcheck_query.clone(),
+            ),
+            (
+                "healthcheck_verify_role".to_string(),
+                config.general.healthcheck_verify_role.to_string(),
+            ),
+            (
+                "healthcheck_interval".to_string(),
+                config.general.healthcheck_interval.to_string(),
+            ),
+            (
+                "interval_jitter".to_string(),
+                config.general.interval_jitter.to_string(),
+            ),
             ("ban_time".to_string(), config.general.ban_time.to_string()),
+            (
+                "allow_multiple_primaries".to_string(),
+                config.general.allow_multiple_primaries.to_string(),
+            ),
+            (
+                "primary_selection".to_string(),
+                config.general.primary_selection.to_string(),
+            ),
+            (
+                "local_zone".to_string(),
+                match config.general.local_zone {
+                    Some(ref zone) => zone.clone(),
+                    None => "-".to_string(),
+                },
+            ),
+            (
+                "sharding_function".to_string(),
+                config.general.sharding_function.to_string(),
+            ),
+            (
+                "ban_backoff".to_string(),
+                config.general.ban_backoff.to_string(),
+            ),
+            (
+                "max_ban_time".to_string(),
+                config.general.max_ban_time.to_string(),
+            ),
+            (
+                "auth_query_cache_ttl".to_string(),
+                config.general.auth_query_cache_ttl.to_string(),
+            ),
+            (
+                "mirror_sample_rate".to_string(),
+                config.general.mirror_sample_rate.to_string(),
+            ),
             (
                 "idle_client_in_transaction_timeout".to_string(),
                 config
@@ -1208,6 +2523,72 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                     .idle_client_in_transaction_timeout
                     .to_string(),
             ),
+            (
+                "statement_timeout".to_string(),
+                config.general.statement_timeout.to_string(),
+            ),
+            (
+                "server_reset_query".to_string(),
+                config.general.server_reset_query.clone(),
+            ),
+            (
+                "pause_timeout".to_string(),
+                config.general.pause_timeout.to_string(),
+            ),
+            (
+                "query_wait_timeout".to_string(),
+                match config.general.query_wait_timeout {
+                    Some(timeout) => timeout.to_string(),
+                    None => "-".to_string(),
+                },
+            ),
+            (
+                "tls_certificate".to_string(),
+                config.general.tls_certificate.clone().unwrap_or_default(),
+            ),
+            (
+                "tls_private_key".to_string(),
+                config.general.tls_private_key.clone().unwrap_or_default(),
+            ),
+            (
+                "tls_min_version".to_string(),
+                config
+                    .general
+                    .tls_min_version
+                    .clone()
+                    .unwrap_or("1.2".to_string()),
+            ),
+            (
+                "tls_ciphers".to_string(),
+                match config.general.tls_ciphers {
+                    Some(ref ciphers) => ciphers.join(","),
+                    None => "-".to_string(),
+                },
+            ),
+            (
+                "client_ca_certificate".to_string(),
+                config
+                    .general
+                    .client_ca_certificate
+                    .clone()
+                    .unwrap_or_default(),
+            ),
+            (
+                "require_client_certificate".to_string(),
+                config.general.require_client_certificate.to_string(),
+            ),
+            (
+                "reject_gss_encryption".to_string(),
+                config.general.reject_gss_encryption.to_string(),
+            ),
+            (
+                "server_tls_ca_certificate".to_string(),
+                config
+                    .general
+                    .server_tls_ca_certificate
+                    .clone()
+                    .unwrap_or_default(),
+            ),
         ];
 
         r.append(&mut static_settings);
@@ -1220,16 +2601,38 @@ impl Config {
     pub fn show(&self) {
         info!("Config path: {}", self.path);
         info!("Ban time: {}s", self.general.ban_time);
+        if self.general.ban_backoff {
+            info!("Max ban time: {}s", self.general.max_ban_time);
+        }
         info!(
             "Idle client in transaction timeout: {}ms",
             self.general.idle_client_in_transaction_timeout
         );
+        info!("Statement timeout: {}ms", self.general.statement_timeout);
+        if self.general.allow_multiple_primaries {
+            info!(
+                "Multiple primaries allowed, selected by: {}",
+                self.general.primary_selection
+            );
+        }
         info!("Worker threads: {}", self.general.worker_threads);
         info!(
             "Healthcheck timeout: {}ms",
             self.general.healthcheck_timeout
         );
         info!("Connection timeout: {}ms", self.general.connect_timeout);
+        if let Some(port) = self.general.healthcheck_port {
+            info!("Healthcheck port: {}", port);
+        }
+        if let Some(port) = self.general.admin_port {
+            info!("Admin port: {}", port);
+        }
+        info!(
+            "Connection retries: {}, backoff: {}ms, max backoff: {}ms",
+            self.general.connect_retries,
+            self.general.connect_retry_backoff,
+            self.general.connect_retry_max_backoff
+        );
         info!("Idle timeout: {}ms", self.general.idle_timeout);
         info!(
             "Log client connections: {}",
@@ -1239,13 +2642,67 @@ impl Config {
             "Log client disconnections: {}",
             self.general.log_client_disconnections
         );
+        info!("Tracing enabled: {}", self.general.tracing_enabled);
+        if let Some(otlp_endpoint) = &self.general.otlp_endpoint {
+            info!("OTLP endpoint: {}", otlp_endpoint);
+        }
+        info!(
+            "Reject GSSAPI encryption requests: {}",
+            self.general.reject_gss_encryption
+        );
+        if !self.hba.is_empty() {
+            info!("HBA rules: {}", self.hba.len());
+        }
+        info!("Max message size: {}", self.general.max_message_size);
+        info!("Max buffer size: {}", self.general.max_buffer_size);
+        info!("Error verbosity: {}", self.general.error_verbosity);
+        info!(
+            "Require healthy startup: {}",
+            self.general.require_healthy_startup
+        );
+        if let Some(local_zone) = &self.general.local_zone {
+            info!("Local zone: {}", local_zone);
+        }
+        if self.general.set_backend_application_name {
+            info!(
+                "Backend application_name: {}",
+                self.general.backend_application_name_template
+            );
+        }
+        info!(
+            "Set backend client address: {}",
+            self.general.set_backend_client_addr
+        );
+        info!(
+            "Default sharding function: {}",
+            self.general.sharding_function
+        );
+        if let Some(duration) = self.general.log_min_duration_ms {
+            info!("Log queries slower than: {}ms", duration);
+            info!("Log query text: {}", self.general.log_query_text);
+        }
         info!("Shutdown timeout: {}ms", self.general.shutdown_timeout);
         info!("Healthcheck delay: {}ms", self.general.healthcheck_delay);
+        info!(
+            "Healthcheck interval: {}",
+            match self.general.healthcheck_interval {
+                0 => "disabled".to_string(),
+                interval => format!("{}ms", interval),
+            }
+        );
+        info!("Interval jitter: {}", self.general.interval_jitter);
+        info!("Healthcheck query: {}", self.general.healthcheck_query);
+        info!(
+            "Healthcheck verifies server role: {}",
+            self.general.healthcheck_verify_role
+        );
         info!(
             "Default max server lifetime: {}ms",
             self.general.server_lifetime
         );
         info!("Server round robin: {}", self.general.server_round_robin);
+        info!("Default pool size: {}", self.general.pool_size);
+        info!("Default min pool size: {}", self.general.min_pool_size);
         match self.general.tls_certificate.clone() {
             Some(tls_certificate) => {
                 info!("TLS certificate: {}", tls_certificate);
@@ -1253,6 +2710,14 @@ impl Config {
                 if let Some(tls_private_key) = self.general.tls_private_key.clone() {
                     info!("TLS private key: {}", tls_private_key);
                     info!("TLS support is enabled");
+
+                    if let Some(ref client_ca_certificate) = self.general.client_ca_certificate {
+                        info!("TLS client CA certificate: {}", client_ca_certificate);
+                        info!(
+                            "TLS client certificate required: {}",
+                            self.general.require_client_certificate
+                        );
+                    }
                 }
             }
 
@@ -1265,6 +2730,9 @@ impl Config {
             "Server TLS certificate verification: {}",
             self.general.verify_server_certificate
         );
+        if let Some(ref ca_certificate) = self.general.server_tls_ca_certificate {
+            info!("Server TLS CA certificate: {}", ca_certificate);
+        }
         info!(
             "Plugins: {}",
             match self.plugins {
@@ -1281,7 +2749,12 @@ impl Config {
                 pool_config
                     .users
                     .values()
-                    .map(|user_cfg| user_cfg.pool_size)
+                    .map(|user_cfg| pool_config
+                        .shards
+                        .values()
+                        .map(|shard| shard.pool_size(user_cfg, &self.general))
+                        .max()
+                        .unwrap_or(self.general.pool_size))
                     .sum::<u32>()
                     .to_string()
             );
@@ -1321,8 +2794,14 @@ impl Config {
             info!(
                 "[pool: {}] Sharding function: {}",
                 pool_name,
-                pool_config.sharding_function.to_string()
+                pool_config.sharding_function(&self.general).to_string()
             );
+            if let Some(catalog_shard) = pool_config.catalog_shard {
+                info!(
+                    "[pool: {}] Catalog queries go to shard: {}",
+                    pool_name, catalog_shard
+                );
+            }
             info!(
                 "[pool: {}] Primary reads: {}",
                 pool_name, pool_config.primary_reads_enabled
@@ -1340,6 +2819,15 @@ impl Config {
                 "[pool: {}] Infer role from query: {}",
                 pool_name, pool_config.query_parser_read_write_splitting
             );
+            info!("[pool: {}] Block DDL: {}", pool_name, pool_config.block_ddl);
+            info!(
+                "[pool: {}] Sticky primary in transactions: {}",
+                pool_name, pool_config.sticky_primary_in_txn
+            );
+            info!(
+                "[pool: {}] Track SET statements: {}",
+                pool_name, pool_config.track_set_statements
+            );
             info!(
                 "[pool: {}] Number of shards: {}",
                 pool_name,
@@ -1398,13 +2886,18 @@ impl Config {
             for user in &pool_config.users {
                 info!(
                     "[pool: {}][user: {}] Pool size: {}",
-                    pool_name, user.1.username, user.1.pool_size,
+                    pool_name,
+                    user.1.username,
+                    match user.1.pool_size {
+                        Some(pool_size) => pool_size.to_string(),
+                        None => "shard or general pool_size".into(),
+                    },
                 );
                 info!(
                     "[pool: {}][user: {}] Minimum pool size: {}",
                     pool_name,
                     user.1.username,
-                    user.1.min_pool_size.unwrap_or(0)
+                    user.1.min_pool_size.unwrap_or(self.general.min_pool_size)
                 );
                 info!(
                     "[pool: {}][user: {}] Statement timeout: {}",
@@ -1446,11 +2939,142 @@ impl Config {
                         None => "not set".to_string(),
                     }
                 );
+                info!(
+                    "[pool: {}][user: {}] Default server role: {}",
+                    pool_name,
+                    user.1.username,
+                    user.1
+                        .default_role
+                        .as_ref()
+                        .unwrap_or(&pool_config.default_role)
+                );
             }
         }
     }
 
     pub fn validate(&mut self) -> Result<(), Error> {
+        self.validate_general()?;
+
+        for pool in self.pools.values_mut() {
+            pool.validate(&self.general)?;
+        }
+
+        Ok(())
+    }
+
+    /// Validate everything but the settings of each pool.
+    fn validate_general(&self) -> Result<(), Error> {
+        if self.general.ban_backoff && self.general.max_ban_time < self.general.ban_time {
+            error!(
+                "max_ban_time ({}) must be greater than or equal to ban_time ({})",
+                self.general.max_ban_time, self.general.ban_time
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if self.general.admin_port == Some(self.general.port) {
+            error!(
+                "admin_port must be different from port ({})",
+                self.general.port
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if let Some(otlp_endpoint) = &self.general.otlp_endpoint {
+            if !otlp_endpoint.starts_with("http://") {
+                error!(
+                    "otlp_endpoint must be an http:// URL, e.g. http://localhost:4318, got {}",
+                    otlp_endpoint
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        for rule in &self.hba {
+            if !rule.is_local() && rule.network().is_none() {
+                error!("hba rule has an invalid cidr: {:?}", rule.cidr);
+                return Err(Error::BadConfig);
+            }
+        }
+
+        // Large enough for the messages of the startup, authentication and a simple query.
+        if self.general.max_message_size < MAX_STARTUP_PACKET_LENGTH as usize {
+            error!(
+                "max_message_size must be at least {} bytes, got {}",
+                MAX_STARTUP_PACKET_LENGTH, self.general.max_message_size
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if self.general.max_buffer_size == 0 {
+            error!("max_buffer_size must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
+        if !(0.0..1.0).contains(&self.general.interval_jitter) {
+            error!(
+                "interval_jitter must be at least 0.0 and less than 1.0, got {}",
+                self.general.interval_jitter
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if !(0.0..=1.0).contains(&self.general.mirror_sample_rate) {
+            error!(
+                "mirror_sample_rate must be between 0.0 and 1.0, got {}",
+                self.general.mirror_sample_rate
+            );
+            return Err(Error::BadConfig);
+        }
+
+        // Mirror servers only get mirrored traffic, they'd be unused without it.
+        if self.general.mirror_sample_rate == 0.0 {
+            for (pool_name, pool) in &self.pools {
+                for (shard_id, shard) in &pool.shards {
+                    if shard
+                        .servers
+                        .iter()
+                        .any(|server| server.role == Role::Mirror && server.enabled)
+                    {
+                        error!(
+                            "Pool {} shard {} has mirror servers but mirroring is disabled, \
+                            mirror_sample_rate must be greater than 0",
+                            pool_name, shard_id
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
+            }
+        }
+
+        if self.general.pool_size == 0 {
+            error!("pool_size must be greater than 0");
+            return Err(Error::BadConfig);
+        }
+
+        // Users without their own min_pool_size use the general one.
+        for (pool_name, pool) in &self.pools {
+            for user in pool.users.values() {
+                let min_pool_size = user.min_pool_size.unwrap_or(self.general.min_pool_size);
+
+                for (shard_idx, shard) in &pool.shards {
+                    let pool_size = shard.pool_size(user, &self.general);
+
+                    if min_pool_size > pool_size {
+                        error!(
+                            "min_pool_size of {} cannot be larger than pool_size of {} for user {} in pool {} shard {}",
+                            min_pool_size,
+                            pool_size,
+                            user.username,
+                            pool_name,
+                            shard_idx
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
+            }
+        }
+
         // Validation for auth_query feature
         if self.general.auth_query.is_some()
             && (self.general.auth_query_user.is_none()
@@ -1527,8 +3151,68 @@ impl Config {
             }
         };
 
-        for pool in self.pools.values_mut() {
-            pool.validate()?;
+        server_config_builder(&self.general)?;
+
+        if let Some(ref client_ca_certificate) = self.general.client_ca_certificate {
+            if self.general.tls_certificate.is_none() {
+                error!(
+                    "client_ca_certificate is set, but TLS is not configured with tls_certificate"
+                );
+                return Err(Error::BadConfig);
+            }
+
+            match load_certs(Path::new(client_ca_certificate)) {
+                Ok(certs) if !certs.is_empty() => (),
+                Ok(_) => {
+                    error!("client_ca_certificate contains no certificates");
+                    return Err(Error::BadConfig);
+                }
+                Err(err) => {
+                    error!("client_ca_certificate is incorrectly configured: {:?}", err);
+                    return Err(Error::BadConfig);
+                }
+            }
+        } else if self.general.require_client_certificate {
+            error!("require_client_certificate is enabled, but client_ca_certificate is not set");
+            return Err(Error::BadConfig);
+        }
+
+        if let Some(ref ca_certificate) = self.general.server_tls_ca_certificate {
+            match load_certs(Path::new(ca_certificate)) {
+                Ok(certs) if !certs.is_empty() => (),
+                Ok(_) => {
+                    error!("server_tls_ca_certificate contains no certificates");
+                    return Err(Error::BadConfig);
+                }
+                Err(err) => {
+                    error!(
+                        "server_tls_ca_certificate is incorrectly configured: {:?}",
+                        err
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        if !self.general.allow_multiple_primaries {
+            for (pool_name, pool) in &self.pools {
+                for shard in pool.shards.values() {
+                    let primary_count = shard
+                        .servers
+                        .iter()
+                        .filter(|server| server.role == Role::Primary && server.enabled)
+                        .count();
+
+                    if primary_count > 1 {
+                        error!(
+                            "Shard {} of pool {} has more than one primary configured, \
+                            set allow_multiple_primaries to allow it",
+                            shard.database, pool_name
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
+            }
         }
 
         Ok(())
@@ -1542,18 +3226,195 @@ pub fn get_config() -> Config {
     (*(*CONFIG.load())).clone()
 }
 
+pub fn get_reload_status() -> ReloadStatus {
+    (*(*RELOAD_STATUS.load())).clone()
+}
+
+fn set_reload_status(error: Option<String>) {
+    let now = chrono::offset::Utc::now().naive_utc();
+    let last_success = match error {
+        Some(_) => RELOAD_STATUS.load().last_success,
+        None => Some(now),
+    };
+
+    RELOAD_STATUS.store(Arc::new(ReloadStatus {
+        last_success,
+        last_attempt: Some(now),
+        error,
+    }));
+}
+
 pub fn get_idle_client_in_transaction_timeout() -> u64 {
     CONFIG.load().general.idle_client_in_transaction_timeout
 }
 
+pub fn get_statement_timeout() -> u64 {
+    CONFIG.load().general.statement_timeout
+}
+
+pub fn get_pause_timeout() -> u64 {
+    CONFIG.load().general.pause_timeout
+}
+
+pub fn get_tracing_enabled() -> bool {
+    CONFIG.load().general.tracing_enabled
+}
+
+pub fn get_otlp_endpoint() -> Option<String> {
+    CONFIG.load().general.otlp_endpoint.clone()
+}
+
+pub fn get_log_min_duration() -> Option<u64> {
+    CONFIG.load().general.log_min_duration_ms
+}
+
+pub fn get_log_query_text() -> bool {
+    CONFIG.load().general.log_query_text
+}
+
+/// The application_name to set on the server for a client, if `set_backend_application_name` is on.
+pub fn get_backend_application_name(
+    application_name: &str,
+    client_addr: &str,
+    username: &str,
+    pool_name: &str,
+) -> Option<String> {
+    let config = CONFIG.load();
+
+    if !config.general.set_backend_application_name {
+        return None;
+    }
+
+    Some(
+        config
+            .general
+            .backend_application_name_template
+            .replace("{application_name}", application_name)
+            .replace("{client_addr}", client_addr)
+            .replace("{user}", username)
+            .replace("{pool}", pool_name),
+    )
+}
+
+pub fn get_max_message_size() -> usize {
+    CONFIG.load().general.max_message_size
+}
+
+pub fn get_max_buffer_size() -> usize {
+    CONFIG.load().general.max_buffer_size
+}
+
+pub fn get_error_verbosity() -> ErrorVerbosity {
+    CONFIG.load().general.error_verbosity
+}
+
+/// Randomize an interval by up to `jitter` (a fraction of it) either way,
+/// so the periodic tasks of pgcat instances sharing servers don't hit them all at once.
+pub fn jittered(interval: Duration, jitter: f64) -> Duration {
+    if jitter <= 0.0 {
+        return interval;
+    }
+
+    interval.mul_f64(1.0 + rand::thread_rng().gen_range(-jitter..=jitter))
+}
+
+pub fn get_local_zone() -> Option<String> {
+    CONFIG.load().general.local_zone.clone()
+}
+
+pub fn get_set_backend_client_addr() -> bool {
+    CONFIG.load().general.set_backend_client_addr
+}
+
+fn parse_toml_config(contents: &str) -> Result<Config, String> {
+    toml::from_str(contents).map_err(|err| err.to_string())
+}
+
+fn parse_yaml_config(contents: &str) -> Result<Config, String> {
+    serde_yaml::from_str(contents).map_err(|err| err.to_string())
+}
+
+/// Parse the contents of a config file, in TOML or YAML depending on its extension.
+fn deserialize_config(path: &str, contents: &str) -> Result<Config, String> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+
+    match extension.as_deref() {
+        Some("toml") => parse_toml_config(contents).map_err(|err| format!("as TOML: {}", err)),
+        Some("yaml") | Some("yml") => {
+            parse_yaml_config(contents).map_err(|err| format!("as YAML: {}", err))
+        }
+
+        // Unknown extension, try both formats.
+        _ => parse_toml_config(contents).or_else(|toml_err| {
+            parse_yaml_config(contents).map_err(|yaml_err| {
+                format!("neither as TOML: {}, nor as YAML: {}", toml_err, yaml_err)
+            })
+        }),
+    }
+}
+
 /// Parse the configuration file located at the path.
 pub async fn parse(path: &str) -> Result<(), Error> {
+    let result = parse_config(path).await;
+    set_reload_status(result.as_ref().err().map(|err| err.to_string()));
+    result
+}
+
+async fn parse_config(path: &str) -> Result<(), Error> {
+    let mut config = load_config(path).await?;
+    config.validate()?;
+
+    // Update the configuration globally.
+    CONFIG.store(Arc::new(config.clone()));
+
+    Ok(())
+}
+
+/// Validate a config file without applying it, e.g. before deploying it.
+/// Unlike `parse`, every pool is validated even if one is invalid.
+/// Returns the sections with errors, which are logged as they're found.
+pub async fn check(path: &str) -> Result<Config, Vec<String>> {
+    let mut config = load_config(path)
+        .await
+        .map_err(|_| vec![String::from("config file")])?;
+
+    let mut errors = Vec::new();
+
+    if config.validate_general().is_err() {
+        errors.push(String::from("general settings"));
+    }
+
+    let mut pool_names: Vec<String> = config.pools.keys().cloned().collect();
+    pool_names.sort();
+
+    for pool_name in pool_names {
+        if let Some(pool) = config.pools.get_mut(&pool_name) {
+            if pool.validate(&config.general).is_err() {
+                errors.push(format!("pool {}", pool_name));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(errors)
+    }
+}
+
+async fn load_config(path: &str) -> Result<Config, Error> {
     let mut contents = String::new();
     let mut file = match File::open(path).await {
         Ok(file) => file,
         Err(err) => {
             error!("Could not open '{}': {}", path, err.to_string());
-            return Err(Error::BadConfig);
+            return Err(Error::ConfigParseError(format!(
+                "could not open '{}': {}",
+                path, err
+            )));
         }
     };
 
@@ -1561,27 +3422,29 @@ pub async fn parse(path: &str) -> Result<(), Error> {
         Ok(_) => (),
         Err(err) => {
             error!("Could not read config file: {}", err.to_string());
-            return Err(Error::BadConfig);
+            return Err(Error::ConfigParseError(format!(
+                "could not read config file: {}",
+                err
+            )));
         }
     };
 
-    let mut config: Config = match toml::from_str(&contents) {
+    let mut config = match deserialize_config(path, &contents) {
         Ok(config) => config,
         Err(err) => {
-            error!("Could not parse config file: {}", err.to_string());
-            return Err(Error::BadConfig);
+            error!("Could not parse config file {}", err);
+            return Err(Error::ConfigParseError(format!(
+                "could not parse config file {}",
+                err
+            )));
         }
     };
 
     config.fill_up_auth_query_config();
-    config.validate()?;
-
+    config.fill_up_sharding_function();
     config.path = path.to_string();
 
-    // Update the configuration globally.
-    CONFIG.store(Arc::new(config.clone()));
-
-    Ok(())
+    Ok(config)
 }
 
 pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, Error> {
@@ -1591,7 +3454,7 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
         Ok(()) => (),
         Err(err) => {
             error!("Config reload error: {:?}", err);
-            return Err(Error::BadConfig);
+            return Err(err);
         }
     };
 
@@ -1604,7 +3467,12 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
 
     if old_config != new_config {
         info!("Config changed, reloading");
-        ConnectionPool::from_config(client_server_map).await?;
+
+        if let Err(err) = ConnectionPool::from_config(client_server_map).await {
+            set_reload_status(Some(err.to_string()));
+            return Err(err);
+        }
+
         Ok(true)
     } else {
         Ok(false)
@@ -1654,7 +3522,10 @@ mod test {
                 .unwrap(),
             "other_user"
         );
-        assert_eq!(get_config().pools["sharded_db"].users["1"].pool_size, 21);
+        assert_eq!(
+            get_config().pools["sharded_db"].users["1"].pool_size,
+            Some(21)
+        );
         assert_eq!(get_config().pools["sharded_db"].default_role, "any");
 
         assert_eq!(
@@ -1682,12 +3553,499 @@ mod test {
                 .unwrap(),
             "simple_user"
         );
-        assert_eq!(get_config().pools["simple_db"].users["0"].pool_size, 5);
+        assert_eq!(
+            get_config().pools["simple_db"].users["0"].pool_size,
+            Some(5)
+        );
         assert_eq!(get_config().general.auth_query, None);
         assert_eq!(get_config().general.auth_query_user, None);
         assert_eq!(get_config().general.auth_query_password, None);
     }
 
+    #[test]
+    fn test_jittered() {
+        let interval = Duration::from_millis(1000);
+        assert_eq!(jittered(interval, 0.0), interval);
+
+        let intervals: Vec<Duration> = (0..100).map(|_| jittered(interval, 0.2)).collect();
+        for jittered in &intervals {
+            assert!(*jittered >= Duration::from_millis(800));
+            assert!(*jittered <= Duration::from_millis(1200));
+        }
+        assert!(intervals.windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let config = check("pgcat.toml").await.unwrap();
+        assert_eq!(config.pools.len(), 2);
+
+        assert_eq!(
+            check("nonexistent.toml").await.unwrap_err(),
+            vec![String::from("config file")]
+        );
+    }
+
+    #[test]
+    fn test_yaml_config() {
+        let toml = std::fs::read_to_string("pgcat.toml").unwrap();
+        let yaml = std::fs::read_to_string("pgcat.yaml").unwrap();
+
+        let mut config = deserialize_config("pgcat.yaml", &yaml).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config, deserialize_config("pgcat.toml", &toml).unwrap());
+
+        // Without a known extension, TOML then YAML are tried.
+        assert_eq!(deserialize_config("pgcat.conf", &yaml).unwrap(), config);
+        assert_eq!(deserialize_config("pgcat.conf", &toml).unwrap(), config);
+
+        let err = deserialize_config("pgcat.conf", "general: [").unwrap_err();
+        assert!(err.starts_with("neither as TOML: "), "{}", err);
+        assert!(err.contains(", nor as YAML: "), "{}", err);
+
+        assert!(deserialize_config("pgcat.yml", &toml)
+            .unwrap_err()
+            .starts_with("as YAML: "));
+
+        // Servers are arrays or tables, and keep precise errors.
+        let yaml = yaml.replace(
+            "- [localhost, 5432, replica]",
+            "- { host: localhost, port: 5432, role: replica }",
+        );
+        assert_eq!(deserialize_config("pgcat.yaml", &yaml).unwrap(), config);
+        let yaml = yaml.replace(
+            "[\"127.0.0.1\", 5432, primary]",
+            "[\"127.0.0.1\", 5432, primry]",
+        );
+        let err = deserialize_config("pgcat.yaml", &yaml).unwrap_err();
+        assert!(err.contains("unknown variant `primry`"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_users() {
+        let mut pool = Pool {
+            shards: BTreeMap::from([(String::from("0"), Shard::default())]),
+            ..Pool::default()
+        };
+
+        let user = User {
+            username: String::from("analytics"),
+            password: Some(String::from("analytics")),
+            ..User::default()
+        };
+
+        pool.users = BTreeMap::from([(String::from("0"), user.clone())]);
+        assert!(pool.validate(&General::default()).is_ok());
+
+        // Same username twice in the same pool.
+        pool.users = BTreeMap::from([
+            (String::from("0"), user.clone()),
+            (String::from("1"), user.clone()),
+        ]);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        // Empty password.
+        pool.users = BTreeMap::from([(
+            String::from("0"),
+            User {
+                password: Some(String::new()),
+                ..user.clone()
+            },
+        )]);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        // Unknown default_role.
+        for (default_role, valid) in [("replica", true), ("standby", false)] {
+            pool.users = BTreeMap::from([(
+                String::from("0"),
+                User {
+                    default_role: Some(default_role.to_string()),
+                    ..user.clone()
+                },
+            )]);
+            assert_eq!(pool.validate(&General::default()).is_ok(), valid);
+        }
+    }
+
+    #[test]
+    fn test_shard_pool_size() {
+        let user = User {
+            password: Some(String::from("secret")),
+            min_pool_size: Some(5),
+            ..User::default()
+        };
+
+        let pool = Pool {
+            users: BTreeMap::from([(String::from("0"), user.clone())]),
+            shards: BTreeMap::from([
+                (String::from("0"), Shard::default()),
+                (
+                    String::from("1"),
+                    Shard {
+                        pool_size: Some(50),
+                        ..Shard::default()
+                    },
+                ),
+            ]),
+            ..Pool::default()
+        };
+
+        let mut config = Config {
+            general: General {
+                pool_size: 10,
+                ..General::default()
+            },
+            pools: HashMap::from([(String::from("db"), pool)]),
+            ..Config::default()
+        };
+
+        // The shard overrides the general pool_size.
+        assert!(config.validate().is_ok());
+        let general = &config.general;
+        assert_eq!(config.pools["db"].shards["0"].pool_size(&user, general), 10);
+        assert_eq!(config.pools["db"].shards["1"].pool_size(&user, general), 50);
+
+        // The user overrides both.
+        let user = User {
+            pool_size: Some(20),
+            ..user
+        };
+        assert_eq!(config.pools["db"].shards["0"].pool_size(&user, general), 20);
+        assert_eq!(config.pools["db"].shards["1"].pool_size(&user, general), 20);
+
+        // Smaller than the user's min_pool_size.
+        let shard = config
+            .pools
+            .get_mut("db")
+            .unwrap()
+            .shards
+            .get_mut("1")
+            .unwrap();
+        shard.pool_size = Some(2);
+        assert_eq!(config.validate(), Err(Error::BadConfig));
+
+        let pool = config.pools.get_mut("db").unwrap();
+        pool.shards.get_mut("1").unwrap().pool_size = Some(0);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        pool.shards.get_mut("1").unwrap().pool_size = None;
+        pool.users.get_mut("0").unwrap().pool_size = Some(0);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        config.general.pool_size = 0;
+        config.pools.clear();
+        assert_eq!(config.validate(), Err(Error::BadConfig));
+    }
+
+    #[test]
+    fn test_shard_timeouts() {
+        let mut shard = Shard {
+            connect_timeout: Some(10_000),
+            ..Shard::default()
+        };
+        shard.servers[0].healthcheck_timeout = Some(5_000);
+        assert!(shard.validate().is_ok());
+
+        shard.servers[0].healthcheck_timeout = Some(0);
+        assert_eq!(shard.validate(), Err(Error::BadConfig));
+
+        shard.servers[0].healthcheck_timeout = None;
+        shard.connect_timeout = Some(0);
+        assert_eq!(shard.validate(), Err(Error::BadConfig));
+    }
+
+    #[test]
+    fn test_hba() {
+        let rule = |user: &str, cidr: &str, method| HbaRule {
+            user: user.to_string(),
+            database: HbaRule::default_all(),
+            cidr: cidr.to_string(),
+            method,
+        };
+        let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+        let mut config = Config::default();
+        assert_eq!(
+            config.hba_method("app", "db", ip("1.2.3.4")),
+            Some(HbaMethod::Password)
+        );
+
+        config.hba = vec![
+            rule("admin", "0.0.0.0/0", HbaMethod::Reject),
+            rule("all", "10.0.0.0/8", HbaMethod::Password),
+            rule("all", "127.0.0.1", HbaMethod::Trust),
+            rule("all", "fd00::/8", HbaMethod::Password),
+        ];
+        assert!(config.validate().is_ok());
+
+        assert_eq!(
+            config.hba_method("app", "db", ip("10.1.2.3")),
+            Some(HbaMethod::Password)
+        );
+        assert_eq!(
+            config.hba_method("admin", "db", ip("10.1.2.3")),
+            Some(HbaMethod::Reject)
+        );
+        assert_eq!(
+            config.hba_method("app", "db", ip("::ffff:127.0.0.1")),
+            Some(HbaMethod::Trust)
+        );
+        assert_eq!(
+            config.hba_method("app", "db", ip("fd12::1")),
+            Some(HbaMethod::Password)
+        );
+        assert_eq!(config.hba_method("app", "db", ip("11.0.0.1")), None);
+        assert_eq!(config.hba_method("app", "db", ip("127.0.0.2")), None);
+
+        // The admin database isn't trusted by a rule for all databases.
+        let admin_username = String::from("pgcat_admin");
+        config.general.admin_username.clone_from(&admin_username);
+        assert_eq!(
+            config.hba_method(&admin_username, "pgcat", ip("127.0.0.1")),
+            Some(HbaMethod::Password)
+        );
+
+        config.hba.insert(
+            0,
+            HbaRule {
+                database: String::from("pgcat"),
+                ..rule("all", "127.0.0.1", HbaMethod::Trust)
+            },
+        );
+        assert_eq!(
+            config.hba_method(&admin_username, "pgcat", ip("127.0.0.1")),
+            Some(HbaMethod::Trust)
+        );
+        assert_eq!(
+            config.hba_method("app", "pgcat", ip("127.0.0.1")),
+            Some(HbaMethod::Password)
+        );
+
+        // Unix socket clients only match local rules.
+        assert_eq!(config.hba_method("app", "db", None), None);
+        config.hba.push(rule("all", "local", HbaMethod::Password));
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.hba_method("app", "db", None),
+            Some(HbaMethod::Password)
+        );
+        assert_eq!(config.hba_method("app", "db", ip("11.0.0.1")), None);
+
+        config.hba = vec![rule("all", "10.0.0.0/33", HbaMethod::Trust)];
+        assert_eq!(config.validate(), Err(Error::BadConfig));
+    }
+
+    #[test]
+    fn test_validate_shard_numbering() {
+        let mut pool = Pool {
+            shards: BTreeMap::from([
+                (String::from("0"), Shard::default()),
+                (String::from("1"), Shard::default()),
+                (String::from("2"), Shard::default()),
+            ]),
+            ..Pool::default()
+        };
+        assert!(pool.validate(&General::default()).is_ok());
+
+        // Gap, shard 2 is missing.
+        pool.shards.remove("2");
+        pool.shards.insert(String::from("3"), Shard::default());
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        // Duplicate, "01" is shard 1.
+        pool.shards.remove("3");
+        pool.shards.insert(String::from("01"), Shard::default());
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        // Replication clients can't be relayed to a sharded pool.
+        pool.shards.remove("01");
+        pool.replication_passthrough = true;
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        pool.shards.remove("1");
+        assert!(pool.validate(&General::default()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_shard_ranges() {
+        let shard = |range_start, range_end| Shard {
+            range_start,
+            range_end,
+            ..Shard::default()
+        };
+
+        let mut pool = Pool {
+            sharding_function: Some(ShardingFunction::Range),
+            ..Pool::default()
+        };
+
+        pool.shards = BTreeMap::from([
+            (String::from("0"), shard(None, Some(100))),
+            (String::from("1"), shard(Some(100), Some(1000))),
+            (String::from("2"), shard(Some(1000), None)),
+        ]);
+        assert!(pool.validate(&General::default()).is_ok());
+        assert_eq!(pool.shard_range_starts(), vec![i64::MIN, 100, 1000]);
+
+        // Gap between shard 0 and shard 1.
+        pool.shards.get_mut("1").unwrap().range_start = Some(101);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        // Overlap between shard 0 and shard 1.
+        pool.shards.get_mut("1").unwrap().range_start = Some(99);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        // Empty range.
+        pool.shards.get_mut("1").unwrap().range_start = Some(100);
+        pool.shards.get_mut("1").unwrap().range_end = Some(100);
+        pool.shards.get_mut("2").unwrap().range_start = Some(100);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+
+        // Keys above the last shard's range_end are not covered.
+        pool.shards.get_mut("1").unwrap().range_end = Some(1000);
+        pool.shards.get_mut("2").unwrap().range_start = Some(1000);
+        pool.shards.get_mut("2").unwrap().range_end = Some(2000);
+        assert_eq!(pool.validate(&General::default()), Err(Error::BadConfig));
+    }
+
+    #[test]
+    fn test_failover_priority() {
+        let mut shard = Shard {
+            auto_failover: true,
+            servers: vec![
+                ServerConfig {
+                    host: String::from("replica-1"),
+                    port: 5432,
+                    role: Role::Replica,
+                    enabled: true,
+                    weight: 1,
+                    zone: None,
+                    connect_timeout: None,
+                    healthcheck_timeout: None,
+                },
+                ServerConfig {
+                    host: String::from("primary"),
+                    port: 5432,
+                    role: Role::Primary,
+                    enabled: true,
+                    weight: 1,
+                    zone: None,
+                    connect_timeout: None,
+                    healthcheck_timeout: None,
+                },
+                ServerConfig {
+                    host: String::from("replica-2"),
+                    port: 5432,
+                    role: Role::Replica,
+                    enabled: true,
+                    weight: 1,
+                    zone: None,
+                    connect_timeout: None,
+                    healthcheck_timeout: None,
+                },
+            ],
+            ..Shard::default()
+        };
+
+        // Replicas in config order by default.
+        assert!(shard.validate().is_ok());
+        assert_eq!(shard.failover_priority(), vec![0, 2]);
+
+        shard.failover_priority = Some(vec![2, 0]);
+        assert!(shard.validate().is_ok());
+        assert_eq!(shard.failover_priority(), vec![2, 0]);
+
+        // Can't fail over to the primary.
+        shard.failover_priority = Some(vec![1]);
+        assert_eq!(shard.validate(), Err(Error::BadConfig));
+
+        shard.failover_priority = Some(vec![3]);
+        assert_eq!(shard.validate(), Err(Error::BadConfig));
+
+        shard.failover_priority = Some(vec![0, 0]);
+        assert_eq!(shard.validate(), Err(Error::BadConfig));
+
+        shard.auto_failover = false;
+        shard.failover_priority = Some(vec![2, 0]);
+        assert!(shard.failover_priority().is_empty());
+    }
+
+    #[test]
+    fn test_disabled_servers() {
+        let server = |host: &str, role, enabled| ServerConfig {
+            host: String::from(host),
+            port: 5432,
+            role,
+            enabled,
+            weight: 1,
+            zone: None,
+            connect_timeout: None,
+            healthcheck_timeout: None,
+        };
+
+        let mut shard = Shard {
+            auto_failover: true,
+            servers: vec![
+                server("replica-1", Role::Replica, false),
+                server("primary", Role::Primary, true),
+                server("replica-2", Role::Replica, true),
+                server("old-primary", Role::Primary, false),
+            ],
+            ..Shard::default()
+        };
+
+        // Disabled primaries don't count towards the one primary limit.
+        assert!(shard.validate().is_ok());
+        assert_eq!(shard.failover_priority(), vec![2]);
+
+        // Disabled servers don't get a pool.
+        assert_eq!(shard.address_index(0), None);
+        assert_eq!(shard.address_index(1), Some(0));
+        assert_eq!(shard.address_index(2), Some(1));
+        assert_eq!(shard.address_index(3), None);
+
+        shard.servers[3].enabled = true;
+        assert_eq!(shard.validate(), Err(Error::BadConfig));
+
+        for server in shard.servers.iter_mut() {
+            server.enabled = false;
+        }
+        shard.auto_failover = false;
+        assert_eq!(shard.validate(), Err(Error::BadConfig));
+    }
+
+    #[test]
+    fn test_sharding_function_fallback() {
+        let mut config = Config::default();
+        config.general.sharding_function = ShardingFunction::Ketama;
+        config
+            .pools
+            .insert(String::from("default"), Pool::default());
+        config.pools.insert(
+            String::from("legacy"),
+            Pool {
+                sharding_function: Some(ShardingFunction::PgBigintHash),
+                ..Pool::default()
+            },
+        );
+
+        let general = &config.general;
+        assert_eq!(
+            config.pools["default"].sharding_function(general),
+            ShardingFunction::Ketama
+        );
+        assert_eq!(
+            config.pools["legacy"].sharding_function(general),
+            ShardingFunction::PgBigintHash
+        );
+
+        config.fill_up_sharding_function();
+        assert_eq!(
+            config.pools["default"].sharding_function,
+            Some(ShardingFunction::Ketama)
+        );
+    }
+
     #[tokio::test]
     async fn test_serialize_configs() {
         parse("pgcat.toml").await.unwrap();
@@ -11,6 +11,18 @@ pub const SSL_REQUEST_CODE: i32 = 80877103;
 // CancelRequest: the cancel request code.
 pub const CANCEL_REQUEST_CODE: i32 = 80877102;
 
+// GSSENCRequest: used to indicate we want a GSSAPI encrypted connection.
+pub const GSSENC_REQUEST_CODE: i32 = 80877104;
+
+// Startup packets longer than this are not from a Postgres client, same limit as Postgres.
+pub const MAX_STARTUP_PACKET_LENGTH: i32 = 10000;
+
+// Startup packets with more parameters than this are rejected.
+pub const MAX_STARTUP_PARAMETERS: usize = 64;
+
+// AuthenticationCleartextPassword
+pub const CLEARTEXT_PASSWORD: i32 = 3;
+
 // AuthenticationMD5Password
 pub const MD5_ENCRYPTED_PASSWORD: i32 = 5;
 
@@ -24,6 +36,23 @@ pub const NONCE_LENGTH: usize = 24;
 // AuthenticationOk
 pub const AUTHENTICATION_SUCCESSFUL: i32 = 0;
 
+// SQLSTATE codes for errors generated by pgcat, see Appendix A of the Postgres docs.
+pub const SYSTEM_ERROR: &str = "58000";
+pub const CONNECTION_FAILURE: &str = "08006";
+pub const PROTOCOL_VIOLATION: &str = "08P01";
+pub const FEATURE_NOT_SUPPORTED: &str = "0A000";
+pub const INVALID_PARAMETER_VALUE: &str = "22023";
+pub const IDLE_IN_TRANSACTION_SESSION_TIMEOUT: &str = "25P03";
+pub const INVALID_SQL_STATEMENT_NAME: &str = "26000";
+pub const INVALID_AUTHORIZATION_SPECIFICATION: &str = "28000";
+pub const INVALID_PASSWORD: &str = "28P01";
+pub const INVALID_CATALOG_NAME: &str = "3D000";
+pub const INSUFFICIENT_PRIVILEGE: &str = "42501";
+pub const TOO_MANY_CONNECTIONS: &str = "53300";
+pub const CONFIGURATION_LIMIT_EXCEEDED: &str = "53400";
+pub const QUERY_CANCELED: &str = "57014";
+pub const ADMIN_SHUTDOWN: &str = "57P01";
+
 // ErrorResponse: A code identifying the field type; if zero, this is the message terminator and no string follows.
 pub const MESSAGE_TERMINATOR: u8 = 0;
 
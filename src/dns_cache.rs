@@ -1,4 +1,4 @@
-use crate::config::get_config;
+use crate::config::{get_config, jittered};
 use crate::errors::Error;
 use arc_swap::ArcSwap;
 use log::{debug, error, info, warn};
@@ -30,6 +30,13 @@ impl AddrSet {
             set: HashSet::new(),
         }
     }
+
+    /// All the resolved addresses, sorted so they are tried in the same order every time.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses: Vec<IpAddr> = self.set.iter().cloned().collect();
+        addresses.sort();
+        addresses
+    }
 }
 
 impl From<LookupIp> for AddrSet {
@@ -216,7 +223,7 @@ impl CachedResolver {
                 }
             }
             debug!("Finished refreshing cached DNS addresses.");
-            sleep(interval).await;
+            sleep(jittered(interval, get_config().general.interval_jitter)).await;
         }
     }
 
@@ -362,6 +369,21 @@ mod tests {
         assert!(!resolver.has_changed(hostname, &addr_set));
     }
 
+    #[test]
+    fn addresses() {
+        let mut addr_set = AddrSet::new();
+        for address in ["10.0.0.2", "10.0.0.1", "10.0.0.3"] {
+            addr_set.set.insert(address.parse().unwrap());
+        }
+
+        let addresses: Vec<String> = addr_set
+            .addresses()
+            .iter()
+            .map(|address| address.to_string())
+            .collect();
+        assert_eq!(addresses, vec!["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    }
+
     #[tokio::test]
     async fn unknown_host() {
         let config = CachedResolverConfig {
@@ -15,8 +15,14 @@ pub enum Error {
     ServerMessageParserError(String),
     ServerStartupError(String, ServerIdentifier),
     ServerAuthError(String, ServerIdentifier),
+    ServerConnectionLimit(String, ServerIdentifier),
+    /// The server rejected a client parameter, with the SQLSTATE code and message it returned.
+    ServerParameterError(String, String),
     BadConfig,
+    ConfigParseError(String),
     AllServersDown,
+    PoolTimeout,
+    ReplicasLagging,
     ClientError(String),
     TlsError,
     StatementTimeout,
@@ -29,7 +35,10 @@ pub enum Error {
     QueryRouterParserError(String),
     QueryRouterError(String),
     InvalidShardId(usize),
+    ShardNotAllowed(usize),
     PreparedStatementError,
+    RetryableReadError(String),
+    MessageTooLarge(usize),
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -119,6 +128,30 @@ impl std::fmt::Display for Error {
             &Error::ServerAuthError(error, server_identifier) => {
                 write!(f, "{} for {}", error, server_identifier,)
             }
+            &Error::ServerConnectionLimit(error, server_identifier) => {
+                write!(f, "{} for {}", error, server_identifier)
+            }
+            &Error::ServerParameterError(_, message) => write!(f, "{}", message),
+
+            &Error::PoolTimeout => write!(
+                f,
+                "pool timeout, no server connection became available in time"
+            ),
+
+            &Error::ReplicasLagging => write!(
+                f,
+                "all replicas are lagging behind the primary by more than max_replica_lag"
+            ),
+            &Error::ShardNotAllowed(shard) => write!(
+                f,
+                "shard {} is not in the allowed_shards of the user",
+                shard
+            ),
+            &Error::MessageTooLarge(len) => write!(
+                f,
+                "message of {} bytes is larger than max_message_size",
+                len
+            ),
 
             // The rest can use Debug.
             err => write!(f, "{:?}", err),
@@ -9,10 +9,14 @@ pub mod errors;
 pub mod logger;
 pub mod messages;
 pub mod mirrors;
+pub mod otlp;
 pub mod plugins;
 pub mod pool;
 pub mod prometheus;
 pub mod query_router;
+pub mod query_tracing;
+pub mod rate_limiter;
+pub mod scatter_gather;
 pub mod scram;
 pub mod server;
 pub mod sharding;
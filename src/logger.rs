@@ -1,20 +1,42 @@
 use crate::cmd_args::{Args, LogFormat};
-use tracing_subscriber;
-use tracing_subscriber::EnvFilter;
+use crate::otlp::OtlpLayer;
+use crate::query_tracing::SPAN_TARGET;
+use tracing::Subscriber;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
 
 pub fn init(args: &Args) {
     // Initialize a default filter, and then override the builtin default "warning" with our
     // commandline, (default: "info")
     let filter = EnvFilter::from_default_env().add_directive(args.log_level.into());
 
-    let trace_sub = tracing_subscriber::fmt()
+    tracing_subscriber::registry()
+        .with(filter)
+        // Query spans, only created when general.tracing_enabled is set, are logged when
+        // they close. Spans of other crates are left out.
+        .with(fmt_layer(args).with_filter(filter_fn(|metadata| {
+            !metadata.is_span() || metadata.target() == SPAN_TARGET
+        })))
+        .with(OtlpLayer)
+        .init();
+}
+
+fn fmt_layer<S>(args: &Args) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let layer = fmt::layer()
         .with_thread_ids(true)
-        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
         .with_ansi(!args.no_color);
 
     match args.log_format {
-        LogFormat::Structured => trace_sub.json().init(),
-        LogFormat::Debug => trace_sub.pretty().init(),
-        _ => trace_sub.init(),
-    };
+        LogFormat::Structured => layer.json().boxed(),
+        LogFormat::Debug => layer.pretty().boxed(),
+        _ => layer.boxed(),
+    }
 }
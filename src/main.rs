@@ -47,7 +47,7 @@ static GLOBAL: Jemalloc = Jemalloc;
 use log::{debug, error, info, warn};
 use parking_lot::Mutex;
 use pgcat::format_duration;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
 #[cfg(not(windows))]
 use tokio::signal::unix::{signal as unix_signal, SignalKind};
 #[cfg(windows)]
@@ -60,19 +60,48 @@ use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
+use pgcat::client::{ClientSocket, Listener};
 use pgcat::cmd_args;
-use pgcat::config::{get_config, reload_config, VERSION};
+use pgcat::config::{get_config, jittered, reload_config, VERSION};
 use pgcat::dns_cache;
 use pgcat::logger;
 use pgcat::messages::configure_socket;
-use pgcat::pool::{ClientServerMap, ConnectionPool};
-use pgcat::prometheus::start_metric_server;
+use pgcat::pool::{close_all_pools, get_all_pools, ClientServerMap, ConnectionPool};
+use pgcat::prometheus::start_http_server;
 use pgcat::stats::{Collector, Reporter, REPORTER};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = cmd_args::parse();
     logger::init(&args);
 
+    if args.check_config {
+        let runtime = Builder::new_current_thread().build()?;
+
+        match runtime.block_on(pgcat::config::check(args.config_file.as_str())) {
+            Ok(config) => {
+                let users: usize = config.pools.values().map(|pool| pool.users.len()).sum();
+                let shards: usize = config.pools.values().map(|pool| pool.shards.len()).sum();
+
+                info!(
+                    "Config {} is valid: {} pools, {} shards, {} users",
+                    args.config_file,
+                    config.pools.len(),
+                    shards,
+                    users
+                );
+                std::process::exit(exitcode::OK);
+            }
+            Err(errors) => {
+                error!(
+                    "Config {} is invalid, see the errors above in: {}",
+                    args.config_file,
+                    errors.join(", ")
+                );
+                std::process::exit(exitcode::CONFIG);
+            }
+        }
+    }
+
     info!("Welcome to PgCat! Meow. (Version {})", VERSION);
 
     if !pgcat::query_router::QueryRouter::setup() {
@@ -105,25 +134,43 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     runtime.block_on(async move {
 
-        if let Some(true) = config.general.enable_prometheus_exporter {
-            let http_addr_str = format!(
-                "{}:{}",
-                config.general.host, config.general.prometheus_exporter_port
-            );
+        let http_addr = |port: u16| {
+            let http_addr_str = format!("{}:{}", config.general.host, port);
 
-            let http_addr = match SocketAddr::from_str(&http_addr_str) {
+            match SocketAddr::from_str(&http_addr_str) {
                 Ok(addr) => addr,
                 Err(err) => {
                     error!("Invalid http address: {}", err);
                     std::process::exit(exitcode::CONFIG);
                 }
-            };
+            }
+        };
+
+        // The health checks are served by the prometheus exporter too,
+        // so they only need their own server if they're on another port.
+        let prometheus_port = match config.general.enable_prometheus_exporter {
+            Some(true) => Some(config.general.prometheus_exporter_port as u16),
+            _ => None,
+        };
+
+        pgcat::otlp::start();
 
+        if let Some(port) = prometheus_port {
+            let http_addr = http_addr(port);
             tokio::task::spawn(async move {
-                start_metric_server(http_addr).await;
+                start_http_server(http_addr, true).await;
             });
         }
 
+        if let Some(port) = config.general.healthcheck_port {
+            if prometheus_port != Some(port) {
+                let http_addr = http_addr(port);
+                tokio::task::spawn(async move {
+                    start_http_server(http_addr, false).await;
+                });
+            }
+        }
+
         let addr = format!("{}:{}", config.general.host, config.general.port);
 
         let listener = match TcpListener::bind(&addr).await {
@@ -136,6 +183,47 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         info!("Running on {}", addr);
 
+        let admin_listener = match config.general.admin_port {
+            Some(port) => {
+                let admin_addr = format!("{}:{}", config.general.host, port);
+
+                match TcpListener::bind(&admin_addr).await {
+                    Ok(sock) => {
+                        info!("Admin database running on {}", admin_addr);
+                        Some(sock)
+                    }
+                    Err(err) => {
+                        error!("Admin listener socket error: {:?}", err);
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let unix_socket_path = config.general.unix_socket_path();
+
+        let unix_listener = match unix_socket_path {
+            Some(ref path) => {
+                if let Err(err) = remove_stale_unix_socket(path) {
+                    error!("Unix socket error: {}", err);
+                    std::process::exit(exitcode::CONFIG);
+                }
+
+                match UnixListener::bind(path) {
+                    Ok(listener) => {
+                        info!("Listening on Unix socket {}", path);
+                        Some(listener)
+                    }
+                    Err(err) => {
+                        error!("Unix socket error: {:?}", err);
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                }
+            }
+            None => None,
+        };
+
         config.show();
 
         // Tracks which client is connected to which server for query cancellation.
@@ -159,11 +247,52 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
+        // Let the orchestrator restart us instead of starting without any server.
+        if config.general.require_healthy_startup {
+            if let Err(err) = ConnectionPool::validate_all().await {
+                error!("Startup failed, no server is reachable: {:?}", err);
+                std::process::exit(exitcode::UNAVAILABLE);
+            }
+        }
+
         tokio::task::spawn(async move {
             let mut stats_collector = Collector::default();
             stats_collector.collect().await;
         });
 
+        // Measure replication lag for pools that limit it, picking up config reloads.
+        tokio::task::spawn(async move {
+            loop {
+                let config = get_config();
+                let interval = tokio::time::Duration::from_millis(config.general.replica_lag_check_interval);
+                tokio::time::sleep(jittered(interval, config.general.interval_jitter)).await;
+
+                for (_, pool) in get_all_pools() {
+                    pool.check_replica_lag().await;
+                }
+            }
+        });
+
+        // Health check the servers in the background if enabled, picking up config reloads.
+        tokio::task::spawn(async move {
+            loop {
+                let config = get_config();
+                let interval = match config.general.healthcheck_interval {
+                    // Disabled, look again later in case a reload enables it.
+                    0 => {
+                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                        continue;
+                    }
+                    interval => tokio::time::Duration::from_millis(interval),
+                };
+                tokio::time::sleep(jittered(interval, config.general.interval_jitter)).await;
+
+                for (_, pool) in get_all_pools() {
+                    pool.check_health().await;
+                }
+            }
+        });
+
         info!("Config autoreloader: {}", match config.general.autoreload {
             Some(interval) => format!("{} ms", interval),
             None => "disabled".into(),
@@ -232,36 +361,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
 
                     admin_only = true;
-
-                    // Broadcast that client tasks need to finish
-                    let _ = shutdown_tx.send(());
-                    let exit_tx = exit_tx.clone();
-                    let _ = drain_tx.send(0).await;
-
-                    tokio::task::spawn(async move {
-                        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(config.general.shutdown_timeout));
-
-                        // First tick fires immediately.
-                        interval.tick().await;
-
-                        // Second one in the interval time.
-                        interval.tick().await;
-
-                        // We're done waiting.
-                        error!("Graceful shutdown timed out. {} active clients being closed", total_clients);
-
-                        let _ = exit_tx.send(()).await;
-                    });
+                    start_draining(&shutdown_tx, &drain_tx, exit_tx.clone(), total_clients).await;
                 },
 
+                // Drain clients on the first SIGTERM, exit immediately on the second.
                 _ = term_signal.recv() => {
-                    info!("Got SIGTERM, closing with {} clients active", total_clients);
-                    break;
+                    if admin_only {
+                        info!("Got SIGTERM, closing with {} clients active", total_clients);
+                        break;
+                    }
+
+                    info!("Got SIGTERM, draining {} clients", total_clients);
+
+                    admin_only = true;
+                    start_draining(&shutdown_tx, &drain_tx, exit_tx.clone(), total_clients).await;
                 },
 
-                new_client = listener.accept() => {
-                    let (socket, addr) = match new_client {
-                        Ok((socket, addr)) => (socket, addr),
+                new_client = accept(&listener, admin_listener.as_ref(), unix_listener.as_ref()) => {
+                    let (socket, addr, client_listener) = match new_client {
+                        Ok(client) => client,
                         Err(err) => {
                             error!("{:?}", err);
                             continue;
@@ -274,13 +392,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                     let tls_certificate = get_config().general.tls_certificate.clone();
 
-                    configure_socket(&socket);
-
                     tokio::task::spawn(async move {
                         let start = chrono::offset::Utc::now().naive_utc();
 
                         match pgcat::client::client_entrypoint(
                             socket,
+                            addr,
+                            client_listener,
                             client_server_map,
                             shutdown_rx,
                             drain_tx,
@@ -335,6 +453,105 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
 
     info!("Shutting down...");
+
+    // Close idle server connections cleanly.
+    close_all_pools();
+
+    if let Some(path) = unix_socket_path {
+        let _ = std::fs::remove_file(path);
+    }
     });
     Ok(())
 }
+
+/// Remove the Unix socket left behind by a previous process that didn't exit cleanly,
+/// unless it's not a socket or another process is still listening on it.
+fn remove_stale_unix_socket(path: &str) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("could not stat {}: {}", path, err)),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket", path));
+    }
+
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(format!("{} is in use, is another pgcat running?", path));
+    }
+
+    std::fs::remove_file(path).map_err(|err| format!("could not remove {}: {}", path, err))
+}
+
+/// Accept a client on the main listener, on the admin one if `admin_port` is set,
+/// or on the Unix socket if enabled, and tell which one it connected to.
+async fn accept(
+    listener: &TcpListener,
+    admin_listener: Option<&TcpListener>,
+    unix_listener: Option<&UnixListener>,
+) -> std::io::Result<(ClientSocket, SocketAddr, Listener)> {
+    let tcp_client = |client: (TcpStream, SocketAddr), listener| {
+        configure_socket(&client.0);
+        (ClientSocket::Tcp(client.0), client.1, listener)
+    };
+
+    tokio::select! {
+        client = listener.accept() => client.map(|client| tcp_client(client, Listener::Main)),
+
+        client = async {
+            match admin_listener {
+                Some(admin_listener) => admin_listener.accept().await,
+                None => std::future::pending().await,
+            }
+        } => client.map(|client| tcp_client(client, Listener::Admin)),
+
+        client = async {
+            match unix_listener {
+                Some(unix_listener) => unix_listener.accept().await,
+                None => std::future::pending().await,
+            }
+        } => client.map(|(socket, _)| {
+            // Unix socket clients don't have an address, they're local.
+            (ClientSocket::Unix(socket), SocketAddr::from(([127, 0, 0, 1], 0)), Listener::Unix)
+        }),
+    }
+}
+
+/// Stop accepting new clients and tell connected ones to disconnect
+/// once their transaction completes. Exit is forced after `shutdown_timeout`.
+async fn start_draining(
+    shutdown_tx: &broadcast::Sender<()>,
+    drain_tx: &mpsc::Sender<i32>,
+    exit_tx: mpsc::Sender<()>,
+    total_clients: i32,
+) {
+    pgcat::pool::start_draining();
+
+    // Broadcast that client tasks need to finish
+    let _ = shutdown_tx.send(());
+    let _ = drain_tx.send(0).await;
+
+    let shutdown_timeout = get_config().general.shutdown_timeout;
+
+    tokio::task::spawn(async move {
+        let mut interval =
+            tokio::time::interval(tokio::time::Duration::from_millis(shutdown_timeout));
+
+        // First tick fires immediately.
+        interval.tick().await;
+
+        // Second one in the interval time.
+        interval.tick().await;
+
+        // We're done waiting.
+        error!(
+            "Graceful shutdown timed out. {} active clients being closed",
+            total_clients
+        );
+
+        let _ = exit_tx.send(()).await;
+    });
+}
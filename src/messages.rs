@@ -8,10 +8,13 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 use crate::client::PREPARED_STATEMENT_COUNTER;
-use crate::config::get_config;
+use crate::config::{get_config, get_max_message_size, ErrorVerbosity};
 use crate::errors::Error;
 
-use crate::constants::MESSAGE_TERMINATOR;
+use crate::constants::{
+    FEATURE_NOT_SUPPORTED, INVALID_CATALOG_NAME, MAX_STARTUP_PARAMETERS, MESSAGE_TERMINATOR, SASL,
+    SASL_CONTINUE, SASL_FINAL, SCRAM_SHA_256, SYSTEM_ERROR, TOO_MANY_CONNECTIONS,
+};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::ffi::CString;
@@ -87,6 +90,97 @@ where
     Ok(salt)
 }
 
+/// Ask the client to authenticate using SASL (SCRAM-SHA-256).
+pub async fn sasl_challenge<S>(stream: &mut S) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mechanism = format!("{}\0\0", SCRAM_SHA_256);
+
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(mechanism.len() as i32 + 8);
+    res.put_i32(SASL);
+    res.put_slice(mechanism.as_bytes());
+
+    write_all(stream, res).await
+}
+
+/// Send the SASL server-first message (AuthenticationSASLContinue).
+pub async fn sasl_continue<S>(stream: &mut S, data: &[u8]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(data.len() as i32 + 8);
+    res.put_i32(SASL_CONTINUE);
+    res.put_slice(data);
+
+    write_all(stream, res).await
+}
+
+/// Send the SASL server-final message (AuthenticationSASLFinal).
+pub async fn sasl_final<S>(stream: &mut S, data: &[u8]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(data.len() as i32 + 8);
+    res.put_i32(SASL_FINAL);
+    res.put_slice(data);
+
+    write_all(stream, res).await
+}
+
+/// Parse the SASLInitialResponse (F) message, returning
+/// the selected mechanism and the client-first message.
+pub fn parse_sasl_initial_response(mut bytes: BytesMut) -> Result<(String, BytesMut), Error> {
+    let code = bytes.get_u8() as char;
+    let _len = bytes.get_i32();
+
+    if code != 'p' {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected p, got {}",
+            code
+        )));
+    }
+
+    let mechanism = bytes.read_string()?;
+
+    if bytes.remaining() < mem::size_of::<i32>() {
+        return Err(Error::ProtocolSyncError(
+            "SASLInitialResponse is too short".into(),
+        ));
+    }
+
+    let data_len = bytes.get_i32();
+
+    if data_len < 0 || data_len as usize > bytes.remaining() {
+        return Err(Error::ProtocolSyncError(
+            "SASLInitialResponse has a bad length".into(),
+        ));
+    }
+
+    Ok((mechanism, bytes.split_to(data_len as usize)))
+}
+
+/// Parse the SASLResponse (F) message, returning the client-final message.
+pub fn parse_sasl_response(mut bytes: BytesMut) -> Result<BytesMut, Error> {
+    let code = bytes.get_u8() as char;
+    let _len = bytes.get_i32();
+
+    if code != 'p' {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected p, got {}",
+            code
+        )));
+    }
+
+    Ok(bytes)
+}
+
 /// Give the client the process_id and secret we generated
 /// used in query cancellation.
 pub async fn backend_key_data<S>(
@@ -116,6 +210,20 @@ pub fn simple_query(query: &str) -> BytesMut {
     res
 }
 
+/// Query text of a simple query (Q) or parse (P) message, if it's valid UTF-8.
+pub fn query_text(message: &BytesMut) -> Option<&str> {
+    let body = message.get(5..)?;
+
+    let body = match message[0] as char {
+        'Q' => body,
+        'P' => &body[body.iter().position(|b| *b == 0)? + 1..],
+        _ => return None,
+    };
+
+    let end = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+    std::str::from_utf8(&body[..end]).ok()
+}
+
 /// Tell the client we're ready for another query.
 pub async fn send_ready_for_query<S>(stream: &mut S) -> Result<(), Error>
 where
@@ -126,7 +234,12 @@ where
 
 /// Send the startup packet the server. We're pretending we're a Pg client.
 /// This tells the server which user we are and what database we want.
-pub async fn startup<S>(stream: &mut S, user: &str, database: &str) -> Result<(), Error>
+pub async fn startup<S>(
+    stream: &mut S,
+    user: &str,
+    database: &str,
+    replication: Option<&str>,
+) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -147,6 +260,14 @@ where
     bytes.put(&b"database\0"[..]);
     bytes.put_slice(database.as_bytes());
     bytes.put_u8(0);
+
+    // Replication mode, for replication clients connected directly to the server.
+    if let Some(replication) = replication {
+        bytes.put(&b"replication\0"[..]);
+        bytes.put_slice(replication.as_bytes());
+        bytes.put_u8(0);
+    }
+
     bytes.put_u8(0); // Null terminator
 
     let len = bytes.len() as i32 + 4i32;
@@ -192,6 +313,10 @@ pub fn parse_params(mut bytes: BytesMut) -> Result<HashMap<String, String>, Erro
         // Null-terminated C-strings.
         while c != 0 {
             tmp.push(c as char);
+
+            if !bytes.has_remaining() {
+                return Err(Error::ClientBadStartup);
+            }
             c = bytes.get_u8();
         }
 
@@ -199,6 +324,10 @@ pub fn parse_params(mut bytes: BytesMut) -> Result<HashMap<String, String>, Erro
             buf.push(tmp.clone());
             tmp.clear();
         }
+
+        if buf.len() > MAX_STARTUP_PARAMETERS * 2 {
+            return Err(Error::ClientBadStartup);
+        }
     }
 
     // Expect pairs of name and value
@@ -283,6 +412,21 @@ where
     write_all(stream, message).await
 }
 
+/// Send the password in cleartext, as requested by AuthenticationCleartextPassword.
+pub async fn cleartext_password<S>(stream: &mut S, password: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut message = BytesMut::with_capacity(password.len() + 6);
+
+    message.put_u8(b'p');
+    message.put_i32(password.len() as i32 + 4 + 1);
+    message.put_slice(password.as_bytes());
+    message.put_u8(0);
+
+    write_all(stream, message).await
+}
+
 pub async fn md5_password_with_hash<S>(stream: &mut S, hash: &str, salt: &[u8]) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
@@ -325,7 +469,20 @@ pub async fn error_response<S>(stream: &mut S, message: &str) -> Result<(), Erro
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    error_response_terminal(stream, message).await?;
+    error_response_with_code(stream, message, SYSTEM_ERROR).await
+}
+
+/// Same as `error_response`, with a SQLSTATE code describing the error
+/// so drivers can tell e.g. connection failures from query cancellations.
+pub async fn error_response_with_code<S>(
+    stream: &mut S,
+    message: &str,
+    code: &str,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    error_response_terminal_with_code(stream, message, code).await?;
     send_ready_for_query(stream).await
 }
 
@@ -333,6 +490,18 @@ where
 /// Tell the client we are ready for the next query and no rollback is necessary.
 /// Docs on error codes: <https://www.postgresql.org/docs/12/errcodes-appendix.html>.
 pub async fn error_response_terminal<S>(stream: &mut S, message: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    error_response_terminal_with_code(stream, message, SYSTEM_ERROR).await
+}
+
+/// Same as `error_response_terminal`, with the given SQLSTATE code.
+pub async fn error_response_terminal_with_code<S>(
+    stream: &mut S,
+    message: &str,
+    code: &str,
+) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -346,9 +515,9 @@ where
     error.put_u8(b'V');
     error.put_slice(&b"FATAL\0"[..]);
 
-    // Error code: not sure how much this matters.
+    // Error code, see Appendix A.
     error.put_u8(b'C');
-    error.put_slice(&b"58000\0"[..]); // system_error, see Appendix A.
+    error.put_slice(format!("{}\0", code).as_bytes());
 
     // The short error message.
     error.put_u8(b'M');
@@ -367,6 +536,48 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Tell the client a statement failed inside a transaction, which is still open.
+/// Unlike the other error responses, the severity is ERROR and the transaction
+/// can go on, as if the statement had been rolled back to a savepoint.
+pub async fn error_response_in_transaction<S>(
+    stream: &mut S,
+    message: &str,
+    code: &str,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut error = BytesMut::new();
+
+    // Error level
+    error.put_u8(b'S');
+    error.put_slice(&b"ERROR\0"[..]);
+
+    // Error level (non-translatable)
+    error.put_u8(b'V');
+    error.put_slice(&b"ERROR\0"[..]);
+
+    // Error code, see Appendix A.
+    error.put_u8(b'C');
+    error.put_slice(format!("{}\0", code).as_bytes());
+
+    // The short error message.
+    error.put_u8(b'M');
+    error.put_slice(format!("{}\0", message).as_bytes());
+
+    // No more fields follow.
+    error.put_u8(0);
+
+    let mut res = BytesMut::with_capacity(error.len() + 11);
+
+    res.put_u8(b'E');
+    res.put_i32(error.len() as i32 + 4);
+    res.put(error);
+    res.put(ready_for_query(true));
+
+    write_all_half(stream, &res).await
+}
+
 pub async fn wrong_password<S>(stream: &mut S, user: &str) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
@@ -403,6 +614,50 @@ where
     write_all(stream, res).await
 }
 
+/// Reject a client connecting to a database no pool is configured for, like Postgres does.
+pub async fn database_does_not_exist<S>(stream: &mut S, database: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    error_response_terminal_with_code(
+        stream,
+        &format!("database \"{}\" does not exist", database),
+        INVALID_CATALOG_NAME,
+    )
+    .await
+}
+
+/// Tell the client its protocol version is not supported, like Postgres does.
+pub async fn unsupported_protocol_version<S>(stream: &mut S, version: i32) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    error_response_terminal_with_code(
+        stream,
+        &format!(
+            "unsupported frontend protocol {}.{}: server supports 3.0 to 3.0",
+            version >> 16,
+            version & 0xffff
+        ),
+        FEATURE_NOT_SUPPORTED,
+    )
+    .await
+}
+
+/// Tell the client the user has too many connections, like Postgres does
+/// when a role reaches its connection limit.
+pub async fn too_many_connections<S>(stream: &mut S, user: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    error_response_terminal_with_code(
+        stream,
+        &format!("too many connections for role \"{}\"", user),
+        TOO_MANY_CONNECTIONS,
+    )
+    .await
+}
+
 /// Respond to a SHOW SHARD command.
 pub async fn show_response<S>(stream: &mut S, name: &str, value: &str) -> Result<(), Error>
 where
@@ -650,6 +905,14 @@ where
         }
     };
 
+    read_message_data(stream, code).await
+}
+
+/// Read the rest of a message whose code was already read from the stream.
+pub async fn read_message_data<S>(stream: &mut S, code: u8) -> Result<BytesMut, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
     let len = match stream.read_i32().await {
         Ok(len) => len,
         Err(err) => {
@@ -660,37 +923,56 @@ where
         }
     };
 
-    let mut bytes = BytesMut::with_capacity(len as usize + 1);
-
-    bytes.put_u8(code);
-    bytes.put_i32(len);
-
-    bytes.resize(bytes.len() + len as usize - mem::size_of::<i32>(), b'0');
-
-    let slice_start = mem::size_of::<u8>() + mem::size_of::<i32>();
-    let slice_end = slice_start + len as usize - mem::size_of::<i32>();
-
     // Avoids a panic
-    if slice_end < slice_start {
+    if len < mem::size_of::<i32>() as i32 {
         return Err(Error::SocketError(format!(
             "Error reading message from socket - Code: {:?} - Length {:?}, Error: {:?}",
             code, len, "Unexpected length value for message"
         )));
     }
 
-    match stream.read_exact(&mut bytes[slice_start..slice_end]).await {
-        Ok(_) => (),
-        Err(err) => {
-            return Err(Error::SocketError(format!(
-                "Error reading message from socket - Code: {:?}, Error: {:?}",
-                code, err
-            )))
+    if len as usize > get_max_message_size() {
+        return Err(Error::MessageTooLarge(len as usize));
+    }
+
+    let message_len = mem::size_of::<u8>() + len as usize;
+    let mut bytes = BytesMut::with_capacity(message_len.min(PREALLOCATED_MESSAGE_SIZE));
+
+    bytes.put_u8(code);
+    bytes.put_i32(len);
+
+    let mut data = (&mut *stream).take((len as usize - mem::size_of::<i32>()) as u64);
+
+    while bytes.len() < message_len {
+        // Grow the buffer at most twofold, so its size follows the data we actually received.
+        if bytes.len() == bytes.capacity() {
+            bytes.reserve(bytes.len().min(message_len - bytes.len()));
         }
-    };
+
+        match data.read_buf(&mut bytes).await {
+            Ok(0) => {
+                return Err(Error::SocketError(format!(
+                    "Error reading message from socket - Code: {:?}, Error: {:?}",
+                    code, "Unexpected end of stream"
+                )))
+            }
+            Ok(_) => (),
+            Err(err) => {
+                return Err(Error::SocketError(format!(
+                    "Error reading message from socket - Code: {:?}, Error: {:?}",
+                    code, err
+                )))
+            }
+        };
+    }
 
     Ok(bytes)
 }
 
+/// Messages up to this size get their whole buffer upfront. Larger ones grow it as their
+/// data arrives, so a peer announcing a large message it doesn't send can't exhaust our memory.
+const PREALLOCATED_MESSAGE_SIZE: usize = 1024 * 1024;
+
 pub fn server_parameter_message(key: &str, value: &str) -> BytesMut {
     let mut server_info = BytesMut::new();
 
@@ -733,7 +1015,7 @@ pub fn configure_socket(stream: &TcpStream) {
         }
         Err(err) => error!("Could not configure socket: {}", err),
     }
-    match sock_ref.set_nodelay(true) {
+    match sock_ref.set_nodelay(conf.general.tcp_no_delay) {
         Ok(_) => (),
         Err(err) => error!("Could not configure TCP_NODELAY for socket: {}", err),
     }
@@ -823,7 +1105,8 @@ pub struct Parse {
     #[allow(dead_code)]
     len: i32,
     pub name: String,
-    query: String,
+    /// Kept as bytes, it's in the client's encoding, which may not be UTF-8.
+    query: Vec<u8>,
     num_params: i16,
     param_types: Vec<i32>,
 }
@@ -836,7 +1119,11 @@ impl TryFrom<&BytesMut> for Parse {
         let code = cursor.get_u8() as char;
         let len = cursor.get_i32();
         let name = cursor.read_string()?;
-        let query = cursor.read_string()?;
+        let mut query = Vec::new();
+        cursor
+            .read_until(b'\0', &mut query)
+            .map_err(|err| Error::ParseBytesError(err.to_string()))?;
+        query.pop();
         let num_params = cursor.get_i16();
         let mut param_types = Vec::new();
 
@@ -913,14 +1200,21 @@ impl Parse {
         cursor.read_string()
     }
 
+    /// The query, if it's valid UTF-8.
+    pub fn query(&self) -> Option<&str> {
+        std::str::from_utf8(&self.query).ok()
+    }
+
     /// Hashes the parse statement to be used as a key in the global cache
     pub fn get_hash(&self) -> u64 {
         // TODO_ZAIN: Take a look at which hashing function is being used
         let mut hasher = DefaultHasher::new();
 
+        // Hash the query's bytes, lossy UTF-8 conversion would make different queries collide.
+        self.query.hash(&mut hasher);
+
         let concatenated = format!(
-            "{}{}{}",
-            self.query,
+            "{}{}",
             self.num_params,
             self.param_types
                 .iter()
@@ -1036,7 +1330,11 @@ impl TryFrom<Bind> for BytesMut {
             + 2; // num_param_values
 
         for (param_len, _) in &bind.param_values {
-            len += 4 + *param_len as usize;
+            len += 4; // param_len
+                      // NULL is sent as a length of -1 with no value bytes.
+            if *param_len > 0 {
+                len += *param_len as usize;
+            }
         }
         len += 2; // num_result_column_format_codes
         len += 2 * bind.num_result_column_format_codes as usize;
@@ -1333,6 +1631,101 @@ impl Display for PgErrorMsg {
     }
 }
 
+/// Rewrite an ErrorResponse from a server as configured with `error_verbosity`.
+/// Only the severity, SQLSTATE, message and position in the query are kept: the detail, hint,
+/// context and names of the objects involved are dropped. `sanitized` also replaces the message,
+/// which often contains names and values, with the generic one of the SQLSTATE class.
+pub fn sanitize_error_response(message: &[u8], verbosity: ErrorVerbosity) -> BytesMut {
+    let body = message.get(5..).unwrap_or_default();
+    let mut fields = BytesMut::new();
+    let mut code: &[u8] = &[];
+
+    // Each field is its type followed by a null terminated string, a null byte ends the list.
+    for field in body.split(|byte| *byte == 0) {
+        let (field_type, value) = match field.split_first() {
+            Some(field) => field,
+            None => break,
+        };
+
+        let keep = match field_type {
+            b'S' | b'V' | b'P' => true,
+            b'C' => {
+                code = value;
+                true
+            }
+            b'M' => verbosity != ErrorVerbosity::Sanitized,
+            _ => false,
+        };
+
+        if keep {
+            fields.put_slice(field);
+            fields.put_u8(0);
+        }
+    }
+
+    if verbosity == ErrorVerbosity::Sanitized {
+        fields.put_u8(b'M');
+        fields.put_slice(sqlstate_class_message(code).as_bytes());
+        fields.put_u8(0);
+    }
+
+    fields.put_u8(MESSAGE_TERMINATOR);
+
+    let mut res = BytesMut::with_capacity(fields.len() + 5);
+    res.put_u8(b'E');
+    res.put_i32(fields.len() as i32 + 4);
+    res.put(fields);
+
+    res
+}
+
+/// Generic message of a class of SQLSTATE codes, see Appendix A of the Postgres docs.
+pub fn sqlstate_class_message(code: &[u8]) -> &'static str {
+    match code.get(..2).unwrap_or_default() {
+        b"03" => "sql statement not yet complete",
+        b"08" => "connection exception",
+        b"09" => "triggered action exception",
+        b"0A" => "feature not supported",
+        b"0B" => "invalid transaction initiation",
+        b"0F" => "locator exception",
+        b"0L" => "invalid grantor",
+        b"0P" => "invalid role specification",
+        b"0Z" => "diagnostics exception",
+        b"20" => "case not found",
+        b"21" => "cardinality violation",
+        b"22" => "data exception",
+        b"23" => "integrity constraint violation",
+        b"24" => "invalid cursor state",
+        b"25" => "invalid transaction state",
+        b"26" => "invalid sql statement name",
+        b"27" => "triggered data change violation",
+        b"28" => "invalid authorization specification",
+        b"2B" => "dependent privilege descriptors still exist",
+        b"2D" => "invalid transaction termination",
+        b"2F" => "sql routine exception",
+        b"34" => "invalid cursor name",
+        b"38" => "external routine exception",
+        b"39" => "external routine invocation exception",
+        b"3B" => "savepoint exception",
+        b"3D" => "invalid catalog name",
+        b"3F" => "invalid schema name",
+        b"40" => "transaction rollback",
+        b"42" => "syntax error or access rule violation",
+        b"44" => "with check option violation",
+        b"53" => "insufficient resources",
+        b"54" => "program limit exceeded",
+        b"55" => "object not in prerequisite state",
+        b"57" => "operator intervention",
+        b"58" => "system error",
+        b"72" => "snapshot failure",
+        b"F0" => "configuration file error",
+        b"HV" => "foreign data wrapper error",
+        b"P0" => "pl/pgsql error",
+        b"XX" => "internal error",
+        _ => "error",
+    }
+}
+
 impl PgErrorMsg {
     pub fn parse(error_msg: &[u8]) -> Result<PgErrorMsg, Error> {
         let mut out = PgErrorMsg {
@@ -1545,4 +1938,209 @@ mod tests {
             PgErrorMsg::parse(&only_mandatory_msg).unwrap()
         );
     }
+
+    #[tokio::test]
+    async fn test_cleartext_password() {
+        let mut stream = Vec::new();
+        super::cleartext_password(&mut stream, "secret")
+            .await
+            .unwrap();
+
+        assert_eq!(stream, b"p\0\0\0\x0bsecret\0".to_vec());
+    }
+
+    #[test]
+    fn test_query_text() {
+        let mut message = bytes::BytesMut::from(&b"Q\0\0\0\x0dSELECT 1\0"[..]);
+        assert_eq!(super::query_text(&message), Some("SELECT 1"));
+
+        message = bytes::BytesMut::from(&b"P\0\0\0\x12stmt\0SELECT 1\0\0\0"[..]);
+        assert_eq!(super::query_text(&message), Some("SELECT 1"));
+
+        message = bytes::BytesMut::from(&b"S\0\0\0\x04"[..]);
+        assert_eq!(super::query_text(&message), None);
+    }
+
+    #[test]
+    fn test_sanitize_error_response() {
+        use crate::config::ErrorVerbosity;
+        use bytes::BufMut;
+
+        let mut fields = bytes::BytesMut::new();
+        for field in [
+            "SERROR",
+            "VERROR",
+            "C23505",
+            "Mduplicate key value violates unique constraint \"users_email_key\"",
+            "DKey (email)=(alice@example.com) already exists.",
+            "spublic",
+            "tusers",
+            "nusers_email_key",
+            "Fnbtinsert.c",
+        ] {
+            fields.put_slice(field.as_bytes());
+            fields.put_u8(0);
+        }
+        fields.put_u8(0);
+
+        let mut message = bytes::BytesMut::new();
+        message.put_u8(b'E');
+        message.put_i32(fields.len() as i32 + 4);
+        message.put(fields);
+
+        let sanitized = super::sanitize_error_response(&message, ErrorVerbosity::Sanitized);
+        let error = PgErrorMsg::parse(&sanitized[5..]).unwrap();
+        assert_eq!(error.code, "23505");
+        assert_eq!(error.severity, "ERROR");
+        assert_eq!(error.message, "integrity constraint violation");
+        assert_eq!(error.detail, None);
+        assert_eq!(error.constraint_name, None);
+        assert_eq!(error.table_name, None);
+        assert_eq!(
+            i32::from_be_bytes(sanitized[1..5].try_into().unwrap()) as usize,
+            sanitized.len() - 1
+        );
+
+        let terse = super::sanitize_error_response(&message, ErrorVerbosity::Terse);
+        let error = PgErrorMsg::parse(&terse[5..]).unwrap();
+        assert_eq!(error.code, "23505");
+        assert_eq!(
+            error.message,
+            "duplicate key value violates unique constraint \"users_email_key\""
+        );
+        assert_eq!(error.detail, None);
+        assert_eq!(error.file_name, None);
+    }
+
+    #[test]
+    fn test_parse_non_utf8() {
+        // SELECT 'é' and SELECT 'è' in LATIN1, neither is valid UTF-8.
+        let message = bytes::BytesMut::from(&b"P\0\0\0\x16stmt\0SELECT '\xe9'\0\0\0"[..]);
+        let other = bytes::BytesMut::from(&b"P\0\0\0\x16stmt\0SELECT '\xe8'\0\0\0"[..]);
+
+        let parse = super::Parse::try_from(&message).unwrap();
+        let rewritten: bytes::BytesMut = (&parse).try_into().unwrap();
+        assert_eq!(rewritten, message);
+
+        let other = super::Parse::try_from(&other).unwrap();
+        assert_ne!(parse.get_hash(), other.get_hash());
+    }
+
+    #[test]
+    fn test_bind_binary() {
+        use bytes::BufMut;
+
+        // Binary int4, timestamptz and NULL parameters, binary results.
+        let mut body = bytes::BytesMut::new();
+        body.put_slice(b"\0stmt\0");
+        body.put_i16(1);
+        body.put_i16(1);
+        body.put_i16(3);
+        body.put_i32(4);
+        body.put_i32(42);
+        body.put_i32(8);
+        body.put_i64(757_382_400_000_000);
+        body.put_i32(-1);
+        body.put_i16(1);
+        body.put_i16(1);
+
+        let mut message = bytes::BytesMut::new();
+        message.put_u8(b'B');
+        message.put_i32(4 + body.len() as i32);
+        message.put_slice(&body);
+
+        let bind = super::Bind::try_from(&message).unwrap();
+        let rewritten: bytes::BytesMut = bind.try_into().unwrap();
+        assert_eq!(rewritten, message);
+
+        let renamed = super::Bind::rename(message.clone(), "PGCAT_1").unwrap();
+        assert_eq!(super::Bind::get_name(&renamed).unwrap(), "PGCAT_1");
+        assert_eq!(renamed.len(), message.len() + 3);
+        assert_eq!(
+            &renamed[renamed.len() - 34..],
+            &message[message.len() - 34..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        use crate::errors::Error;
+        use bytes::BufMut;
+
+        // Larger than the part of the buffer allocated upfront.
+        let query = "x".repeat(3 * 1024 * 1024);
+        let mut message = bytes::BytesMut::new();
+        message.put_u8(b'Q');
+        message.put_i32(4 + query.len() as i32 + 1);
+        message.put_slice(query.as_bytes());
+        message.put_u8(0);
+
+        let mut stream = &message[..];
+        assert_eq!(super::read_message(&mut stream).await.unwrap(), message);
+
+        // Truncated.
+        let mut stream = &message[..1024];
+        assert!(matches!(
+            super::read_message(&mut stream).await,
+            Err(Error::SocketError(_))
+        ));
+
+        // Larger than max_message_size, rejected before reading it.
+        let mut stream = &b"Q\x7f\xff\xff\xff"[..];
+        assert_eq!(
+            super::read_message(&mut stream).await,
+            Err(Error::MessageTooLarge(i32::MAX as usize))
+        );
+
+        let mut stream = &b"Q\xff\xff\xff\xff"[..];
+        assert!(super::read_message(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configure_socket() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        super::configure_socket(&stream);
+
+        let general = crate::config::General::default();
+        let sock_ref = socket2::SockRef::from(&stream);
+        assert!(sock_ref.keepalive().unwrap());
+        assert!(sock_ref.nodelay().unwrap());
+        assert_eq!(
+            sock_ref.keepalive_time().unwrap().as_secs(),
+            general.tcp_keepalives_idle
+        );
+
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                sock_ref.keepalive_interval().unwrap().as_secs(),
+                general.tcp_keepalives_interval
+            );
+            assert_eq!(
+                sock_ref.keepalive_retries().unwrap(),
+                general.tcp_keepalives_count
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_response_with_code() {
+        let mut stream = Vec::new();
+        super::error_response_with_code(&mut stream, "pool statement timeout", "57014")
+            .await
+            .unwrap();
+
+        assert_eq!(stream[0], b'E');
+        let len = i32::from_be_bytes(stream[1..5].try_into().unwrap()) as usize;
+        let error = PgErrorMsg::parse(&stream[5..len + 1]).unwrap();
+        assert_eq!(error.code, "57014");
+        assert_eq!(error.message, "pool statement timeout");
+
+        // ReadyForQuery follows so the client can keep going.
+        assert_eq!(stream[len + 1], b'Z');
+    }
 }
@@ -119,6 +119,9 @@ impl MirroredClient {
 pub struct MirroringManager {
     pub byte_senders: Vec<Sender<Bytes>>,
     pub disconnect_senders: Vec<Sender<()>>,
+
+    /// Fraction of the traffic sent to the mirrors.
+    pub sample_rate: f64,
 }
 impl MirroringManager {
     pub fn from_addresses(
@@ -149,10 +152,16 @@ impl MirroringManager {
         Self {
             byte_senders,
             disconnect_senders: exit_senders,
+            sample_rate: get_config().general.mirror_sample_rate,
         }
     }
 
     pub fn send(&mut self, bytes: &BytesMut) {
+        // Only mirror a sample of the traffic
+        if self.sample_rate < 1.0 && rand::random::<f64>() >= self.sample_rate {
+            return;
+        }
+
         // We want to avoid performing an allocation if we won't be able to send the message
         // There is a possibility of a race here where we check the capacity and then the channel is
         // closed or the capacity is reduced to 0, but mirroring is best effort anyway
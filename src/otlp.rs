@@ -0,0 +1,348 @@
+//! Export the query spans to an OpenTelemetry collector, see `general.otlp_endpoint`.
+//!
+//! Spans are sent in batches by a background task, encoded as JSON with OTLP/HTTP
+//! to `<otlp_endpoint>/v1/traces`. A query span whose client propagated a W3C trace context
+//! joins the client's trace, with the client's span as its parent.
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::client::conn::http1;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use log::warn;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{channel, Sender};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::config::{get_otlp_endpoint, VERSION};
+use crate::query_tracing::SPAN_TARGET;
+
+/// Spans waiting to be exported. When the collector can't keep up, new spans are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// Spans sent to the collector in one request.
+const MAX_BATCH_SIZE: usize = 512;
+
+/// How often the spans waiting are sent, if there are fewer than `MAX_BATCH_SIZE`.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for the collector to answer.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// SpanKind of the OTLP protocol.
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+static EXPORTER: OnceCell<Sender<Value>> = OnceCell::new();
+
+/// Start exporting the spans. The endpoint is read from the config on every batch,
+/// so it can be changed or removed with a reload.
+pub fn start() {
+    let (sender, mut receiver) = channel(QUEUE_SIZE);
+
+    if EXPORTER.set(sender).is_err() {
+        return;
+    }
+
+    tokio::task::spawn(async move {
+        let mut batch = Vec::new();
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            tokio::select! {
+                span = receiver.recv() => match span {
+                    Some(span) => {
+                        batch.push(span);
+
+                        if batch.len() < MAX_BATCH_SIZE {
+                            continue;
+                        }
+                    }
+
+                    None => return,
+                },
+
+                _ = interval.tick() => {
+                    if batch.is_empty() {
+                        continue;
+                    }
+                }
+            }
+
+            let spans = std::mem::take(&mut batch);
+
+            if let Some(endpoint) = get_otlp_endpoint() {
+                if let Err(err) = send(&endpoint, spans).await {
+                    warn!("Could not export spans to {}: {}", endpoint, err);
+                }
+            }
+        }
+    });
+}
+
+fn exporting() -> bool {
+    EXPORTER.get().is_some() && get_otlp_endpoint().is_some()
+}
+
+/// Send a batch of spans to the collector.
+async fn send(endpoint: &str, spans: Vec<Value>) -> Result<(), String> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", json!({ "stringValue": "pgcat" }))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "pgcat", "version": VERSION },
+                "spans": spans,
+            }],
+        }],
+    });
+
+    let uri: Uri = format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+        .parse()
+        .map_err(|err| format!("invalid endpoint: {}", err))?;
+
+    let authority = uri
+        .authority()
+        .ok_or_else(|| "invalid endpoint: no host".to_string())?
+        .clone();
+
+    let request = Request::post(uri.path())
+        .header(HOST, authority.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .map_err(|err| err.to_string())?;
+
+    let response = tokio::time::timeout(SEND_TIMEOUT, async {
+        let stream = TcpStream::connect((authority.host(), authority.port_u16().unwrap_or(80)))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        tokio::task::spawn(connection);
+
+        sender
+            .send_request(request)
+            .await
+            .map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|_| "timed out".to_string())??;
+
+    if !response.status().is_success() {
+        return Err(format!("the collector answered {}", response.status()));
+    }
+
+    Ok(())
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// A span being recorded, kept in the span's extensions until it closes.
+#[derive(Debug)]
+struct OtlpSpan {
+    name: &'static str,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<Value>,
+}
+
+impl OtlpSpan {
+    fn to_json(&self, end: SystemTime) -> Value {
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            // The query span is the server side of the client's query.
+            "kind": match self.name {
+                "query" => SPAN_KIND_SERVER,
+                _ => SPAN_KIND_INTERNAL,
+            },
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": self.attributes,
+        });
+
+        if let Some(parent_span_id) = self.parent_span_id {
+            span["parentSpanId"] = json!(hex(&parent_span_id));
+        }
+
+        span
+    }
+
+    fn record(&mut self, field: &Field, value: Value) {
+        self.attributes.push(attribute(field.name(), value));
+    }
+}
+
+impl Visit for OtlpSpan {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            // The trace context propagated by the client.
+            "trace_id" => {
+                if let Some(trace_id) = decode_hex(value) {
+                    self.trace_id = trace_id;
+                }
+            }
+
+            "parent_span_id" => self.parent_span_id = decode_hex(value),
+
+            _ => self.record(field, json!({ "stringValue": value })),
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, json!({ "boolValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record(field, json!({ "stringValue": format!("{:?}", value) }));
+    }
+}
+
+/// Collects the query spans and hands them to the exporter when they close.
+pub struct OtlpLayer;
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().target() != SPAN_TARGET || !exporting() {
+            return;
+        }
+
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        // Children are part of their parent's trace.
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<OtlpSpan>()
+                .map(|parent| (parent.trace_id, parent.span_id))
+        });
+
+        let mut otlp_span = OtlpSpan {
+            name: attrs.metadata().name(),
+            trace_id: parent.map_or_else(rand::random, |(trace_id, _)| trace_id),
+            span_id: rand::random(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+
+        attrs.record(&mut otlp_span);
+        span.extensions_mut().insert(otlp_span);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(otlp_span) = span.extensions_mut().get_mut::<OtlpSpan>() {
+                values.record(otlp_span);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let otlp_span = match ctx.span(&id) {
+            Some(span) => span.extensions_mut().remove::<OtlpSpan>(),
+            None => None,
+        };
+
+        if let (Some(otlp_span), Some(exporter)) = (otlp_span, EXPORTER.get()) {
+            // Dropped if the queue is full, tracing must not slow down queries.
+            let _ = exporter.try_send(otlp_span.to_json(SystemTime::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_otlp_span() {
+        assert_eq!(
+            decode_hex::<8>("b7ad6b7169203331"),
+            Some([0xb7, 0xad, 0x6b, 0x71, 0x69, 0x20, 0x33, 0x31])
+        );
+        assert_eq!(decode_hex::<8>("b7ad6b71"), None);
+        assert_eq!(decode_hex::<2>("zzzz"), None);
+
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let span = OtlpSpan {
+            name: "query",
+            trace_id: decode_hex("0af7651916cd43dd8448eb211c80319c").unwrap(),
+            span_id: [1; 8],
+            parent_span_id: decode_hex("b7ad6b7169203331"),
+            start,
+            attributes: vec![attribute("user", json!({ "stringValue": "postgres" }))],
+        };
+
+        assert_eq!(
+            span.to_json(start + Duration::from_millis(2)),
+            json!({
+                "traceId": "0af7651916cd43dd8448eb211c80319c",
+                "spanId": "0101010101010101",
+                "parentSpanId": "b7ad6b7169203331",
+                "name": "query",
+                "kind": 2,
+                "startTimeUnixNano": "1000000000",
+                "endTimeUnixNano": "1002000000",
+                "attributes": [{ "key": "user", "value": { "stringValue": "postgres" } }],
+            })
+        );
+    }
+}
@@ -1,36 +1,38 @@
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy};
+use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy, RunError};
 use chrono::naive::NaiveDateTime;
 use log::{debug, error, info, warn};
 use lru::LruCache;
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{thread_rng, Rng};
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::num::NonZeroUsize;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::Notify;
 
 use crate::config::{
-    get_config, Address, DefaultShard, General, LoadBalancingMode, Plugins, PoolMode, Role, User,
+    get_config, get_local_zone, Address, CrossShardTransactions, DefaultShard, General,
+    LoadBalancingMode, Plugins, PoolMode, PrimarySelection, Role, User,
 };
 use crate::errors::Error;
 
 use crate::auth_passthrough::AuthPassthrough;
 use crate::messages::Parse;
 use crate::plugins::prewarmer;
-use crate::server::{Server, ServerParameters};
-use crate::sharding::ShardingFunction;
-use crate::stats::{AddressStats, ClientStats, ServerStats};
+use crate::rate_limiter::RateLimiter;
+use crate::server::{ConnectRetries, Server, ServerParameters};
+use crate::sharding::{ShardCache, ShardingFunction, KETAMA_DEFAULT_VNODES};
+use crate::stats::{AddressStats, ClientStats, LatencyStats, ServerStats};
 
 pub type ProcessId = i32;
 pub type SecretKey = i32;
@@ -46,6 +48,9 @@ pub type PoolMap = HashMap<PoolIdentifier, ConnectionPool>;
 /// The pool is recreated dynamically when the config is reloaded.
 pub static POOLS: Lazy<ArcSwap<PoolMap>> = Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
 
+/// Set once PgCat starts shutting down and waits for clients to finish.
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
 // Reasons for banning a server.
 #[derive(Debug, PartialEq, Clone)]
 pub enum BanReason {
@@ -54,9 +59,43 @@ pub enum BanReason {
     MessageReceiveFailed,
     FailedCheckout,
     StatementTimeout,
+    RoleMismatch,
     AdminBan(i64),
 }
 
+/// Why a health check failed.
+#[derive(Debug)]
+enum HealthCheckError {
+    /// The connection was closed, e.g. by idle_session_timeout or a firewall.
+    Closed,
+
+    /// The server is unhealthy and should be banned.
+    Ban(BanReason),
+}
+
+/// Closed idle connections replaced during one checkout before the server is banned.
+const MAX_CLOSED_CONNECTION_REPLACEMENTS: usize = 3;
+
+/// Consecutive bans of an address, to ban flapping servers for longer with `ban_backoff`.
+#[derive(Debug, Clone)]
+struct BanBackoff {
+    /// Bans since the address was last healthy for `max_ban_time`.
+    bans: u32,
+
+    /// Duration of the current or last ban, in seconds.
+    ban_time: i64,
+
+    /// When the current or last ban started.
+    banned_at: NaiveDateTime,
+}
+
+/// Ban time doubling with every consecutive ban, up to `max_ban_time`.
+fn backoff_ban_time(ban_time: i64, max_ban_time: i64, bans: u32) -> i64 {
+    ban_time
+        .saturating_mul(2i64.saturating_pow(bans.saturating_sub(1)))
+        .min(max_ban_time)
+}
+
 pub type PreparedStatementCacheType = Arc<Mutex<PreparedStatementCache>>;
 
 // TODO: Add stats the this cache
@@ -152,6 +191,9 @@ pub struct PoolSettings {
     /// Random or LeastOutstandingConnections.
     pub load_balancing_mode: LoadBalancingMode,
 
+    // How writes are spread over several primaries.
+    pub primary_selection: PrimarySelection,
+
     /// Maximum number of checkout failures a client is allowed before it
     /// gets disconnected. This is needed to prevent persistent client/server
     /// imbalance in high availability setups where multiple PgCat instances are placed
@@ -163,6 +205,12 @@ pub struct PoolSettings {
     // Number of shards.
     pub shards: usize,
 
+    // Maximum number of server connections per server, for each shard.
+    pub shard_pool_sizes: Vec<u32>,
+
+    // Idle server connections kept open to each server.
+    pub min_pool_size: u32,
+
     // Connecting user.
     pub user: User,
     pub db: String,
@@ -179,9 +227,24 @@ pub struct PoolSettings {
     // Infer role
     pub query_parser_read_write_splitting: bool,
 
+    // Reject DDL and utility statements.
+    pub block_ddl: bool,
+
+    // Send all transactions to the primary, even read-only ones.
+    pub sticky_primary_in_txn: bool,
+
+    // Replay the client's SET statements on every server it checks out.
+    pub track_set_statements: bool,
+
     // Read from the primary as well or not.
     pub primary_reads_enabled: bool,
 
+    // Read from the primary as well or not, for each shard.
+    pub shard_primary_reads_enabled: Vec<bool>,
+
+    // Replicas lagging by more than this (ms) are excluded from reads.
+    pub max_replica_lag: Option<u64>,
+
     // Automatic primary/replica selection based on recent activity.
     pub db_activity_based_routing: bool,
 
@@ -197,18 +260,57 @@ pub struct PoolSettings {
     // Sharding function.
     pub sharding_function: ShardingFunction,
 
+    // Number of points per shard on the ketama ring.
+    pub ketama_vnodes: usize,
+
+    // First key of each shard's range for range sharding.
+    pub shard_range_starts: Vec<i64>,
+
+    // Shard of recently seen sharding keys, shared by all the clients of the pool.
+    pub shard_cache: Option<Arc<ShardCache>>,
+
+    // Replicas to send writes to while the primary is banned, for each shard,
+    // as indices in the shard's addresses.
+    pub shard_failover_priority: Vec<Vec<usize>>,
+
     // Sharding key
     pub automatic_sharding_key: Option<String>,
 
-    // Health check timeout
-    pub healthcheck_timeout: u64,
+    // Error out when the sharding keys of a query are on different shards
+    pub reject_cross_shard_queries: bool,
+
+    // What to do when a statement of a transaction goes to another shard
+    pub cross_shard_transactions: CrossShardTransactions,
+
+    // Send reads without a sharding key to all shards
+    pub enable_scatter_gather: bool,
+
+    // Skip shards that failed a scatter-gather query instead of failing it
+    pub scatter_gather_best_effort: bool,
+
+    // Retry reads on another replica when the server connection fails
+    // before anything was sent to the client
+    pub read_retry_count: usize,
 
     // Health check delay
     pub healthcheck_delay: u64,
 
+    // Health check query
+    pub healthcheck_query: String,
+
+    // Query resetting the session state of server connections returned to the pool
+    pub server_reset_query: Option<String>,
+
+    // Check that servers are in recovery if and only if they are replicas
+    pub healthcheck_verify_role: bool,
+
     // Ban time
     pub ban_time: i64,
 
+    // Double the ban time of servers banned repeatedly, up to max_ban_time
+    pub ban_backoff: bool,
+    pub max_ban_time: i64,
+
     // Regex for searching for the sharding key in SQL statements
     pub sharding_key_regex: Option<Regex>,
 
@@ -218,10 +320,18 @@ pub struct PoolSettings {
     // What to do when no shard is selected in a sharded system
     pub default_shard: DefaultShard,
 
+    // Shard and role answering catalog queries
+    pub catalog_shard: Option<usize>,
+    pub catalog_role: Option<Role>,
+
+    // Connect replication clients to the primary instead of rejecting them
+    pub replication_passthrough: bool,
+
     // Limit how much of each query is searched for a potential shard regex match
     pub regex_search_limit: usize,
 
     // Auth query parameters
+    pub auth_query_cache_ttl: u64,
     pub auth_query: Option<String>,
     pub auth_query_user: Option<String>,
     pub auth_query_password: Option<String>,
@@ -235,28 +345,53 @@ impl Default for PoolSettings {
         PoolSettings {
             pool_mode: PoolMode::Transaction,
             load_balancing_mode: LoadBalancingMode::Random,
+            primary_selection: PrimarySelection::RoundRobin,
             checkout_failure_limit: None,
             shards: 1,
+            shard_pool_sizes: vec![General::default_pool_size()],
+            min_pool_size: 0,
             user: User::default(),
             db: String::default(),
             default_role: None,
             query_parser_enabled: false,
             query_parser_max_length: None,
             query_parser_read_write_splitting: false,
+            block_ddl: false,
+            sticky_primary_in_txn: true,
+            track_set_statements: false,
             primary_reads_enabled: true,
+            shard_primary_reads_enabled: vec![true],
+            max_replica_lag: None,
             db_activity_based_routing: false,
             db_activity_init_delay: 100,
             db_activity_ttl: 15 * 60,
             table_mutation_cache_ms_ttl: 50,
             sharding_function: ShardingFunction::PgBigintHash,
+            ketama_vnodes: KETAMA_DEFAULT_VNODES,
+            shard_range_starts: Vec::new(),
+            shard_cache: None,
+            shard_failover_priority: Vec::new(),
             automatic_sharding_key: None,
+            reject_cross_shard_queries: false,
+            cross_shard_transactions: CrossShardTransactions::Reject,
+            enable_scatter_gather: false,
+            scatter_gather_best_effort: false,
+            read_retry_count: 0,
             healthcheck_delay: General::default_healthcheck_delay(),
-            healthcheck_timeout: General::default_healthcheck_timeout(),
+            healthcheck_query: General::default_healthcheck_query(),
+            server_reset_query: Some(General::default_server_reset_query()),
+            healthcheck_verify_role: false,
             ban_time: General::default_ban_time(),
+            ban_backoff: false,
+            max_ban_time: General::default_max_ban_time(),
             sharding_key_regex: None,
             shard_id_regex: None,
             regex_search_limit: 1000,
             default_shard: DefaultShard::Shard(0),
+            catalog_shard: None,
+            catalog_role: None,
+            replication_passthrough: false,
+            auth_query_cache_ttl: General::default_auth_query_cache_ttl(),
             auth_query: None,
             auth_query_user: None,
             auth_query_password: None,
@@ -265,6 +400,15 @@ impl Default for PoolSettings {
     }
 }
 
+impl PoolSettings {
+    /// Whether the primary serves reads, for the shard if it's known.
+    pub fn primary_reads_enabled(&self, shard: Option<usize>) -> bool {
+        shard
+            .and_then(|shard| self.shard_primary_reads_enabled.get(shard).copied())
+            .unwrap_or(self.primary_reads_enabled)
+    }
+}
+
 /// The globally accessible connection pool.
 #[derive(Clone, Debug, Default)]
 pub struct ConnectionPool {
@@ -279,6 +423,9 @@ pub struct ConnectionPool {
     /// that should not be queried.
     banlist: BanList,
 
+    /// Consecutive bans of the addresses, when `ban_backoff` is enabled.
+    ban_backoff: Arc<Mutex<HashMap<Address, BanBackoff>>>,
+
     /// The server information has to be passed to the
     /// clients on startup. We pre-connect to all shards and replicas
     /// on pool creation and save the startup parameters here.
@@ -296,6 +443,11 @@ pub struct ConnectionPool {
     /// the pool after a RELOAD command
     pub config_hash: u64,
 
+    /// Hash value of the pool configs except the shards, and of each shard config.
+    /// Shards that didn't change keep their server connections on RELOAD.
+    settings_hash: u64,
+    shard_hashes: Arc<Vec<u64>>,
+
     /// If the pool has been paused or not.
     paused: Arc<AtomicBool>,
     paused_waiter: Arc<Notify>,
@@ -303,8 +455,39 @@ pub struct ConnectionPool {
     /// AuthInfo
     pub auth_hash: Arc<RwLock<Option<String>>>,
 
+    /// When the auth hash was obtained with auth_query.
+    auth_hash_fetched_at: Arc<RwLock<Option<Instant>>>,
+
     /// Cache
     pub prepared_statement_cache: Option<PreparedStatementCacheType>,
+
+    /// Query latency histograms.
+    pub latency: Arc<LatencyStats>,
+
+    /// Queries per second limit shared by all the clients of the user.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// Number of clients connected as the user, see `max_client_connections`.
+    client_count: Arc<AtomicUsize>,
+
+    /// Number of clients that failed to authenticate as the user.
+    auth_failures: Arc<AtomicU64>,
+
+    /// When the pool was first created, reloads don't change it.
+    created_at: NaiveDateTime,
+
+    /// Writes sent to the primaries, to spread them in round robin.
+    primary_round_robin: Arc<AtomicUsize>,
+}
+
+/// Counts a client against the user's `max_client_connections` until it's dropped.
+#[derive(Debug)]
+pub struct ClientSlot(Arc<AtomicUsize>);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl ConnectionPool {
@@ -313,17 +496,24 @@ impl ConnectionPool {
         let config = get_config();
 
         let mut new_pools = HashMap::new();
-        let mut address_id: usize = 0;
+
+        // Addresses of unchanged shards are reused, so new ones can't take their IDs.
+        let mut address_id: usize = get_all_pools()
+            .values()
+            .flat_map(|pool| pool.addresses.iter().flatten().map(|address| address.id))
+            .max()
+            .map_or(0, |id| id + 1);
 
         for (pool_name, pool_config) in &config.pools {
             let new_pool_hash_value = pool_config.hash_value();
+            let new_settings_hash_value = pool_config.settings_hash_value();
 
             // There is one pool per database/user pair.
             for user in pool_config.users.values() {
                 let old_pool_ref = get_pool(pool_name, &user.username);
                 let identifier = PoolIdentifier::new(pool_name, &user.username);
 
-                if let Some(pool) = old_pool_ref {
+                if let Some(ref pool) = old_pool_ref {
                     // If the pool hasn't changed, get existing reference and insert it into the new_pools.
                     // We replace all pools at the end, but if the reference is kept, the pool won't get re-created (bb8).
                     if pool.config_hash == new_pool_hash_value {
@@ -341,9 +531,52 @@ impl ConnectionPool {
                     pool_name, user.username
                 );
 
+                // Keep the latency histograms across reloads, like the address stats.
+                let latency = match old_pool_ref {
+                    Some(ref pool) => pool.latency.clone(),
+                    None => Arc::new(LatencyStats::default()),
+                };
+
+                // Keep the bucket if the limit didn't change, so reloading doesn't allow a burst.
+                let rate_limiter = user.max_queries_per_second.map(|queries_per_second| {
+                    old_pool_ref
+                        .as_ref()
+                        .and_then(|pool| pool.rate_limiter.clone())
+                        .filter(|rate_limiter| {
+                            rate_limiter.queries_per_second() == queries_per_second
+                        })
+                        .unwrap_or_else(|| Arc::new(RateLimiter::new(queries_per_second)))
+                });
+
+                let created_at = match old_pool_ref {
+                    Some(ref pool) => pool.created_at,
+                    None => chrono::offset::Utc::now().naive_utc(),
+                };
+
+                // Clients connected before the reload still count towards the limit.
+                let client_count = match old_pool_ref {
+                    Some(ref pool) => pool.client_count.clone(),
+                    None => Arc::new(AtomicUsize::new(0)),
+                };
+
+                // Flapping servers shouldn't get a short ban because of a reload.
+                let ban_backoff = match old_pool_ref {
+                    Some(ref pool) => pool.ban_backoff.clone(),
+                    None => Arc::new(Mutex::new(HashMap::new())),
+                };
+
+                // Connections are opened in the background until there are
+                // this many idle ones to each server, and again when they are used or recycled.
+                let min_pool_size = user.min_pool_size.unwrap_or(config.general.min_pool_size);
+
+                // Only the shards changed, the others can keep their server pools.
+                let reusable_pool =
+                    old_pool_ref.filter(|pool| pool.settings_hash == new_settings_hash_value);
+
                 let mut shards = Vec::new();
                 let mut addresses = Vec::new();
                 let mut banlist = Vec::new();
+                let mut shard_hashes = Vec::new();
                 let mut shard_ids = pool_config
                     .shards
                     .clone()
@@ -352,41 +585,94 @@ impl ConnectionPool {
 
                 // Sort by shard number to ensure consistency.
                 shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
-                let pool_auth_hash: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+                let pool_auth_hash: Arc<RwLock<Option<String>>> = match reusable_pool {
+                    Some(ref pool) => pool.auth_hash.clone(),
+                    None => Arc::new(RwLock::new(None)),
+                };
 
                 for shard_idx in &shard_ids {
                     let shard = &pool_config.shards[shard_idx];
+                    let shard_number = shard_idx.parse::<usize>().unwrap();
+                    let shard_hash = shard.hash_value();
+
+                    if let Some(ref pool) = reusable_pool {
+                        if pool.shard_hashes.get(shard_number) == Some(&shard_hash) {
+                            info!(
+                                "[pool: {}][user: {}] shard {} has not changed",
+                                pool_name, user.username, shard_number
+                            );
+                            shards.push(pool.databases[shard_number].clone());
+                            addresses.push(pool.addresses[shard_number].clone());
+                            banlist.push(pool.banlist.read()[shard_number].clone());
+                            shard_hashes.push(shard_hash);
+                            continue;
+                        }
+                    }
+
                     let mut pools = Vec::new();
                     let mut servers = Vec::new();
                     let mut replica_number = 0;
 
                     // Load Mirror settings
                     for (address_index, server) in shard.servers.iter().enumerate() {
+                        // Mirror servers never serve clients, they get a copy
+                        // of the traffic sent to the other servers of the shard.
+                        // Disabled servers are left out until they are enabled again.
+                        if !server.pooled() {
+                            continue;
+                        }
+
+                        // The most specific timeouts win.
+                        let connect_timeout = server
+                            .connect_timeout
+                            .or(shard.connect_timeout)
+                            .or(user.connect_timeout)
+                            .or(pool_config.connect_timeout)
+                            .unwrap_or(config.general.connect_timeout);
+                        let healthcheck_timeout = server
+                            .healthcheck_timeout
+                            .or(shard.healthcheck_timeout)
+                            .unwrap_or(config.general.healthcheck_timeout);
+
+                        let mirror_servers = shard
+                            .servers
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, mirror)| mirror.role == Role::Mirror && mirror.enabled)
+                            .map(|(mirror_idx, mirror)| (mirror_idx, &mirror.host, mirror.port));
+
                         let mut mirror_addresses = vec![];
-                        if let Some(mirror_settings_vec) = &shard.mirrors {
-                            for (mirror_idx, mirror_settings) in
-                                mirror_settings_vec.iter().enumerate()
-                            {
-                                if mirror_settings.mirroring_target_index != address_index {
-                                    continue;
-                                }
-                                mirror_addresses.push(Address {
-                                    id: address_id,
-                                    database: shard.database.clone(),
-                                    host: mirror_settings.host.clone(),
-                                    port: mirror_settings.port,
-                                    role: server.role,
-                                    address_index: mirror_idx,
-                                    replica_number,
-                                    shard: shard_idx.parse::<usize>().unwrap(),
-                                    username: user.username.clone(),
-                                    pool_name: pool_name.clone(),
-                                    mirrors: vec![],
-                                    stats: Arc::new(AddressStats::default()),
-                                    error_count: Arc::new(AtomicU64::new(0)),
-                                });
-                                address_id += 1;
-                            }
+                        for (mirror_idx, host, port) in shard
+                            .mirrors
+                            .iter()
+                            .flatten()
+                            .enumerate()
+                            .filter(|(_, mirror)| mirror.mirroring_target_index == address_index)
+                            .map(|(mirror_idx, mirror)| (mirror_idx, &mirror.host, mirror.port))
+                            .chain(mirror_servers)
+                        {
+                            mirror_addresses.push(Address {
+                                id: address_id,
+                                database: shard.database.clone(),
+                                host: host.clone(),
+                                port,
+                                role: server.role,
+                                address_index: mirror_idx,
+                                replica_number,
+                                shard: shard_idx.parse::<usize>().unwrap(),
+                                username: user.username.clone(),
+                                pool_name: pool_name.clone(),
+                                mirrors: vec![],
+                                stats: Arc::new(AddressStats::default()),
+                                error_count: Arc::new(AtomicU64::new(0)),
+                                replica_lag: Arc::new(AtomicU64::new(0)),
+                                login_error: Arc::new(Mutex::new(None)),
+                                weight: server.weight,
+                                zone: server.zone.clone(),
+                                connect_timeout,
+                                healthcheck_timeout,
+                            });
+                            address_id += 1;
                         }
 
                         let address = Address {
@@ -395,7 +681,8 @@ impl ConnectionPool {
                             host: server.host.clone(),
                             port: server.port,
                             role: server.role,
-                            address_index,
+                            // Mirrors and disabled servers are skipped, so this is the index in `servers`, not in the config.
+                            address_index: servers.len(),
                             replica_number,
                             shard: shard_idx.parse::<usize>().unwrap(),
                             username: user.username.clone(),
@@ -403,6 +690,12 @@ impl ConnectionPool {
                             mirrors: mirror_addresses,
                             stats: Arc::new(AddressStats::default()),
                             error_count: Arc::new(AtomicU64::new(0)),
+                            replica_lag: Arc::new(AtomicU64::new(0)),
+                            login_error: Arc::new(Mutex::new(None)),
+                            weight: server.weight,
+                            zone: server.zone.clone(),
+                            connect_timeout,
+                            healthcheck_timeout,
                         };
 
                         address_id += 1;
@@ -461,14 +754,6 @@ impl ConnectionPool {
                             pool_config.prepared_statements_cache_size,
                         );
 
-                        let connect_timeout = match user.connect_timeout {
-                            Some(connect_timeout) => connect_timeout,
-                            None => match pool_config.connect_timeout {
-                                Some(connect_timeout) => connect_timeout,
-                                None => config.general.connect_timeout,
-                            },
-                        };
-
                         let idle_timeout = match user.idle_timeout {
                             Some(idle_timeout) => idle_timeout,
                             None => match pool_config.idle_timeout {
@@ -485,10 +770,14 @@ impl ConnectionPool {
                             },
                         };
 
+                        // A timeout of 0 disables it.
                         let reaper_rate = *[idle_timeout, server_lifetime, POOL_REAPER_RATE]
                             .iter()
+                            .filter(|rate| **rate > 0)
                             .min()
                             .unwrap();
+                        // bb8 runs its reaper at a fixed rate, so it can't be jittered.
+                        let reaper_rate = Duration::from_millis(reaper_rate);
 
                         let queue_strategy = match config.general.server_round_robin {
                             true => QueueStrategy::Fifo,
@@ -497,16 +786,44 @@ impl ConnectionPool {
 
                         debug!(
                             "[pool: {}][user: {}] Pool reaper rate: {}ms",
-                            pool_name, user.username, reaper_rate
+                            pool_name,
+                            user.username,
+                            reaper_rate.as_millis()
                         );
 
+                        // How long clients wait for a connection, including creating one,
+                        // which can take every connection attempt when retries are enabled.
+                        let mut checkout_timeout =
+                            config.general.query_wait_timeout.unwrap_or(connect_timeout);
+
+                        if config.general.connect_retries > 0 {
+                            let retries = ConnectRetries {
+                                timeout: Duration::from_millis(connect_timeout),
+                                ..ConnectRetries::from_config(&config.general)
+                            };
+                            let total_timeout = u64::try_from(retries.total_timeout().as_millis())
+                                .unwrap_or(u64::MAX);
+
+                            checkout_timeout = checkout_timeout.max(total_timeout);
+                        }
+
                         let pool = Pool::builder()
-                            .max_size(user.pool_size)
-                            .min_idle(user.min_pool_size)
-                            .connection_timeout(std::time::Duration::from_millis(connect_timeout))
-                            .idle_timeout(Some(std::time::Duration::from_millis(idle_timeout)))
-                            .max_lifetime(Some(std::time::Duration::from_millis(server_lifetime)))
-                            .reaper_rate(std::time::Duration::from_millis(reaper_rate))
+                            .max_size(shard.pool_size(user, &config.general))
+                            .min_idle(Some(min_pool_size))
+                            .connection_timeout(std::time::Duration::from_millis(checkout_timeout))
+                            .idle_timeout(match idle_timeout {
+                                0 => None,
+                                idle_timeout => {
+                                    Some(std::time::Duration::from_millis(idle_timeout))
+                                }
+                            })
+                            .max_lifetime(match server_lifetime {
+                                0 => None,
+                                server_lifetime => {
+                                    Some(std::time::Duration::from_millis(server_lifetime))
+                                }
+                            })
+                            .reaper_rate(reaper_rate)
                             .queue_strategy(queue_strategy)
                             .test_on_check_out(false);
 
@@ -523,6 +840,7 @@ impl ConnectionPool {
                     shards.push(pools);
                     addresses.push(servers);
                     banlist.push(HashMap::new());
+                    shard_hashes.push(shard_hash);
                 }
 
                 assert_eq!(shards.len(), addresses.len());
@@ -533,12 +851,18 @@ impl ConnectionPool {
                     );
                 }
 
+                let auth_hash_fetched_at = pool_auth_hash.read().as_ref().map(|_| Instant::now());
+
                 let pool = ConnectionPool {
                     databases: Arc::new(shards),
                     addresses: Arc::new(addresses),
                     banlist: Arc::new(RwLock::new(banlist)),
+                    ban_backoff,
                     config_hash: new_pool_hash_value,
+                    settings_hash: new_settings_hash_value,
+                    shard_hashes: Arc::new(shard_hashes),
                     original_server_parameters: Arc::new(RwLock::new(ServerParameters::new())),
+                    auth_hash_fetched_at: Arc::new(RwLock::new(auth_hash_fetched_at)),
                     auth_hash: pool_auth_hash,
                     settings: Arc::new(PoolSettings {
                         pool_mode: match user.pool_mode {
@@ -546,12 +870,25 @@ impl ConnectionPool {
                             None => pool_config.pool_mode,
                         },
                         load_balancing_mode: pool_config.load_balancing_mode,
+                        primary_selection: config.general.primary_selection,
                         checkout_failure_limit: pool_config.checkout_failure_limit,
                         // shards: pool_config.shards.clone(),
                         shards: shard_ids.len(),
+                        shard_pool_sizes: shard_ids
+                            .iter()
+                            .map(|shard_idx| {
+                                pool_config.shards[shard_idx].pool_size(user, &config.general)
+                            })
+                            .collect(),
+                        min_pool_size,
                         user: user.clone(),
                         db: pool_name.clone(),
-                        default_role: match pool_config.default_role.as_str() {
+                        default_role: match user
+                            .default_role
+                            .as_ref()
+                            .unwrap_or(&pool_config.default_role)
+                            .as_str()
+                        {
                             "any" => None,
                             "replica" => Some(Role::Replica),
                             "primary" => Some(Role::Primary),
@@ -561,16 +898,61 @@ impl ConnectionPool {
                         query_parser_max_length: pool_config.query_parser_max_length,
                         query_parser_read_write_splitting: pool_config
                             .query_parser_read_write_splitting,
+                        block_ddl: pool_config.block_ddl,
+                        sticky_primary_in_txn: pool_config.sticky_primary_in_txn,
+                        track_set_statements: pool_config.track_set_statements,
                         primary_reads_enabled: pool_config.primary_reads_enabled,
-                        sharding_function: pool_config.sharding_function,
+                        shard_primary_reads_enabled: shard_ids
+                            .iter()
+                            .map(|shard_idx| {
+                                pool_config.shards[shard_idx].primary_reads_enabled(pool_config)
+                            })
+                            .collect(),
+                        max_replica_lag: pool_config.max_replica_lag,
+                        sharding_function: pool_config.sharding_function(&config.general),
+                        ketama_vnodes: pool_config.ketama_vnodes,
+                        shard_range_starts: pool_config.shard_range_starts(),
+                        // A new pool gets an empty cache, so keys aren't sent
+                        // to the shards of the previous sharding configuration.
+                        shard_cache: NonZeroUsize::new(pool_config.shard_cache_size)
+                            .map(|size| Arc::new(Mutex::new(LruCache::new(size)))),
+                        shard_failover_priority: shard_ids
+                            .iter()
+                            .map(|shard_idx| {
+                                let shard = &pool_config.shards[shard_idx];
+
+                                // Disabled replicas can't be promoted.
+                                shard
+                                    .failover_priority()
+                                    .into_iter()
+                                    .filter_map(|index| shard.address_index(index))
+                                    .collect()
+                            })
+                            .collect(),
                         db_activity_based_routing: pool_config.db_activity_based_routing,
                         db_activity_init_delay: pool_config.db_activity_init_delay,
                         db_activity_ttl: pool_config.db_activity_ttl,
                         table_mutation_cache_ms_ttl: pool_config.table_mutation_cache_ms_ttl,
                         automatic_sharding_key: pool_config.automatic_sharding_key.clone(),
+                        reject_cross_shard_queries: pool_config.reject_cross_shard_queries,
+                        cross_shard_transactions: pool_config.cross_shard_transactions,
+                        enable_scatter_gather: pool_config.enable_scatter_gather,
+                        scatter_gather_best_effort: pool_config.scatter_gather_best_effort,
+                        read_retry_count: pool_config.read_retry_count,
                         healthcheck_delay: config.general.healthcheck_delay,
-                        healthcheck_timeout: config.general.healthcheck_timeout,
+                        healthcheck_query: config.general.healthcheck_query.clone(),
+                        server_reset_query: Some(
+                            pool_config
+                                .server_reset_query
+                                .as_ref()
+                                .unwrap_or(&config.general.server_reset_query)
+                                .clone(),
+                        )
+                        .filter(|query| !query.is_empty()),
+                        healthcheck_verify_role: config.general.healthcheck_verify_role,
                         ban_time: config.general.ban_time,
+                        ban_backoff: config.general.ban_backoff,
+                        max_ban_time: config.general.max_ban_time,
                         sharding_key_regex: pool_config
                             .sharding_key_regex
                             .clone()
@@ -581,6 +963,10 @@ impl ConnectionPool {
                             .map(|regex| Regex::new(regex.as_str()).unwrap()),
                         regex_search_limit: pool_config.regex_search_limit.unwrap_or(1000),
                         default_shard: pool_config.default_shard,
+                        catalog_shard: pool_config.catalog_shard,
+                        catalog_role: pool_config.catalog_role,
+                        replication_passthrough: pool_config.replication_passthrough,
+                        auth_query_cache_ttl: config.general.auth_query_cache_ttl,
                         auth_query: pool_config.auth_query.clone(),
                         auth_query_user: pool_config.auth_query_user.clone(),
                         auth_query_password: pool_config.auth_query_password.clone(),
@@ -590,13 +976,23 @@ impl ConnectionPool {
                         },
                     }),
                     validated: Arc::new(AtomicBool::new(false)),
+                    latency,
+                    rate_limiter,
+                    client_count,
+                    auth_failures: Arc::new(AtomicU64::new(0)),
+                    created_at,
+                    primary_round_robin: Arc::new(AtomicUsize::new(0)),
                     paused: Arc::new(AtomicBool::new(false)),
                     paused_waiter: Arc::new(Notify::new()),
-                    prepared_statement_cache: match pool_config.prepared_statements_cache_size {
-                        0 => None,
-                        _ => Some(Arc::new(Mutex::new(PreparedStatementCache::new(
-                            pool_config.prepared_statements_cache_size,
-                        )))),
+                    prepared_statement_cache: match reusable_pool {
+                        // Servers kept from the old pool may have these statements prepared.
+                        Some(ref pool) => pool.prepared_statement_cache.clone(),
+                        None => match pool_config.prepared_statements_cache_size {
+                            0 => None,
+                            _ => Some(Arc::new(Mutex::new(PreparedStatementCache::new(
+                                pool_config.prepared_statements_cache_size,
+                            )))),
+                        },
                     },
                 };
 
@@ -628,14 +1024,21 @@ impl ConnectionPool {
     pub async fn validate(&self) -> Result<(), Error> {
         let mut futures = Vec::new();
         let validated = Arc::clone(&self.validated);
+        let ban_time = Duration::from_secs(self.settings.ban_time as u64);
 
         for shard in 0..self.shards() {
             for server in 0..self.servers(shard) {
                 let databases = self.databases.clone();
                 let validated = Arc::clone(&validated);
                 let pool_server_parameters = Arc::clone(&self.original_server_parameters);
+                let address = self.address(shard, server).clone();
 
                 let task = tokio::task::spawn(async move {
+                    if let Some(err @ Error::ServerAuthError(..)) = address.login_error(ban_time) {
+                        error!("Shard {} down or misconfigured: {}", shard, err);
+                        return;
+                    }
+
                     let connection = match databases[shard][server].get().await {
                         Ok(conn) => conn,
                         Err(err) => {
@@ -663,12 +1066,56 @@ impl ConnectionPool {
         // all shards are running identical configurations.
         if !self.validated() {
             error!("Could not validate connection pool");
-            return Err(Error::AllServersDown);
+
+            // Tell the client why the servers didn't let us in, if they told us.
+            return Err(self
+                .addresses
+                .iter()
+                .flatten()
+                .find_map(|address| address.login_error(ban_time))
+                .unwrap_or(Error::AllServersDown));
         }
 
         Ok(())
     }
 
+    /// Validate all the pools at startup, see `require_healthy_startup`.
+    /// Fails if none of them could connect to any of its servers.
+    pub async fn validate_all() -> Result<(), Error> {
+        let pools = get_all_pools();
+
+        if pools.is_empty() {
+            error!("No pools are configured");
+            return Err(Error::AllServersDown);
+        }
+
+        let results = futures::future::join_all(pools.values().map(|pool| async move {
+            match pool.validated() {
+                true => Ok(()),
+                false => pool.validate().await,
+            }
+        }))
+        .await;
+
+        if results.iter().any(|result| result.is_ok()) {
+            return Ok(());
+        }
+
+        // None of the pools could connect, so none of their servers are reachable.
+        let unreachable = pools
+            .values()
+            .flat_map(|pool| pool.addresses.iter().flatten())
+            .map(|address| format!("{}:{}", address.host, address.port))
+            .collect::<BTreeSet<String>>();
+
+        error!(
+            "No pool could connect to its servers, unreachable: {}",
+            unreachable.into_iter().collect::<Vec<String>>().join(", ")
+        );
+
+        Err(Error::AllServersDown)
+    }
+
     /// The pool can be used by clients.
     ///
     /// If not, we need to validate it first by connecting to servers.
@@ -677,6 +1124,51 @@ impl ConnectionPool {
         self.validated.load(Ordering::Relaxed)
     }
 
+    /// Count a new client of the user. Returns None if the user
+    /// already has `max_client_connections` clients.
+    pub fn register_client(&self) -> Option<ClientSlot> {
+        let max_client_connections = match self.settings.user.max_client_connections {
+            Some(max_client_connections) => max_client_connections as usize,
+            None => usize::MAX,
+        };
+
+        self.client_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count < max_client_connections {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+
+        Some(ClientSlot(self.client_count.clone()))
+    }
+
+    /// Count a client that failed to authenticate, reported by SHOW STATS.
+    pub fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failures(&self) -> u64 {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+
+    /// Zero the cumulative counters of the pool and its servers, see `RESET STATS`.
+    pub fn reset_stats(&self) {
+        self.auth_failures.swap(0, Ordering::Relaxed);
+
+        for address in self.addresses.iter().flatten() {
+            address.stats.reset_totals();
+        }
+    }
+
+    /// How long the pool has existed, reported by SHOW POOLS.
+    pub fn uptime(&self) -> Duration {
+        let now = chrono::offset::Utc::now().naive_utc();
+        (now - self.created_at).to_std().unwrap_or_default()
+    }
+
     /// Pause the pool, allowing no more queries and make clients wait.
     pub fn pause(&self) {
         self.paused.store(true, Ordering::Relaxed);
@@ -723,6 +1215,15 @@ impl ConnectionPool {
             shard
         };
 
+        if let Some(shard) = effective_shard_id.or(match self.settings.default_shard {
+            DefaultShard::Shard(shard) => Some(shard),
+            _ => None,
+        }) {
+            if !self.settings.user.shard_allowed(shard) {
+                return Err(Error::ShardNotAllowed(shard));
+            }
+        }
+
         let mut candidates = self
             .addresses
             .iter()
@@ -741,8 +1242,12 @@ impl ConnectionPool {
                 DefaultShard::Shard(shard_id) => {
                     candidates.retain(|address| address.shard == shard_id)
                 }
-                DefaultShard::Random => (),
+                DefaultShard::Random => {
+                    candidates.retain(|address| self.settings.user.shard_allowed(address.shard))
+                }
                 DefaultShard::RandomHealthy => {
+                    candidates.retain(|address| self.settings.user.shard_allowed(address.shard));
+
                     candidates.sort_by(|a, b| {
                         b.error_count
                             .load(Ordering::Relaxed)
@@ -753,6 +1258,38 @@ impl ConnectionPool {
             },
         };
 
+        // Don't serve stale reads from replicas that fell behind.
+        if self.settings.max_replica_lag.is_some() {
+            let lagging_shards = candidates
+                .iter()
+                .filter(|address| self.lagging(address))
+                .map(|address| address.shard)
+                .collect::<HashSet<usize>>();
+
+            candidates.retain(|address| !self.lagging(address));
+
+            if candidates.is_empty() && !lagging_shards.is_empty() {
+                if !lagging_shards
+                    .iter()
+                    .all(|shard| self.settings.primary_reads_enabled(Some(*shard)))
+                {
+                    client_stats.checkout_error();
+                    return Err(Error::ReplicasLagging);
+                }
+
+                warn!("All replicas are lagging, reading from the primary");
+
+                candidates = self
+                    .addresses
+                    .iter()
+                    .flatten()
+                    .filter(|address| {
+                        address.role == Role::Primary && lagging_shards.contains(&address.shard)
+                    })
+                    .collect();
+            }
+        }
+
         if self.settings.load_balancing_mode == LoadBalancingMode::LeastOutstandingConnections {
             candidates.sort_by(|a, b| {
                 self.busy_connection_count(b)
@@ -761,13 +1298,25 @@ impl ConnectionPool {
             });
         }
 
+        if let Some(local_zone) = get_local_zone() {
+            Self::prefer_local_zone(&mut candidates, &local_zone);
+        }
+
+        // The shard has several primaries taking writes (allow_multiple_primaries).
+        if role == Some(Role::Primary) && effective_shard_id.is_some() && candidates.len() > 1 {
+            self.select_primary(&mut candidates);
+        }
+
         // Indicate we're waiting on a server connection from a pool.
         let now = Instant::now();
         client_stats.waiting();
 
+        let ban_time = Duration::from_secs(self.settings.ban_time as u64);
+        let mut login_error = None;
+
         while !candidates.is_empty() {
             // Get the next candidate
-            let address = match candidates.pop() {
+            let mut address = match candidates.pop() {
                 Some(address) => address,
                 None => break,
             };
@@ -777,96 +1326,177 @@ impl ConnectionPool {
             if self.is_banned(address) {
                 if self.try_unban(address).await {
                     force_healthcheck = true;
+                } else if let Some(replica) = self.promoted_replica(address.shard) {
+                    // The primary is down, send its traffic to the replica we failed over to.
+                    debug!("Address {:?} is banned, using {:?}", address, replica);
+                    address = replica;
                 } else {
                     debug!("Address {:?} is banned", address);
                     continue;
                 }
             }
 
-            // Check if we can connect
-            let mut conn = match self.databases[address.shard][address.address_index]
-                .get()
-                .await
-            {
-                Ok(conn) => {
-                    address.reset_error_count();
-                    conn
-                }
-                Err(err) => {
-                    error!(
-                        "Connection checkout error for instance {:?}, error: {:?}",
-                        address, err
-                    );
-                    self.ban(address, BanReason::FailedCheckout, Some(client_stats));
-                    address.stats.error();
-                    client_stats.checkout_error();
-                    continue;
+            // The server rejected our credentials recently, don't hammer it with logins
+            // that will fail the same way until the ban time passes.
+            if let Some(err @ Error::ServerAuthError(..)) = address.login_error(ban_time) {
+                debug!("Address {:?} rejected our login, skipping it", address);
+                login_error = Some(err);
+                continue;
+            }
+
+            // Idle connections found closed by their health check are replaced before the server
+            // is banned, as idle_session_timeout or a firewall may have closed them while the
+            // server is fine. Timeouts and query errors ban the server right away.
+            let mut replacements = 0;
+
+            loop {
+                // Check if we can connect
+                let mut conn = match self.databases[address.shard][address.address_index]
+                    .get()
+                    .await
+                {
+                    Ok(conn) => {
+                        address.reset_error_count();
+                        conn
+                    }
+                    Err(RunError::TimedOut) if self.exhausted(address) => {
+                        // The server is fine, all of its connections are just in use.
+                        warn!(
+                            "Timed out waiting for a connection to instance {:?}, pool is exhausted",
+                            address
+                        );
+                        address.stats.pool_timeout();
+                        client_stats.checkout_error();
+                        return Err(Error::PoolTimeout);
+                    }
+                    Err(err) => {
+                        error!(
+                            "Connection checkout error for instance {:?}, error: {:?}",
+                            address, err
+                        );
+                        if matches!(err, RunError::TimedOut) {
+                            address.stats.pool_timeout();
+                        }
+                        // Tell the client why the server didn't let us in.
+                        if let Some(err) = address.login_error(ban_time) {
+                            login_error = Some(err);
+                        }
+                        self.ban(address, BanReason::FailedCheckout, Some(client_stats));
+                        address.stats.error();
+                        client_stats.checkout_error();
+                        break;
+                    }
+                };
+
+                // // Check if this server is alive with a health check.
+                let server = &mut *conn;
+
+                // Will return error if timestamp is greater than current system time, which it should never be set to
+                let require_healthcheck = force_healthcheck
+                    || server.last_activity().elapsed().unwrap().as_millis()
+                        > self.settings.healthcheck_delay as u128;
+
+                // Do not issue a health check unless it's been a little while
+                // since we last checked the server is ok.
+                // Health checks are pretty expensive.
+                if !require_healthcheck {
+                    let checkout_time = now.elapsed().as_micros() as u64;
+                    client_stats.checkout_success();
+                    server
+                        .stats()
+                        .checkout_time(checkout_time, client_stats.application_name());
+                    server.stats().active(client_stats.application_name());
+                    client_stats.active();
+                    return Ok((conn, address.clone()));
                 }
-            };
 
-            // // Check if this server is alive with a health check.
-            let server = &mut *conn;
-
-            // Will return error if timestamp is greater than current system time, which it should never be set to
-            let require_healthcheck = force_healthcheck
-                || server.last_activity().elapsed().unwrap().as_millis()
-                    > self.settings.healthcheck_delay as u128;
-
-            // Do not issue a health check unless it's been a little while
-            // since we last checked the server is ok.
-            // Health checks are pretty expensive.
-            if !require_healthcheck {
-                let checkout_time = now.elapsed().as_micros() as u64;
-                client_stats.checkout_success();
-                server
-                    .stats()
-                    .checkout_time(checkout_time, client_stats.application_name());
-                server.stats().active(client_stats.application_name());
-                client_stats.active();
-                return Ok((conn, address.clone()));
-            }
+                match self
+                    .run_health_check(address, server, now, client_stats)
+                    .await
+                {
+                    Ok(()) => {
+                        let checkout_time = now.elapsed().as_micros() as u64;
+                        client_stats.checkout_success();
+                        server
+                            .stats()
+                            .checkout_time(checkout_time, client_stats.application_name());
+                        server.stats().active(client_stats.application_name());
+                        client_stats.active();
+                        return Ok((conn, address.clone()));
+                    }
 
-            if self
-                .run_health_check(address, server, now, client_stats)
-                .await
-            {
-                let checkout_time = now.elapsed().as_micros() as u64;
-                client_stats.checkout_success();
-                server
-                    .stats()
-                    .checkout_time(checkout_time, client_stats.application_name());
-                server.stats().active(client_stats.application_name());
-                client_stats.active();
-                return Ok((conn, address.clone()));
-            } else {
-                continue;
+                    Err(HealthCheckError::Closed)
+                        if !force_healthcheck
+                            && replacements < MAX_CLOSED_CONNECTION_REPLACEMENTS =>
+                    {
+                        replacements += 1;
+                        info!(
+                            "Replacing a closed connection to {:?} that failed its health check",
+                            address
+                        );
+                    }
+
+                    Err(HealthCheckError::Closed) => {
+                        self.ban(address, BanReason::FailedHealthCheck, Some(client_stats));
+                        break;
+                    }
+
+                    Err(HealthCheckError::Ban(reason)) => {
+                        self.ban(address, reason, Some(client_stats));
+                        break;
+                    }
+                }
             }
         }
 
         client_stats.checkout_error();
 
-        Err(Error::AllServersDown)
+        Err(login_error.unwrap_or(Error::AllServersDown))
     }
 
+    /// Check the server connection works, or return why it doesn't.
     async fn run_health_check(
         &self,
         address: &Address,
         server: &mut Server,
         start: Instant,
         client_info: &ClientStats,
-    ) -> bool {
+    ) -> Result<(), HealthCheckError> {
         debug!("Running health check on server {:?}", address);
 
         server.stats().tested();
 
+        let verify_role = self.settings.healthcheck_verify_role && address.role != Role::Mirror;
+        let healthcheck = async {
+            // The default, ";", is cheap as it skips the query planner.
+            server.query(&self.settings.healthcheck_query).await?;
+
+            if verify_role {
+                server.in_recovery().await.map(Some)
+            } else {
+                Ok(None)
+            }
+        };
+
         match tokio::time::timeout(
-            tokio::time::Duration::from_millis(self.settings.healthcheck_timeout),
-            server.query(";"), // Cheap query as it skips the query planner
+            tokio::time::Duration::from_millis(address.healthcheck_timeout),
+            healthcheck,
         )
         .await
         {
             // Check if health check succeeded.
             Ok(res) => match res {
+                // A replica was promoted, or a primary is now a standby.
+                Ok(Some(in_recovery)) if in_recovery != (address.role == Role::Replica) => {
+                    error!(
+                        "Instance {:?} is configured as a {} but pg_is_in_recovery() is {}",
+                        address, address.role, in_recovery
+                    );
+
+                    server.mark_bad("server role does not match the config");
+                    return Err(HealthCheckError::Ban(BanReason::RoleMismatch));
+                }
+
                 Ok(_) => {
                     let checkout_time: u64 = start.elapsed().as_micros() as u64;
                     client_info.checkout_success();
@@ -875,7 +1505,17 @@ impl ConnectionPool {
                         .checkout_time(checkout_time, client_info.application_name());
                     server.stats().active(client_info.application_name());
 
-                    return true;
+                    return Ok(());
+                }
+
+                // The connection was closed under us, the server itself may be fine.
+                Err(err @ Error::SocketError(_)) => {
+                    warn!(
+                        "Failed health check on instance {:?}, connection closed: {:?}",
+                        address, err
+                    );
+                    server.mark_bad("connection closed");
+                    return Err(HealthCheckError::Closed);
                 }
 
                 // Health check failed.
@@ -899,8 +1539,157 @@ impl ConnectionPool {
         // Don't leave a bad connection in the pool.
         server.mark_bad("failed health check");
 
-        self.ban(address, BanReason::FailedHealthCheck, Some(client_info));
-        false
+        Err(HealthCheckError::Ban(BanReason::FailedHealthCheck))
+    }
+
+    /// The replica is further behind the primary than `max_replica_lag`.
+    pub fn lagging(&self, address: &Address) -> bool {
+        match self.settings.max_replica_lag {
+            Some(max_replica_lag) => {
+                address.role == Role::Replica && address.replica_lag() > max_replica_lag
+            }
+            None => false,
+        }
+    }
+
+    /// Measure the replication lag of every replica, if the pool limits it.
+    pub async fn check_replica_lag(&self) {
+        if self.settings.max_replica_lag.is_none() {
+            return;
+        }
+
+        for address in self.addresses.iter().flatten() {
+            if address.role != Role::Replica || self.is_banned(address) {
+                continue;
+            }
+
+            let mut conn = match tokio::time::timeout(
+                tokio::time::Duration::from_millis(address.healthcheck_timeout),
+                self.databases[address.shard][address.address_index].get(),
+            )
+            .await
+            {
+                Ok(Ok(conn)) => conn,
+                _ => {
+                    debug!("Could not check replica lag of {:?}", address);
+                    continue;
+                }
+            };
+
+            match tokio::time::timeout(
+                tokio::time::Duration::from_millis(address.healthcheck_timeout),
+                conn.replica_lag(),
+            )
+            .await
+            {
+                Ok(Ok(lag)) => {
+                    if self.settings.max_replica_lag.is_some_and(|max| lag > max)
+                        && !self.lagging(address)
+                    {
+                        warn!("Replica {:?} is lagging by {}ms", address, lag);
+                    }
+
+                    address.set_replica_lag(lag);
+                }
+
+                Ok(Err(err)) => {
+                    error!("Failed to check replica lag of {:?}: {:?}", address, err);
+                    conn.mark_bad("failed replica lag check");
+                }
+
+                Err(_) => {
+                    error!("Replica lag check timeout on {:?}", address);
+                    conn.mark_bad("replica lag check timeout");
+                }
+            }
+        }
+    }
+
+    /// Health check the servers in the background, see `healthcheck_interval`.
+    /// Banned servers are left alone, they're checked when their ban expires.
+    pub async fn check_health(&self) {
+        for address in self.addresses.iter().flatten() {
+            // All connections are in use, the server is busy serving queries.
+            if self.is_banned(address) || self.exhausted(address) {
+                continue;
+            }
+
+            let pool = &self.databases[address.shard][address.address_index];
+            let timeout = Duration::from_millis(address.healthcheck_timeout);
+
+            let mut conn = match tokio::time::timeout(timeout, pool.get()).await {
+                Ok(Ok(conn)) => conn,
+
+                Ok(Err(RunError::User(err))) => {
+                    error!("Health check could not connect to {:?}: {:?}", address, err);
+                    self.ban(address, BanReason::FailedCheckout, None);
+                    continue;
+                }
+
+                // The server may just be slow to hand out a connection, clients checking out
+                // connections will ban it if it's really down.
+                _ => {
+                    warn!(
+                        "Health check timed out waiting for a connection to {:?}",
+                        address
+                    );
+                    continue;
+                }
+            };
+
+            if self.health_check_query(address, &mut conn, timeout).await {
+                address.reset_error_count();
+                continue;
+            }
+
+            // The idle connection may have been closed while the server is fine,
+            // only ban the server if a new one fails too.
+            drop(conn);
+
+            let healthy = match tokio::time::timeout(timeout, pool.dedicated_connection()).await {
+                Ok(Ok(mut server)) => self.health_check_query(address, &mut server, timeout).await,
+
+                Ok(Err(err)) => {
+                    error!("Health check could not connect to {:?}: {:?}", address, err);
+                    false
+                }
+
+                Err(_) => {
+                    error!("Health check timed out connecting to {:?}", address);
+                    false
+                }
+            };
+
+            if healthy {
+                address.reset_error_count();
+            } else {
+                self.ban(address, BanReason::FailedHealthCheck, None);
+            }
+        }
+    }
+
+    /// Run the health check query on a background health check connection.
+    async fn health_check_query(
+        &self,
+        address: &Address,
+        server: &mut Server,
+        timeout: Duration,
+    ) -> bool {
+        match tokio::time::timeout(timeout, server.query(&self.settings.healthcheck_query)).await {
+            Ok(Ok(())) => true,
+
+            Ok(Err(err)) => {
+                error!("Failed health check on {:?}: {:?}", address, err);
+                server.mark_bad("failed health check");
+                false
+            }
+
+            Err(_) => {
+                error!("Health check timeout on {:?}", address);
+                server.mark_bad("health check timeout");
+                false
+            }
+        }
     }
 
     /// Ban an address (i.e. replica). It no longer will serve
@@ -919,24 +1708,115 @@ impl ConnectionPool {
             _ => (),
         };
 
-        // Primary can never be banned
-        if address.role == Role::Primary {
+        // Primary can never be banned, unless we can fail over to a replica or another primary.
+        if address.role == Role::Primary
+            && !self.auto_failover(address.shard)
+            && self.primary_count(address.shard) < 2
+        {
             return;
         }
 
-        error!("Banning instance {:?}, reason: {:?}", address, reason);
-
         let now = chrono::offset::Utc::now().naive_utc();
         let mut guard = self.banlist.write();
 
+        // Don't leave the shard without replicas to read from because of a transient error,
+        // unless the primary can serve reads. Admin bans are always honored.
+        if address.role == Role::Replica
+            && !self.settings.primary_reads_enabled(Some(address.shard))
+            && !matches!(reason, BanReason::AdminBan(_) | BanReason::RoleMismatch)
+        {
+            let last_replica = self.addresses[address.shard]
+                .iter()
+                .filter(|replica| replica.role == Role::Replica && *replica != address)
+                .all(|replica| guard[address.shard].contains_key(replica));
+
+            if last_replica {
+                // Keep the least recently failed replica in rotation,
+                // it had the most time to recover.
+                let least_recently_failed = guard[address.shard]
+                    .iter()
+                    .filter(|(replica, (reason, _))| {
+                        replica.role == Role::Replica
+                            && !matches!(reason, BanReason::AdminBan(_) | BanReason::RoleMismatch)
+                    })
+                    .min_by_key(|(_, (_, timestamp))| *timestamp)
+                    .map(|(replica, _)| replica.clone());
+
+                match least_recently_failed {
+                    Some(replica) => {
+                        warn!(
+                            "Banning {:?} would leave shard {} without replicas, keeping {:?} in rotation",
+                            address, address.shard, replica
+                        );
+                        guard[address.shard].remove(&replica);
+                    }
+
+                    None => {
+                        warn!(
+                            "Not banning {:?}, reason: {:?}, it's the last replica of shard {}",
+                            address, reason, address.shard
+                        );
+                        return;
+                    }
+                }
+            }
+        }
+
+        error!("Banning instance {:?}, reason: {:?}", address, reason);
+
         if let Some(client_info) = client_info {
             client_info.ban_error();
             address.stats.error();
         }
 
+        if self.settings.ban_backoff
+            && !matches!(reason, BanReason::AdminBan(_))
+            && !guard[address.shard].contains_key(address)
+        {
+            let mut ban_backoff = self.ban_backoff.lock();
+            let backoff = ban_backoff
+                .entry(address.clone())
+                .or_insert_with(|| BanBackoff {
+                    bans: 0,
+                    ban_time: self.settings.ban_time,
+                    banned_at: now,
+                });
+
+            // The address was healthy for long enough since its last ban, start over.
+            let healthy_time = now.timestamp() - backoff.banned_at.timestamp() - backoff.ban_time;
+            if healthy_time > self.settings.max_ban_time {
+                backoff.bans = 0;
+            }
+
+            backoff.bans += 1;
+            backoff.ban_time = backoff_ban_time(
+                self.settings.ban_time,
+                self.settings.max_ban_time,
+                backoff.bans,
+            );
+            backoff.banned_at = now;
+
+            warn!(
+                "{:?} was banned {} times in a row, banning it for {}s",
+                address, backoff.bans, backoff.ban_time
+            );
+        }
+
         guard[address.shard].insert(address.clone(), (reason, now));
     }
 
+    /// How long the address is banned for when it fails, in seconds.
+    /// With `ban_backoff`, it's the duration of its current or last ban.
+    pub fn ban_time(&self, address: &Address) -> i64 {
+        if self.settings.ban_backoff {
+            if let Some(backoff) = self.ban_backoff.lock().get(address) {
+                return backoff.ban_time;
+            }
+        }
+
+        self.settings.ban_time
+    }
+
     /// Clear the replica to receive traffic again. Takes effect immediately
     /// for all new transactions.
     pub fn unban(&self, address: &Address) {
@@ -958,31 +1838,79 @@ impl ConnectionPool {
         }
     }
 
-    /// Determines trying to unban this server was successful
-    pub async fn try_unban(&self, address: &Address) -> bool {
-        // If somehow primary ends up being banned we should return true here
-        if address.role == Role::Primary {
-            return true;
-        }
+    /// At least one server isn't banned and didn't fail since its last successful checkout,
+    /// so the pool can serve queries.
+    pub fn has_available_server(&self) -> bool {
+        (0..self.shards()).any(|shard| {
+            (0..self.servers(shard)).any(|server| {
+                let address = self.address(shard, server);
+                address.error_count() == 0 && !self.is_banned(address)
+            })
+        })
+    }
 
-        // Check if all replicas are banned, in that case unban all of them
-        let replicas_available = self.addresses[address.shard]
-            .iter()
-            .filter(|addr| addr.role == Role::Replica)
-            .count();
+    /// Seconds left until the ban on this address expires, if it's banned.
+    pub fn ban_remaining(&self, address: &Address) -> Option<i64> {
+        let guard = self.banlist.read();
+        let (ban_reason, timestamp) = guard[address.shard].get(address)?;
 
-        debug!("Available targets: {}", replicas_available);
+        let ban_duration = match ban_reason {
+            BanReason::AdminBan(duration) => *duration,
+            _ => self.ban_time(address),
+        };
+        let now = chrono::offset::Utc::now().naive_utc();
 
-        let read_guard = self.banlist.read();
-        let all_replicas_banned = read_guard[address.shard].len() == replicas_available;
-        drop(read_guard);
+        Some((ban_duration - (now.timestamp() - timestamp.timestamp())).max(0))
+    }
 
-        if all_replicas_banned {
-            let mut write_guard = self.banlist.write();
-            warn!("Unbanning all replicas.");
-            write_guard[address.shard].clear();
+    /// Determines trying to unban this server was successful
+    pub async fn try_unban(&self, address: &Address) -> bool {
+        if address.role == Role::Primary {
+            if self.primary_count(address.shard) > 1 {
+                // Don't leave the shard without a primary to write to.
+                let read_guard = self.banlist.read();
+                let all_primaries_banned = self.addresses[address.shard]
+                    .iter()
+                    .filter(|addr| addr.role == Role::Primary)
+                    .all(|addr| read_guard[address.shard].contains_key(addr));
+                drop(read_guard);
+
+                if all_primaries_banned {
+                    let mut write_guard = self.banlist.write();
+                    warn!("Unbanning all primaries.");
+                    write_guard[address.shard].retain(|addr, _| addr.role != Role::Primary);
 
-            return true;
+                    return true;
+                }
+            } else if !self.auto_failover(address.shard) {
+                // If somehow primary ends up being banned we should return true here,
+                // unless it was banned to fail over to a replica.
+                return true;
+            }
+        } else {
+            // Check if all replicas are banned, in that case unban all of them
+            let replicas_available = self.addresses[address.shard]
+                .iter()
+                .filter(|addr| addr.role == Role::Replica)
+                .count();
+
+            debug!("Available targets: {}", replicas_available);
+
+            let read_guard = self.banlist.read();
+            let all_replicas_banned = read_guard[address.shard]
+                .keys()
+                .filter(|addr| addr.role == Role::Replica)
+                .count()
+                == replicas_available;
+            drop(read_guard);
+
+            if all_replicas_banned {
+                let mut write_guard = self.banlist.write();
+                warn!("Unbanning all replicas.");
+                write_guard[address.shard].retain(|addr, _| addr.role != Role::Replica);
+
+                return true;
+            }
         }
 
         // Check if ban time is expired
@@ -994,7 +1922,7 @@ impl ConnectionPool {
                     BanReason::AdminBan(duration) => {
                         now.timestamp() - timestamp.timestamp() > *duration
                     }
-                    _ => now.timestamp() - timestamp.timestamp() > self.settings.ban_time,
+                    _ => now.timestamp() - timestamp.timestamp() > self.ban_time(address),
                 }
             }
             None => return true,
@@ -1014,6 +1942,100 @@ impl ConnectionPool {
         }
     }
 
+    /// Number of primaries of the shard, more than one with `allow_multiple_primaries`.
+    fn primary_count(&self, shard: usize) -> usize {
+        self.addresses[shard]
+            .iter()
+            .filter(|address| address.role == Role::Primary)
+            .count()
+    }
+
+    /// Move the replicas in our zone last in the candidates, so they're tried first.
+    /// The order within each group is kept and the others are only tried if all local replicas fail.
+    fn prefer_local_zone(candidates: &mut [&Address], local_zone: &str) {
+        candidates.sort_by_key(|address| {
+            address.role == Role::Replica && address.zone.as_deref() == Some(local_zone)
+        });
+    }
+
+    /// Put the primary that should take the next write last in the candidates,
+    /// the others are kept in case it's unavailable.
+    fn select_primary(&self, candidates: &mut Vec<&Address>) {
+        let selected = match self.settings.primary_selection {
+            PrimarySelection::RoundRobin => {
+                candidates.sort_by_key(|address| address.id);
+                self.primary_round_robin.fetch_add(1, Ordering::Relaxed) % candidates.len()
+            }
+
+            PrimarySelection::Weighted => {
+                let total: u32 = candidates.iter().map(|address| address.weight).sum();
+                let mut pick = thread_rng().gen_range(0..total.max(1));
+
+                candidates
+                    .iter()
+                    .position(|address| {
+                        if pick < address.weight {
+                            return true;
+                        }
+                        pick -= address.weight;
+                        false
+                    })
+                    .unwrap_or(0)
+            }
+        };
+
+        let primary = candidates.remove(selected);
+        candidates.push(primary);
+    }
+
+    /// Automatic failover to a replica is enabled for the shard.
+    fn auto_failover(&self, shard: usize) -> bool {
+        match self.settings.shard_failover_priority.get(shard) {
+            Some(failover_priority) => !failover_priority.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Replica receiving the primary's traffic while the primary of the shard is banned:
+    /// the first one in failover priority order that is not banned itself.
+    pub fn promoted_replica(&self, shard: usize) -> Option<&Address> {
+        let primary = self.addresses[shard]
+            .iter()
+            .find(|address| address.role == Role::Primary)?;
+
+        if !self.is_banned(primary) {
+            return None;
+        }
+
+        self.settings
+            .shard_failover_priority
+            .get(shard)?
+            .iter()
+            .map(|index| &self.addresses[shard][*index])
+            .find(|replica| !self.is_banned(replica))
+    }
+
+    /// Password hash obtained with auth_query, unless it's older than `auth_query_cache_ttl`
+    /// and should be fetched again.
+    pub fn cached_auth_hash(&self) -> Option<String> {
+        let ttl = self.settings.auth_query_cache_ttl;
+
+        if ttl > 0 {
+            match *self.auth_hash_fetched_at.read() {
+                Some(fetched_at) if fetched_at.elapsed().as_millis() < ttl as u128 => (),
+                _ => return None,
+            }
+        }
+
+        (*self.auth_hash.read()).clone()
+    }
+
+    /// Save a password hash obtained with auth_query.
+    pub fn set_auth_hash(&self, hash: String) {
+        *self.auth_hash.write() = Some(hash);
+        *self.auth_hash_fetched_at.write() = Some(Instant::now());
+    }
+
     /// Get the number of configured shards.
     pub fn shards(&self) -> usize {
         self.databases.len()
@@ -1030,13 +2052,14 @@ impl ConnectionPool {
         bans
     }
 
-    /// Get the address from the host url
+    /// Get the addresses matching a host url or an address name,
+    /// e.g. `sharded_db_shard_0_replica_0`.
     pub fn get_addresses_from_host(&self, host: &str) -> Vec<Address> {
         let mut addresses = Vec::new();
         for shard in 0..self.shards() {
             for server in 0..self.servers(shard) {
                 let address = self.address(shard, server);
-                if address.host == host {
+                if address.host == host || address.name() == host {
                     addresses.push(address.clone());
                 }
             }
@@ -1059,11 +2082,22 @@ impl ConnectionPool {
         databases
     }
 
+    /// Maximum number of server connections to each server of the shard.
+    pub fn pool_size(&self, shard: usize) -> u32 {
+        self.settings.shard_pool_sizes[shard]
+    }
+
     /// Get pool state for a particular shard server as reported by bb8.
     pub fn pool_state(&self, shard: usize, server: usize) -> bb8::State {
         self.databases[shard][server].state()
     }
 
+    /// All connections to the server are open and checked out.
+    fn exhausted(&self, address: &Address) -> bool {
+        let state = self.pool_state(address.shard, address.address_index);
+        state.idle_connections == 0 && state.connections >= self.pool_size(address.shard)
+    }
+
     /// Get the address information for a shard server.
     pub fn address(&self, shard: usize, server: usize) -> &Address {
         &self.addresses[shard][server]
@@ -1074,16 +2108,9 @@ impl ConnectionPool {
     }
 
     /// Get the number of checked out connection for an address
-    fn busy_connection_count(&self, address: &Address) -> u32 {
-        let state = self.pool_state(address.shard, address.address_index);
-        let idle = state.idle_connections;
-        let provisioned = state.connections;
-
-        if idle > provisioned {
-            // Unlikely but avoids an overflow panic if this ever happens
-            return 0;
-        }
-        let busy = provisioned - idle;
+    /// Number of server connections to the address currently checked out by clients.
+    pub fn busy_connection_count(&self, address: &Address) -> u64 {
+        let busy = address.stats.in_flight();
         debug!("{:?} has {:?} busy connections", address, busy);
         busy
     }
@@ -1185,6 +2212,12 @@ impl ManageConnection for ServerPool {
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
         info!("Creating a new server connection {:?}", self.address);
 
+        // The server rejected our credentials, they won't work any better until the ban time passes.
+        let ban_time = Duration::from_secs(get_config().general.ban_time as u64);
+        if let Some(err @ Error::ServerAuthError(..)) = self.address.login_error(ban_time) {
+            return Err(err);
+        }
+
         let stats = Arc::new(ServerStats::new(
             self.address.clone(),
             tokio::time::Instant::now(),
@@ -1203,6 +2236,7 @@ impl ManageConnection for ServerPool {
             self.cleanup_connections,
             self.log_client_parameter_status_changes,
             self.prepared_statement_cache_size,
+            None,
         )
         .await
         {
@@ -1219,10 +2253,16 @@ impl ManageConnection for ServerPool {
                     }
                 }
 
+                self.address.set_login_error(None);
                 stats.idle();
                 Ok(conn)
             }
             Err(err) => {
+                if let Error::ServerAuthError(..) | Error::ServerConnectionLimit(..) = err {
+                    self.address.set_login_error(Some(err.clone()));
+                }
+
+                self.address.stats.server_connect_error();
                 stats.disconnect();
                 Err(err)
             }
@@ -1236,7 +2276,19 @@ impl ManageConnection for ServerPool {
 
     /// Synchronously determine if the connection is no longer usable, if possible.
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.is_bad()
+        if conn.is_bad() {
+            return true;
+        }
+
+        // Given back in the middle of a response, a COPY or a transaction, e.g. because
+        // the client went away while we were talking to it: it can't be used by another client.
+        // Closing it makes Postgres roll back what it was doing.
+        if conn.is_data_available() || conn.in_copy_mode() || conn.in_transaction() {
+            conn.mark_bad("returned to the pool in the middle of a query or transaction");
+            return true;
+        }
+
+        false
     }
 }
 
@@ -1251,3 +2303,112 @@ pub fn get_pool(db: &str, user: &str) -> Option<ConnectionPool> {
 pub fn get_all_pools() -> HashMap<PoolIdentifier, ConnectionPool> {
     (*(*POOLS.load())).clone()
 }
+
+/// Mark all pools as draining. New clients are refused and
+/// connected clients are disconnected once their transaction completes.
+pub fn start_draining() {
+    DRAINING.store(true, Ordering::Relaxed);
+}
+
+/// Check if PgCat is draining clients before shutting down.
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Drop all pools, closing their idle server connections
+/// with a Terminate message.
+pub fn close_all_pools() {
+    POOLS.store(Arc::new(HashMap::default()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefer_local_zone() {
+        let replica = |id, zone: &str| Address {
+            id,
+            role: Role::Replica,
+            zone: Some(zone.to_string()),
+            ..Default::default()
+        };
+        let primary = Address {
+            id: 0,
+            role: Role::Primary,
+            zone: Some("a".to_string()),
+            ..Default::default()
+        };
+        let (local, remote, other_local) = (replica(1, "a"), replica(2, "b"), replica(3, "a"));
+
+        let mut candidates = vec![&local, &remote, &other_local, &primary];
+        ConnectionPool::prefer_local_zone(&mut candidates, "a");
+
+        // Candidates are popped from the end.
+        let ids: Vec<usize> = candidates.iter().map(|address| address.id).collect();
+        assert_eq!(ids, vec![2, 0, 1, 3]);
+    }
+
+    #[test]
+    fn test_select_primary() {
+        let primary = |id, weight| Address {
+            id,
+            role: Role::Primary,
+            weight,
+            ..Default::default()
+        };
+        let primaries = [primary(0, 1), primary(1, 0)];
+
+        let mut pool = ConnectionPool::default();
+        let mut selected = Vec::new();
+        for _ in 0..4 {
+            let mut candidates = vec![&primaries[1], &primaries[0]];
+            pool.select_primary(&mut candidates);
+            selected.push(candidates.last().unwrap().id);
+        }
+        assert_eq!(selected, vec![0, 1, 0, 1]);
+
+        pool.settings = Arc::new(PoolSettings {
+            primary_selection: PrimarySelection::Weighted,
+            ..Default::default()
+        });
+        for _ in 0..10 {
+            let mut candidates = vec![&primaries[0], &primaries[1]];
+            pool.select_primary(&mut candidates);
+            assert_eq!(candidates.last().unwrap().id, 0);
+        }
+    }
+
+    #[test]
+    fn test_backoff_ban_time() {
+        let ban_times: Vec<i64> = (1..=6)
+            .map(|bans| backoff_ban_time(60, 600, bans))
+            .collect();
+        assert_eq!(ban_times, vec![60, 120, 240, 480, 600, 600]);
+
+        // Doesn't overflow with lots of bans.
+        assert_eq!(backoff_ban_time(60, 600, 1000), 600);
+    }
+
+    #[test]
+    fn test_register_client() {
+        let pool = ConnectionPool {
+            settings: Arc::new(PoolSettings {
+                user: User {
+                    max_client_connections: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let first = pool.register_client().unwrap();
+        let _second = pool.register_client().unwrap();
+        assert!(pool.register_client().is_none());
+
+        // The slot is freed when the client disconnects.
+        drop(first);
+        assert!(pool.register_client().is_some());
+    }
+}
@@ -14,7 +14,7 @@ use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use tokio::net::TcpListener;
 
-use crate::config::Address;
+use crate::config::{get_reload_status, Address};
 use crate::pool::{get_all_pools, PoolIdentifier};
 use crate::stats::get_server_stats;
 use crate::stats::pool::PoolStats;
@@ -68,6 +68,18 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "Total time client waited for a server connection",
         ty: "counter",
     },
+    "stats_total_server_connect_errors" => MetricHelpType {
+        help: "Number of failed attempts to connect to the server",
+        ty: "counter",
+    },
+    "stats_total_query_errors" => MetricHelpType {
+        help: "Number of errors returned by the server to clients",
+        ty: "counter",
+    },
+    "stats_total_pool_timeouts" => MetricHelpType {
+        help: "Number of times a client timed out waiting for a server connection",
+        ty: "counter",
+    },
     "stats_avg_query_count" => MetricHelpType {
         help: "Average of total_query_count every 15 seconds",
         ty: "gauge",
@@ -136,6 +148,14 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "How many server connections are currently waiting on a health check to succeed",
         ty: "gauge",
     },
+    "pools_sv_recycled" => MetricHelpType {
+        help: "How many server connections were closed for exceeding idle_timeout or server_lifetime",
+        ty: "counter",
+    },
+    "pools_uptime" => MetricHelpType {
+        help: "Seconds since the pool was created",
+        ty: "gauge",
+    },
     "servers_bytes_received" => MetricHelpType {
         help: "Volume in bytes of network traffic received by server",
         ty: "counter",
@@ -299,11 +319,12 @@ impl<Value: fmt::Display> PrometheusMetric<Value> {
     }
 }
 
-async fn prometheus_stats(
+async fn http_handler(
     request: Request<body::Incoming>,
+    metrics: bool,
 ) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
     match (request.method(), request.uri().path()) {
-        (&Method::GET, "/metrics") => {
+        (&Method::GET, "/metrics") if metrics => {
             let mut lines = Vec::new();
             push_address_stats(&mut lines);
             push_pool_stats(&mut lines);
@@ -315,12 +336,37 @@ async fn prometheus_stats(
                 .header("content-type", "text/plain; version=0.0.4")
                 .body(lines.join("\n").into())
         }
+        // The process is up and serving requests.
+        (&Method::GET, "/health") => Response::builder().body("OK\n".into()),
+        (&Method::GET, "/ready") => match readiness() {
+            Ok(()) => Response::builder().body("OK\n".into()),
+            Err(reason) => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(format!("{}\n", reason).into()),
+        },
         _ => Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body("".into()),
     }
 }
 
+/// Ready to take traffic: the last config reload succeeded and at least one pool
+/// has a server that isn't banned or failing.
+fn readiness() -> Result<(), &'static str> {
+    if get_reload_status().error.is_some() {
+        return Err("last configuration reload failed");
+    }
+
+    if !get_all_pools()
+        .values()
+        .any(|pool| pool.has_available_server())
+    {
+        return Err("no pool has an available server");
+    }
+
+    Ok(())
+}
+
 // Adds metrics shown in a SHOW STATS admin command.
 fn push_address_stats(lines: &mut Vec<String>) {
     let mut grouped_metrics: HashMap<String, Vec<PrometheusMetric<u64>>> = HashMap::new();
@@ -386,13 +432,12 @@ fn push_pool_stats(lines: &mut Vec<String>) {
 fn push_database_stats(lines: &mut Vec<String>) {
     let mut grouped_metrics: HashMap<String, Vec<PrometheusMetric<u32>>> = HashMap::new();
     for (_, pool) in get_all_pools() {
-        let pool_config = pool.settings.clone();
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
                 let address = pool.address(shard, server);
                 let pool_state = pool.pool_state(shard, server);
                 let metrics = vec![
-                    ("pool_size", pool_config.user.pool_size),
+                    ("pool_size", pool.pool_size(shard)),
                     ("current_connections", pool_state.connections),
                 ];
                 for (key, value) in metrics {
@@ -495,18 +540,25 @@ fn push_server_stats(lines: &mut Vec<String>) {
     }
 }
 
-pub async fn start_metric_server(http_addr: SocketAddr) {
+/// Serve `/health` and `/ready`, and the prometheus metrics on `/metrics` if `metrics` is set.
+pub async fn start_http_server(http_addr: SocketAddr, metrics: bool) {
     let listener = TcpListener::bind(http_addr);
     let listener = match listener.await {
         Ok(listener) => listener,
         Err(e) => {
-            error!("Failed to bind prometheus server to HTTP address: {}.", e);
+            error!("Failed to bind HTTP server to HTTP address: {}.", e);
             return;
         }
     };
+    if metrics {
+        info!(
+            "Exposing prometheus metrics on http://{}/metrics.",
+            http_addr
+        );
+    }
     info!(
-        "Exposing prometheus metrics on http://{}/metrics.",
-        http_addr
+        "Exposing health checks on http://{}/health and http://{}/ready.",
+        http_addr, http_addr
     );
     loop {
         let stream = match listener.accept().await {
@@ -520,10 +572,13 @@ pub async fn start_metric_server(http_addr: SocketAddr) {
 
         tokio::task::spawn(async move {
             if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(prometheus_stats))
+                .serve_connection(
+                    io,
+                    service_fn(move |request| http_handler(request, metrics)),
+                )
                 .await
             {
-                eprintln!("Error serving HTTP connection for metrics: {:?}", err);
+                eprintln!("Error serving HTTP connection: {:?}", err);
             }
         });
     }
@@ -7,14 +7,14 @@ use once_cell::sync::OnceCell;
 use regex::{Regex, RegexSet};
 use sqlparser::ast::Statement::{Delete, Insert, Query, StartTransaction, Update};
 use sqlparser::ast::{
-    Assignment, BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, SetExpr, Statement,
-    TableFactor, TableWithJoins, Value,
+    Assignment, BinaryOperator, Expr, FromTable, Ident, JoinConstraint, JoinOperator, SetExpr,
+    Statement, TableFactor, TableWithJoins, TransactionAccessMode, TransactionMode, Value,
 };
 use sqlparser::dialect::PostgreSqlDialect;
 use sqlparser::parser::Parser;
 use std::sync::OnceLock;
 
-use crate::config::Role;
+use crate::config::{DefaultShard, Role};
 use crate::errors::Error;
 use crate::messages::BytesMutReader;
 use crate::plugins::{Intercept, Plugin, PluginOutput, QueryLogger, TableAccess};
@@ -28,7 +28,7 @@ use std::{cmp, mem};
 
 /// Regexes used to parse custom commands.
 const CUSTOM_SQL_REGEXES: [&str; 7] = [
-    r"(?i)^ *SET SHARDING KEY TO '?([0-9]+)'? *;? *$",
+    r"(?i)^ *SET SHARDING KEY TO '?(-?[0-9]+)'? *;? *$",
     r"(?i)^ *SET SHARD TO '?([0-9]+|ANY)'? *;? *$",
     r"(?i)^ *SHOW SHARD *;? *$",
     r"(?i)^ *SET SERVER ROLE TO '(PRIMARY|REPLICA|ANY|AUTO|DEFAULT)' *;? *$",
@@ -55,6 +55,15 @@ pub enum ShardingKey {
     Placeholder(i16),
 }
 
+/// Session-level `SET`, `RESET` or `DISCARD ALL`, see `track_set_statements`.
+#[derive(PartialEq, Debug)]
+pub enum SetStatement {
+    /// Parameter name and its value, as written in the query.
+    Set(String, String),
+    Reset(String),
+    ResetAll,
+}
+
 #[derive(Clone, Debug)]
 enum ParameterFormat {
     Text,
@@ -100,6 +109,18 @@ pub struct QueryRouter {
 
     // Placeholders from prepared statement.
     placeholders: Vec<i16>,
+
+    /// Shard requested by a comment hint, for the current statement only.
+    hinted_shard: Option<usize>,
+
+    /// Role requested by a comment hint, for the current statement only.
+    hinted_role: Option<Role>,
+
+    /// Role requested by the client with `target_session_attrs`, for the whole session.
+    session_role: Option<Role>,
+
+    /// Use the primary when no replica is available, `target_session_attrs=prefer-standby`.
+    prefer_standby: bool,
 }
 
 struct ExtractedExprsAndTables<'a> {
@@ -145,6 +166,10 @@ impl QueryRouter {
             primary_reads_enabled: None,
             pool_settings: PoolSettings::default(),
             placeholders: Vec::new(),
+            hinted_shard: None,
+            hinted_role: None,
+            session_role: None,
+            prefer_standby: false,
         }
     }
 
@@ -157,6 +182,17 @@ impl QueryRouter {
         &self.pool_settings
     }
 
+    /// Sharder configured with the pool's sharding settings.
+    fn sharder(&self) -> Sharder {
+        Sharder::new(
+            self.pool_settings.shards,
+            self.pool_settings.sharding_function,
+        )
+        .ketama_vnodes(self.pool_settings.ketama_vnodes)
+        .range_starts(self.pool_settings.shard_range_starts.clone())
+        .cache(self.pool_settings.shard_cache.clone())
+    }
+
     /// Try to parse a command and execute it.
     pub fn try_execute_command(&mut self, message_buffer: &BytesMut) -> Option<(Command, String)> {
         let mut message_cursor = Cursor::new(message_buffer);
@@ -196,17 +232,15 @@ impl QueryRouter {
 
                     // Check for a sharding_key included in the query
                     if let Some(sharding_key_regex) = &self.pool_settings.sharding_key_regex {
-                        let sharding_key =
-                            sharding_key_regex
-                                .captures(&initial_segment)
-                                .and_then(|cap| {
-                                    cap.get(1).and_then(|id| id.as_str().parse::<i64>().ok())
-                                });
+                        let sharding_key = sharding_key_regex
+                            .captures(&initial_segment)
+                            .and_then(|cap| cap.get(1));
                         if let Some(sharding_key) = sharding_key {
-                            debug!("Setting sharding_key to {:?}", sharding_key);
-                            self.set_sharding_key(sharding_key);
-                            // Skip other command processing since a sharding command was found
-                            return None;
+                            if self.set_sharding_key(sharding_key.as_str()).is_some() {
+                                debug!("Setting sharding_key to {:?}", sharding_key.as_str());
+                                // Skip other command processing since a sharding command was found
+                                return None;
+                            }
                         }
                     }
                 }
@@ -273,7 +307,7 @@ impl QueryRouter {
             Command::ShowShard => self
                 .shard()
                 .map_or_else(|| "unset".to_string(), |x| x.to_string()),
-            Command::ShowServerRole => match self.active_role {
+            Command::ShowServerRole => match self.session_role.or(self.active_role) {
                 Some(Role::Primary) => Role::Primary.to_string(),
                 Some(Role::Replica) => Role::Replica.to_string(),
                 Some(Role::Mirror) => Role::Mirror.to_string(),
@@ -294,21 +328,27 @@ impl QueryRouter {
 
         match command {
             Command::SetShardingKey => {
-                // TODO: some error handling here
-                value = self
-                    .set_sharding_key(value.parse::<i64>().unwrap())
-                    .unwrap()
-                    .to_string();
+                // Keys that don't fit in a bigint are left for the server to reject.
+                value = self.set_sharding_key(&value)?.to_string();
             }
 
             Command::SetShard => {
                 self.active_shard = match value.to_ascii_uppercase().as_ref() {
-                    "ANY" => Some(rand::random::<usize>() % self.pool_settings.shards),
-                    _ => Some(value.parse::<usize>().unwrap()),
+                    "ANY" => match self.pool_settings.user.allowed_shards {
+                        Some(ref allowed_shards) => {
+                            Some(allowed_shards[rand::random::<usize>() % allowed_shards.len()])
+                        }
+                        None => Some(rand::random::<usize>() % self.pool_settings.shards),
+                    },
+                    _ => Some(value.parse::<usize>().ok()?),
                 };
             }
 
             Command::SetServerRole => {
+                // The client changed its mind about target_session_attrs.
+                self.session_role = None;
+                self.prefer_standby = false;
+
                 self.active_role = match value.to_ascii_lowercase().as_ref() {
                     "primary" => {
                         self.query_parser_enabled = Some(false);
@@ -359,6 +399,268 @@ impl QueryRouter {
         Some((command, value))
     }
 
+    /// Check if the query is a LISTEN statement, which only works
+    /// if the client keeps the same server connection (session mode).
+    pub fn is_listen(message: &BytesMut) -> bool {
+        let mut message_cursor = Cursor::new(message);
+
+        if message_cursor.get_u8() as char != 'Q' {
+            return false;
+        }
+
+        let _len = message_cursor.get_i32();
+
+        let query = match message_cursor.read_string() {
+            Ok(query) => query,
+            Err(_) => return false,
+        };
+
+        let mut words = query.split_whitespace();
+
+        match words.next() {
+            Some(word) => word.eq_ignore_ascii_case("listen") && words.next().is_some(),
+            None => false,
+        }
+    }
+
+    /// Whether the statement changes the schema or is a utility statement like `VACUUM`,
+    /// judging by its first keyword. Used for statements the parser doesn't support.
+    fn is_utility_statement(query: &str) -> bool {
+        const UTILITY_KEYWORDS: [&str; 19] = [
+            "create",
+            "alter",
+            "drop",
+            "truncate",
+            "comment",
+            "grant",
+            "revoke",
+            "vacuum",
+            "analyze",
+            "analyse",
+            "refresh",
+            "reindex",
+            "cluster",
+            "checkpoint",
+            "import",
+            "security",
+            "do",
+            "call",
+            "lock",
+        ];
+
+        match Self::skip_comments(query)
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .next()
+        {
+            Some(word) => UTILITY_KEYWORDS
+                .iter()
+                .any(|keyword| word.eq_ignore_ascii_case(keyword)),
+            None => false,
+        }
+    }
+
+    /// The query without its leading whitespace, comments and parentheses.
+    fn skip_comments(mut query: &str) -> &str {
+        loop {
+            query = query.trim_start_matches(|c: char| c.is_whitespace() || c == '(');
+
+            if let Some(rest) = query.strip_prefix("--") {
+                query = rest.split_once('\n').map_or("", |(_, rest)| rest);
+            } else if query.starts_with("/*") {
+                // Block comments nest in Postgres.
+                let mut depth = 0;
+                let mut end = query.len();
+
+                for (i, _) in query.char_indices() {
+                    if query[i..].starts_with("/*") {
+                        depth += 1;
+                    } else if query[i..].starts_with("*/") && i > 0 {
+                        depth -= 1;
+
+                        if depth == 0 {
+                            end = i + 2;
+                            break;
+                        }
+                    }
+                }
+
+                query = &query[end..];
+            } else {
+                return query;
+            }
+        }
+    }
+
+    /// The session-level `SET` or `RESET` sent in a simple query, if that's all the query does.
+    /// `SET LOCAL`, `SET TRANSACTION` and multi-statement queries aren't tracked.
+    pub fn set_statement(message: &BytesMut) -> Option<SetStatement> {
+        let mut message_cursor = Cursor::new(message);
+
+        if message_cursor.get_u8() as char != 'Q' {
+            return None;
+        }
+
+        let _len = message_cursor.get_i32();
+        let query = message_cursor.read_string().ok()?;
+
+        Self::parse_set_statement(&query)
+    }
+
+    fn parse_set_statement(query: &str) -> Option<SetStatement> {
+        // Split the next keyword or parameter name, e.g. `search_path` or `myapp.user_id`.
+        fn next_word(query: &str) -> Option<(String, &str)> {
+            let query = query.trim_start();
+            let end = query
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(query.len());
+
+            match end {
+                0 => None,
+                _ => Some((query[..end].to_lowercase(), &query[end..])),
+            }
+        }
+
+        let query = query.trim().trim_end_matches(';').trim_end();
+
+        if query.contains(';') {
+            return None;
+        }
+
+        let (command, rest) = next_word(query)?;
+
+        match command.as_str() {
+            "set" => {
+                let (mut name, mut rest) = next_word(rest)?;
+
+                if name == "session" {
+                    (name, rest) = next_word(rest)?;
+                }
+
+                let value = match name.as_str() {
+                    "local" | "transaction" => return None,
+
+                    "time" => {
+                        let (zone, value) = next_word(rest)?;
+                        if zone != "zone" {
+                            return None;
+                        }
+                        name = "timezone".to_string();
+                        value
+                    }
+
+                    _ => match rest.trim_start().strip_prefix('=') {
+                        Some(value) => value,
+                        None => match next_word(rest)? {
+                            (to, value) if to == "to" => value,
+                            _ => return None,
+                        },
+                    },
+                };
+
+                match value.trim() {
+                    "" => None,
+                    value if value.eq_ignore_ascii_case("default") => {
+                        Some(SetStatement::Reset(name))
+                    }
+                    value => Some(SetStatement::Set(name, value.to_string())),
+                }
+            }
+
+            "reset" => match next_word(rest)? {
+                (name, _) if name == "all" => Some(SetStatement::ResetAll),
+                (name, rest) if rest.trim().is_empty() => Some(SetStatement::Reset(name)),
+                _ => None,
+            },
+
+            "discard" => match next_word(rest)? {
+                (all, _) if all == "all" => Some(SetStatement::ResetAll),
+                _ => None,
+            },
+
+            _ => None,
+        }
+    }
+
+    /// Whether the statement changes the schema, e.g. `CREATE TABLE` or `GRANT`,
+    /// or runs a procedure, which can.
+    fn is_ddl(statement: &Statement) -> bool {
+        use sqlparser::ast::Statement::*;
+
+        matches!(
+            statement,
+            CreateTable(_)
+                | CreateView { .. }
+                | CreateVirtualTable { .. }
+                | CreateIndex(_)
+                | CreateRole { .. }
+                | CreatePolicy { .. }
+                | CreateExtension { .. }
+                | CreateSchema { .. }
+                | CreateDatabase { .. }
+                | CreateFunction { .. }
+                | CreateTrigger { .. }
+                | CreateProcedure { .. }
+                | CreateSequence { .. }
+                | CreateType { .. }
+                | AlterTable { .. }
+                | AlterIndex { .. }
+                | AlterView { .. }
+                | AlterRole { .. }
+                | AlterPolicy { .. }
+                | Drop { .. }
+                | DropFunction { .. }
+                | DropProcedure { .. }
+                | DropPolicy { .. }
+                | DropTrigger { .. }
+                | Truncate { .. }
+                | Comment { .. }
+                | Grant { .. }
+                | Revoke { .. }
+                | Analyze { .. }
+                | Call(_)
+        )
+    }
+
+    fn ddl_blocked_error() -> Error {
+        Error::QueryRouterError("DDL statements are not allowed on this pool (block_ddl)".into())
+    }
+
+    /// Route a query the parser couldn't parse. Utility statements like `VACUUM` or
+    /// `REFRESH MATERIALIZED VIEW` aren't supported by the parser, but they have to go to the primary.
+    /// With `block_ddl`, these queries are rejected, since what they do can't be known for sure.
+    pub fn infer_unparsed(&mut self, message: &BytesMut) -> Result<(), Error> {
+        let mut message_cursor = Cursor::new(message);
+
+        let query = match message_cursor.get_u8() as char {
+            'Q' => {
+                let _len = message_cursor.get_i32();
+                message_cursor.read_string()
+            }
+            'P' => {
+                let _len = message_cursor.get_i32();
+                let _name = message_cursor.read_string();
+                message_cursor.read_string()
+            }
+            _ => return Ok(()),
+        };
+
+        match query {
+            Ok(_) if self.pool_settings.block_ddl => Err(Error::QueryRouterError(
+                "Statements the query parser doesn't support are not allowed on this pool (block_ddl)"
+                    .into(),
+            )),
+            Ok(query) if Self::is_utility_statement(&query) => {
+                if self.pool_settings.query_parser_read_write_splitting {
+                    debug!("Utility statement found, going to primary");
+                    self.active_role = Some(Role::Primary);
+                }
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn parse(&self, message: &BytesMut) -> Result<Vec<Statement>, Error> {
         let mut message_cursor = Cursor::new(message);
 
@@ -411,6 +713,17 @@ impl QueryRouter {
     fn is_mutation_query(q: &sqlparser::ast::Query) -> bool {
         use sqlparser::ast::*;
 
+        // Data-modifying statements in WITH, e.g. `WITH t AS (DELETE ...) SELECT ...`.
+        if let Some(with) = &q.with {
+            if with
+                .cte_tables
+                .iter()
+                .any(|cte| Self::is_mutation_query(&cte.query))
+            {
+                return true;
+            }
+        }
+
         match q.body.as_ref() {
             SetExpr::Insert(_) => true,
             SetExpr::Update(_) => true,
@@ -456,6 +769,23 @@ impl QueryRouter {
 
     /// Try to infer which server to connect to based on the contents of the query.
     pub fn infer(&mut self, ast: &Vec<sqlparser::ast::Statement>) -> Result<(), Error> {
+        if self.pool_settings.block_ddl && ast.iter().any(Self::is_ddl) {
+            return Err(Self::ddl_blocked_error());
+        }
+
+        // Catalog queries go to the same server whatever the session's shard is,
+        // so introspection gives the same results every time. Explicit hints still win.
+        if let Some(catalog_shard) = self.pool_settings.catalog_shard {
+            if self.hinted_shard.is_none() && self.is_catalog_query(ast) {
+                debug!("Catalog query, going to shard {}", catalog_shard);
+
+                self.hinted_shard = Some(catalog_shard);
+                if self.hinted_role.is_none() {
+                    self.hinted_role = self.pool_settings.catalog_role;
+                }
+            }
+        }
+
         if !self.pool_settings.query_parser_read_write_splitting {
             return Ok(()); // Nothing to do
         }
@@ -487,10 +817,25 @@ impl QueryRouter {
 
         for q in ast {
             match q {
-                // All transactions go to the primary, probably a write.
-                StartTransaction { .. } => {
-                    self.active_role = Some(Role::Primary);
-                    break;
+                // All transactions go to the primary, probably a write. The server is held
+                // until the transaction ends, so reads after a write see it.
+                StartTransaction { modes, .. } => {
+                    let read_only = modes.contains(&TransactionMode::AccessMode(
+                        TransactionAccessMode::ReadOnly,
+                    ));
+
+                    if self.pool_settings.sticky_primary_in_txn || !read_only {
+                        self.active_role = Some(Role::Primary);
+                        break;
+                    }
+
+                    // A read-only transaction can't write, a replica can serve it.
+                    if !visited_write_statement {
+                        self.active_role = match self.primary_reads_enabled() {
+                            false => Some(Role::Replica),
+                            true => None,
+                        };
+                    }
                 }
 
                 // Likely a read-only query
@@ -518,7 +863,7 @@ impl QueryRouter {
                             // or discard shard selection. If they point to the same shard though,
                             // we can let them through as-is.
                             // This is basically building a database now :)
-                            let inferred_shard = self.infer_shard(query);
+                            let inferred_shard = self.infer_shard(query)?;
                             self.handle_inferred_shard(inferred_shard, &mut prev_inferred_shard)?;
                         }
 
@@ -569,6 +914,48 @@ impl QueryRouter {
         Ok(())
     }
 
+    /// Read-only queries that only read `pg_catalog` and `information_schema`,
+    /// e.g. the ones psql runs for `\d`.
+    fn is_catalog_query(&self, ast: &[Statement]) -> bool {
+        !ast.is_empty()
+            && ast.iter().all(|statement| match statement {
+                Query(query) => {
+                    let table_names = self.table_names(query);
+
+                    query.locks.is_empty()
+                        && !Self::is_mutation_query(query)
+                        && !table_names.is_empty()
+                        && table_names.iter().all(|name| Self::is_catalog_table(name))
+                }
+                _ => false,
+            })
+    }
+
+    fn is_catalog_table(name: &[Ident]) -> bool {
+        if name.len() < 2 {
+            return false;
+        }
+
+        let schema = name[name.len() - 2].value.to_lowercase();
+        schema == "pg_catalog" || schema == "information_schema"
+    }
+
+    /// Whether the query should be sent to all shards: a single read-only `SELECT`,
+    /// when no shard was picked by the client or a sharding key.
+    pub fn is_scatter_gather(&self, ast: &[Statement]) -> bool {
+        if !self.pool_settings.enable_scatter_gather
+            || self.pool_settings.shards < 2
+            || self.shard().is_some()
+        {
+            return false;
+        }
+
+        match ast {
+            [Query(query)] => query.locks.is_empty() && !Self::is_mutation_query(query),
+            _ => false,
+        }
+    }
+
     fn handle_inferred_shard(
         &mut self,
         inferred_shard: Option<usize>,
@@ -638,13 +1025,15 @@ impl QueryRouter {
                 }
             }
             Delete(d) => {
-                if let Some(expr) = &d.selection {
-                    exprs.push(expr.clone());
-                }
-
                 // Multi-tables delete are not supported in postgres.
                 assert!(d.tables.is_empty());
 
+                match &d.from {
+                    FromTable::WithFromKeyword(tables) | FromTable::WithoutKeyword(tables) => {
+                        Self::process_tables_with_join(tables, &mut exprs, &mut table_names);
+                    }
+                }
+
                 if let Some(using_tbl_with_join) = &d.using {
                     Self::process_tables_with_join(
                         using_tbl_with_join,
@@ -689,7 +1078,7 @@ impl QueryRouter {
                 self.assignment_parser(assignments)?;
             }
 
-            Ok(self.infer_shard_from_exprs(exprs, table_names))
+            self.infer_shard_from_exprs(exprs, table_names)
         } else {
             Ok(None)
         }
@@ -842,108 +1231,143 @@ impl QueryRouter {
             return false;
         }
 
-        let sharder = Sharder::new(
-            self.pool_settings.shards,
-            self.pool_settings.sharding_function,
-        );
-
-        let mut shards = BTreeSet::new();
+        let sharder = self.sharder();
 
         let _portal = message_cursor.read_string();
         let _name = message_cursor.read_string();
 
+        let shards =
+            match Self::bind_parameter_shards(&mut message_cursor, &self.placeholders, &sharder) {
+                Some(shards) => shards,
+                None => {
+                    debug!("Malformed bind message, not routing on its parameters");
+                    BTreeSet::new()
+                }
+            };
+
+        self.placeholders.clear();
+        self.placeholders.shrink_to_fit();
+
+        // We only support querying one shard at a time.
+        // TODO: Support multi-shard queries some day.
+        if shards.len() == 1 {
+            debug!("Found one sharding key");
+            self.set_shard(Some(*shards.first().unwrap()));
+            true
+        } else {
+            debug!("Found no sharding keys");
+            false
+        }
+    }
+
+    /// Shards of the bind parameters used as sharding keys.
+    /// Returns `None` if the message is malformed.
+    fn bind_parameter_shards(
+        message_cursor: &mut Cursor<&BytesMut>,
+        placeholders: &[i16],
+        sharder: &Sharder,
+    ) -> Option<BTreeSet<usize>> {
+        let parse_format = |code: i16| match code {
+            0 => Some(ParameterFormat::Text),
+            1 => Some(ParameterFormat::Binary),
+            _ => None,
+        };
+
+        let mut shards = BTreeSet::new();
+
+        if message_cursor.remaining() < mem::size_of::<i16>() {
+            return None;
+        }
+
         let num_params = message_cursor.get_i16();
+        if num_params < 0 || message_cursor.remaining() < num_params as usize * 2 {
+            return None;
+        }
+
         let parameter_format = match num_params {
             0 => ParameterFormat::Text, // Text
-            1 => {
-                let param_format = message_cursor.get_i16();
-                ParameterFormat::Uniform(match param_format {
-                    0 => Box::new(ParameterFormat::Text),
-                    1 => Box::new(ParameterFormat::Binary),
-                    _ => unreachable!(),
-                })
-            }
+            1 => ParameterFormat::Uniform(Box::new(parse_format(message_cursor.get_i16())?)),
             n => {
                 let mut v = Vec::with_capacity(n as usize);
                 for _ in 0..n {
-                    let param_format = message_cursor.get_i16();
-                    v.push(match param_format {
-                        0 => ParameterFormat::Text,
-                        1 => ParameterFormat::Binary,
-                        _ => unreachable!(),
-                    });
+                    v.push(parse_format(message_cursor.get_i16())?);
                 }
                 ParameterFormat::Specified(v)
             }
         };
 
+        if message_cursor.remaining() < mem::size_of::<i16>() {
+            return None;
+        }
+
         let num_parameters = message_cursor.get_i16();
 
         for i in 0..num_parameters {
-            let mut len = message_cursor.get_i32() as usize;
-            let format = match &parameter_format {
-                ParameterFormat::Text => ParameterFormat::Text,
-                ParameterFormat::Uniform(format) => *format.clone(),
-                ParameterFormat::Specified(formats) => formats[i as usize].clone(),
-                _ => unreachable!(),
-            };
+            if message_cursor.remaining() < mem::size_of::<i32>() {
+                return None;
+            }
 
-            debug!("Parameter {} (len: {}): {:?}", i, len, format);
+            let len = message_cursor.get_i32();
+
+            // NULL has no value bytes.
+            if len < 0 {
+                continue;
+            }
+
+            let len = len as usize;
+            if message_cursor.remaining() < len {
+                return None;
+            }
+
+            let mut data = vec![0u8; len];
+            message_cursor.copy_to_slice(&mut data);
 
             // Postgres counts placeholders starting at 1
             let placeholder = i + 1;
 
-            if self.placeholders.contains(&placeholder) {
-                let value = match format {
-                    ParameterFormat::Text => {
-                        let mut value = String::new();
-                        while len > 0 {
-                            value.push(message_cursor.get_u8() as char);
-                            len -= 1;
-                        }
+            if !placeholders.contains(&placeholder) {
+                continue;
+            }
 
-                        match value.parse::<i64>() {
-                            Ok(value) => value,
-                            Err(_) => {
-                                debug!("Error parsing bind value: {}", value);
-                                continue;
-                            }
-                        }
-                    }
+            let format = match &parameter_format {
+                ParameterFormat::Uniform(format) => *format.clone(),
+                ParameterFormat::Specified(formats) => formats.get(i as usize)?.clone(),
+                _ => ParameterFormat::Text,
+            };
 
-                    ParameterFormat::Binary => match len {
-                        2 => message_cursor.get_i16() as i64,
-                        4 => message_cursor.get_i32() as i64,
-                        8 => message_cursor.get_i64(),
-                        _ => {
-                            error!(
-                                "Got wrong length for integer type parameter in bind: {}",
-                                len
-                            );
+            debug!("Parameter {} (len: {}): {:?}", i, len, format);
+
+            let value = match format {
+                ParameterFormat::Text => {
+                    let value = String::from_utf8_lossy(&data);
+
+                    match value.parse::<i64>() {
+                        Ok(value) => value,
+                        Err(_) => {
+                            debug!("Error parsing bind value: {}", value);
                             continue;
                         }
-                    },
+                    }
+                }
 
-                    _ => unreachable!(),
-                };
+                _ => match len {
+                    2 => i16::from_be_bytes([data[0], data[1]]) as i64,
+                    4 => i32::from_be_bytes(data[..4].try_into().unwrap()) as i64,
+                    8 => i64::from_be_bytes(data[..8].try_into().unwrap()),
+                    _ => {
+                        error!(
+                            "Got wrong length for integer type parameter in bind: {}",
+                            len
+                        );
+                        continue;
+                    }
+                },
+            };
 
-                shards.insert(sharder.shard(value));
-            }
+            shards.insert(sharder.shard(value));
         }
 
-        self.placeholders.clear();
-        self.placeholders.shrink_to_fit();
-
-        // We only support querying one shard at a time.
-        // TODO: Support multi-shard queries some day.
-        if shards.len() == 1 {
-            debug!("Found one sharding key");
-            self.set_shard(Some(*shards.first().unwrap()));
-            true
-        } else {
-            debug!("Found no sharding keys");
-            false
-        }
+        Some(shards)
     }
 
     /// An `assignments` exists in the `UPDATE` statements. This parses the assignments and makes
@@ -983,7 +1407,6 @@ impl QueryRouter {
     /// the clause and extracts the sharding key, if present.
     fn selection_parser(&self, expr: &Expr, table_names: &Vec<Vec<Ident>>) -> Vec<ShardingKey> {
         let mut result = Vec::new();
-        let mut found = false;
 
         let sharding_key = self
             .pool_settings
@@ -997,102 +1420,147 @@ impl QueryRouter {
         // Sharding key must be always fully qualified
         assert_eq!(sharding_key.len(), 2);
 
-        // This parses `sharding_key = 5`. But it's technically
-        // legal to write `5 = sharding_key`. I don't judge the people
-        // who do that, but I think ORMs will still use the first variant,
-        // so we can leave the second as a TODO.
-        if let Expr::BinaryOp { left, op, right } = expr {
-            match &**left {
-                Expr::BinaryOp { .. } => result.extend(self.selection_parser(left, table_names)),
-                Expr::Identifier(ident) => {
-                    // Only if we're dealing with only one table
-                    // and there is no ambiguity
-                    if ident.value.to_lowercase() == sharding_key[1].value {
-                        // Sharding key is unique enough, don't worry about
-                        // table names.
-                        if &sharding_key[0].value == "*" {
-                            found = true;
-                        } else if table_names.len() == 1 {
-                            let table = &table_names[0];
-
-                            if table.len() == 1 {
-                                // Table is not fully qualified, e.g.
-                                //      SELECT * FROM t WHERE sharding_key = 5
-                                // Make sure the table name from the sharding key matches
-                                // the table name from the query.
-                                found = sharding_key[0].value == table[0].value.to_lowercase();
-                            } else if table.len() == 2 {
-                                // Table name is fully qualified with the schema: e.g.
-                                //      SELECT * FROM public.t WHERE sharding_key = 5
-                                // Ignore the schema (TODO: at some point, we want schema support)
-                                // and use the table name only.
-                                found = sharding_key[0].value == table[1].value.to_lowercase();
-                            } else {
-                                debug!("Got table name with more than two idents, which is not possible");
-                            }
-                        }
-                    }
+        match expr {
+            // This parses `sharding_key = 5`. But it's technically
+            // legal to write `5 = sharding_key`. I don't judge the people
+            // who do that, but I think ORMs will still use the first variant,
+            // so we can leave the second as a TODO.
+            Expr::BinaryOp { left, op, right } => {
+                let found = Self::is_sharding_key(left, &sharding_key, table_names);
+
+                if Self::is_predicate(left) {
+                    result.extend(self.selection_parser(left, table_names));
                 }
 
-                Expr::CompoundIdentifier(idents) => {
-                    // The key is fully qualified in the query,
-                    // it will exist or Postgres will throw an error.
-                    if idents.len() == 2 {
-                        found = (&sharding_key[0].value == "*"
-                            || sharding_key[0].value == idents[0].value.to_lowercase())
-                            && sharding_key[1].value == idents[1].value.to_lowercase();
+                match op {
+                    BinaryOperator::Eq => (),
+                    BinaryOperator::Or => (),
+                    BinaryOperator::And => (),
+                    _ => {
+                        // TODO: support other operators than equality.
+                        debug!("Unsupported operation: {:?}", op);
+                        return Vec::new();
                     }
-                    // TODO: key can have schema as well, e.g. public.data.id (len == 3)
+                };
+
+                if Self::is_predicate(right) {
+                    result.extend(self.selection_parser(right, table_names));
+                } else if found {
+                    result.extend(Self::sharding_key_value(right));
                 }
-                _ => (),
-            };
+            }
 
-            match op {
-                BinaryOperator::Eq => (),
-                BinaryOperator::Or => (),
-                BinaryOperator::And => (),
-                _ => {
-                    // TODO: support other operators than equality.
-                    debug!("Unsupported operation: {:?}", op);
-                    return Vec::new();
+            // `sharding_key IN (1, 2, 3)`
+            Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } if Self::is_sharding_key(expr, &sharding_key, table_names) => {
+                result.extend(list.iter().filter_map(Self::sharding_key_value));
+            }
+
+            Expr::Nested(expr) => result.extend(self.selection_parser(expr, table_names)),
+
+            _ => (),
+        };
+
+        debug!("Sharding keys found: {:?}", result);
+
+        result
+    }
+
+    /// Expressions that can contain sharding key predicates.
+    fn is_predicate(expr: &Expr) -> bool {
+        matches!(
+            expr,
+            Expr::BinaryOp { .. } | Expr::InList { .. } | Expr::Nested(_)
+        )
+    }
+
+    /// Whether the column in the expression is the sharding key.
+    fn is_sharding_key(expr: &Expr, sharding_key: &[Ident], table_names: &[Vec<Ident>]) -> bool {
+        match expr {
+            Expr::Identifier(ident) => {
+                // Only if we're dealing with only one table
+                // and there is no ambiguity
+                if ident.value.to_lowercase() != sharding_key[1].value {
+                    return false;
                 }
-            };
 
-            match &**right {
-                Expr::BinaryOp { .. } => result.extend(self.selection_parser(right, table_names)),
-                Expr::Value(Value::Number(value, ..)) => {
-                    if found {
-                        match value.parse::<i64>() {
-                            Ok(value) => result.push(ShardingKey::Value(value)),
-                            Err(_) => {
-                                debug!("Sharding key was not an integer: {}", value);
-                            }
-                        };
-                    }
+                // Sharding key is unique enough, don't worry about
+                // table names.
+                if &sharding_key[0].value == "*" {
+                    return true;
                 }
 
-                Expr::Value(Value::Placeholder(placeholder)) => {
-                    match placeholder.replace('$', "").parse::<i16>() {
-                        Ok(placeholder) => result.push(ShardingKey::Placeholder(placeholder)),
-                        Err(_) => {
-                            debug!(
-                                "Prepared statement didn't have integer placeholders: {}",
-                                placeholder
-                            );
-                        }
-                    }
+                if table_names.len() != 1 {
+                    return false;
                 }
-                _ => (),
-            };
+
+                let table = &table_names[0];
+
+                if table.len() == 1 {
+                    // Table is not fully qualified, e.g.
+                    //      SELECT * FROM t WHERE sharding_key = 5
+                    // Make sure the table name from the sharding key matches
+                    // the table name from the query.
+                    sharding_key[0].value == table[0].value.to_lowercase()
+                } else if table.len() == 2 {
+                    // Table name is fully qualified with the schema: e.g.
+                    //      SELECT * FROM public.t WHERE sharding_key = 5
+                    // Ignore the schema (TODO: at some point, we want schema support)
+                    // and use the table name only.
+                    sharding_key[0].value == table[1].value.to_lowercase()
+                } else {
+                    debug!("Got table name with more than two idents, which is not possible");
+                    false
+                }
+            }
+
+            Expr::CompoundIdentifier(idents) => {
+                // The key is fully qualified in the query,
+                // it will exist or Postgres will throw an error.
+                // TODO: key can have schema as well, e.g. public.data.id (len == 3)
+                idents.len() == 2
+                    && (&sharding_key[0].value == "*"
+                        || sharding_key[0].value == idents[0].value.to_lowercase())
+                    && sharding_key[1].value == idents[1].value.to_lowercase()
+            }
+
+            _ => false,
         }
+    }
 
-        debug!("Sharding keys found: {:?}", result);
+    /// The value compared to the sharding key, if we can use it.
+    fn sharding_key_value(expr: &Expr) -> Option<ShardingKey> {
+        match expr {
+            Expr::Value(Value::Number(value, ..)) => match value.parse::<i64>() {
+                Ok(value) => Some(ShardingKey::Value(value)),
+                Err(_) => {
+                    debug!("Sharding key was not an integer: {}", value);
+                    None
+                }
+            },
 
-        result
+            Expr::Value(Value::Placeholder(placeholder)) => {
+                match placeholder.replace('$', "").parse::<i16>() {
+                    Ok(placeholder) => Some(ShardingKey::Placeholder(placeholder)),
+                    Err(_) => {
+                        debug!(
+                            "Prepared statement didn't have integer placeholders: {}",
+                            placeholder
+                        );
+                        None
+                    }
+                }
+            }
+
+            _ => None,
+        }
     }
 
     /// Try to figure out which shard the query should go to.
-    fn infer_shard(&mut self, query: &sqlparser::ast::Query) -> Option<usize> {
+    fn infer_shard(&mut self, query: &sqlparser::ast::Query) -> Result<Option<usize>, Error> {
         let mut exprs = Vec::new();
 
         // Collect all table names from the query.
@@ -1118,13 +1586,10 @@ impl QueryRouter {
         &mut self,
         exprs: Vec<Expr>,
         table_names: Vec<Vec<Ident>>,
-    ) -> Option<usize> {
+    ) -> Result<Option<usize>, Error> {
         let mut shards = BTreeSet::new();
 
-        let sharder = Sharder::new(
-            self.pool_settings.shards,
-            self.pool_settings.sharding_function,
-        );
+        let sharder = self.sharder();
 
         // Look for sharding keys in either the join condition
         // or the selection.
@@ -1151,15 +1616,23 @@ impl QueryRouter {
             // Didn't find a sharding key, you're on your own.
             0 => {
                 debug!("No sharding keys found");
-                None
+                Ok(None)
             }
 
-            1 => Some(shards.into_iter().last().unwrap()),
+            1 => Ok(Some(shards.into_iter().last().unwrap())),
 
-            // TODO: support querying multiple shards (some day...)
+            // The query is routed like one without a sharding key, unless that's not allowed.
             _ => {
-                debug!("More than one sharding key found");
-                None
+                debug!("Sharding keys found on more than one shard: {:?}", shards);
+
+                if self.pool_settings.reject_cross_shard_queries {
+                    Err(Error::QueryRouterError(format!(
+                        "query spans multiple shards: {:?}",
+                        shards
+                    )))
+                } else {
+                    Ok(None)
+                }
             }
         }
     }
@@ -1205,34 +1678,202 @@ impl QueryRouter {
             if let Ok(PluginOutput::Deny(error)) = result {
                 return Ok(PluginOutput::Deny(error));
             }
-        }
+        }
+
+        Ok(PluginOutput::Allow)
+    }
+
+    /// Route to the shard of the sharding key, None if it isn't a bigint.
+    fn set_sharding_key(&mut self, sharding_key: &str) -> Option<usize> {
+        let shard = self.sharder().shard_for_key(sharding_key)?;
+        self.set_shard(Some(shard));
+        self.active_shard
+    }
+
+    /// Set active_role as the default_role specified in the pool.
+    pub fn set_default_role(&mut self) {
+        self.active_role = self.pool_settings.default_role;
+    }
+
+    /// Route the session like libpq's `target_session_attrs`, overriding `default_role`
+    /// and the role inferred from the queries: `read-write` goes to the primary,
+    /// `read-only` to the replicas and `prefer-standby` to the replicas if there are any.
+    pub fn set_target_session_attrs(&mut self, value: &str) -> Result<(), Error> {
+        let (session_role, prefer_standby) = Self::target_session_role(value)?;
+
+        self.session_role = session_role;
+        self.prefer_standby = prefer_standby;
+
+        Ok(())
+    }
+
+    /// The role of a `target_session_attrs` value, and whether it falls back to the primary.
+    pub fn target_session_role(value: &str) -> Result<(Option<Role>, bool), Error> {
+        Ok(match value.to_ascii_lowercase().as_str() {
+            "any" => (None, false),
+            "read-write" | "primary" => (Some(Role::Primary), false),
+            "read-only" | "standby" => (Some(Role::Replica), false),
+            "prefer-standby" => (Some(Role::Replica), true),
+            _ => {
+                return Err(Error::QueryRouterError(format!(
+                    "invalid target_session_attrs value: \"{}\"",
+                    value
+                )))
+            }
+        })
+    }
+
+    /// The session falls back to the primary when no replica is available.
+    pub fn prefer_standby(&self) -> bool {
+        self.prefer_standby
+    }
+
+    /// Get the current desired server role we should be talking to.
+    pub fn role(&self) -> Option<Role> {
+        self.hinted_role.or(self.session_role).or(self.active_role)
+    }
+
+    /// Check the user is allowed on the shard the query goes to, see `allowed_shards`.
+    /// Queries without a shard go to the default shard, or to a random allowed one.
+    pub fn check_shard_allowed(&self) -> Result<(), Error> {
+        let shard = match (self.shard(), self.pool_settings.default_shard) {
+            (Some(shard), _) => shard,
+            (None, DefaultShard::Shard(shard)) => shard,
+            (None, _) => return Ok(()),
+        };
+
+        if self.pool_settings.user.shard_allowed(shard) {
+            Ok(())
+        } else {
+            Err(Error::ShardNotAllowed(shard))
+        }
+    }
+
+    /// Same as `statement_shard`, for the parameters of a Bind message.
+    pub fn bind_shard(&mut self, message: &BytesMut) -> Option<usize> {
+        let active_shard = self.active_shard.take();
+
+        let shard = match self.infer_shard_from_bind(message) {
+            true => self.active_shard,
+            false => None,
+        };
+
+        self.active_shard = active_shard;
+
+        shard
+    }
+
+    /// Get desired shard we should be talking to.
+    pub fn shard(&self) -> Option<usize> {
+        self.hinted_shard.or(self.active_shard)
+    }
+
+    /// The shard a statement is routed to by its own sharding key or routing hint, if any,
+    /// leaving the session's shard and role as they were. Used inside transactions,
+    /// which can't move to another shard. Catalog queries can run on any shard.
+    pub fn statement_shard(&mut self, ast: Option<&Vec<Statement>>) -> Option<usize> {
+        let hinted_shard = self.hinted_shard;
+        let hinted_role = self.hinted_role;
+        let active_shard = self.active_shard.take();
+        let active_role = self.active_role;
+
+        if let Some(ast) = ast {
+            if let Err(err) = self.infer(ast) {
+                debug!("Could not infer the shard of the statement: {}", err);
+            }
+        }
+
+        let shard = hinted_shard.or(self.active_shard);
+
+        self.hinted_shard = hinted_shard;
+        self.hinted_role = hinted_role;
+        self.active_shard = active_shard;
+        self.active_role = active_role;
+
+        shard
+    }
+
+    /// Read routing hints from the comments leading a query, e.g.
+    /// `/* shard: 3, role: replica */ SELECT ...`. The hints only apply
+    /// to this statement and override the session's shard and role.
+    pub fn parse_routing_hints(&mut self, message: &BytesMut) {
+        let code = message[0] as char;
+
+        if code != 'Q' && code != 'P' {
+            return;
+        }
+
+        self.clear_routing_hints();
+
+        let mut message_cursor = Cursor::new(message);
+        message_cursor.advance(mem::size_of::<u8>() + mem::size_of::<i32>());
+
+        // Skip the prepared statement name.
+        if code == 'P' && message_cursor.read_string().is_err() {
+            return;
+        }
+
+        let query = match message_cursor.read_string() {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+
+        let mut rest = query.trim_start();
+
+        while let Some(comment) = rest.strip_prefix("/*") {
+            let end = match comment.find("*/") {
+                Some(end) => end,
+                None => {
+                    debug!("Ignoring unterminated routing hint comment");
+                    return;
+                }
+            };
+
+            for hint in comment[..end].split(',') {
+                self.apply_routing_hint(hint);
+            }
+
+            rest = comment[end + 2..].trim_start();
+        }
+    }
+
+    fn apply_routing_hint(&mut self, hint: &str) {
+        let (key, value) = match hint.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim().trim_matches('\'')),
+            None => {
+                debug!("Ignoring malformed routing hint: {}", hint.trim());
+                return;
+            }
+        };
 
-        Ok(PluginOutput::Allow)
-    }
+        match key.to_ascii_lowercase().as_str() {
+            "shard" => match value.parse::<usize>() {
+                Ok(shard) if shard < self.pool_settings.shards => self.hinted_shard = Some(shard),
+                _ => debug!("Ignoring invalid shard routing hint: {}", value),
+            },
 
-    fn set_sharding_key(&mut self, sharding_key: i64) -> Option<usize> {
-        let sharder = Sharder::new(
-            self.pool_settings.shards,
-            self.pool_settings.sharding_function,
-        );
-        let shard = sharder.shard(sharding_key);
-        self.set_shard(Some(shard));
-        self.active_shard
-    }
+            "role" => match value.to_ascii_lowercase().as_str() {
+                "primary" => self.hinted_role = Some(Role::Primary),
+                "replica" => self.hinted_role = Some(Role::Replica),
+                _ => debug!("Ignoring invalid role routing hint: {}", value),
+            },
 
-    /// Set active_role as the default_role specified in the pool.
-    pub fn set_default_role(&mut self) {
-        self.active_role = self.pool_settings.default_role;
+            _ => debug!("Ignoring unknown routing hint: {}", key),
+        }
     }
 
-    /// Get the current desired server role we should be talking to.
-    pub fn role(&self) -> Option<Role> {
-        self.active_role
+    /// Forget the hints of the previous statement.
+    pub fn clear_routing_hints(&mut self) {
+        self.hinted_shard = None;
+        self.hinted_role = None;
     }
 
-    /// Get desired shard we should be talking to.
-    pub fn shard(&self) -> Option<usize> {
-        self.active_shard
+    /// The statement, if the query only starts a transaction, e.g. `BEGIN`.
+    pub fn started_transaction(ast: &[Statement]) -> Option<String> {
+        match ast {
+            [statement @ StartTransaction { .. }] => Some(statement.to_string()),
+            _ => None,
+        }
     }
 
     pub fn set_shard(&mut self, shard: Option<usize>) {
@@ -1262,7 +1903,7 @@ impl QueryRouter {
 
     pub fn primary_reads_enabled(&self) -> bool {
         match self.primary_reads_enabled {
-            None => self.pool_settings.primary_reads_enabled,
+            None => self.pool_settings.primary_reads_enabled(self.shard()),
             Some(value) => value,
         }
     }
@@ -1291,6 +1932,216 @@ mod test {
         assert_eq!(qr.role(), None);
     }
 
+    #[test]
+    fn test_set_statement() {
+        let set = |name: &str, value: &str| Some(SetStatement::Set(name.into(), value.into()));
+
+        for (query, expected) in [
+            (
+                "SET statement_timeout=5000;",
+                set("statement_timeout", "5000"),
+            ),
+            (
+                "set SESSION search_path TO app, public",
+                set("search_path", "app, public"),
+            ),
+            ("SET TIME ZONE 'UTC'", set("timezone", "'UTC'")),
+            ("SET myapp.user_id = '42'", set("myapp.user_id", "'42'")),
+            (
+                "SET lock_timeout TO DEFAULT",
+                Some(SetStatement::Reset("lock_timeout".into())),
+            ),
+            (
+                "RESET statement_timeout",
+                Some(SetStatement::Reset("statement_timeout".into())),
+            ),
+            ("RESET ALL;", Some(SetStatement::ResetAll)),
+            ("DISCARD ALL", Some(SetStatement::ResetAll)),
+            ("SET LOCAL statement_timeout = 1000", None),
+            ("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE", None),
+            ("SET statement_timeout = 1; SELECT 1", None),
+            ("SELECT 1", None),
+        ] {
+            assert_eq!(
+                QueryRouter::set_statement(&simple_query(query)),
+                expected,
+                "{}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn test_ddl_routing() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.query_parser_enabled = true;
+        qr.pool_settings.default_role = Some(Role::Replica);
+
+        // Parsed by sqlparser.
+        let query = simple_query("CREATE TABLE users (id BIGINT PRIMARY KEY)");
+        qr.set_default_role();
+        let ast = qr.parse(&query).unwrap();
+        assert!(qr.infer(&ast).is_ok());
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        // Not supported by sqlparser.
+        for query in [
+            "REFRESH MATERIALIZED VIEW user_counts",
+            "VACUUM",
+            "vacuum;",
+            "/* a /* nested */ comment */ VACUUM",
+            "-- comment\n(VACUUM)",
+            "DO $$ BEGIN DROP TABLE users; END $$",
+            "LOCK users IN SHARE MODE +",
+        ] {
+            let query = simple_query(query);
+            qr.set_default_role();
+            assert!(qr.parse(&query).is_err());
+            assert!(qr.infer_unparsed(&query).is_ok());
+            assert_eq!(qr.role(), Some(Role::Primary));
+        }
+
+        // A read the parser doesn't understand keeps the default role.
+        let query = simple_query("SELECT 1 FROM users FOR NO KEY SHARE OF users NOWAIT +");
+        qr.set_default_role();
+        assert!(qr.infer_unparsed(&query).is_ok());
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        qr.pool_settings.block_ddl = true;
+        for query in ["CREATE TABLE users (id BIGINT)", "DROP TABLE users"] {
+            let ast = qr.parse(&simple_query(query)).unwrap();
+            assert!(matches!(qr.infer(&ast), Err(Error::QueryRouterError(_))));
+        }
+        for query in [
+            "VACUUM users",
+            "/* comment */ DROP TABLE users +",
+            "DO $$ BEGIN DROP TABLE users; END $$",
+            "SELECT 1 FROM users FOR NO KEY SHARE OF users NOWAIT +",
+        ] {
+            assert!(matches!(
+                qr.infer_unparsed(&simple_query(query)),
+                Err(Error::QueryRouterError(_))
+            ));
+        }
+        let ast = qr.parse(&simple_query("CALL cleanup()")).unwrap();
+        assert!(matches!(qr.infer(&ast), Err(Error::QueryRouterError(_))));
+        let ast = qr.parse(&simple_query("SELECT * FROM users")).unwrap();
+        assert!(qr.infer(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_target_session_attrs() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.query_parser_enabled = true;
+        qr.pool_settings.default_role = Some(Role::Replica);
+        qr.set_default_role();
+
+        // Overrides the role inferred from the query.
+        assert!(qr.set_target_session_attrs("read-write").is_ok());
+        let ast = qr.parse(&simple_query("SELECT * FROM users")).unwrap();
+        assert!(qr.infer(&ast).is_ok());
+        assert_eq!(qr.role(), Some(Role::Primary));
+        assert!(!qr.prefer_standby());
+
+        assert!(qr.set_target_session_attrs("read-only").is_ok());
+        let ast = qr
+            .parse(&simple_query("INSERT INTO users (id) VALUES (1)"))
+            .unwrap();
+        assert!(qr.infer(&ast).is_ok());
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        assert!(qr.set_target_session_attrs("Prefer-Standby").is_ok());
+        assert_eq!(qr.role(), Some(Role::Replica));
+        assert!(qr.prefer_standby());
+
+        // Comment hints still win.
+        qr.parse_routing_hints(&simple_query("/* role: primary */ SELECT 1"));
+        assert_eq!(qr.role(), Some(Role::Primary));
+        qr.parse_routing_hints(&simple_query("SELECT 1"));
+
+        assert!(qr.set_target_session_attrs("any").is_ok());
+        assert_eq!(qr.role(), qr.active_role);
+
+        assert!(qr.set_target_session_attrs("read-only").is_ok());
+        let query = simple_query("SET SERVER ROLE TO 'primary'");
+        assert!(qr.try_execute_command(&query).is_some());
+        assert_eq!(qr.role(), Some(Role::Primary));
+        assert!(!qr.prefer_standby());
+
+        assert!(qr.set_target_session_attrs("read-mostly").is_err());
+
+        assert_eq!(
+            QueryRouter::target_session_role("Read-Write").unwrap(),
+            (Some(Role::Primary), false)
+        );
+        assert_eq!(
+            QueryRouter::target_session_role("prefer-standby").unwrap(),
+            (Some(Role::Replica), true)
+        );
+        assert_eq!(
+            QueryRouter::target_session_role("any").unwrap(),
+            (None, false)
+        );
+    }
+
+    #[test]
+    fn test_transaction_routing() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.query_parser_enabled = true;
+        qr.pool_settings.default_role = Some(Role::Replica);
+        qr.pool_settings.sticky_primary_in_txn = true;
+        qr.pool_settings.primary_reads_enabled = false;
+
+        for query in [
+            "BEGIN",
+            "BEGIN; INSERT INTO users (id) VALUES (1); SELECT * FROM users",
+            "BEGIN; SELECT * FROM users",
+            "BEGIN READ ONLY; SELECT * FROM users",
+        ] {
+            qr.set_default_role();
+            let ast = qr.parse(&simple_query(query)).unwrap();
+            assert!(qr.infer(&ast).is_ok());
+            assert_eq!(qr.role(), Some(Role::Primary), "{}", query);
+        }
+
+        // Only read-only transactions can go to a replica.
+        qr.pool_settings.sticky_primary_in_txn = false;
+        for (query, role) in [
+            ("BEGIN; SELECT * FROM users", Role::Primary),
+            ("BEGIN READ ONLY; SELECT * FROM users", Role::Replica),
+            (
+                "START TRANSACTION READ ONLY; SELECT * FROM users FOR UPDATE",
+                Role::Primary,
+            ),
+            (
+                "BEGIN READ ONLY; WITH t AS (UPDATE users SET id = 1 RETURNING id) SELECT * FROM t",
+                Role::Primary,
+            ),
+        ] {
+            qr.set_default_role();
+            let ast = qr.parse(&simple_query(query)).unwrap();
+            assert!(qr.infer(&ast).is_ok());
+            assert_eq!(qr.role(), Some(role), "{}", query);
+        }
+
+        // Only a query starting a transaction and doing nothing else can move to another shard.
+        let ast = qr
+            .parse(&simple_query("BEGIN ISOLATION LEVEL SERIALIZABLE"))
+            .unwrap();
+        assert_eq!(
+            QueryRouter::started_transaction(&ast).as_deref(),
+            Some("BEGIN TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+        );
+        let ast = qr.parse(&simple_query("BEGIN; SELECT 1")).unwrap();
+        assert_eq!(QueryRouter::started_transaction(&ast), None);
+    }
+
     #[test]
     fn test_split_cte_queries() {
         QueryRouter::setup();
@@ -1360,6 +2211,116 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_routing_hints() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.shards = 4;
+        qr.set_shard(Some(0));
+
+        qr.parse_routing_hints(&simple_query("/* shard: 3, role: replica */ SELECT 1"));
+        assert_eq!(qr.shard(), Some(3));
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        // Hints only last for one statement.
+        qr.parse_routing_hints(&simple_query("SELECT 1"));
+        assert_eq!(qr.shard(), Some(0));
+        assert_eq!(qr.role(), None);
+
+        // Multi-line queries and comments.
+        qr.parse_routing_hints(&simple_query(
+            "\n  /* role: primary */\n/*\n shard: '2'\n*/\nSELECT *\nFROM users",
+        ));
+        assert_eq!(qr.shard(), Some(2));
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        // Hints in the middle of a statement are ignored.
+        qr.parse_routing_hints(&simple_query("SELECT /* shard: 3 */ 1"));
+        assert_eq!(qr.shard(), Some(0));
+
+        // Malformed hints are ignored.
+        for query in [
+            "/* shard: 4 */ SELECT 1",
+            "/* shard: three */ SELECT 1",
+            "/* role: leader */ SELECT 1",
+            "/* shard 3 */ SELECT 1",
+            "/* shard: 3 SELECT 1",
+        ] {
+            qr.parse_routing_hints(&simple_query(query));
+            assert_eq!(qr.shard(), Some(0));
+            assert_eq!(qr.role(), None);
+        }
+
+        // Extended protocol.
+        let mut parse = BytesMut::new();
+        let body = b"stmt\0/* shard: 1 */ SELECT $1\0\0\0";
+        parse.put_u8(b'P');
+        parse.put_i32(4 + body.len() as i32);
+        parse.put_slice(body);
+        qr.parse_routing_hints(&parse);
+        assert_eq!(qr.shard(), Some(1));
+
+        qr.clear_routing_hints();
+        assert_eq!(qr.shard(), Some(0));
+    }
+
+    #[test]
+    fn test_catalog_routing() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.catalog_shard = Some(2);
+
+        // psql's \dt
+        let describe_tables = simple_query(
+            "SELECT n.nspname as \"Schema\", c.relname as \"Name\" \
+             FROM pg_catalog.pg_class c \
+             LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
+             WHERE c.relkind IN ('r','p','') AND n.nspname <> 'pg_catalog' \
+             AND pg_catalog.pg_table_is_visible(c.oid) ORDER BY 1,2",
+        );
+
+        for session_shard in [None, Some(0), Some(1)] {
+            qr.set_shard(session_shard);
+            qr.parse_routing_hints(&describe_tables);
+            assert!(qr.infer(&qr.parse(&describe_tables).unwrap()).is_ok());
+            assert_eq!(qr.shard(), Some(2));
+        }
+
+        let columns = simple_query("SELECT * FROM information_schema.columns");
+        qr.parse_routing_hints(&columns);
+        assert!(qr.infer(&qr.parse(&columns).unwrap()).is_ok());
+        assert_eq!(qr.shard(), Some(2));
+
+        // Queries touching user tables keep the session's shard.
+        qr.set_shard(Some(1));
+        for query in [
+            "SELECT * FROM users",
+            "SELECT * FROM pg_catalog.pg_class c JOIN public.users u ON u.id = c.oid",
+            "SELECT 1",
+        ] {
+            let query = simple_query(query);
+            qr.parse_routing_hints(&query);
+            assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+            assert_eq!(qr.shard(), Some(1));
+        }
+
+        // Explicit hints win.
+        let hinted = simple_query("/* shard: 0 */ SELECT * FROM pg_catalog.pg_class");
+        qr.parse_routing_hints(&hinted);
+        assert!(qr.infer(&qr.parse(&hinted).unwrap()).is_ok());
+        assert_eq!(qr.shard(), Some(0));
+    }
+
+    #[test]
+    fn test_is_listen() {
+        assert!(QueryRouter::is_listen(&simple_query("LISTEN foo")));
+        assert!(QueryRouter::is_listen(&simple_query("listen   foo;")));
+        assert!(!QueryRouter::is_listen(&simple_query("LISTEN")));
+        assert!(!QueryRouter::is_listen(&simple_query("SELECT 1")));
+        assert!(!QueryRouter::is_listen(&simple_query("UNLISTEN foo")));
+    }
+
     #[test]
     fn test_select_for_update() {
         QueryRouter::setup();
@@ -1396,6 +2357,32 @@ mod test {
         assert_eq!(qr.role(), None);
     }
 
+    #[test]
+    fn test_shard_primary_reads_enabled() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.shards = 2;
+        qr.pool_settings.primary_reads_enabled = true;
+        qr.pool_settings.shard_primary_reads_enabled = vec![true, false];
+        let query = simple_query("SELECT * FROM items WHERE id = 5");
+
+        for (shard, role) in [(0, None), (1, Some(Role::Replica))] {
+            assert!(qr
+                .try_execute_command(&simple_query(&format!("SET SHARD TO '{}'", shard)))
+                .is_some());
+            assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+            assert_eq!(qr.role(), role);
+        }
+
+        // The session setting overrides the shard's.
+        assert!(qr
+            .try_execute_command(&simple_query("SET PRIMARY READS TO on"))
+            .is_some());
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.role(), None);
+    }
+
     #[test]
     fn test_infer_parse_prepared() {
         QueryRouter::setup();
@@ -1504,6 +2491,18 @@ mod test {
         );
         assert_eq!(qr.shard().unwrap(), 0);
 
+        // Negative sharding keys are valid bigints
+        let query = simple_query("SET SHARDING KEY TO '-13'");
+        assert!(matches!(
+            qr.try_execute_command(&query),
+            Some((Command::SetShardingKey, _))
+        ));
+
+        // Out of range keys are not intercepted
+        let query = simple_query("SET SHARDING KEY TO 99999999999999999999");
+        assert_eq!(qr.try_execute_command(&query), None);
+        assert_eq!(qr.shard().unwrap(), 0);
+
         // SetShard
         let query = simple_query("SET SHARD TO '1'");
         assert_eq!(
@@ -1617,23 +2616,48 @@ mod test {
         let pool_settings = PoolSettings {
             pool_mode: PoolMode::Transaction,
             load_balancing_mode: crate::config::LoadBalancingMode::Random,
+            primary_selection: crate::config::PrimarySelection::RoundRobin,
             checkout_failure_limit: None,
             shards: 2,
+            shard_pool_sizes: vec![crate::config::General::default_pool_size(); 2],
+            min_pool_size: 0,
             user: crate::config::User::default(),
             default_role: Some(Role::Replica),
             query_parser_enabled: true,
             query_parser_max_length: None,
             query_parser_read_write_splitting: true,
+            block_ddl: false,
+            sticky_primary_in_txn: true,
+            track_set_statements: false,
             primary_reads_enabled: false,
+            shard_primary_reads_enabled: vec![false; 2],
+            max_replica_lag: None,
             sharding_function: ShardingFunction::PgBigintHash,
+            ketama_vnodes: crate::sharding::KETAMA_DEFAULT_VNODES,
+            shard_range_starts: vec![],
+            shard_cache: None,
+            shard_failover_priority: vec![],
             automatic_sharding_key: Some(String::from("test.id")),
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
-            healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
+            healthcheck_query: PoolSettings::default().healthcheck_query,
+            server_reset_query: None,
+            healthcheck_verify_role: PoolSettings::default().healthcheck_verify_role,
             ban_time: PoolSettings::default().ban_time,
+            reject_cross_shard_queries: false,
+            cross_shard_transactions: crate::config::CrossShardTransactions::Reject,
+            enable_scatter_gather: false,
+            scatter_gather_best_effort: false,
+            read_retry_count: 0,
+            ban_backoff: false,
+            max_ban_time: 3600,
             sharding_key_regex: None,
             shard_id_regex: None,
             default_shard: crate::config::DefaultShard::Shard(0),
+            catalog_shard: None,
+            catalog_role: None,
+            replication_passthrough: false,
             regex_search_limit: 1000,
+            auth_query_cache_ttl: 0,
             auth_query: None,
             auth_query_password: None,
             auth_query_user: None,
@@ -1700,23 +2724,48 @@ mod test {
         let pool_settings = PoolSettings {
             pool_mode: PoolMode::Transaction,
             load_balancing_mode: crate::config::LoadBalancingMode::Random,
+            primary_selection: crate::config::PrimarySelection::RoundRobin,
             checkout_failure_limit: Some(10),
             shards: 5,
+            shard_pool_sizes: vec![crate::config::General::default_pool_size(); 5],
+            min_pool_size: 0,
             user: crate::config::User::default(),
             default_role: Some(Role::Replica),
             query_parser_enabled: true,
             query_parser_max_length: None,
             query_parser_read_write_splitting: true,
+            block_ddl: false,
+            sticky_primary_in_txn: true,
+            track_set_statements: false,
             primary_reads_enabled: false,
+            shard_primary_reads_enabled: vec![false; 5],
+            max_replica_lag: None,
             sharding_function: ShardingFunction::PgBigintHash,
+            ketama_vnodes: crate::sharding::KETAMA_DEFAULT_VNODES,
+            shard_range_starts: vec![],
+            shard_cache: None,
+            shard_failover_priority: vec![],
             automatic_sharding_key: None,
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
-            healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
+            healthcheck_query: PoolSettings::default().healthcheck_query,
+            server_reset_query: None,
+            healthcheck_verify_role: PoolSettings::default().healthcheck_verify_role,
             ban_time: PoolSettings::default().ban_time,
+            reject_cross_shard_queries: false,
+            cross_shard_transactions: crate::config::CrossShardTransactions::Reject,
+            enable_scatter_gather: false,
+            scatter_gather_best_effort: false,
+            read_retry_count: 0,
+            ban_backoff: false,
+            max_ban_time: 3600,
             sharding_key_regex: Some(Regex::new(r"/\* sharding_key: (\d+) \*/").unwrap()),
             shard_id_regex: Some(Regex::new(r"/\* shard_id: (\d+) \*/").unwrap()),
             default_shard: crate::config::DefaultShard::Shard(0),
+            catalog_shard: None,
+            catalog_role: None,
+            replication_passthrough: false,
             regex_search_limit: 1000,
+            auth_query_cache_ttl: 0,
             auth_query: None,
             auth_query_password: None,
             auth_query_user: None,
@@ -1755,6 +2804,127 @@ mod test {
         assert_eq!(qr.active_shard, Some(2));
     }
 
+    #[test]
+    fn test_automatic_sharding_key_in_list() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query(
+                    "SELECT * FROM data WHERE id IN (5, 5) AND (value = 1 OR value = 2)"
+                ))
+                .unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), Some(2));
+
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query("DELETE FROM data WHERE (id = 6)"))
+                    .unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), Some(0));
+
+        // No sharding key in the query.
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+
+        assert!(qr
+            .infer(
+                &qr.parse(&simple_query("SELECT * FROM data WHERE value IN (5, 6)"))
+                    .unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), None);
+
+        // The keys are on different shards.
+        let query = simple_query("UPDATE data SET value = 1 WHERE id IN (5, 6)");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.shard(), None);
+
+        qr.pool_settings.reject_cross_shard_queries = true;
+        assert_eq!(
+            qr.infer(&qr.parse(&query).unwrap()),
+            Err(Error::QueryRouterError(
+                "query spans multiple shards: {0, 2}".into()
+            ))
+        );
+        assert_eq!(qr.shard(), None);
+    }
+
+    #[test]
+    fn test_check_shard_allowed() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.user.allowed_shards = Some(vec![2]);
+
+        // Key 5 is on shard 2, key 6 on shard 0.
+        let ast = qr
+            .parse(&simple_query("SELECT * FROM data WHERE id = 5"))
+            .unwrap();
+        assert!(qr.infer(&ast).is_ok());
+        assert_eq!(qr.check_shard_allowed(), Ok(()));
+
+        let ast = qr
+            .parse(&simple_query("SELECT * FROM data WHERE id = 6"))
+            .unwrap();
+        assert!(qr.infer(&ast).is_ok());
+        assert_eq!(qr.check_shard_allowed(), Err(Error::ShardNotAllowed(0)));
+
+        // Without a shard, queries go to the default shard 0.
+        qr.set_shard(None);
+        assert_eq!(qr.check_shard_allowed(), Err(Error::ShardNotAllowed(0)));
+
+        qr.pool_settings.default_shard = DefaultShard::Random;
+        assert_eq!(qr.check_shard_allowed(), Ok(()));
+
+        // SET SHARD TO 'ANY' picks one of the allowed shards.
+        qr.try_execute_command(&simple_query("SET SHARD TO 'ANY'"));
+        assert_eq!(qr.shard(), Some(2));
+    }
+
+    #[test]
+    fn test_statement_shard() {
+        QueryRouter::setup();
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.set_shard(Some(1));
+
+        let ast = qr
+            .parse(&simple_query(
+                "INSERT INTO data (id, value) VALUES (5, 'x')",
+            ))
+            .unwrap();
+        assert_eq!(qr.statement_shard(Some(&ast)), Some(2));
+
+        // The session's shard doesn't change.
+        assert_eq!(qr.shard(), Some(1));
+
+        let ast = qr
+            .parse(&simple_query("SELECT count(*) FROM data"))
+            .unwrap();
+        assert_eq!(qr.statement_shard(Some(&ast)), None);
+
+        let message = simple_query("/* shard: 0 */ SELECT 1");
+        qr.parse_routing_hints(&message);
+        assert_eq!(qr.statement_shard(None), Some(0));
+    }
+
     #[test]
     fn test_automatic_sharding_key() {
         QueryRouter::setup();
@@ -2092,6 +3262,66 @@ mod test {
         assert!(qr.placeholders.is_empty());
     }
 
+    #[test]
+    fn test_prepared_statements_mixed_parameters() {
+        let stmt = "SELECT * FROM data WHERE name = $1 AND id = $2";
+
+        let bind = |payload: BytesMut| {
+            let mut bind = BytesMut::from(&b"B"[..]);
+            bind.put_i32(payload.len() as i32 + 4);
+            bind.put(payload);
+            bind
+        };
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+
+        // A text parameter before a binary bigint sharding key.
+        let mut payload = BytesMut::from(&b"\0\0"[..]);
+        payload.put_i16(2);
+        payload.put_i16(0);
+        payload.put_i16(1);
+        payload.put_i16(2);
+        payload.put_i32(3);
+        payload.put(&b"bob"[..]);
+        payload.put_i32(8);
+        payload.put_i64(5);
+        payload.put_i16(0);
+
+        assert!(qr.infer(&qr.parse(&simple_query(stmt)).unwrap()).is_ok());
+        assert!(qr.infer_shard_from_bind(&bind(payload)));
+        assert_eq!(qr.shard().unwrap(), 2);
+
+        // A NULL parameter before a text sharding key.
+        let mut payload = BytesMut::from(&b"\0\0"[..]);
+        payload.put_i16(0);
+        payload.put_i16(2);
+        payload.put_i32(-1);
+        payload.put_i32(1);
+        payload.put(&b"5"[..]);
+        payload.put_i16(0);
+
+        qr.set_shard(None);
+        assert!(qr.infer(&qr.parse(&simple_query(stmt)).unwrap()).is_ok());
+        assert!(qr.infer_shard_from_bind(&bind(payload)));
+        assert_eq!(qr.shard().unwrap(), 2);
+
+        // Invalid format codes and truncated messages are not routed.
+        let mut payload = BytesMut::from(&b"\0\0"[..]);
+        payload.put_i16(1);
+        payload.put_i16(7);
+        payload.put_i16(1);
+        payload.put_i32(8);
+
+        qr.set_shard(None);
+        assert!(qr.infer(&qr.parse(&simple_query(stmt)).unwrap()).is_ok());
+        assert!(!qr.infer_shard_from_bind(&bind(payload)));
+        assert_eq!(qr.shard(), None);
+        assert!(qr.placeholders.is_empty());
+    }
+
     #[tokio::test]
     async fn test_table_access_plugin() {
         use crate::config::{Plugins, TableAccess};
@@ -0,0 +1,196 @@
+//! Per-query tracing spans, enabled with `general.tracing_enabled`.
+//!
+//! Every query gets a `query` span carrying the user, database, shard, role and
+//! backend address it was routed to, with two children: `wait_for_connection`
+//! (checkout from the pool) and `server_execution` (time spent talking to Postgres).
+//! When tracing is disabled, the spans are `Span::none()` and cost next to nothing.
+use bytes::BytesMut;
+use tokio::time::Instant;
+use tracing::field::Empty;
+use tracing::{info_span, Span};
+
+use crate::config::{get_tracing_enabled, Address};
+use crate::messages::query_text;
+
+/// Target of the query spans, to tell them apart from the spans of other crates.
+pub const SPAN_TARGET: &str = module_path!();
+
+/// W3C trace context (https://www.w3.org/TR/trace-context/#traceparent-header)
+/// the client propagated to us, so our spans can be tied to the application's trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub parent_id: String,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` value, e.g. `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`.
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let mut parts = traceparent.trim().split('-');
+
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if parts.next().is_some()
+            || !is_hex(version, 2)
+            || version == "ff"
+            || !is_hex(trace_id, 32)
+            || !is_hex(parent_id, 16)
+            || !is_hex(flags, 2)
+            || trace_id.bytes().all(|b| b == b'0')
+            || parent_id.bytes().all(|b| b == b'0')
+        {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id: trace_id.to_lowercase(),
+            parent_id: parent_id.to_lowercase(),
+        })
+    }
+
+    /// Find the trace context in a query, passed as a sqlcommenter comment,
+    /// e.g. `SELECT 1 /*traceparent='00-...-01'*/`, or in the application_name,
+    /// either as the whole value or as a `traceparent=...` entry.
+    pub fn find(query: Option<&str>, application_name: &str) -> Option<TraceContext> {
+        query
+            .and_then(Self::after_key)
+            .or_else(|| Self::parse(application_name))
+            .or_else(|| Self::after_key(application_name))
+    }
+
+    fn after_key(text: &str) -> Option<TraceContext> {
+        let start = text.find("traceparent=")? + "traceparent=".len();
+        let value = text[start..].trim_start_matches(['\'', '"']);
+        let end = value
+            .find(|c: char| !(c.is_ascii_hexdigit() || c == '-'))
+            .unwrap_or(value.len());
+
+        Self::parse(&value[..end])
+    }
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The span of the query the client is currently running.
+/// The duration is recorded when it's dropped.
+pub struct QuerySpan {
+    span: Span,
+    start: Option<Instant>,
+}
+
+impl QuerySpan {
+    pub fn disabled() -> QuerySpan {
+        QuerySpan {
+            span: Span::none(),
+            start: None,
+        }
+    }
+
+    pub fn new(
+        user: &str,
+        database: &str,
+        message: &BytesMut,
+        application_name: &str,
+    ) -> QuerySpan {
+        if !get_tracing_enabled() {
+            return QuerySpan::disabled();
+        }
+
+        let context = TraceContext::find(query_text(message), application_name);
+
+        let span = info_span!(
+            "query",
+            user,
+            database,
+            shard = Empty,
+            role = Empty,
+            server = Empty,
+            duration_us = Empty,
+            trace_id = Empty,
+            parent_span_id = Empty,
+        );
+
+        if let Some(context) = context {
+            span.record("trace_id", context.trace_id.as_str());
+            span.record("parent_span_id", context.parent_id.as_str());
+        }
+
+        QuerySpan {
+            span,
+            start: Some(Instant::now()),
+        }
+    }
+
+    /// Record where the query was routed to.
+    pub fn record_server(&self, address: &Address) {
+        if self.span.is_disabled() {
+            return;
+        }
+
+        self.span.record("shard", address.shard);
+        self.span.record("role", address.role.to_string().as_str());
+        self.span.record(
+            "server",
+            format!("{}:{}", address.host, address.port).as_str(),
+        );
+    }
+
+    pub fn wait_for_connection(&self) -> Span {
+        info_span!(parent: &self.span, "wait_for_connection")
+    }
+
+    pub fn server_execution(&self) -> Span {
+        info_span!(parent: &self.span, "server_execution")
+    }
+}
+
+impl Drop for QuerySpan {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            self.span
+                .record("duration_us", start.elapsed().as_micros() as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trace_context() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let context = TraceContext {
+            trace_id: "0af7651916cd43dd8448eb211c80319c".to_string(),
+            parent_id: "b7ad6b7169203331".to_string(),
+        };
+
+        assert_eq!(TraceContext::parse(traceparent), Some(context.clone()));
+        assert_eq!(TraceContext::parse("00-0af76519-b7ad6b7169203331-01"), None);
+        assert_eq!(
+            TraceContext::parse("00-00000000000000000000000000000000-b7ad6b7169203331-01"),
+            None
+        );
+        assert_eq!(TraceContext::parse("psql"), None);
+
+        let query = format!("SELECT 1 /*traceparent='{}'*/", traceparent);
+        assert_eq!(
+            TraceContext::find(Some(&query), "psql"),
+            Some(context.clone())
+        );
+        assert_eq!(TraceContext::find(None, traceparent), Some(context.clone()));
+        assert_eq!(
+            TraceContext::find(
+                Some("SELECT 1"),
+                &format!("app traceparent={}", traceparent)
+            ),
+            Some(context)
+        );
+        assert_eq!(TraceContext::find(Some("SELECT 1"), "psql"), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Limit the number of queries per second of a user.
+
+use parking_lot::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+/// Token bucket shared by all the clients of a pool. It holds up to
+/// a second worth of queries, so short bursts are allowed.
+#[derive(Debug)]
+pub struct RateLimiter {
+    queries_per_second: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(queries_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            queries_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: queries_per_second as f64,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn queries_per_second(&self) -> u64 {
+        self.queries_per_second
+    }
+
+    /// Queries that can run right now without waiting.
+    pub fn available(&self) -> u64 {
+        let mut bucket = self.bucket.lock();
+        self.refill(&mut bucket);
+        bucket.tokens as u64
+    }
+
+    /// Take a token, or return how long to wait until one is available.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock();
+        self.refill(&mut bucket);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.queries_per_second as f64,
+            ))
+        }
+    }
+
+    /// Take a token, waiting up to `timeout` for one. Returns false if it timed out.
+    pub async fn acquire(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match self.try_acquire() {
+                Ok(()) => return true,
+
+                Err(wait) => {
+                    if Instant::now() + wait > deadline {
+                        return false;
+                    }
+
+                    sleep(wait).await;
+                }
+            }
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+
+        bucket.tokens = (bucket.tokens + elapsed * self.queries_per_second as f64)
+            .min(self.queries_per_second as f64);
+        bucket.updated_at = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let rate_limiter = RateLimiter::new(10);
+
+        for _ in 0..10 {
+            assert!(rate_limiter.try_acquire().is_ok());
+        }
+
+        let wait = rate_limiter.try_acquire().unwrap_err();
+        assert!(wait <= Duration::from_millis(100), "wait: {:?}", wait);
+
+        // Too short to get a token.
+        assert!(!rate_limiter.acquire(Duration::from_millis(1)).await);
+        assert!(rate_limiter.acquire(Duration::from_millis(200)).await);
+
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(rate_limiter.available(), 10);
+    }
+}
@@ -0,0 +1,427 @@
+//! Send a read-only query to all shards and return the results as one result set.
+//!
+//! The shards are queried concurrently, and their responses are read up to their RowDescription.
+//! The rows are then forwarded to the client shard after shard as they arrive, so the result set
+//! isn't held in memory: the servers wait until we read the rows of the shards before theirs.
+
+use bytes::{BufMut, BytesMut};
+use log::warn;
+
+use crate::config::ErrorVerbosity;
+use crate::constants::CONNECTION_FAILURE;
+use crate::errors::Error;
+use crate::messages::{
+    command_complete, notify, ready_for_query, sqlstate_class_message, BytesMutReader, PgErrorMsg,
+};
+
+/// Split a buffer of messages returned by a server.
+pub fn split_messages(mut messages: BytesMut) -> Result<Vec<BytesMut>, Error> {
+    let mut split = Vec::new();
+
+    while !messages.is_empty() {
+        let end = messages
+            .get(1..5)
+            .map(|len| i32::from_be_bytes(len.try_into().unwrap()))
+            .and_then(|len| usize::try_from(len).ok())
+            .filter(|len| *len >= 4)
+            .and_then(|len| len.checked_add(1))
+            .filter(|end| *end <= messages.len());
+
+        match end {
+            Some(end) => split.push(messages.split_to(end)),
+            None => {
+                return Err(Error::ServerMessageParserError(
+                    "scatter-gather response has a truncated message".into(),
+                ))
+            }
+        }
+    }
+
+    Ok(split)
+}
+
+/// Why a shard couldn't answer the query, with the SQLSTATE code sent to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardError {
+    pub code: String,
+    pub message: String,
+}
+
+impl ShardError {
+    /// The ErrorResponse of the server, already stripped as configured with `error_verbosity`.
+    fn from_response(message: &BytesMut) -> Result<ShardError, Error> {
+        let error = PgErrorMsg::parse(&message[5..])?;
+
+        Ok(ShardError {
+            code: error.code,
+            message: error.message,
+        })
+    }
+
+    /// We couldn't query the shard, e.g. none of its servers are up.
+    pub fn from_error(error: &Error, verbosity: ErrorVerbosity) -> ShardError {
+        let message = match verbosity {
+            ErrorVerbosity::Sanitized => {
+                sqlstate_class_message(CONNECTION_FAILURE.as_bytes()).to_string()
+            }
+            _ => error.to_string(),
+        };
+
+        ShardError {
+            code: CONNECTION_FAILURE.to_string(),
+            message,
+        }
+    }
+}
+
+/// Response of a shard to a simple query, up to its RowDescription.
+#[derive(Debug, Default)]
+pub struct ShardResponse {
+    row_description: Option<BytesMut>,
+    notices: BytesMut,
+    error: Option<ShardError>,
+
+    /// Messages that came after the RowDescription, handled with the rows.
+    rest: BytesMut,
+}
+
+impl ShardResponse {
+    /// Add messages returned by the server. Returns true once the rows can be read,
+    /// i.e. the RowDescription or the end of the response was received.
+    pub fn read(&mut self, messages: BytesMut) -> Result<bool, Error> {
+        let mut ready = self.row_description.is_some();
+
+        for message in split_messages(messages)? {
+            if self.row_description.is_some() {
+                self.rest.put(message);
+                continue;
+            }
+
+            match message[0] as char {
+                'T' => {
+                    self.row_description = Some(message);
+                    ready = true;
+                }
+
+                'N' => self.notices.put(message),
+
+                'E' => self.error = Some(ShardError::from_response(&message)?),
+
+                'Z' => ready = true,
+
+                _ => (),
+            }
+        }
+
+        Ok(ready)
+    }
+
+    /// Same as the server returning an error, for shards we couldn't query.
+    pub fn error(error: ShardError) -> ShardResponse {
+        ShardResponse {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+
+    /// The messages that came after the RowDescription, to read before the next ones.
+    pub fn take_rest(&mut self) -> BytesMut {
+        std::mem::take(&mut self.rest)
+    }
+}
+
+/// Column name, type OID, type size, type modifier and format.
+type Column = (String, i32, i16, i32, i16);
+
+/// Columns of a RowDescription, without the table OIDs and column numbers
+/// which are different on every shard.
+fn columns(row_description: &BytesMut) -> Result<Vec<Column>, Error> {
+    let truncated = || Error::ServerMessageParserError("RowDescription is truncated".into());
+
+    let mut message = row_description.clone();
+
+    if message.len() < 7 {
+        return Err(truncated());
+    }
+
+    let _header = message.split_to(5);
+    let count = i16::from_be_bytes([message[0], message[1]]);
+    let _count = message.split_to(2);
+    let mut columns = Vec::with_capacity(count.max(0) as usize);
+
+    for _ in 0..count {
+        let name = message.read_string()?;
+
+        if message.len() < 18 {
+            return Err(truncated());
+        }
+
+        let field = message.split_to(18);
+
+        columns.push((
+            name,
+            i32::from_be_bytes(field[6..10].try_into().unwrap()),
+            i16::from_be_bytes(field[10..12].try_into().unwrap()),
+            i32::from_be_bytes(field[12..16].try_into().unwrap()),
+            i16::from_be_bytes(field[16..18].try_into().unwrap()),
+        ));
+    }
+
+    Ok(columns)
+}
+
+/// Check the responses of the shards, in shard order, before their rows are read.
+/// Returns what the client gets before the rows: the notices of the shards, then the RowDescription.
+/// With `best_effort`, failed shards are reported with a notice instead of failing the query.
+/// Returns the error to send to the client otherwise.
+pub fn start(
+    responses: &mut [(usize, ShardResponse)],
+    best_effort: bool,
+) -> Result<BytesMut, ShardError> {
+    let mut result = BytesMut::new();
+    let mut row_description: Option<BytesMut> = None;
+    let mut errors = Vec::new();
+
+    for (shard, response) in responses.iter_mut() {
+        result.put(std::mem::take(&mut response.notices));
+
+        if let Some(error) = &response.error {
+            warn!(
+                "Scatter-gather query failed on shard {}: {}",
+                shard, error.message
+            );
+
+            if !best_effort {
+                return Err(failed_on_shard(*shard, error));
+            }
+
+            errors.push((*shard, error.clone()));
+            continue;
+        }
+
+        let shard_row_description = match &response.row_description {
+            Some(row_description) => row_description,
+            None => return Err(query_error("scatter-gather query did not return rows")),
+        };
+
+        match &row_description {
+            Some(row_description) => {
+                let same_columns = match (columns(row_description), columns(shard_row_description))
+                {
+                    (Ok(expected), Ok(columns)) => expected == columns,
+                    _ => false,
+                };
+
+                if !same_columns {
+                    return Err(query_error(&format!(
+                        "shard {} returned different columns than the other shards",
+                        shard
+                    )));
+                }
+            }
+
+            None => row_description = Some(shard_row_description.clone()),
+        };
+    }
+
+    let row_description = match row_description {
+        Some(row_description) => row_description,
+        None => {
+            let (shard, error) = &errors[0];
+            return Err(failed_on_shard(*shard, error));
+        }
+    };
+
+    for (shard, error) in errors {
+        result.put(failed_shard_notice(shard, &error));
+    }
+
+    result.put(row_description);
+
+    Ok(result)
+}
+
+/// Filter messages returned by a shard after its RowDescription: its rows and notices are
+/// forwarded to the client, counted in `row_count`. Returns them, and the shard's error if it failed.
+pub fn rows(
+    messages: BytesMut,
+    row_count: &mut usize,
+) -> Result<(BytesMut, Option<ShardError>), Error> {
+    let mut rows = BytesMut::new();
+    let mut error = None;
+
+    for message in split_messages(messages)? {
+        match message[0] as char {
+            'D' if error.is_none() => {
+                rows.put(message);
+                *row_count += 1;
+            }
+
+            'N' => rows.put(message),
+
+            'E' => error = Some(ShardError::from_response(&message)?),
+
+            // CommandComplete is rebuilt for all the shards,
+            // and ReadyForQuery is always idle, we're not in a transaction.
+            _ => (),
+        }
+    }
+
+    Ok((rows, error))
+}
+
+/// The end of the result set, once the rows of all the shards were sent.
+pub fn end(row_count: usize) -> BytesMut {
+    let mut end = command_complete(&format!("SELECT {}", row_count));
+    end.put(ready_for_query(false));
+    end
+}
+
+/// The query failed on a shard, for the client.
+pub fn failed_on_shard(shard: usize, error: &ShardError) -> ShardError {
+    ShardError {
+        code: error.code.clone(),
+        message: format!("query failed on shard {}: {}", shard, error.message),
+    }
+}
+
+/// The query failed on a shard, with `scatter_gather_best_effort`.
+pub fn failed_shard_notice(shard: usize, error: &ShardError) -> BytesMut {
+    notify(
+        &format!("scatter-gather query failed on shard {}", shard),
+        error.message.clone(),
+    )
+}
+
+fn query_error(message: &str) -> ShardError {
+    ShardError {
+        code: crate::constants::SYSTEM_ERROR.to_string(),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::{data_row, row_description, DataType};
+
+    fn error_response(message: &str, code: &str) -> BytesMut {
+        let fields = format!("SERROR\0VERROR\0C{}\0M{}\0\0", code, message);
+        let mut error = BytesMut::new();
+        error.put_u8(b'E');
+        error.put_i32(fields.len() as i32 + 4);
+        error.put_slice(fields.as_bytes());
+        error
+    }
+
+    fn shard_response(columns: &Vec<(&str, DataType)>, rows: &[&str]) -> (ShardResponse, BytesMut) {
+        let mut messages = row_description(columns);
+
+        for row in rows {
+            messages.put(data_row(&vec![row.to_string()]));
+        }
+
+        messages.put(command_complete(&format!("SELECT {}", rows.len())));
+        messages.put(ready_for_query(false));
+
+        let mut response = ShardResponse::default();
+        assert!(response.read(messages).unwrap());
+        let rest = response.take_rest();
+
+        (response, rest)
+    }
+
+    #[test]
+    fn test_split_messages() {
+        let mut messages = data_row(&vec!["1".to_string()]);
+        messages.put(ready_for_query(false));
+        assert_eq!(split_messages(messages).unwrap().len(), 2);
+
+        for truncated in [
+            &b"D\x00\x00\x00\x10"[..],
+            b"D\xff\xff\xff\xff",
+            b"D\x00\x00",
+        ] {
+            assert!(split_messages(BytesMut::from(truncated)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_scatter_gather() {
+        let columns = vec![("id", DataType::Text)];
+
+        let (first, first_rows) = shard_response(&columns, &["1", "2"]);
+        let (second, second_rows) = shard_response(&columns, &["3"]);
+        let mut responses = vec![(0, first), (1, second)];
+
+        assert_eq!(
+            start(&mut responses, false).unwrap(),
+            row_description(&columns)
+        );
+
+        let mut row_count = 0;
+        let mut merged = BytesMut::new();
+        for rest in [first_rows, second_rows] {
+            let (rows, error) = rows(rest, &mut row_count).unwrap();
+            assert_eq!(error, None);
+            merged.put(rows);
+        }
+        merged.put(end(row_count));
+
+        let mut expected = BytesMut::new();
+        for row in ["1", "2", "3"] {
+            expected.put(data_row(&vec![row.to_string()]));
+        }
+        expected.put(command_complete("SELECT 3"));
+        expected.put(ready_for_query(false));
+        assert_eq!(merged, expected);
+
+        // Different columns
+        let mut responses = vec![
+            (0, shard_response(&columns, &["1"]).0),
+            (2, shard_response(&vec![("id", DataType::Int4)], &["2"]).0),
+        ];
+        assert_eq!(
+            start(&mut responses, false).unwrap_err().message,
+            "shard 2 returned different columns than the other shards"
+        );
+
+        // Partial failure
+        let error = ShardError::from_error(&Error::AllServersDown, ErrorVerbosity::Verbose);
+        let responses = || {
+            vec![
+                (0, shard_response(&columns, &["1"]).0),
+                (1, ShardResponse::error(error.clone())),
+            ]
+        };
+        let failed = start(&mut responses(), false).unwrap_err();
+        assert_eq!(failed.code, CONNECTION_FAILURE);
+        assert_eq!(failed.message, "query failed on shard 1: AllServersDown");
+
+        let started = start(&mut responses(), true).unwrap();
+        assert_eq!(started[0], b'N');
+        assert!(started.ends_with(&row_description(&columns)));
+
+        // The details of pooler errors are hidden like the ones of the servers.
+        assert_eq!(
+            ShardError::from_error(&Error::AllServersDown, ErrorVerbosity::Sanitized).message,
+            "connection exception"
+        );
+
+        // A shard failing after sending rows.
+        let mut messages = data_row(&vec!["1".to_string()]);
+        messages.put(error_response("division by zero", "22012"));
+        messages.put(ready_for_query(false));
+        let mut row_count = 0;
+        let (forwarded, error) = rows(messages, &mut row_count).unwrap();
+        assert_eq!(row_count, 1);
+        assert_eq!(forwarded, data_row(&vec!["1".to_string()]));
+        assert_eq!(
+            error,
+            Some(ShardError {
+                code: "22012".into(),
+                message: "division by zero".into()
+            })
+        );
+    }
+}
@@ -42,18 +42,7 @@ impl ScramSha256 {
     /// Create the Scram state from a password. It'll automatically
     /// generate a nonce.
     pub fn new(password: &str) -> ScramSha256 {
-        let mut rng = rand::thread_rng();
-        let nonce = (0..NONCE_LENGTH)
-            .map(|_| {
-                let mut v = rng.gen_range(0x21u8..0x7e);
-                if v == 0x2c {
-                    v = 0x7e
-                }
-                v as char
-            })
-            .collect::<String>();
-
-        Self::from_nonce(password, &nonce)
+        Self::from_nonce(password, &generate_nonce())
     }
 
     /// Used for testing.
@@ -214,6 +203,281 @@ impl ScramSha256 {
     }
 }
 
+/// Default number of PBKDF2 iterations used when we have to derive
+/// SCRAM keys from a cleartext password ourselves. Matches Postgres.
+const SCRAM_DEFAULT_ITERATIONS: u32 = 4096;
+
+/// Default salt length used when deriving SCRAM keys from a cleartext password.
+const SCRAM_DEFAULT_SALT_LENGTH: usize = 16;
+
+/// Stored SCRAM-SHA-256 secret, as found in `pg_authid.rolpassword`:
+/// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScramVerifier {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramVerifier {
+    /// Check if the password looks like a SCRAM verifier instead of a cleartext password.
+    pub fn is_verifier(password: &str) -> bool {
+        password.starts_with(&format!("{}$", SCRAM_SHA_256))
+    }
+
+    /// Parse a SCRAM verifier string.
+    pub fn parse(verifier: &str) -> Result<ScramVerifier, Error> {
+        let bad_verifier = || Error::AuthError("invalid SCRAM-SHA-256 verifier".into());
+
+        let parts = verifier.split('$').collect::<Vec<&str>>();
+
+        if parts.len() != 3 || parts[0] != SCRAM_SHA_256 {
+            return Err(bad_verifier());
+        }
+
+        let (iterations, salt) = parts[1].split_once(':').ok_or_else(bad_verifier)?;
+        let (stored_key, server_key) = parts[2].split_once(':').ok_or_else(bad_verifier)?;
+
+        let iterations = iterations.parse::<u32>().map_err(|_| bad_verifier())?;
+        let salt = general_purpose::STANDARD
+            .decode(salt)
+            .map_err(|_| bad_verifier())?;
+        let stored_key = general_purpose::STANDARD
+            .decode(stored_key)
+            .map_err(|_| bad_verifier())?;
+        let server_key = general_purpose::STANDARD
+            .decode(server_key)
+            .map_err(|_| bad_verifier())?;
+
+        if iterations == 0 || stored_key.len() != 32 || server_key.len() != 32 {
+            return Err(bad_verifier());
+        }
+
+        Ok(ScramVerifier {
+            iterations,
+            salt,
+            stored_key,
+            server_key,
+        })
+    }
+
+    /// Derive the verifier from a cleartext password.
+    pub fn from_password(password: &str, salt: &[u8], iterations: u32) -> ScramVerifier {
+        let salted_password = ScramSha256::hi(&normalize(password.as_bytes()), salt, iterations);
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&salted_password)
+            .expect("HMAC is able to accept all key sizes");
+        hmac.update(b"Client Key");
+        let client_key = hmac.finalize().into_bytes();
+
+        let mut hash = Sha256::default();
+        hash.update(client_key.as_slice());
+        let stored_key = hash.finalize_fixed();
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&salted_password)
+            .expect("HMAC is able to accept all key sizes");
+        hmac.update(b"Server Key");
+        let server_key = hmac.finalize().into_bytes();
+
+        ScramVerifier {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: stored_key.to_vec(),
+            server_key: server_key.to_vec(),
+        }
+    }
+}
+
+impl std::fmt::Display for ScramVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}:{}${}:{}",
+            SCRAM_SHA_256,
+            self.iterations,
+            general_purpose::STANDARD.encode(&self.salt),
+            general_purpose::STANDARD.encode(&self.stored_key),
+            general_purpose::STANDARD.encode(&self.server_key),
+        )
+    }
+}
+
+/// Server side of the SCRAM-SHA-256 exchange, used to authenticate clients.
+/// It takes the client-first and the client-final messages to complete the authentication.
+pub struct ScramServer {
+    verifier: ScramVerifier,
+    nonce: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+}
+
+impl ScramServer {
+    /// Create the server state from the configured password, which can either
+    /// be in cleartext or a SCRAM verifier. It'll automatically generate a nonce.
+    pub fn new(password: &str) -> Result<ScramServer, Error> {
+        let verifier = if ScramVerifier::is_verifier(password) {
+            ScramVerifier::parse(password)?
+        } else {
+            let salt: [u8; SCRAM_DEFAULT_SALT_LENGTH] = rand::random();
+            ScramVerifier::from_password(password, &salt, SCRAM_DEFAULT_ITERATIONS)
+        };
+
+        Ok(Self::from_nonce(verifier, &generate_nonce()))
+    }
+
+    /// Used for testing.
+    pub fn from_nonce(verifier: ScramVerifier, nonce: &str) -> ScramServer {
+        ScramServer {
+            verifier,
+            nonce: String::from(nonce),
+            gs2_header: String::new(),
+            client_first_bare: String::new(),
+            server_first: String::new(),
+        }
+    }
+
+    /// Handle the client-first message and produce the server-first message.
+    pub fn server_first(&mut self, message: &[u8]) -> Result<BytesMut, Error> {
+        let message = String::from_utf8_lossy(message);
+
+        // gs2-header: channel binding flag and authzid, followed by the bare message.
+        let mut parts = message.splitn(3, ',');
+        let cbind_flag = parts.next().unwrap_or("");
+        let _authzid = parts.next();
+        let client_first_bare = match parts.next() {
+            Some(bare) => bare,
+            None => return Err(Error::ProtocolSyncError("SCRAM".to_string())),
+        };
+
+        // We don't advertise SCRAM-SHA-256-PLUS, so channel binding can't be used.
+        if cbind_flag.starts_with("p=") {
+            return Err(Error::AuthError(
+                "SCRAM channel binding is not supported".into(),
+            ));
+        }
+
+        let client_nonce = match client_first_bare
+            .split(',')
+            .find_map(|attr| attr.strip_prefix("r="))
+        {
+            Some(nonce) if !nonce.is_empty() => nonce,
+            _ => return Err(Error::ProtocolSyncError("SCRAM".to_string())),
+        };
+
+        self.nonce = format!("{}{}", client_nonce, self.nonce);
+        self.gs2_header = message[..message.len() - client_first_bare.len()].to_string();
+        self.client_first_bare = client_first_bare.to_string();
+        self.server_first = format!(
+            "r={},s={},i={}",
+            self.nonce,
+            general_purpose::STANDARD.encode(&self.verifier.salt),
+            self.verifier.iterations
+        );
+
+        Ok(BytesMut::from(self.server_first.as_bytes()))
+    }
+
+    /// Verify the client-final message and produce the server-final message.
+    pub fn server_final(&mut self, message: &[u8]) -> Result<BytesMut, Error> {
+        let message = String::from_utf8_lossy(message);
+
+        let (client_final_without_proof, proof) = match message.rsplit_once(",p=") {
+            Some(parts) => parts,
+            None => return Err(Error::ProtocolSyncError("SCRAM".to_string())),
+        };
+
+        let nonce = client_final_without_proof
+            .split(',')
+            .find_map(|attr| attr.strip_prefix("r="));
+
+        if nonce != Some(self.nonce.as_str()) {
+            return Err(Error::ProtocolSyncError("SCRAM".to_string()));
+        }
+
+        // The channel binding must repeat the gs2-header the client sent first.
+        let channel_binding = client_final_without_proof
+            .split(',')
+            .find_map(|attr| attr.strip_prefix("c="));
+
+        if channel_binding != Some(general_purpose::STANDARD.encode(&self.gs2_header).as_str()) {
+            return Err(Error::AuthError(
+                "SCRAM channel binding does not match".into(),
+            ));
+        }
+
+        let proof = match general_purpose::STANDARD.decode(proof) {
+            Ok(proof) => proof,
+            Err(_) => return Err(Error::ProtocolSyncError("SCRAM".to_string())),
+        };
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, client_final_without_proof
+        );
+
+        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.verifier.stored_key) {
+            Ok(hmac) => hmac,
+            Err(_) => return Err(Error::ServerError),
+        };
+        hmac.update(auth_message.as_bytes());
+        let client_signature = hmac.finalize().into_bytes();
+
+        if proof.len() != client_signature.len() {
+            return Err(Error::AuthError("invalid SCRAM client proof".into()));
+        }
+
+        // Recover the client key from the proof and check it hashes to the stored key.
+        let client_key = proof
+            .iter()
+            .zip(client_signature)
+            .map(|(proof, signature)| proof ^ signature)
+            .collect::<Vec<u8>>();
+
+        let mut hash = Sha256::default();
+        hash.update(&client_key);
+
+        if !constant_time_eq(hash.finalize_fixed().as_slice(), &self.verifier.stored_key) {
+            return Err(Error::AuthError("invalid SCRAM client proof".into()));
+        }
+
+        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.verifier.server_key) {
+            Ok(hmac) => hmac,
+            Err(_) => return Err(Error::ServerError),
+        };
+        hmac.update(auth_message.as_bytes());
+        let server_signature = hmac.finalize().into_bytes();
+
+        Ok(BytesMut::from(
+            format!(
+                "v={}",
+                general_purpose::STANDARD.encode(server_signature.as_slice())
+            )
+            .as_bytes(),
+        ))
+    }
+}
+
+/// Compare two byte slices without exiting early on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Generate a printable nonce, excluding the comma.
+fn generate_nonce() -> String {
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LENGTH)
+        .map(|_| {
+            let mut v = rng.gen_range(0x21u8..0x7e);
+            if v == 0x2c {
+                v = 0x7e
+            }
+            v as char
+        })
+        .collect::<String>()
+}
+
 /// Parse the server challenge.
 struct Message {
     nonce: String,
@@ -322,4 +586,89 @@ mod test {
             .finish(&BytesMut::from(server_final.as_bytes()))
             .unwrap();
     }
+
+    // Same recorded exchange as above, but with pgcat playing the server.
+    #[test]
+    fn server_exchange() {
+        let client_first = "n,,n=,r=9IZ2O01zb9IgiIZ1WJ/zgpJB";
+        let server_first =
+            "r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,s=fs3IXBy7U7+IvVjZ,i\
+             =4096";
+        let client_final =
+            "c=biws,r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,p=AmNKosjJzS3\
+             1NTlQYNs5BTeQjdHdk7lOflDo5re2an8=";
+        let server_final = "v=U+ppxD5XUKtradnv8e2MkeupiA8FU87Sg8CXzXHDAzw=";
+
+        let salt = general_purpose::STANDARD
+            .decode("fs3IXBy7U7+IvVjZ")
+            .unwrap();
+        let verifier = ScramVerifier::from_password("foobar", &salt, 4096);
+
+        // Round trip through the verifier string format.
+        let verifier = ScramVerifier::parse(&verifier.to_string()).unwrap();
+
+        let mut scram = ScramServer::from_nonce(verifier.clone(), "jx/oIRLs02gGSHcw1KEty3eY");
+
+        let result = scram.server_first(client_first.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_first);
+
+        let result = scram.server_final(client_final.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_final);
+
+        // Wrong password.
+        let mut scram = ScramServer::from_nonce(
+            ScramVerifier::from_password("barfoo", &salt, 4096),
+            "jx/oIRLs02gGSHcw1KEty3eY",
+        );
+        scram.server_first(client_first.as_bytes()).unwrap();
+        assert!(scram.server_final(client_final.as_bytes()).is_err());
+
+        // Channel binding that doesn't match the gs2-header ("y,," instead of "n,,").
+        let mut scram = ScramServer::from_nonce(verifier.clone(), "jx/oIRLs02gGSHcw1KEty3eY");
+        scram.server_first(client_first.as_bytes()).unwrap();
+        assert_eq!(
+            scram.server_final(client_final.replacen("c=biws", "c=eSws", 1).as_bytes()),
+            Err(Error::AuthError(
+                "SCRAM channel binding does not match".into()
+            ))
+        );
+
+        // Channel binding missing altogether.
+        let mut scram = ScramServer::from_nonce(verifier, "jx/oIRLs02gGSHcw1KEty3eY");
+        scram.server_first(client_first.as_bytes()).unwrap();
+        assert!(scram
+            .server_final(client_final.replacen("c=biws,", "", 1).as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn constant_time_compare() {
+        assert!(constant_time_eq(b"stored", b"stored"));
+        assert!(!constant_time_eq(b"stored", b"storeD"));
+        assert!(!constant_time_eq(b"stored", b"store"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn client_server_exchange() {
+        let mut client = ScramSha256::new("hunter2");
+        let mut server = ScramServer::new("hunter2").unwrap();
+
+        let server_first = server.server_first(&client.message()).unwrap();
+        let client_final = client.update(&server_first).unwrap();
+        let server_final = server.server_final(&client_final).unwrap();
+        client.finish(&server_final).unwrap();
+    }
+
+    #[test]
+    fn parse_verifier() {
+        assert!(ScramVerifier::is_verifier(
+            "SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$a:b"
+        ));
+        assert!(!ScramVerifier::is_verifier(
+            "md5a3556571e93b0d20722ba62be61e8c2d"
+        ));
+        assert!(ScramVerifier::parse("SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ$a:b").is_err());
+        assert!(ScramVerifier::parse("SCRAM-SHA-256$abc").is_err());
+    }
 }
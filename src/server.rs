@@ -9,16 +9,22 @@ use parking_lot::{Mutex, RwLock};
 use postgres_protocol::message;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufStream};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufStream, ReadBuf};
 use tokio::net::TcpStream;
 use tokio_rustls::rustls::{OwnedTrustAnchor, RootCertStore};
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
-use crate::config::{get_config, Address, User};
+use crate::config::{
+    get_config, get_error_verbosity, get_max_buffer_size, Address, ErrorVerbosity, General, User,
+};
 use crate::constants::*;
 use crate::dns_cache::{AddrSet, CACHED_RESOLVER};
 use crate::errors::{Error, ServerIdentifier};
@@ -28,6 +34,7 @@ use crate::mirrors::MirroringManager;
 use crate::pool::ClientServerMap;
 use crate::scram::ScramSha256;
 use crate::stats::ServerStats;
+use crate::tls::load_certs;
 use std::io::Write;
 
 use pin_project::pin_project;
@@ -114,6 +121,9 @@ struct CleanupState {
 
     /// If server connection requires DEALLOCATE ALL before checkin because of prepare statement
     needs_cleanup_prepare: bool,
+
+    /// If server connection requires UNLISTEN * before checkin because of listen statement
+    needs_cleanup_listen: bool,
 }
 
 impl CleanupState {
@@ -121,21 +131,24 @@ impl CleanupState {
         CleanupState {
             needs_cleanup_set: false,
             needs_cleanup_prepare: false,
+            needs_cleanup_listen: false,
         }
     }
 
     fn needs_cleanup(&self) -> bool {
-        self.needs_cleanup_set || self.needs_cleanup_prepare
+        self.needs_cleanup_set || self.needs_cleanup_prepare || self.needs_cleanup_listen
     }
 
     fn set_true(&mut self) {
         self.needs_cleanup_set = true;
         self.needs_cleanup_prepare = true;
+        self.needs_cleanup_listen = true;
     }
 
     fn reset(&mut self) {
         self.needs_cleanup_set = false;
         self.needs_cleanup_prepare = false;
+        self.needs_cleanup_listen = false;
     }
 }
 
@@ -143,8 +156,8 @@ impl std::fmt::Display for CleanupState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "SET: {}, PREPARE: {}",
-            self.needs_cleanup_set, self.needs_cleanup_prepare
+            "SET: {}, PREPARE: {}, LISTEN: {}",
+            self.needs_cleanup_set, self.needs_cleanup_prepare, self.needs_cleanup_listen
         )
     }
 }
@@ -159,6 +172,71 @@ static TRACKED_PARAMETERS: Lazy<HashSet<String>> = Lazy::new(|| {
     set
 });
 
+/// Parameters clients can set in their startup message which Postgres doesn't report
+/// with ParameterStatus, so we keep track of the values we set on the server ourselves.
+static SESSION_PARAMETERS: Lazy<HashSet<String>> = Lazy::new(|| {
+    let mut set = HashSet::new();
+    set.insert("search_path".to_string());
+    set.insert("extra_float_digits".to_string());
+    set.insert("statement_timeout".to_string());
+    set.insert("lock_timeout".to_string());
+    set.insert(BACKEND_CLIENT_ADDR.to_string());
+    set
+});
+
+/// Setting holding the address of the client using the server, see `general.set_backend_client_addr`.
+pub const BACKEND_CLIENT_ADDR: &str = "pgcat.client_addr";
+
+/// Split the `options` startup parameter, e.g. `-c search_path=foo --lock_timeout=1s`,
+/// into parameter names and values. Spaces can be escaped with a backslash, like Postgres does.
+pub fn parse_startup_options(options: &str) -> Vec<(String, String)> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut chars = options.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => word.extend(chars.next()),
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    let mut parameters = Vec::new();
+    let mut words = words.into_iter();
+
+    while let Some(word) = words.next() {
+        let parameter = if word == "-c" {
+            words.next()
+        } else if let Some(parameter) = word.strip_prefix("-c") {
+            Some(parameter.to_string())
+        } else if let Some(parameter) = word.strip_prefix("--") {
+            Some(parameter.replace('-', "_"))
+        } else {
+            debug!("Ignoring startup option {}", word);
+            None
+        };
+
+        match parameter
+            .as_ref()
+            .and_then(|parameter| parameter.split_once('='))
+        {
+            Some((key, value)) => parameters.push((key.to_string(), value.to_string())),
+            None => debug!("Ignoring startup option {:?}", parameter),
+        }
+    }
+
+    parameters
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerParameters {
     parameters: HashMap<String, String>,
@@ -211,8 +289,52 @@ impl ServerParameters {
         }
     }
 
-    // Gets the diff of the parameters
-    fn compare_params(&self, incoming_parameters: &ServerParameters) -> HashMap<String, String> {
+    /// Set the parameters a client sent in its startup message, including the ones
+    /// in `options`. The ones we can't set on the server connections are dropped.
+    pub fn set_from_startup_parameters(&mut self, parameters: &HashMap<String, String>) {
+        for (key, value) in parameters {
+            match key.as_str() {
+                // Used to connect, not settings.
+                "user" | "database" | "replication" => (),
+
+                "options" => {
+                    for (key, value) in parse_startup_options(value) {
+                        self.set_client_param(key, value);
+                    }
+                }
+
+                _ => self.set_client_param(key.to_string(), value.to_string()),
+            }
+        }
+    }
+
+    fn set_client_param(&mut self, key: String, value: String) {
+        // Parameter names are case insensitive.
+        let key = key.to_lowercase();
+
+        if SESSION_PARAMETERS.contains(&key) {
+            self.parameters.insert(key, value);
+        } else if TRACKED_PARAMETERS
+            .iter()
+            .any(|tracked| tracked.eq_ignore_ascii_case(&key))
+        {
+            self.set_param(key, value, false);
+        } else {
+            debug!("Dropping unsupported startup parameter {}", key);
+        }
+    }
+
+    /// Forget the session parameters, after the server state was reset.
+    fn reset_session_params(&mut self) {
+        self.parameters
+            .retain(|key, _| !SESSION_PARAMETERS.contains(key));
+    }
+
+    // Gets the diff of the parameters, None means the parameter should be reset.
+    fn compare_params(
+        &self,
+        incoming_parameters: &ServerParameters,
+    ) -> HashMap<String, Option<String>> {
         let mut diff = HashMap::new();
 
         // iterate through tracked parameters
@@ -220,12 +342,21 @@ impl ServerParameters {
             if let Some(incoming_value) = incoming_parameters.parameters.get(key) {
                 if let Some(value) = self.parameters.get(key) {
                     if value != incoming_value {
-                        diff.insert(key.to_string(), incoming_value.to_string());
+                        diff.insert(key.to_string(), Some(incoming_value.to_string()));
                     }
                 }
             }
         }
 
+        // Session parameters are only known if we set them.
+        for key in SESSION_PARAMETERS.iter() {
+            let incoming_value = incoming_parameters.parameters.get(key);
+
+            if self.parameters.get(key) != incoming_value {
+                diff.insert(key.to_string(), incoming_value.cloned());
+            }
+        }
+
         diff
     }
 
@@ -291,6 +422,12 @@ pub struct Server {
     /// Is the server in copy-in or copy-out modes
     in_copy_mode: bool,
 
+    /// Did the server return an error to the last request sent to it.
+    query_failed: bool,
+
+    /// The backend reported it's shutting down (57P01), e.g. it was terminated by an admin.
+    admin_shutdown: bool,
+
     /// Is the server broken? We'll remote it from the pool if so.
     bad: bool,
 
@@ -344,6 +481,7 @@ impl Server {
         cleanup_connections: bool,
         log_client_parameter_status_changes: bool,
         prepared_statement_cache_size: usize,
+        replication: Option<&str>,
     ) -> Result<Server, Error> {
         let cached_resolver = CACHED_RESOLVER.load();
         let mut addr_set: Option<AddrSet> = None;
@@ -363,17 +501,31 @@ impl Server {
             }
         };
 
-        let mut stream =
-            match TcpStream::connect(&format!("{}:{}", &address.host, address.port)).await {
-                Ok(stream) => stream,
-                Err(err) => {
-                    error!("Could not connect to server: {}", err);
-                    return Err(Error::SocketError(format!(
-                        "Could not connect to server: {}",
-                        err
-                    )));
-                }
-            };
+        // Connect to the addresses in the cache, so the connection is dropped when they change.
+        // Every A record is a candidate, they are tried in turn until one accepts the connection.
+        let candidates: Vec<SocketAddr> = match addr_set {
+            Some(ref addr_set) => addr_set
+                .addresses()
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, address.port))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let host = format!("{}:{}", &address.host, address.port);
+        let retries = ConnectRetries {
+            timeout: Duration::from_millis(address.connect_timeout),
+            ..ConnectRetries::from_config(&get_config().general)
+        };
+
+        let mut stream = connect_with_retries(address, retries, || async {
+            if candidates.is_empty() {
+                TcpStream::connect(host.as_str()).await
+            } else {
+                TcpStream::connect(&candidates[..]).await
+            }
+        })
+        .await?;
 
         // TCP timeouts.
         configure_socket(&stream);
@@ -400,22 +552,49 @@ impl Server {
                     debug!("Connecting to server using TLS");
 
                     let mut root_store = RootCertStore::empty();
-                    root_store.add_server_trust_anchors(
-                        webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-                            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                                ta.subject,
-                                ta.spki,
-                                ta.name_constraints,
-                            )
-                        }),
-                    );
+
+                    // Trust only the configured CA if there is one,
+                    // otherwise fall back to the public roots.
+                    match config.general.server_tls_ca_certificate {
+                        Some(ref ca_certificate) => {
+                            let certs = match load_certs(Path::new(ca_certificate)) {
+                                Ok(certs) => certs,
+                                Err(err) => {
+                                    error!(
+                                        "Could not load server_tls_ca_certificate {}: {:?}",
+                                        ca_certificate, err
+                                    );
+                                    return Err(Error::TlsError);
+                                }
+                            };
+
+                            for cert in certs {
+                                if let Err(err) = root_store.add(&cert) {
+                                    error!("Invalid server TLS CA certificate: {:?}", err);
+                                    return Err(Error::TlsError);
+                                }
+                            }
+                        }
+
+                        None => {
+                            root_store.add_server_trust_anchors(
+                                webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                                        ta.subject,
+                                        ta.spki,
+                                        ta.name_constraints,
+                                    )
+                                }),
+                            );
+                        }
+                    };
 
                     let mut tls_config = rustls::ClientConfig::builder()
                         .with_safe_defaults()
                         .with_root_certificates(root_store)
                         .with_no_client_auth();
 
-                    // Equivalent to sslmode=prefer which is fine most places.
+                    // Equivalent to sslmode=require which is fine most places.
                     // If you want verify-full, change `verify_server_certificate` to true.
                     if !config.general.verify_server_certificate {
                         let mut dangerous = tls_config.dangerous();
@@ -439,7 +618,16 @@ impl Server {
                 }
 
                 // Server does not support TLS
-                'N' => StreamInner::Plain { stream },
+                'N' => {
+                    error!(
+                        "Server {} rejected the TLS request, but server_tls is enabled",
+                        address.name()
+                    );
+                    return Err(Error::SocketError(format!(
+                        "Server {} does not support TLS, disable server_tls to connect without it",
+                        address.name()
+                    )));
+                }
 
                 // Something else?
                 m => {
@@ -466,7 +654,7 @@ impl Server {
             None => user.password.as_ref(),
         };
 
-        startup(&mut stream, username, database).await?;
+        startup(&mut stream, username, database, replication).await?;
 
         let mut process_id: i32 = 0;
         let mut secret_key: i32 = 0;
@@ -563,6 +751,21 @@ impl Server {
                             }
                         }
 
+                        CLEARTEXT_PASSWORD => match password {
+                            Some(password) => cleartext_password(&mut stream, password).await?,
+
+                            // A hash obtained with auth passthrough can't be used here.
+                            None => {
+                                return Err(Error::ServerAuthError(
+                                    "Cleartext auth required and no password specified. \
+                                    Auth passthrough (auth_query) method is unsupported \
+                                    for cleartext auth"
+                                        .into(),
+                                    server_identifier,
+                                ))
+                            }
+                        },
+
                         AUTHENTICATION_SUCCESSFUL => (),
 
                         SASL => {
@@ -707,9 +910,11 @@ impl Server {
 
                         // An error message will be present.
                         _ => {
-                            let mut error = vec![0u8; len as usize];
+                            // The rest of the message, after the type of its first field we just read.
+                            let mut error = vec![0u8; len as usize - mem::size_of::<i32>()];
+                            error[0] = error_code;
 
-                            match stream.read_exact(&mut error).await {
+                            match stream.read_exact(&mut error[1..]).await {
                                 Ok(_) => (),
                                 Err(_) => {
                                     return Err(Error::ServerStartupError(
@@ -726,7 +931,39 @@ impl Server {
                                 }
                             };
                             trace!("error fields: {}", &fields);
-                            error!("server error: {}: {}", fields.severity, fields.message);
+
+                            match fields.code.as_str() {
+                                // The server doesn't accept our credentials, retrying won't help.
+                                INVALID_PASSWORD | INVALID_AUTHORIZATION_SPECIFICATION => {
+                                    error!(
+                                        "Server {} rejected the login: {}: {}, check the credentials of user {} in the config",
+                                        address, fields.severity, fields.message, user.username
+                                    );
+                                    return Err(Error::ServerAuthError(
+                                        format!("server login failed: {}", fields.message),
+                                        server_identifier,
+                                    ));
+                                }
+
+                                // The server is full for now, we can try again later.
+                                TOO_MANY_CONNECTIONS => {
+                                    warn!(
+                                        "Server {} has too many connections: {}",
+                                        address, fields.message
+                                    );
+                                    return Err(Error::ServerConnectionLimit(
+                                        format!(
+                                            "server connection limit reached: {}",
+                                            fields.message
+                                        ),
+                                        server_identifier,
+                                    ));
+                                }
+
+                                _ => {
+                                    error!("server error: {}: {}", fields.severity, fields.message)
+                                }
+                            }
                         }
                     };
 
@@ -780,6 +1017,8 @@ impl Server {
                             ))
                         }
                     };
+
+                    stats.backend_pid.store(process_id, Ordering::Relaxed);
                 }
 
                 // ReadyForQuery
@@ -805,6 +1044,8 @@ impl Server {
                         secret_key,
                         in_transaction: false,
                         in_copy_mode: false,
+                        query_failed: false,
+                        admin_shutdown: false,
                         data_available: false,
                         bad: false,
                         cleanup_state: CleanupState::new(),
@@ -857,12 +1098,24 @@ impl Server {
         process_id: i32,
         secret_key: i32,
     ) -> Result<(), Error> {
-        let mut stream = match TcpStream::connect(&format!("{}:{}", host, port)).await {
-            Ok(stream) => stream,
-            Err(err) => {
+        // Don't let an unreachable server hold up the cancelling client.
+        let connect_timeout = Duration::from_millis(get_config().general.connect_timeout);
+
+        let mut stream = match tokio::time::timeout(
+            connect_timeout,
+            TcpStream::connect(&format!("{}:{}", host, port)),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
                 error!("Could not connect to server: {}", err);
                 return Err(Error::SocketError("Error reading cancel message".into()));
             }
+            Err(_) => {
+                error!("Timed out connecting to server {}:{} to cancel", host, port);
+                return Err(Error::SocketError("Error reading cancel message".into()));
+            }
         };
         configure_socket(&stream);
 
@@ -886,6 +1139,7 @@ impl Server {
             Ok(_) => {
                 // Successfully sent to server
                 self.last_activity = SystemTime::now();
+                self.query_failed = false;
                 Ok(())
             }
             Err(err) => {
@@ -906,6 +1160,8 @@ impl Server {
         &mut self,
         mut client_server_parameters: Option<&mut ServerParameters>,
     ) -> Result<BytesMut, Error> {
+        let max_buffer_size = get_max_buffer_size();
+
         loop {
             let mut message = match read_message(&mut self.stream).await {
                 Ok(message) => message,
@@ -915,12 +1171,31 @@ impl Server {
                         self.address, err
                     );
                     self.bad = true;
+                    self.query_failed = true;
+
+                    // The client sent nothing wrong, don't reject it for it.
+                    if let Error::MessageTooLarge(_) = err {
+                        return Err(Error::ServerMessageParserError(err.to_string()));
+                    }
+
                     return Err(err);
                 }
             };
 
-            // Buffer the message we'll forward to the client later.
-            self.buffer.put(&message[..]);
+            // Buffer the message we'll forward to the client later. Errors are logged in full
+            // before they're stripped of what the client can't see, see `error_verbosity`.
+            match get_error_verbosity() {
+                ErrorVerbosity::Verbose => self.buffer.put(&message[..]),
+                _ if message[0] != b'E' => self.buffer.put(&message[..]),
+                verbosity => {
+                    if let Ok(error) = PgErrorMsg::parse(&message[5..]) {
+                        info!("Server {} returned error: {}", self.address.name(), error);
+                    }
+
+                    self.buffer
+                        .put(sanitize_error_response(&message, verbosity));
+                }
+            }
 
             let code = message.get_u8() as char;
             let _len = message.get_i32();
@@ -929,6 +1204,9 @@ impl Server {
 
             match code {
                 // ReadyForQuery
+                // The transaction status is authoritative, we don't parse SQL to know
+                // if the client is in a transaction: savepoints, BEGIN in a multi-statement query
+                // and failed transactions are all reported here.
                 'Z' => {
                     let transaction_state = message.get_u8() as char;
 
@@ -943,7 +1221,8 @@ impl Server {
                             self.in_transaction = false;
                         }
 
-                        // Some error occurred, the transaction was rolled back.
+                        // Some error occurred, the transaction is aborted
+                        // and must be rolled back by the client.
                         'E' => {
                             self.in_transaction = true;
                         }
@@ -969,6 +1248,13 @@ impl Server {
                         self.in_copy_mode = false;
                     }
 
+                    self.address.stats.query_error();
+                    self.query_failed = true;
+
+                    if let Ok(error) = PgErrorMsg::parse(&message) {
+                        self.admin_shutdown = error.code == ADMIN_SHUTDOWN;
+                    }
+
                     // Remove the prepared statement from the cache, it has a syntax error or something else bad happened.
                     if let Some(prepared_stmt_name) =
                         self.registering_prepared_statement.pop_front()
@@ -1028,6 +1314,11 @@ impl Server {
                                     debug!("Server connection marked for clean up");
                                     self.cleanup_state.needs_cleanup_prepare = true;
                                 }
+
+                                "LISTEN" => {
+                                    debug!("Server connection marked for clean up");
+                                    self.cleanup_state.needs_cleanup_listen = true;
+                                }
                                 _ => (),
                             }
                         }
@@ -1058,7 +1349,9 @@ impl Server {
                     self.data_available = true;
 
                     // Don't flush yet, the more we buffer, the faster this goes...up to a limit.
-                    if self.buffer.len() >= 8196 {
+                    // The client gets the rows we have and we don't read more until it took them,
+                    // so a large result set or a slow client doesn't pile up in memory.
+                    if self.buffer.len() >= max_buffer_size {
                         break;
                     }
                 }
@@ -1079,7 +1372,7 @@ impl Server {
                 // CopyData
                 'd' => {
                     // Don't flush yet, buffer until we reach limit
-                    if self.buffer.len() >= 8196 {
+                    if self.buffer.len() >= max_buffer_size {
                         break;
                     }
                 }
@@ -1114,6 +1407,49 @@ impl Server {
         Ok(bytes)
     }
 
+    /// The client issued LISTEN on this connection and can receive notifications.
+    pub fn is_listening(&self) -> bool {
+        self.cleanup_state.needs_cleanup_listen
+    }
+
+    /// Wait until the server sends something while the client is idle,
+    /// e.g. a notification. Nothing is read, so this is cancel safe.
+    pub async fn wait_for_data(&mut self) -> Result<(), Error> {
+        match self.stream.fill_buf().await {
+            Ok(buf) if !buf.is_empty() => Ok(()),
+            Ok(_) => {
+                self.bad = true;
+                Err(Error::SocketError(format!(
+                    "Server {:?} closed the connection",
+                    self.address
+                )))
+            }
+            Err(err) => {
+                self.bad = true;
+                Err(Error::SocketError(format!(
+                    "Error reading from server {:?}: {:?}",
+                    self.address, err
+                )))
+            }
+        }
+    }
+
+    /// Receive a single message sent by the server outside of a query,
+    /// e.g. NotificationResponse, NoticeResponse or ParameterStatus.
+    pub async fn recv_async_message(&mut self) -> Result<BytesMut, Error> {
+        let message = match read_message(&mut self.stream).await {
+            Ok(message) => message,
+            Err(err) => {
+                self.bad = true;
+                return Err(err);
+            }
+        };
+
+        self.stats().data_received(message.len());
+
+        Ok(message)
+    }
+
     // Determines if the server already has a prepared statement with the given name
     // Increments the prepared statement cache hit counter
     pub fn has_prepared_statement(&mut self, name: &str) -> bool {
@@ -1213,6 +1549,11 @@ impl Server {
         }
     }
 
+    /// Process id and secret key to cancel the query running on this server.
+    pub fn cancel_key(&self) -> (i32, i32) {
+        (self.process_id, self.secret_key)
+    }
+
     /// If the server is still inside a transaction.
     /// If the client disconnects while the server is in a transaction, we will clean it up.
     pub fn in_transaction(&self) -> bool {
@@ -1225,6 +1566,20 @@ impl Server {
         self.in_copy_mode
     }
 
+    /// The server returned an error to the last request.
+    pub fn query_failed(&self) -> bool {
+        self.query_failed
+    }
+
+    /// SQLSTATE code describing why the connection to the server was lost.
+    pub fn connection_lost_code(&self) -> &'static str {
+        if self.admin_shutdown {
+            ADMIN_SHUTDOWN
+        } else {
+            CONNECTION_FAILURE
+        }
+    }
+
     /// We don't buffer all of server responses, e.g. COPY OUT produces too much data.
     /// The client is responsible to call `self.recv()` while this method returns true.
     pub fn is_data_available(&self) -> bool {
@@ -1267,14 +1622,36 @@ impl Server {
 
         let mut query = String::from("");
 
-        for (key, value) in parameter_diff {
-            query.push_str(&format!("SET {} TO '{}';", key, value));
+        for (key, value) in parameter_diff.iter() {
+            match value {
+                // set_config parses the value like a startup parameter, e.g. a list for search_path.
+                Some(value) if SESSION_PARAMETERS.contains(key) => query.push_str(&format!(
+                    "SELECT pg_catalog.set_config('{}', '{}', false);",
+                    key,
+                    value.replace('\'', "''")
+                )),
+                Some(value) => {
+                    query.push_str(&format!("SET {} TO '{}';", key, value.replace('\'', "''")))
+                }
+                None => query.push_str(&format!("RESET {};", key)),
+            }
         }
 
-        let res = self.query(&query).await;
+        let res = self.query_parameters(&query).await;
 
         self.cleanup_state.reset();
 
+        if res.is_ok() {
+            for (key, value) in parameter_diff {
+                if SESSION_PARAMETERS.contains(&key) {
+                    match value {
+                        Some(value) => self.server_parameters.parameters.insert(key, value),
+                        None => self.server_parameters.parameters.remove(&key),
+                    };
+                }
+            }
+        }
+
         res
     }
 
@@ -1286,6 +1663,8 @@ impl Server {
 
     /// Claim this server as mine for the purposes of query cancellation.
     pub fn claim(&mut self, process_id: i32, secret_key: i32) {
+        self.stats.linked(process_id);
+
         let mut guard = self.client_server_map.lock();
         guard.insert(
             (process_id, secret_key),
@@ -1319,9 +1698,101 @@ impl Server {
         Ok(())
     }
 
+    /// Run the queries setting the client's parameters. If the server rejects one,
+    /// e.g. a `client_encoding` it can't convert to, its error is returned so the client sees it.
+    async fn query_parameters(&mut self, query: &str) -> Result<(), Error> {
+        self.send(&simple_query(query)).await?;
+
+        let mut response = BytesMut::new();
+
+        loop {
+            response.put(self.recv(None).await?);
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        if !self.query_failed {
+            return Ok(());
+        }
+
+        while response.len() > mem::size_of::<u8>() + mem::size_of::<i32>() {
+            let code = response.get_u8();
+            let len = response.get_i32() as usize;
+            let body = response.split_to(len - mem::size_of::<i32>());
+
+            if code == b'E' {
+                let error = PgErrorMsg::parse(&body)?;
+                warn!(
+                    "Server {:?} rejected client parameters: {}",
+                    self.address, error.message
+                );
+
+                return Err(Error::ServerParameterError(error.code, error.message));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Execute a query returning a single row and get its values as text.
+    async fn query_row(&mut self, query: &str) -> Result<Vec<String>, Error> {
+        self.send(&simple_query(query)).await?;
+
+        let mut response = BytesMut::new();
+
+        loop {
+            response.put(self.recv(None).await?);
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        parse_query_message(&mut response).await
+    }
+
+    /// Check if the server is a standby, i.e. `pg_is_in_recovery()` is true.
+    pub async fn in_recovery(&mut self) -> Result<bool, Error> {
+        match self.query_row("SELECT pg_is_in_recovery()").await?.first() {
+            Some(value) if value == "t" => Ok(true),
+            Some(value) if value == "f" => Ok(false),
+            value => Err(Error::ServerMessageParserError(format!(
+                "Unexpected pg_is_in_recovery() result: {:?}",
+                value
+            ))),
+        }
+    }
+
+    /// How far behind the primary this replica is replaying (ms).
+    /// Caught up replicas and servers that are not in recovery report 0.
+    pub async fn replica_lag(&mut self) -> Result<u64, Error> {
+        let row = self
+            .query_row(
+                "SELECT CASE WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+                ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) * 1000, 0) \
+                END::bigint",
+            )
+            .await?;
+
+        match row.first().map(|value| value.parse::<i64>()) {
+            Some(Ok(lag)) => Ok(lag.max(0) as u64),
+            _ => Err(Error::ServerMessageParserError(format!(
+                "Unexpected replica lag result: {:?}",
+                row
+            ))),
+        }
+    }
+
     /// Perform any necessary cleanup before putting the server
     /// connection back in the pool
-    pub async fn checkin_cleanup(&mut self) -> Result<(), Error> {
+    pub async fn checkin_cleanup(&mut self, reset_query: Option<&str>) -> Result<(), Error> {
+        // The connection is broken and will be discarded, there is nothing to clean up.
+        if self.bad {
+            return Ok(());
+        }
+
         // Client disconnected with an open transaction on the server connection.
         // Pgbouncer behavior is to close the server connection but that can cause
         // server connection thrashing if clients repeatedly do this.
@@ -1342,6 +1813,11 @@ impl Server {
 
             if self.cleanup_state.needs_cleanup_set {
                 reset_string.push_str("RESET ALL;");
+                self.server_parameters.reset_session_params();
+            };
+
+            if self.cleanup_state.needs_cleanup_listen {
+                reset_string.push_str("UNLISTEN *;");
             };
 
             if self.cleanup_state.needs_cleanup_prepare {
@@ -1358,6 +1834,16 @@ impl Server {
 
         if self.in_copy_mode() {
             warn!(target: "pgcat::server::cleanup", "Server returned while still in copy-mode");
+        } else if let Some(reset_query) = reset_query.filter(|_| self.cleanup_connections) {
+            // Reset everything we can't track, e.g. SET in a transaction or temporary tables.
+            self.query(reset_query).await?;
+            self.cleanup_state.reset();
+            self.server_parameters.reset_session_params();
+
+            // DISCARD ALL deallocates the prepared statements as well.
+            if let Some(cache) = &mut self.prepared_statement_cache {
+                cache.clear();
+            }
         }
 
         Ok(())
@@ -1396,6 +1882,26 @@ impl Server {
         }
     }
 
+    /// Relay the bytes of a client talking directly to this server, e.g. a replication client,
+    /// until both of them close the connection. The server can't be reused afterwards.
+    pub async fn proxy<R, W>(&mut self, read: &mut R, write: &mut W) -> Result<(), Error>
+    where
+        R: AsyncRead + std::marker::Unpin,
+        W: AsyncWrite + std::marker::Unpin,
+    {
+        self.bad = true;
+
+        let mut client = ClientStream { read, write };
+
+        match tokio::io::copy_bidirectional(&mut client, &mut self.stream).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::SocketError(format!(
+                "Error relaying to server: {:?}",
+                err
+            ))),
+        }
+    }
+
     // This is so we can execute out of band queries to the server.
     // The connection will be opened, the query executed and closed.
     pub async fn exec_simple_query(
@@ -1416,6 +1922,7 @@ impl Server {
             true,
             false,
             0,
+            None,
         )
         .await?;
         debug!("Connected!, sending query.");
@@ -1426,6 +1933,46 @@ impl Server {
     }
 }
 
+/// The two halves of a client's connection as one stream, for `copy_bidirectional`.
+struct ClientStream<'a, R, W> {
+    read: &'a mut R,
+    write: &'a mut W,
+}
+
+impl<R, W> AsyncRead for ClientStream<'_, R, W>
+where
+    R: AsyncRead + std::marker::Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().read).poll_read(cx, buf)
+    }
+}
+
+impl<R, W> AsyncWrite for ClientStream<'_, R, W>
+where
+    W: AsyncWrite + std::marker::Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().write).poll_shutdown(cx)
+    }
+}
+
 async fn parse_query_message(message: &mut BytesMut) -> Result<Vec<String>, Error> {
     let mut pair = Vec::<String>::new();
     match message::backend::Message::parse(message) {
@@ -1535,3 +2082,228 @@ impl Drop for Server {
         );
     }
 }
+
+/// How to retry failed connection attempts to a server, see `connect_retries`.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectRetries {
+    pub retries: u32,
+    pub timeout: Duration,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ConnectRetries {
+    pub fn from_config(general: &General) -> ConnectRetries {
+        ConnectRetries {
+            retries: general.connect_retries,
+            timeout: Duration::from_millis(general.connect_timeout),
+            backoff: Duration::from_millis(general.connect_retry_backoff),
+            max_backoff: Duration::from_millis(general.connect_retry_max_backoff),
+        }
+    }
+
+    /// Time to wait before the given retry, doubling each time up to `max_backoff`.
+    fn backoff(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2_u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+
+    /// The longest it can take to connect, with every attempt timing out or
+    /// every retry waiting for its backoff.
+    pub fn total_timeout(&self) -> Duration {
+        (1..=self.retries).fold(self.timeout, |total, retry| {
+            total
+                .saturating_add(self.timeout)
+                .saturating_add(self.backoff(retry))
+        })
+    }
+}
+
+/// Open a TCP connection to a server, with up to `retries` more attempts if it fails.
+/// A server refusing the connection or a host that doesn't resolve is likely restarting,
+/// so the next attempt waits for an exponential backoff. An attempt that timed out already
+/// waited `connect_timeout`, so it's retried right away.
+pub async fn connect_with_retries<S, F, Fut>(
+    address: &Address,
+    retries: ConnectRetries,
+    mut connect: F,
+) -> Result<S, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<S>>,
+{
+    let mut retry = 0;
+
+    loop {
+        let (err, backoff) = match tokio::time::timeout(retries.timeout, connect()).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => (err.to_string(), retries.backoff(retry + 1)),
+            Err(_) => ("timed out".to_string(), Duration::ZERO),
+        };
+
+        if retry >= retries.retries {
+            error!("Could not connect to server {:?}: {}", address, err);
+            return Err(Error::SocketError(format!(
+                "Could not connect to server: {}",
+                err
+            )));
+        }
+
+        retry += 1;
+
+        warn!(
+            "Could not connect to server {:?}: {}, retrying in {}ms ({} / {})",
+            address,
+            err,
+            backoff.as_millis(),
+            retry,
+            retries.retries
+        );
+
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_startup_parameters() {
+        assert_eq!(
+            parse_startup_options(
+                "-c search_path=foo,public -clock_timeout=1s --extra-float-digits=3 -x"
+            ),
+            vec![
+                ("search_path".to_string(), "foo,public".to_string()),
+                ("lock_timeout".to_string(), "1s".to_string()),
+                ("extra_float_digits".to_string(), "3".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse_startup_options(r"-c search_path=my\ schema"),
+            vec![("search_path".to_string(), "my schema".to_string())]
+        );
+
+        let mut parameters = ServerParameters::new();
+        parameters.set_from_startup_parameters(&HashMap::from([
+            ("user".to_string(), "user".to_string()),
+            ("options".to_string(), "-c search_path=foo".to_string()),
+            ("TimeZone".to_string(), "UTC".to_string()),
+            ("work_mem".to_string(), "1GB".to_string()),
+        ]));
+        assert_eq!(parameters.parameters["search_path"], "foo");
+        assert_eq!(parameters.parameters["TimeZone"], "UTC");
+        assert!(!parameters.parameters.contains_key("work_mem"));
+        assert!(!parameters.parameters.contains_key("user"));
+
+        let server = ServerParameters::new();
+        assert_eq!(
+            server.compare_params(&parameters),
+            HashMap::from([
+                ("search_path".to_string(), Some("foo".to_string())),
+                ("TimeZone".to_string(), Some("UTC".to_string())),
+            ])
+        );
+        assert_eq!(parameters.compare_params(&server)["search_path"], None);
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_retries() {
+        let retries = ConnectRetries {
+            retries: 5,
+            timeout: Duration::from_secs(1),
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(1),
+        };
+        assert_eq!(retries.backoff(1), Duration::from_millis(200));
+        assert_eq!(retries.backoff(2), Duration::from_millis(400));
+        assert_eq!(retries.backoff(4), Duration::from_secs(1));
+        // 6 attempts and 200 + 400 + 800 + 1000 + 1000ms of backoff.
+        assert_eq!(retries.total_timeout(), Duration::from_millis(9400));
+
+        let retries = ConnectRetries {
+            backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            ..retries
+        };
+
+        // The server refuses the first two attempts.
+        let mut attempts = 0;
+        let stream = connect_with_retries(&Address::default(), retries, || {
+            attempts += 1;
+            let attempt = attempts;
+
+            async move {
+                match attempt {
+                    1 | 2 => Err(std::io::ErrorKind::ConnectionRefused.into()),
+                    _ => Ok(attempt),
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(stream.unwrap(), 3);
+        assert_eq!(attempts, 3);
+
+        // Gives up after the last retry.
+        let retries = ConnectRetries {
+            retries: 1,
+            ..retries
+        };
+        let mut attempts = 0;
+        let stream = connect_with_retries(&Address::default(), retries, || {
+            attempts += 1;
+            async { Err::<(), _>(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)) }
+        })
+        .await;
+
+        assert!(matches!(stream, Err(Error::SocketError(_))));
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_retries_timeout() {
+        let retries = ConnectRetries {
+            retries: 2,
+            timeout: Duration::from_millis(10),
+            backoff: Duration::from_secs(60),
+            max_backoff: Duration::from_secs(60),
+        };
+
+        // The server never answers: attempts time out and are retried without a backoff.
+        let mut attempts = 0;
+        let stream = connect_with_retries(&Address::default(), retries, || {
+            attempts += 1;
+            std::future::pending::<std::io::Result<()>>()
+        })
+        .await;
+
+        match stream {
+            Err(Error::SocketError(err)) => {
+                assert_eq!(err, "Could not connect to server: timed out")
+            }
+            stream => panic!("unexpected result: {:?}", stream),
+        }
+        assert_eq!(attempts, 3);
+
+        // It answers on the last retry.
+        let mut attempts = 0;
+        let stream = connect_with_retries(&Address::default(), retries, || {
+            attempts += 1;
+            let attempt = attempts;
+
+            async move {
+                if attempt < 3 {
+                    std::future::pending::<()>().await;
+                }
+
+                Ok(attempt)
+            }
+        })
+        .await;
+
+        assert_eq!(stream.unwrap(), 3);
+    }
+}
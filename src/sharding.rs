@@ -1,10 +1,29 @@
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use serde_derive::{Deserialize, Serialize};
 /// Implements various sharding functions.
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 /// See: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/include/catalog/partition.h#L20>.
 const PARTITION_HASH_SEED: u64 = 0x7A5B22367996DCFD;
 
+/// Number of points each shard gets on the consistent hashing ring.
+pub const KETAMA_DEFAULT_VNODES: usize = 160;
+
+/// Rings are keyed by number of shards and number of vnodes per shard.
+type KetamaRings = HashMap<(usize, usize), Arc<KetamaRing>>;
+
+/// Consistent hashing rings are the same for the same number of shards and vnodes,
+/// so we build them once and share them between all sharders.
+static KETAMA_RINGS: Lazy<Mutex<KetamaRings>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Shard of recently seen sharding keys.
+pub type ShardCache = Mutex<LruCache<i64, usize>>;
+
 /// The sharding functions we support.
 #[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize, Hash, std::cmp::Eq)]
 pub enum ShardingFunction {
@@ -12,6 +31,14 @@ pub enum ShardingFunction {
     PgBigintHash,
     #[serde(alias = "sha1", alias = "Sha1")]
     Sha1,
+    #[serde(alias = "sha256", alias = "Sha256")]
+    Sha256,
+    #[serde(alias = "murmur3", alias = "Murmur3")]
+    Murmur3,
+    #[serde(alias = "ketama", alias = "Ketama")]
+    Ketama,
+    #[serde(alias = "range", alias = "Range")]
+    Range,
 }
 
 impl std::fmt::Display for ShardingFunction {
@@ -19,10 +46,47 @@ impl std::fmt::Display for ShardingFunction {
         match self {
             ShardingFunction::PgBigintHash => write!(f, "pg_bigint_hash"),
             ShardingFunction::Sha1 => write!(f, "sha1"),
+            ShardingFunction::Sha256 => write!(f, "sha256"),
+            ShardingFunction::Murmur3 => write!(f, "murmur3"),
+            ShardingFunction::Ketama => write!(f, "ketama"),
+            ShardingFunction::Range => write!(f, "range"),
+        }
+    }
+}
+
+impl std::str::FromStr for ShardingFunction {
+    type Err = ();
+
+    /// Parse a sharding function by its name in the config, e.g. `pg_bigint_hash`.
+    fn from_str(name: &str) -> Result<ShardingFunction, ()> {
+        match name {
+            "pg_bigint_hash" | "PgBigintHash" => Ok(ShardingFunction::PgBigintHash),
+            "sha1" | "Sha1" => Ok(ShardingFunction::Sha1),
+            "sha256" | "Sha256" => Ok(ShardingFunction::Sha256),
+            "murmur3" | "Murmur3" => Ok(ShardingFunction::Murmur3),
+            "ketama" | "Ketama" => Ok(ShardingFunction::Ketama),
+            "range" | "Range" => Ok(ShardingFunction::Range),
+            _ => Err(()),
         }
     }
 }
 
+/// Shard of a sharding key, as pgcat computes it for `SET SHARDING KEY TO '<key>'`
+/// or a key found in a query, with the default settings of the sharding function
+/// (e.g. `ketama_vnodes`). The key must be a bigint, written in decimal.
+///
+/// Returns None if the key isn't a bigint, the sharding function is unknown,
+/// there are no shards, or the sharding function is `range`, which needs the shards' bounds.
+pub fn shard_for_key(key: &str, shards: usize, sharding_function: &str) -> Option<usize> {
+    let sharding_function = sharding_function.parse::<ShardingFunction>().ok()?;
+
+    if shards == 0 || sharding_function == ShardingFunction::Range {
+        return None;
+    }
+
+    Sharder::new(shards, sharding_function).shard_for_key(key)
+}
+
 /// The sharder.
 pub struct Sharder {
     /// Number of shards in the cluster.
@@ -30,6 +94,15 @@ pub struct Sharder {
 
     /// The sharding function in use.
     sharding_function: ShardingFunction,
+
+    /// Number of points per shard on the ring used by the ketama sharding function.
+    ketama_vnodes: usize,
+
+    /// First key of each shard's range used by the range sharding function, in shard order.
+    range_starts: Vec<i64>,
+
+    /// Shard of recently seen keys, consulted before the sharding function.
+    cache: Option<Arc<ShardCache>>,
 }
 
 impl Sharder {
@@ -38,14 +111,59 @@ impl Sharder {
         Sharder {
             shards,
             sharding_function,
+            ketama_vnodes: KETAMA_DEFAULT_VNODES,
+            range_starts: Vec::new(),
+            cache: None,
         }
     }
 
+    /// Set the number of points per shard on the consistent hashing ring.
+    pub fn ketama_vnodes(mut self, ketama_vnodes: usize) -> Sharder {
+        self.ketama_vnodes = ketama_vnodes;
+        self
+    }
+
+    /// Set the first key of each shard's range, in shard order.
+    pub fn range_starts(mut self, range_starts: Vec<i64>) -> Sharder {
+        self.range_starts = range_starts;
+        self
+    }
+
+    /// Remember the shard of recently seen keys in this cache.
+    pub fn cache(mut self, cache: Option<Arc<ShardCache>>) -> Sharder {
+        self.cache = cache;
+        self
+    }
+
     /// Compute the shard given sharding key.
     pub fn shard(&self, key: i64) -> usize {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return self.compute_shard(key),
+        };
+
+        if let Some(shard) = cache.lock().get(&key) {
+            return *shard;
+        }
+
+        let shard = self.compute_shard(key);
+        cache.lock().put(key, shard);
+        shard
+    }
+
+    /// Compute the shard of a sharding key given as text, None if it isn't a bigint.
+    pub fn shard_for_key(&self, key: &str) -> Option<usize> {
+        key.parse::<i64>().ok().map(|key| self.shard(key))
+    }
+
+    fn compute_shard(&self, key: i64) -> usize {
         match self.sharding_function {
             ShardingFunction::PgBigintHash => self.pg_bigint_hash(key),
             ShardingFunction::Sha1 => self.sha1(key),
+            ShardingFunction::Sha256 => self.sha256(key),
+            ShardingFunction::Murmur3 => self.murmur3(key),
+            ShardingFunction::Ketama => self.ketama(key),
+            ShardingFunction::Range => self.range(key),
         }
     }
 
@@ -71,12 +189,93 @@ impl Sharder {
         // Convert the SHA1 hash into hex so we can parse it as a large integer.
         let hex = format!("{:x}", result);
 
-        // Parse the last 8 bytes as an integer (8 bytes = bigint).
+        // Parse the last 4 bytes (8 hex digits) as an integer.
         let key = i64::from_str_radix(&hex[hex.len() - 8..], 16).unwrap() as usize;
 
         key % self.shards
     }
 
+    /// SHA-256 of the key in decimal, e.g. `"1234"`. The last 4 bytes
+    /// of the digest, read as a big-endian integer, modulo the number of shards.
+    fn sha256(&self, key: i64) -> usize {
+        let result = Sha256::digest(key.to_string().as_bytes());
+
+        u32::from_be_bytes(result[28..].try_into().unwrap()) as usize % self.shards
+    }
+
+    /// MurmurHash3 (x86, 32-bit) with seed 0 of the key in decimal, e.g. `"1234"`,
+    /// modulo the number of shards.
+    fn murmur3(&self, key: i64) -> usize {
+        Self::murmur3_32(key.to_string().as_bytes(), 0) as usize % self.shards
+    }
+
+    /// Reference: <https://github.com/aappleby/smhasher/blob/61a0530f28277f2e850bfc39600ce61d02b518de/src/MurmurHash3.cpp#L94>.
+    fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+        const C1: u32 = 0xcc9e2d51;
+        const C2: u32 = 0x1b873593;
+
+        let mut h1 = seed;
+        let mut chunks = data.chunks_exact(4);
+
+        for chunk in &mut chunks {
+            let mut k1 = u32::from_le_bytes(chunk.try_into().unwrap());
+
+            k1 = k1.wrapping_mul(C1);
+            k1 = k1.rotate_left(15);
+            k1 = k1.wrapping_mul(C2);
+
+            h1 ^= k1;
+            h1 = h1.rotate_left(13);
+            h1 = h1.wrapping_mul(5).wrapping_add(0xe6546b64);
+        }
+
+        let tail = chunks.remainder();
+
+        if !tail.is_empty() {
+            let mut k1 = tail
+                .iter()
+                .rev()
+                .fold(0u32, |k1, byte| (k1 << 8) | *byte as u32);
+
+            k1 = k1.wrapping_mul(C1);
+            k1 = k1.rotate_left(15);
+            k1 = k1.wrapping_mul(C2);
+            h1 ^= k1;
+        }
+
+        h1 ^= data.len() as u32;
+
+        // Finalization mix, forces all bits of the hash to avalanche.
+        h1 ^= h1 >> 16;
+        h1 = h1.wrapping_mul(0x85ebca6b);
+        h1 ^= h1 >> 13;
+        h1 = h1.wrapping_mul(0xc2b2ae35);
+        h1 ^= h1 >> 16;
+
+        h1
+    }
+
+    /// Ranges are contiguous and sorted by shard number, so the key belongs
+    /// to the last shard whose range starts at or before it.
+    /// The first shard's range is unbounded below and the last shard's range unbounded above.
+    fn range(&self, key: i64) -> usize {
+        self.range_starts
+            .partition_point(|start| *start <= key)
+            .saturating_sub(1)
+    }
+
+    /// Consistent hashing: the key goes to the first shard point
+    /// found on the ring clockwise from the key's own position.
+    fn ketama(&self, key: i64) -> usize {
+        let ring = KETAMA_RINGS
+            .lock()
+            .entry((self.shards, self.ketama_vnodes))
+            .or_insert_with(|| Arc::new(KetamaRing::new(self.shards, self.ketama_vnodes)))
+            .clone();
+
+        ring.shard(key)
+    }
+
     #[inline]
     fn rot(x: u32, k: u32) -> u32 {
         (x << k) | (x >> (32 - k))
@@ -157,9 +356,60 @@ impl Sharder {
     }
 }
 
+/// Consistent hashing ring with virtual nodes.
+///
+/// The position of each shard's points only depends on the shard number,
+/// so adding a shard only moves the keys that now land on the new shard's points,
+/// roughly 1/N of all keys, while the others stay on the shard they were on.
+#[derive(Debug)]
+struct KetamaRing {
+    /// Ring positions and the shard they belong to, sorted by position.
+    points: Vec<(u64, usize)>,
+}
+
+impl KetamaRing {
+    fn new(shards: usize, vnodes: usize) -> KetamaRing {
+        let mut points = Vec::with_capacity(shards * vnodes);
+
+        for shard in 0..shards {
+            for vnode in 0..vnodes {
+                points.push((
+                    Self::hash(format!("shard-{}-{}", shard, vnode).as_bytes()),
+                    shard,
+                ));
+            }
+        }
+
+        points.sort_unstable();
+
+        KetamaRing { points }
+    }
+
+    fn shard(&self, key: i64) -> usize {
+        let hash = Self::hash(key.to_string().as_bytes());
+
+        let index = self.points.partition_point(|(point, _)| *point < hash);
+
+        // Past the last point, wrap around to the start of the ring.
+        match self.points.get(index).or(self.points.first()) {
+            Some((_, shard)) => *shard,
+            None => 0,
+        }
+    }
+
+    fn hash(value: &[u8]) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.update(value);
+        let result = hasher.finalize();
+
+        u64::from_be_bytes(result[..8].try_into().unwrap())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::num::NonZeroUsize;
 
     // See tests/sharding/partition_hash_test_setup.sql
     // The output of those SELECT statements will match this test,
@@ -213,4 +463,184 @@ mod test {
             assert_eq!(sharder.shard(*id), shards[i]);
         }
     }
+
+    // Pins the shard of fixed keys, so changes to the sharding functions
+    // that would move existing data to other shards are caught.
+    // The pg_bigint_hash shards are those of a table partitioned with
+    // PARTITION BY HASH (id) and 5 partitions, see tests/sharding/partition_hash_test_setup.sql.
+    #[test]
+    fn test_shard_for_key() {
+        let cases = [
+            ("1", 5, "pg_bigint_hash", Some(0)),
+            ("2", 5, "pg_bigint_hash", Some(1)),
+            ("6", 5, "pg_bigint_hash", Some(2)),
+            ("8", 5, "pg_bigint_hash", Some(3)),
+            ("9", 5, "pg_bigint_hash", Some(4)),
+            ("0", 5, "pg_bigint_hash", Some(3)),
+            ("-1", 5, "pg_bigint_hash", Some(4)),
+            ("1000000", 5, "pg_bigint_hash", Some(1)),
+            ("9223372036854775807", 5, "pg_bigint_hash", Some(1)),
+            ("-9223372036854775808", 5, "pg_bigint_hash", Some(4)),
+            ("0", 12, "sha1", Some(4)),
+            ("1", 12, "sha1", Some(7)),
+            ("2", 12, "sha1", Some(8)),
+            ("9", 12, "sha1", Some(11)),
+            ("19", 12, "sha1", Some(3)),
+            ("1", 1, "sha1", Some(0)),
+            // Not a bigint.
+            ("abc", 5, "pg_bigint_hash", None),
+            ("1.5", 5, "sha1", None),
+            ("9223372036854775808", 5, "pg_bigint_hash", None),
+            (" 1", 5, "pg_bigint_hash", None),
+            // Unknown sharding function, no shards, or range without bounds.
+            ("1", 5, "md5", None),
+            ("1", 0, "sha1", None),
+            ("1", 5, "range", None),
+        ];
+
+        for (key, shards, sharding_function, expected) in cases {
+            assert_eq!(
+                shard_for_key(key, shards, sharding_function),
+                expected,
+                "key {} with {} shards and {}",
+                key,
+                shards,
+                sharding_function
+            );
+        }
+    }
+
+    #[test]
+    fn test_murmur3_32() {
+        // Reference values from the C++ implementation.
+        assert_eq!(Sharder::murmur3_32(b"", 0), 0);
+        assert_eq!(Sharder::murmur3_32(b"", 1), 0x514e28b7);
+        assert_eq!(Sharder::murmur3_32(b"hello", 0), 0x248bfa47);
+        assert_eq!(
+            Sharder::murmur3_32(b"The quick brown fox jumps over the lazy dog", 0),
+            0x2e4ff723
+        );
+    }
+
+    // Golden values, shard assignments must never change.
+    #[test]
+    fn test_sha256_hash() {
+        let sharder = Sharder::new(12, ShardingFunction::Sha256);
+        let shards = [
+            1, 7, 5, 10, 2, 5, 3, 5, 7, 11, 5, 8, 0, 8, 9, 7, 9, 11, 2, 11,
+        ];
+
+        for (id, shard) in shards.iter().enumerate() {
+            assert_eq!(sharder.shard(id as i64), *shard, "key {}", id);
+        }
+    }
+
+    // Golden values, shard assignments must never change.
+    #[test]
+    fn test_murmur3_hash() {
+        let sharder = Sharder::new(12, ShardingFunction::Murmur3);
+        let shards = [3, 3, 3, 0, 8, 0, 8, 8, 7, 5, 11, 6, 5, 1, 5, 4, 8, 8, 6, 3];
+
+        for (id, shard) in shards.iter().enumerate() {
+            assert_eq!(sharder.shard(id as i64), *shard, "key {}", id);
+        }
+    }
+
+    #[test]
+    fn test_ketama() {
+        let before = Sharder::new(4, ShardingFunction::Ketama);
+        let after = Sharder::new(5, ShardingFunction::Ketama);
+
+        let mut moved = 0;
+
+        for key in 0..10_000 {
+            let shard = before.shard(key);
+            assert!(shard < 4);
+
+            // Keys either stay where they were or move to the new shard.
+            let new_shard = after.shard(key);
+            if new_shard != shard {
+                assert_eq!(new_shard, 4);
+                moved += 1;
+            }
+        }
+
+        // About 1/5 of the keys should move to the new shard.
+        assert!(moved > 1_000 && moved < 3_000, "moved {} keys", moved);
+    }
+
+    #[test]
+    fn test_shard_cache() {
+        let cache = Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(2).unwrap())));
+        let sharder = Sharder::new(12, ShardingFunction::Sha1).cache(Some(cache.clone()));
+
+        assert_eq!(sharder.shard(1), 7);
+        assert_eq!(sharder.shard(2), 8);
+        assert_eq!(cache.lock().peek(&1), Some(&7));
+
+        // Cached shards are used without calling the sharding function.
+        cache.lock().put(2, 0);
+        assert_eq!(sharder.shard(2), 0);
+
+        // Least recently used keys are evicted.
+        sharder.shard(3);
+        assert_eq!(cache.lock().len(), 2);
+        assert!(!cache.lock().contains(&1));
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture bench_shard_cache`.
+    #[test]
+    #[ignore]
+    fn bench_shard_cache() {
+        let keys = 1_000;
+        let iterations = 1_000;
+
+        for sharding_function in [
+            ShardingFunction::PgBigintHash,
+            ShardingFunction::Sha1,
+            ShardingFunction::Murmur3,
+        ] {
+            let cache = Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(keys).unwrap())));
+            let uncached = Sharder::new(12, sharding_function);
+            let cached = Sharder::new(12, sharding_function).cache(Some(cache));
+
+            for (name, sharder) in [("recompute", &uncached), ("cache hit", &cached)] {
+                // Warm up the cache.
+                for key in 0..keys as i64 {
+                    sharder.shard(key);
+                }
+
+                let started = std::time::Instant::now();
+                for _ in 0..iterations {
+                    for key in 0..keys as i64 {
+                        std::hint::black_box(sharder.shard(key));
+                    }
+                }
+
+                println!(
+                    "{}: {}: {:?} per key",
+                    sharding_function,
+                    name,
+                    started.elapsed() / (keys * iterations) as u32
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        // Shards: (-inf, 100), [100, 1000), [1000, +inf)
+        let sharder =
+            Sharder::new(3, ShardingFunction::Range).range_starts(vec![i64::MIN, 100, 1000]);
+
+        assert_eq!(sharder.shard(i64::MIN), 0);
+        assert_eq!(sharder.shard(-5), 0);
+        assert_eq!(sharder.shard(99), 0);
+
+        // range_end is exclusive, the key belongs to the next shard.
+        assert_eq!(sharder.shard(100), 1);
+        assert_eq!(sharder.shard(999), 1);
+        assert_eq!(sharder.shard(1000), 2);
+        assert_eq!(sharder.shard(i64::MAX), 2);
+    }
 }
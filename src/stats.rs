@@ -8,13 +8,17 @@ use std::collections::HashMap;
 
 use std::sync::Arc;
 
+use crate::pool::get_all_pools;
+
 // Structs that hold stats for different resources
 pub mod address;
 pub mod client;
+pub mod latency;
 pub mod pool;
 pub mod server;
 pub use address::AddressStats;
 pub use client::{ClientState, ClientStats};
+pub use latency::LatencyStats;
 pub use server::{ServerState, ServerStats};
 
 /// Convenience types for various stats
@@ -107,6 +111,10 @@ impl Collector {
                 for stats in server_stats.values() {
                     stats.set_address_stat_average_is_updated_status(false);
                 }
+
+                for pool in get_all_pools().values() {
+                    pool.latency.update_reported();
+                }
             }
         });
     }
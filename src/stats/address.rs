@@ -24,6 +24,18 @@ pub struct AddressStats {
 
     // Determines if the averages have been updated since the last time they were reported
     pub averages_updated: Arc<AtomicBool>,
+
+    // Number of server connections currently checked out by clients
+    in_flight: Arc<AtomicU64>,
+
+    // Failed attempts to open a server connection
+    server_connect_errors: Arc<AtomicU64>,
+
+    // ErrorResponses relayed from the server to clients
+    query_errors: Arc<AtomicU64>,
+
+    // Clients that timed out waiting for a server connection
+    pool_timeouts: Arc<AtomicU64>,
 }
 
 impl IntoIterator for AddressStats {
@@ -96,12 +108,36 @@ impl IntoIterator for AddressStats {
                 "avg_wait_time".to_string(),
                 self.averages.wait_time.load(Ordering::Relaxed),
             ),
+            (
+                "total_server_connect_errors".to_string(),
+                self.server_connect_errors.load(Ordering::Relaxed),
+            ),
+            (
+                "total_query_errors".to_string(),
+                self.query_errors.load(Ordering::Relaxed),
+            ),
+            (
+                "total_pool_timeouts".to_string(),
+                self.pool_timeouts.load(Ordering::Relaxed),
+            ),
         ]
         .into_iter()
     }
 }
 
 impl AddressStats {
+    pub fn checkout(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn checkin(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
     pub fn xact_count_add(&self) {
         self.total.xact_count.fetch_add(1, Ordering::Relaxed);
         self.current.xact_count.fetch_add(1, Ordering::Relaxed);
@@ -146,6 +182,18 @@ impl AddressStats {
         self.current.errors.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn server_connect_error(&self) {
+        self.server_connect_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn query_error(&self) {
+        self.query_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pool_timeout(&self) {
+        self.pool_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn update_averages(&self) {
         let stat_period_per_second = crate::stats::STAT_PERIOD / 1_000;
 
@@ -207,6 +255,27 @@ impl AddressStats {
             .store(current_errors / stat_period_per_second, Ordering::Relaxed);
     }
 
+    /// Zero the cumulative counters reported by SHOW STATS, see `RESET STATS`.
+    /// Each counter is swapped to zero, so a query finishing during the reset is
+    /// counted either before or after it, never lost.
+    pub fn reset_totals(&self) {
+        for counter in [
+            &self.total.xact_count,
+            &self.total.query_count,
+            &self.total.bytes_received,
+            &self.total.bytes_sent,
+            &self.total.xact_time,
+            &self.total.query_time,
+            &self.total.wait_time,
+            &self.total.errors,
+            &self.server_connect_errors,
+            &self.query_errors,
+            &self.pool_timeouts,
+        ] {
+            counter.swap(0, Ordering::Relaxed);
+        }
+    }
+
     pub fn reset_current_counts(&self) {
         self.current.xact_count.store(0, Ordering::Relaxed);
         self.current.xact_time.store(0, Ordering::Relaxed);
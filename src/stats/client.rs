@@ -1,5 +1,7 @@
 use super::{get_reporter, Reporter};
+use crate::config::Role;
 use atomic_enum::atomic_enum;
+use parking_lot::Mutex;
 use std::sync::atomic::*;
 use std::sync::Arc;
 use tokio::time::Instant;
@@ -31,10 +33,14 @@ pub struct ClientStats {
     application_name: String,
     username: String,
     pool_name: String,
+    addr: String,
     connect_time: Instant,
 
     reporter: Reporter,
 
+    /// Shard and role of the server currently assigned to the client
+    pub server: Arc<Mutex<Option<(usize, Role)>>>,
+
     /// Total time spent waiting for a connection from pool, measures in microseconds
     pub total_wait_time: Arc<AtomicU64>,
 
@@ -57,6 +63,9 @@ pub struct ClientStats {
 
     /// Number of errors made by this client
     pub error_count: Arc<AtomicU64>,
+
+    /// Number of queries that hit the user's rate limit
+    pub rate_limited_count: Arc<AtomicU64>,
 }
 
 impl Default for ClientStats {
@@ -67,6 +76,8 @@ impl Default for ClientStats {
             application_name: String::new(),
             username: String::new(),
             pool_name: String::new(),
+            addr: String::new(),
+            server: Arc::new(Mutex::new(None)),
             total_wait_time: Arc::new(AtomicU64::new(0)),
             max_wait_time: Arc::new(AtomicU64::new(0)),
             wait_start_us: Arc::new(AtomicU64::new(0)),
@@ -74,6 +85,7 @@ impl Default for ClientStats {
             transaction_count: Arc::new(AtomicU64::new(0)),
             query_count: Arc::new(AtomicU64::new(0)),
             error_count: Arc::new(AtomicU64::new(0)),
+            rate_limited_count: Arc::new(AtomicU64::new(0)),
             reporter: get_reporter(),
         }
     }
@@ -85,6 +97,7 @@ impl ClientStats {
         application_name: &str,
         username: &str,
         pool_name: &str,
+        addr: &str,
         connect_time: Instant,
     ) -> Self {
         Self {
@@ -93,6 +106,7 @@ impl ClientStats {
             application_name: application_name.to_string(),
             username: username.to_string(),
             pool_name: pool_name.to_string(),
+            addr: addr.to_string(),
             ..Default::default()
         }
     }
@@ -113,6 +127,12 @@ impl ClientStats {
     /// Reports a client is done querying the server and is no longer assigned a server connection
     pub fn idle(&self) {
         self.state.store(ClientState::Idle, Ordering::Relaxed);
+        *self.server.lock() = None;
+    }
+
+    /// Reports which shard and role the server assigned to the client belongs to
+    pub fn server_assigned(&self, shard: usize, role: Role) {
+        *self.server.lock() = Some((shard, role));
     }
 
     /// Reports a client is waiting for a connection
@@ -181,6 +201,11 @@ impl ClientStats {
         self.transaction_count.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Report a query that hit the user's rate limit
+    pub fn rate_limited(&self) {
+        self.rate_limited_count.fetch_add(1, Ordering::Relaxed);
+    }
+
     // Helper methods for show clients
     pub fn connect_time(&self) -> Instant {
         self.connect_time
@@ -201,4 +226,8 @@ impl ClientStats {
     pub fn pool_name(&self) -> String {
         self.pool_name.clone()
     }
+
+    pub fn addr(&self) -> String {
+        self.addr.clone()
+    }
 }
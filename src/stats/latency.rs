@@ -0,0 +1,170 @@
+use std::sync::atomic::*;
+use std::time::Duration;
+
+/// Values below 4 get a bucket each, then every power of two
+/// is split into 4 buckets, so a bucket is at most 25% wide.
+const BUCKETS: usize = 252;
+
+fn bucket(value: u64) -> usize {
+    if value < 4 {
+        return value as usize;
+    }
+
+    let log2 = 63 - value.leading_zeros() as usize;
+    let sub_bucket = ((value >> (log2 - 2)) & 3) as usize;
+
+    (log2 - 1) * 4 + sub_bucket
+}
+
+/// Largest value that falls in the bucket.
+fn bucket_upper_bound(bucket: usize) -> u64 {
+    if bucket < 4 {
+        return bucket as u64;
+    }
+
+    let log2 = bucket / 4 + 1;
+    let sub_bucket = (bucket % 4) as u64;
+    let lower_bound = (4 + sub_bucket) << (log2 - 2);
+
+    lower_bound + ((1 << (log2 - 2)) - 1)
+}
+
+/// Bucketed histogram of latencies in microseconds. Recording is a couple
+/// of relaxed atomic operations, so it's cheap to do for every query.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, microseconds: u64) {
+        self.buckets[bucket(microseconds)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(microseconds, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Latency under which `percentile` percent of the values are,
+    /// rounded up to the bucket bound.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let count = self.count();
+
+        if count == 0 {
+            return 0;
+        }
+
+        let rank = ((percentile / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+
+        for (bucket, value) in self.buckets.iter().enumerate() {
+            seen += value.load(Ordering::Relaxed);
+
+            if seen >= rank {
+                return bucket_upper_bound(bucket).min(self.max());
+            }
+        }
+
+        self.max()
+    }
+
+    /// Move the values of `other` into this histogram, replacing its own.
+    fn take_from(&self, other: &Histogram) {
+        for (bucket, other_bucket) in self.buckets.iter().zip(other.buckets.iter()) {
+            bucket.store(other_bucket.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+        }
+
+        self.max
+            .store(other.max.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Query latency of a pool. Like the averages in `SHOW STATS`,
+/// what's reported covers the last stats period.
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    /// Time the server took to return the query results.
+    current_server: Histogram,
+
+    /// Time from receiving the query from the client to returning the results,
+    /// including waiting for a server connection.
+    current_client: Histogram,
+
+    reported_server: Histogram,
+    reported_client: Histogram,
+}
+
+impl LatencyStats {
+    /// Report a query executed by a client against a server.
+    pub fn query(&self, server_time: Duration, client_time: Duration) {
+        self.current_server.record(server_time.as_micros() as u64);
+        self.current_client.record(client_time.as_micros() as u64);
+    }
+
+    /// Report the latencies of the period that just ended and start a new one.
+    pub fn update_reported(&self) {
+        self.reported_server.take_from(&self.current_server);
+        self.reported_client.take_from(&self.current_client);
+    }
+
+    pub fn server(&self) -> &Histogram {
+        &self.reported_server
+    }
+
+    pub fn client(&self) -> &Histogram {
+        &self.reported_client
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        for value in [0, 3, 4, 7, 8, 100, 1_000_000, u64::MAX] {
+            assert!(bucket_upper_bound(bucket(value)) >= value);
+            assert!(bucket(value) < BUCKETS);
+        }
+
+        let histogram = Histogram::default();
+        assert_eq!(histogram.percentile(99.0), 0);
+
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.max(), 1000);
+
+        // Within the 25% bucket width.
+        let p50 = histogram.percentile(50.0);
+        assert!((500..=625).contains(&p50), "p50: {}", p50);
+        let p99 = histogram.percentile(99.0);
+        assert!((990..=1000).contains(&p99), "p99: {}", p99);
+
+        let reported = Histogram::default();
+        reported.take_from(&histogram);
+        assert_eq!(reported.count(), 1000);
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.max(), 0);
+    }
+}
@@ -5,13 +5,20 @@ use crate::{config::PoolMode, messages::DataType, pool::PoolIdentifier};
 use std::collections::HashMap;
 use std::sync::atomic::*;
 
-use crate::pool::get_all_pools;
+use crate::pool::{draining, get_all_pools};
 
 #[derive(Debug, Clone)]
 /// A struct that holds information about a Pool .
 pub struct PoolStats {
     pub identifier: PoolIdentifier,
     pub mode: PoolMode,
+    /// Server connections allowed to each server, the largest one if shards override it.
+    pub pool_size: u64,
+    /// Idle server connections kept open to each server.
+    pub min_pool_size: u64,
+    pub promoted: Vec<String>,
+    /// One of "active", "paused" or "draining".
+    pub state: &'static str,
     pub cl_idle: u64,
     pub cl_active: u64,
     pub cl_waiting: u64,
@@ -21,13 +28,21 @@ pub struct PoolStats {
     pub sv_used: u64,
     pub sv_tested: u64,
     pub sv_login: u64,
+    /// Server connections closed for being idle or too old.
+    pub sv_recycled: u64,
     pub maxwait: u64,
+    /// Seconds since the pool was created.
+    pub uptime: u64,
 }
 impl PoolStats {
-    pub fn new(identifier: PoolIdentifier, mode: PoolMode) -> Self {
+    pub fn new(identifier: PoolIdentifier, mode: PoolMode, pool_size: u64) -> Self {
         PoolStats {
             identifier,
             mode,
+            pool_size,
+            min_pool_size: 0,
+            promoted: Vec::new(),
+            state: "active",
             cl_idle: 0,
             cl_active: 0,
             cl_waiting: 0,
@@ -37,7 +52,9 @@ impl PoolStats {
             sv_used: 0,
             sv_tested: 0,
             sv_login: 0,
+            sv_recycled: 0,
             maxwait: 0,
+            uptime: 0,
         }
     }
 
@@ -47,10 +64,40 @@ impl PoolStats {
         let server_map = super::get_server_stats();
 
         for (identifier, pool) in get_all_pools() {
-            map.insert(
-                identifier.clone(),
-                PoolStats::new(identifier, pool.settings.pool_mode),
-            );
+            let pool_size = (0..pool.shards())
+                .map(|shard| pool.pool_size(shard) as u64)
+                .max()
+                .unwrap_or_default();
+
+            let mut pool_stats =
+                PoolStats::new(identifier.clone(), pool.settings.pool_mode, pool_size);
+
+            pool_stats.min_pool_size = pool.settings.min_pool_size as u64;
+
+            // Replicas receiving writes because their shard's primary is down.
+            pool_stats.promoted = (0..pool.shards())
+                .filter_map(|shard| pool.promoted_replica(shard))
+                .map(|address| address.name())
+                .collect();
+
+            pool_stats.sv_recycled = (0..pool.shards())
+                .flat_map(|shard| (0..pool.servers(shard)).map(move |server| (shard, server)))
+                .map(|(shard, server)| {
+                    let statistics = pool.pool_state(shard, server).statistics;
+                    statistics.connections_closed_idle_timeout
+                        + statistics.connections_closed_max_lifetime
+                })
+                .sum();
+
+            pool_stats.uptime = pool.uptime().as_secs();
+
+            if draining() {
+                pool_stats.state = "draining";
+            } else if pool.paused() {
+                pool_stats.state = "paused";
+            }
+
+            map.insert(identifier, pool_stats);
         }
 
         for client in client_map.values() {
@@ -97,6 +144,7 @@ impl PoolStats {
             ("database", DataType::Text),
             ("user", DataType::Text),
             ("pool_mode", DataType::Text),
+            ("pool_size", DataType::Numeric),
             ("cl_idle", DataType::Numeric),
             ("cl_active", DataType::Numeric),
             ("cl_waiting", DataType::Numeric),
@@ -108,6 +156,11 @@ impl PoolStats {
             ("sv_login", DataType::Numeric),
             ("maxwait", DataType::Numeric),
             ("maxwait_us", DataType::Numeric),
+            ("promoted", DataType::Text),
+            ("state", DataType::Text),
+            ("sv_recycled", DataType::Numeric),
+            ("min_pool_size", DataType::Numeric),
+            ("uptime", DataType::Numeric),
         ]
     }
 
@@ -116,6 +169,7 @@ impl PoolStats {
             self.identifier.db.clone(),
             self.identifier.user.clone(),
             self.mode.to_string(),
+            self.pool_size.to_string(),
             self.cl_idle.to_string(),
             self.cl_active.to_string(),
             self.cl_waiting.to_string(),
@@ -127,6 +181,11 @@ impl PoolStats {
             self.sv_login.to_string(),
             (self.maxwait / 1_000_000).to_string(),
             (self.maxwait % 1_000_000).to_string(),
+            self.promoted.join(","),
+            self.state.to_string(),
+            self.sv_recycled.to_string(),
+            self.min_pool_size.to_string(),
+            self.uptime.to_string(),
         ]
     }
 }
@@ -146,8 +205,10 @@ impl IntoIterator for PoolStats {
             ("sv_used".to_string(), self.sv_used),
             ("sv_tested".to_string(), self.sv_tested),
             ("sv_login".to_string(), self.sv_login),
+            ("sv_recycled".to_string(), self.sv_recycled),
             ("maxwait".to_string(), self.maxwait / 1_000_000),
             ("maxwait_us".to_string(), self.maxwait % 1_000_000),
+            ("uptime".to_string(), self.uptime),
         ]
         .into_iter()
     }
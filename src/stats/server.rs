@@ -51,6 +51,12 @@ pub struct ServerStats {
     pub prepared_miss_count: Arc<AtomicU64>,
     pub prepared_eviction_count: Arc<AtomicU64>,
     pub prepared_cache_size: Arc<AtomicU64>,
+
+    /// PID of the Postgres backend, from BackendKeyData.
+    pub backend_pid: Arc<AtomicI32>,
+
+    /// Client the server is linked to, 0 when it's not in use.
+    pub client_id: Arc<AtomicI32>,
 }
 
 impl Default for ServerStats {
@@ -71,6 +77,8 @@ impl Default for ServerStats {
             prepared_miss_count: Arc::new(AtomicU64::new(0)),
             prepared_eviction_count: Arc::new(AtomicU64::new(0)),
             prepared_cache_size: Arc::new(AtomicU64::new(0)),
+            backend_pid: Arc::new(AtomicI32::new(0)),
+            client_id: Arc::new(AtomicI32::new(0)),
         }
     }
 }
@@ -100,34 +108,56 @@ impl ServerStats {
     /// Reports a server connection is no longer assigned to a client
     /// and is available for the next client to pick it up
     pub fn idle(&self) {
-        self.state.store(ServerState::Idle, Ordering::Relaxed);
+        self.set_state(ServerState::Idle);
+        self.client_id.store(0, Ordering::Relaxed);
+    }
+
+    /// Reports the server connection was claimed by a client.
+    pub fn linked(&self, client_id: i32) {
+        self.client_id.store(client_id, Ordering::Relaxed);
     }
 
     /// Reports a server connection is disconnecting from the pooler.
     /// Also updates metrics on the pool regarding server usage.
     pub fn disconnect(&self) {
+        if self.state.load(Ordering::Relaxed) == ServerState::Active {
+            self.address.stats.checkin();
+        }
+
         self.reporter.server_disconnecting(self.server_id);
     }
 
     /// Reports a server connection is being tested before being given to a client.
     pub fn tested(&self) {
         self.set_undefined_application();
-        self.state.store(ServerState::Tested, Ordering::Relaxed);
+        self.set_state(ServerState::Tested);
     }
 
     /// Reports a server connection is attempting to login.
     pub fn login(&self) {
-        self.state.store(ServerState::Login, Ordering::Relaxed);
+        self.set_state(ServerState::Login);
         self.set_undefined_application();
     }
 
     /// Reports a server connection has been assigned to a client that
     /// is about to query the server
     pub fn active(&self, application_name: String) {
-        self.state.store(ServerState::Active, Ordering::Relaxed);
+        self.set_state(ServerState::Active);
         self.set_application(application_name);
     }
 
+    /// Change the server state, keeping track of the number of
+    /// active server connections for each address.
+    fn set_state(&self, state: ServerState) {
+        let previous = self.state.swap(state, Ordering::Relaxed);
+
+        if previous != ServerState::Active && state == ServerState::Active {
+            self.address.stats.checkout();
+        } else if previous == ServerState::Active && state != ServerState::Active {
+            self.address.stats.checkin();
+        }
+    }
+
     pub fn address_stats(&self) -> Arc<AddressStats> {
         self.address.stats.clone()
     }
@@ -156,6 +186,10 @@ impl ServerStats {
         self.address.name()
     }
 
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
     pub fn connect_time(&self) -> Instant {
         self.connect_time
     }
@@ -1,5 +1,6 @@
 // Stream wrapper.
 
+use log::error;
 use rustls_pemfile::{certs, read_one, Item};
 use std::iter;
 use std::path::Path;
@@ -8,11 +9,13 @@ use std::time::SystemTime;
 use tokio_rustls::rustls::{
     self,
     client::{ServerCertVerified, ServerCertVerifier},
-    Certificate, PrivateKey, ServerName,
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+    Certificate, ConfigBuilder, PrivateKey, RootCertStore, ServerConfig, ServerName,
+    SupportedCipherSuite, SupportedProtocolVersion, WantsVerifier,
 };
 use tokio_rustls::TlsAcceptor;
 
-use crate::config::get_config;
+use crate::config::{get_config, General};
 use crate::errors::Error;
 
 // TLS
@@ -36,6 +39,65 @@ pub fn load_keys(path: &Path) -> std::io::Result<Vec<PrivateKey>> {
         .collect()
 }
 
+/// TLS versions allowed by `tls_min_version`, newest first.
+pub fn tls_versions(
+    min_version: Option<&str>,
+) -> Result<Vec<&'static SupportedProtocolVersion>, Error> {
+    match min_version {
+        None | Some("1.2") => Ok(vec![&rustls::version::TLS13, &rustls::version::TLS12]),
+        Some("1.3") => Ok(vec![&rustls::version::TLS13]),
+        Some(version) => {
+            error!(
+                "tls_min_version '{}' is not supported, use \"1.2\" or \"1.3\"",
+                version
+            );
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// Cipher suites allowed by `tls_ciphers`, e.g. "TLS13_AES_256_GCM_SHA384".
+pub fn cipher_suites(names: Option<&[String]>) -> Result<Vec<SupportedCipherSuite>, Error> {
+    let names = match names {
+        Some(names) => names,
+        None => return Ok(rustls::DEFAULT_CIPHER_SUITES.to_vec()),
+    };
+
+    names
+        .iter()
+        .map(|name| {
+            rustls::ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()) == name.to_uppercase())
+                .copied()
+                .ok_or_else(|| {
+                    error!(
+                        "tls_ciphers contains an unsupported cipher suite '{}'",
+                        name
+                    );
+                    Error::BadConfig
+                })
+        })
+        .collect()
+}
+
+/// Server config builder with the protocol versions and cipher suites from the config.
+pub fn server_config_builder(
+    general: &General,
+) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, Error> {
+    let versions = tls_versions(general.tls_min_version.as_deref())?;
+    let suites = cipher_suites(general.tls_ciphers.as_deref())?;
+
+    ServerConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)
+        .map_err(|err| {
+            error!("tls_min_version and tls_ciphers are incompatible: {}", err);
+            Error::BadConfig
+        })
+}
+
 pub struct Tls {
     pub acceptor: TlsAcceptor,
 }
@@ -44,6 +106,11 @@ impl Tls {
     pub fn new() -> Result<Self, Error> {
         let config = get_config();
 
+        let builder = match server_config_builder(&config.general) {
+            Ok(builder) => builder,
+            Err(_) => return Err(Error::TlsError),
+        };
+
         let certs = match load_certs(Path::new(&config.general.tls_certificate.unwrap())) {
             Ok(certs) => certs,
             Err(_) => return Err(Error::TlsError),
@@ -54,9 +121,36 @@ impl Tls {
             Err(_) => return Err(Error::TlsError),
         };
 
-        let config = match rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
+        // Verify client certificates against our own CA (mTLS).
+        let builder = match config.general.client_ca_certificate {
+            Some(ref client_ca_certificate) => {
+                let mut roots = RootCertStore::empty();
+
+                let certs = match load_certs(Path::new(client_ca_certificate)) {
+                    Ok(certs) => certs,
+                    Err(_) => return Err(Error::TlsError),
+                };
+
+                for cert in certs {
+                    if roots.add(&cert).is_err() {
+                        return Err(Error::TlsError);
+                    }
+                }
+
+                if config.general.require_client_certificate {
+                    builder
+                        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                } else {
+                    builder.with_client_cert_verifier(
+                        AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                    )
+                }
+            }
+
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = match builder
             .with_single_cert(certs, keys.remove(0))
             .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
         {
@@ -85,3 +179,33 @@ impl ServerCertVerifier for NoCertificateVerification {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tls_versions_and_ciphers() {
+        assert_eq!(tls_versions(None).unwrap().len(), 2);
+        assert_eq!(
+            tls_versions(Some("1.3")).unwrap(),
+            vec![&rustls::version::TLS13]
+        );
+        assert_eq!(tls_versions(Some("1.1")), Err(Error::BadConfig));
+
+        let suites = cipher_suites(Some(&["TLS13_AES_256_GCM_SHA384".to_string()])).unwrap();
+        assert_eq!(suites, vec![rustls::cipher_suite::TLS13_AES_256_GCM_SHA384]);
+        assert_eq!(
+            cipher_suites(Some(&["TLS_RSA_WITH_RC4_128_SHA".to_string()])),
+            Err(Error::BadConfig)
+        );
+
+        // TLS 1.3 only with TLS 1.2 ciphers can't negotiate anything.
+        let general = General {
+            tls_min_version: Some("1.3".into()),
+            tls_ciphers: Some(vec!["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".into()]),
+            ..General::default()
+        };
+        assert!(server_config_builder(&general).is_err());
+    }
+}
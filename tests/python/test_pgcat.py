@@ -1,4 +1,5 @@
 
+import os
 import signal
 import time
 
@@ -240,3 +241,50 @@ def test_shutdown_logic():
     utils.pg_cat_send_signal(signal.SIGTERM)
 
     # - - - - - - - - - - - - - - - - - -
+    # HANDLE TRANSACTION WITH SIGTERM
+
+    # Start pgcat
+    utils.pgcat_start()
+
+    # Create client connection and begin transaction
+    conn, cur = utils.connect_db()
+
+    cur.execute("BEGIN;")
+    cur.execute("SELECT 1;")
+
+    # Send sigterm to pgcat while still in transaction
+    utils.pg_cat_send_signal(signal.SIGTERM, wait_for_exit=False)
+    time.sleep(1)
+
+    # The transaction should be allowed to complete while draining
+    try:
+        cur.execute("SELECT 1;")
+        cur.execute("COMMIT;")
+    except psycopg2.OperationalError as e:
+        raise Exception("Server closed while in transaction", e.pgerror)
+
+    # pgcat exits once the last client is gone
+    utils.cleanup_conn(conn, cur)
+    time.sleep(1)
+    if not os.system("pgrep pgcat"):
+        raise Exception("pgcat not closed after draining")
+
+    # - - - - - - - - - - - - - - - - - -
+    # SECOND SIGTERM FORCES SHUTDOWN
+
+    # Start pgcat
+    utils.pgcat_start()
+
+    # Create client connection and begin transaction
+    conn, cur = utils.connect_db()
+
+    cur.execute("BEGIN;")
+    cur.execute("SELECT 1;")
+
+    utils.pg_cat_send_signal(signal.SIGTERM, wait_for_exit=False)
+    time.sleep(1)
+    utils.pg_cat_send_signal(signal.SIGTERM)
+
+    utils.cleanup_conn(conn, cur)
+
+    # - - - - - - - - - - - - - - - - - -
@@ -44,7 +44,7 @@ def glauth_send_signal(signal: signal.Signals):
             raise Exception("glauth not closed after SIGTERM")
 
 
-def pg_cat_send_signal(signal: signal.Signals):
+def pg_cat_send_signal(signal: signal.Signals, wait_for_exit: bool = True):
     try:
         for proc in psutil.process_iter(["pid", "name"]):
             if "pgcat" == proc.name():
@@ -53,7 +53,8 @@ def pg_cat_send_signal(signal: signal.Signals):
         # The process can be gone when we send this signal
         print(e)
 
-    if signal == signal.SIGTERM:
+    # A draining pgcat only exits once its clients are gone.
+    if signal == signal.SIGTERM and wait_for_exit:
         # Returns 0 if pgcat process exists
         time.sleep(2)
         if not os.system('pgrep pgcat'):
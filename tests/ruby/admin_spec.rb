@@ -52,6 +52,26 @@ describe "Admin" do
         expect(results.count).to eq(0)
       end
 
+      it "bans/unbans servers by name" do
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+
+        results = admin_conn.async_exec("BAN sharded_db_shard_0_replica_0 60").to_a
+        expect(results.count).to eq(1)
+        expect(results[0]["name"]).to eq("sharded_db_shard_0_replica_0")
+
+        databases = admin_conn.async_exec("SHOW DATABASES").to_a
+        banned = databases.find { |r| r["name"] == "sharded_db_shard_0_replica_0" }
+        expect(banned["disabled"]).to eq("1")
+        expect(banned["ban_remaining_seconds"].to_i).to be_between(1, 60)
+        expect(databases.reject { |r| r["name"] == "sharded_db_shard_0_replica_0" }.map { |r| r["ban_remaining_seconds"] }.uniq).to eq(["0"])
+
+        results = admin_conn.async_exec("UNBAN sharded_db_shard_0_replica_0").to_a
+        expect(results.count).to eq(1)
+
+        databases = admin_conn.async_exec("SHOW DATABASES").to_a
+        expect(databases.map { |r| r["disabled"] }.uniq).to eq(["0"])
+      end
+
       it "honors ban duration" do
         admin_conn = PG::connect(processes.pgcat.admin_connection_string)
 
@@ -112,6 +132,55 @@ describe "Admin" do
     end
   end
 
+  describe "SHOW VERSION" do
+    it "returns the version and git hash" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      result = admin_conn.async_exec("SHOW VERSION")[0]
+      expect(result["version"]).to start_with("PgCat ")
+      expect(result["git_hash"]).not_to be_empty
+      admin_conn.close
+    end
+  end
+
+  describe "SHOW CONFIG" do
+    it "redacts the TLS private key" do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["tls_certificate"] = "../../.circleci/server.cert"
+      current_configs["general"]["tls_private_key"] = "../../.circleci/server.key"
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW CONFIG").to_a.to_h { |r| [r["key"], r["value"]] }
+      expect(results["tls_private_key"]).to eq("<redacted>")
+      expect(results["tls_certificate"]).to eq("../../.circleci/server.cert")
+      expect(results.keys).to include("pools.sharded_db.shard_count")
+      admin_conn.close
+    end
+  end
+
+  describe "SHOW RELOAD" do
+    it "reports the config path and the last reload" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      result = admin_conn.async_exec("SHOW RELOAD")[0]
+      expect(result["config_path"]).to eq(processes.pgcat.config_filename)
+      expect(result["status"]).to eq("ok")
+      expect(result["error"]).to eq("")
+      last_reload = result["last_reload"]
+
+      File.write(processes.pgcat.config_filename, "[general\n")
+      expect { admin_conn.async_exec("RELOAD") }.to raise_error(PG::Error)
+
+      result = admin_conn.async_exec("SHOW RELOAD")[0]
+      expect(result["status"]).to eq("failed")
+      expect(result["error"]).to match(/could not parse config file/)
+      expect(result["last_reload"]).to eq(last_reload)
+      expect(result["last_reload_attempt"]).to be > last_reload
+
+      admin_conn.close
+    end
+  end
+
   describe "PAUSE" do
     it "pauses all pools" do
       admin_conn = PG::connect(processes.pgcat.admin_connection_string)
@@ -129,6 +198,36 @@ describe "Admin" do
       expect(results.map{ |r| r["paused"] }.uniq).to eq(["0"])
     end
 
+    it "pauses all pools of a database" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("PAUSE sharded_db")
+
+      results = admin_conn.async_exec("SHOW DATABASES").to_a
+      expect(results.map{ |r| r["paused"] }.uniq).to eq(["1"])
+
+      admin_conn.async_exec("RESUME sharded_db")
+
+      results = admin_conn.async_exec("SHOW DATABASES").to_a
+      expect(results.map{ |r| r["paused"] }.uniq).to eq(["0"])
+    end
+
+    it "errors out queries waiting longer than pause_timeout" do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["pause_timeout"] = 500
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("PAUSE")
+
+      conn = PG::connect(pgcat_conn_str)
+      expect { conn.async_exec("SELECT 1") }.to raise_error(PG::SystemError, /paused pool/)
+
+      admin_conn.async_exec("RESUME")
+      expect(conn.async_exec("SELECT 1").to_a).to eq([{ "?column?" => "1" }])
+      conn.close
+    end
+
     it "handles errors" do
       admin_conn = PG::connect(processes.pgcat.admin_connection_string)
       expect { admin_conn.async_exec("PAUSE foo").to_a }.to raise_error(PG::SystemError)
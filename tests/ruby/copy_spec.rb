@@ -99,4 +99,33 @@ describe "COPY Handling" do
     end
   end
 
+  describe "COPY FROM and COPY TO" do
+    it "streams several megabytes in both directions" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      rows = 50_000.times.map { |i| "#{i},#{"x" * 32},#{"y" * 32},#{i * 2}\n" }
+
+      Timeout.timeout(30) do
+        conn.copy_data "COPY copy_test_table FROM STDIN CSV" do
+          rows.each { |row| conn.put_copy_data(row) }
+        end
+      end
+
+      res = []
+      Timeout.timeout(30) do
+        conn.copy_data "COPY copy_test_table TO STDOUT CSV" do
+          while row = conn.get_copy_data
+            res << row
+          end
+        end
+      end
+
+      expect(rows.sum(&:bytesize)).to be > 3_000_000
+      expect(res).to eq(rows)
+
+      # The connection is usable after the COPY
+      expect(conn.async_exec("SELECT count(*) FROM copy_test_table")[0]["count"]).to eq("50000")
+      conn.close
+    end
+  end
+
 end
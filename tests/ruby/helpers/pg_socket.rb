@@ -21,6 +21,13 @@ class PostgresSocket
     @verbose = true
   end
 
+  # Ask for GSSAPI encryption, returns the one byte answer ('N' or 'E').
+  def send_gssenc_request
+    @socket.write([8, 80877104].pack('l>l>'))
+    log "[F] Sent GSSENCRequest"
+    @socket.recv(1)
+  end
+
   def send_md5_password_message(username, password, salt)
     m = Digest::MD5.hexdigest(password + username)
     m = Digest::MD5.hexdigest(m + salt.map(&:chr).join(""))
@@ -126,6 +133,11 @@ class PostgresSocket
     log "[F] Sent Q message (#{query})"
   end
 
+  def send_raw(bytes)
+    @socket.write(bytes)
+    log "[F] Sent #{bytes.bytesize} raw bytes"
+  end
+
   def send_parse_message(query)
     query_size = query.length
     message_size = 2 + 2 + 4 + query_size
@@ -57,6 +57,61 @@ describe "Random Load Balancing" do
     end
   end
 
+  context "when a replica is disabled" do
+    it "sends it no queries until it's enabled again" do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["shards"]["0"]["servers"][1] << false
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      QUERY_COUNT.times { conn.async_exec("SELECT 1 + 2") }
+      expect(processes.replicas[0].count_select_1_plus_2).to eq(0)
+
+      admin_conn = PG.connect(processes.pgcat.admin_connection_string)
+      expect(admin_conn.async_exec("SHOW DATABASES").map { |row| row["port"] }).not_to include(processes.replicas[0].port.to_s)
+
+      new_configs["pools"]["sharded_db"]["shards"]["0"]["servers"][1].pop
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      QUERY_COUNT.times { conn.async_exec("SELECT 1 + 2") }
+      expect(processes.replicas[0].count_select_1_plus_2).to be > 0
+
+      [conn, admin_conn].map(&:close)
+    end
+  end
+
+  context "when users have their own default_role" do
+    it "sends each user's queries to its role" do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["users"]["0"]["default_role"] = "primary"
+      new_configs["pools"]["sharded_db"]["users"]["1"] = {
+        "username" => "other_user",
+        "password" => "other_user",
+        "pool_size" => 5,
+        "statement_timeout" => 0,
+        "default_role" => "replica",
+      }
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+
+      reporting_conn = PG.connect(processes.pgcat.connection_string("sharded_db", "other_user"))
+      QUERY_COUNT.times { reporting_conn.async_exec("SELECT 1 + 2") }
+      expect(processes.primary.count_select_1_plus_2).to eq(0)
+      expect(processes.replicas.map(&:count_select_1_plus_2).sum).to eq(QUERY_COUNT)
+      processes.all_databases.map(&:reset_stats)
+
+      oltp_conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      QUERY_COUNT.times { oltp_conn.async_exec("SELECT 1 + 2") }
+      expect(processes.primary.count_select_1_plus_2).to eq(QUERY_COUNT)
+      expect(processes.replicas.map(&:count_select_1_plus_2).sum).to eq(0)
+
+      [reporting_conn, oltp_conn].map(&:close)
+    end
+  end
+
   context "when all replicas are down " do
     let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "debug", {"default_role" => "replica"}) }
 
@@ -93,6 +148,99 @@ describe "Random Load Balancing" do
   end
 end
 
+describe "Replica banning" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", {"default_role" => "replica"}) }
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "keeps one replica in rotation when all of them fail" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    number_of_replicas = processes[:replicas].length
+
+    # Every replica fails a checkout and gets banned in turn.
+    processes[:replicas].each(&:take_down)
+    (number_of_replicas * 2).times do
+      conn.async_exec("SELECT 1 + 2")
+    rescue
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    end
+
+    admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+    bans = admin_conn.async_exec("SHOW BANS").to_a
+    expect(bans.count).to eq(number_of_replicas - 1)
+    admin_conn.close
+
+    # Ban time is 60 seconds, reads succeed right away through the replica left in rotation.
+    processes[:replicas].each(&:reset)
+    expect(conn.async_exec("SELECT 1 + 2")[0]["?column?"]).to eq("3")
+  end
+end
+
+describe "Health check role verification" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", {"default_role" => "replica"}) }
+  before do
+    new_configs = processes.pgcat.current_config
+    new_configs["general"]["healthcheck_delay"] = 0
+    new_configs["general"]["healthcheck_query"] = "SELECT 1"
+    new_configs["general"]["healthcheck_verify_role"] = true
+    processes.pgcat.update_config(new_configs)
+    processes.pgcat.reload_config
+  end
+
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "bans replicas that report they are a primary" do
+    # None of the test replicas are actually in recovery.
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    failed_count = 0
+    10.times do
+      conn.async_exec("SELECT 1 + 2")
+    rescue PG::Error
+      failed_count += 1
+    end
+    expect(failed_count).to be > 0
+
+    admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+    bans = admin_conn.async_exec("SHOW BANS").to_a
+    expect(bans.map { |r| r["role"] }.uniq).to eq(["replica"])
+    expect(bans.map { |r| r["reason"] }.uniq).to eq(["RoleMismatch"])
+    admin_conn.close
+
+    # The primary is what it says it is.
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    conn.async_exec("SET SERVER ROLE TO 'primary'")
+    expect(conn.async_exec("SELECT 1 + 2")[0]["?column?"]).to eq("3")
+  end
+end
+
+describe "Replica lag" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", {"default_role" => "replica", "max_replica_lag" => 10000}) }
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "measures replica lag and reads from replicas that are caught up" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    expect(conn.async_exec("SELECT 1 + 2")[0]["?column?"]).to eq("3")
+    sleep(2)
+
+    admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+    servers = admin_conn.async_exec("SHOW SERVERS").to_a
+    expect(servers.map { |r| r["replica_lag_ms"] }.uniq).to eq(["0"])
+    admin_conn.close
+
+    processes.replicas.map(&:reset)
+    QUERY_COUNT.times { conn.async_exec("SELECT 1 + 2") }
+    expect(processes.replicas.map(&:count_select_1_plus_2).sum).to eq(QUERY_COUNT)
+  end
+end
+
 describe "Least Outstanding Queries Load Balancing" do
   let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 1, "transaction", "loc") }
   after do
@@ -196,3 +344,170 @@ describe "Least Outstanding Queries Load Balancing" do
     end
   end
 end
+
+describe "Read retries" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", { "default_role" => "replica", "read_retry_count" => 3 }) }
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "retries reads on another replica when a replica goes down" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+    # Open server connections to all the replicas.
+    QUERY_COUNT.times { conn.async_exec("SELECT 1") }
+
+    failed_count = 0
+
+    processes.replicas[0].take_down do
+      QUERY_COUNT.times do
+        conn.async_exec("SELECT 1 + 2")
+      rescue
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        failed_count += 1
+      end
+    end
+
+    expect(failed_count).to eq(0)
+    expect(processes.replicas[0].count_select_1_plus_2).to eq(0)
+    conn.close
+  end
+end
+
+describe "Ban backoff" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", { "default_role" => "replica" }) }
+
+  before do
+    new_configs = processes.pgcat.current_config
+    new_configs["general"]["ban_time"] = 1
+    new_configs["general"]["ban_backoff"] = true
+    new_configs["general"]["max_ban_time"] = 4
+    processes.pgcat.update_config(new_configs)
+    processes.pgcat.reload_config
+  end
+
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "doubles the ban time of a server failing repeatedly, up to max_ban_time" do
+    admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    ban_durations = []
+
+    processes.replicas[0].take_down do
+      4.times do
+        ban = nil
+
+        # Query until the replica is picked and banned again.
+        100.times do
+          begin
+            conn.async_exec("SELECT 1")
+          rescue
+            conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+          end
+
+          ban = admin_conn.async_exec("SHOW BANS").find { |b| b["name"] == "sharded_db_shard_0_replica_0" }
+          break if ban
+        end
+
+        expect(ban).not_to be_nil
+        ban_durations << ban["ban_duration_seconds"].to_i
+        sleep(ban_durations.last + 1)
+      end
+    end
+
+    expect(ban_durations).to eq([1, 2, 4, 4])
+    conn.close
+    admin_conn.close
+  end
+end
+
+describe "Multiple primaries" do
+  let(:processes) do
+    Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", {
+      "default_role" => "primary",
+    })
+  end
+
+  after do
+    processes.all_databases.each do |instance|
+      instance.with_connection { |c| c.async_exec("DROP TABLE IF EXISTS multi_primary_writes") }
+    end
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "distributes writes between the primaries with allow_multiple_primaries" do
+    primaries = [processes.primary, processes.replicas[0]]
+    primaries.each do |instance|
+      instance.with_connection { |c| c.async_exec("CREATE TABLE multi_primary_writes (id INT)") }
+    end
+
+    new_configs = processes.pgcat.current_config
+    new_configs["general"]["allow_multiple_primaries"] = true
+    new_configs["pools"]["sharded_db"]["shards"]["0"]["servers"][1][2] = "primary"
+    processes.pgcat.update_config(new_configs)
+    processes.pgcat.reload_config
+
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    20.times { |i| conn.async_exec("INSERT INTO multi_primary_writes VALUES (#{i})") }
+    conn.close
+
+    # Round robin by default.
+    primaries.each do |instance|
+      rows = instance.with_connection { |c| c.async_exec("SELECT COUNT(*) AS count FROM multi_primary_writes")[0]["count"].to_i }
+      expect(rows).to eq(10)
+    end
+  end
+end
+
+describe "Zone affinity" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5) }
+
+  before do
+    new_configs = processes.pgcat.current_config
+    new_configs["general"]["local_zone"] = "zone-a"
+    new_configs["pools"]["sharded_db"]["shards"]["0"]["servers"].map!.with_index do |(host, port, role), i|
+      { "host" => host, "port" => port, "role" => role, "zone" => i == 1 ? "zone-a" : "zone-b" }
+    end
+    processes.pgcat.update_config(new_configs)
+    processes.pgcat.reload_config
+  end
+
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "prefers the replica in the local zone" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    QUERY_COUNT.times { conn.async_exec("SELECT 1 + 2") }
+
+    processes.all_databases.each do |instance|
+      expected = instance == processes.replicas[0] ? QUERY_COUNT : 0
+      expect(instance.count_select_1_plus_2).to eq(expected)
+    end
+
+    admin_conn = PG.connect(processes.pgcat.admin_connection_string)
+    zones = admin_conn.async_exec("SHOW SERVERS").map { |row| row["zone"] }
+    expect(zones).to include("zone-a")
+  end
+
+  it "falls back to the other zones when the local replica is down" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+    processes.replicas[0].take_down do
+      QUERY_COUNT.times do
+        conn.async_exec("SELECT 1 + 2")
+      rescue PG::Error
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      end
+    end
+
+    remote = processes.all_databases - [processes.replicas[0]]
+    expect(remote.sum(&:count_select_1_plus_2)).to be > 0
+  end
+end
@@ -35,6 +35,31 @@ describe "Query Mirroing" do
     expect(mirror_pg.count_select_1_plus_2).to be > ((runs - 5) * 3)
   end
 
+  context "when a server has the mirror role" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["shards"]["0"].delete("mirrors")
+      new_configs["pools"]["sharded_db"]["shards"]["0"]["servers"] << [mirror_host, mirror_pg.port.to_i, "mirror"]
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "receives mirrored queries but serves no clients" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      runs = 15
+      runs.times { conn.async_exec("SELECT 1 + 2") }
+      sleep 0.5
+      expect(processes.all_databases.first.count_select_1_plus_2).to eq(runs)
+      # Allow some slack in mirroring successes
+      expect(mirror_pg.count_select_1_plus_2).to be > (runs - 5)
+
+      admin_conn = PG.connect(processes.pgcat.admin_connection_string)
+      expect(admin_conn.async_exec("SHOW DATABASES").map { |row| row["port"] }).not_to include(mirror_pg.port.to_s)
+
+      [conn, admin_conn].map(&:close)
+    end
+  end
+
   context "when main server connection is closed" do
     it "closes the mirror connection" do
       baseline_count = processes.all_databases.first.count_connections
@@ -186,6 +186,25 @@ describe 'Prepared statements' do
     end
   end
 
+  context 'when the client encoding is not UTF-8' do
+    it 'round-trips text in the client encoding' do
+      conn = PG.connect(processes.pgcat.connection_string('sharded_db', 'sharding_user', parameters: { "client_encoding" => "LATIN1" }))
+      expect(conn.exec("SHOW client_encoding").getvalue(0, 0)).to eq("LATIN1")
+
+      text = "café".encode("ISO-8859-1")
+      expect(conn.exec("SELECT '#{text}'::text").getvalue(0, 0)).to eq(text)
+
+      conn.prepare("latin1", "SELECT '#{text}' || $1::text")
+      result = conn.exec_prepared("latin1", ["è".encode("ISO-8859-1")]).getvalue(0, 0)
+      expect(result).to eq("caféè".encode("ISO-8859-1"))
+    end
+
+    it 'returns the server error for an unsupported encoding' do
+      conn = PG.connect(processes.pgcat.connection_string('sharded_db', 'sharding_user', parameters: { "client_encoding" => "FOO" }))
+      expect { conn.exec("SELECT 1") }.to raise_error(PG::Error, /invalid value for parameter "client_encoding"/)
+    end
+  end
+
   context 'when reloading config' do
     let(:pool_size) { 1 }
 
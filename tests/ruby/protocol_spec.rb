@@ -131,6 +131,60 @@ describe "Portocol handling" do
     it_behaves_like "at parity with database"
   end
 
+  context "Oversized message" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["general"]["max_message_size"] = 1024 * 1024
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "rejects it and closes the connection without reading it" do
+      pgcat_socket.send_startup_message("sharding_user", "sharded_db", "sharding_user")
+
+      # Only the header of a 1 GB Query message.
+      pgcat_socket.send_raw(["Q".ord, 1024 * 1024 * 1024].pack("Cl>"))
+
+      messages = pgcat_socket.read_from_server
+      expect(messages.map { |message| message[:code] }).to eq(["E"])
+      expect(messages[0][:bytes].pack("C*")).to include("08P01")
+      expect(messages[0][:bytes].pack("C*")).to include("larger than max_message_size")
+
+      # Other clients aren't affected.
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.async_exec("SELECT 1").to_a).to eq([{ "?column?" => "1" }])
+      conn.close
+    end
+  end
+
+  context "GSSAPI encryption request" do
+    it "is declined and the client goes on without it" do
+      expect(pgcat_socket.send_gssenc_request).to eq("N")
+
+      parameters = pgcat_socket.send_startup_message("sharding_user", "sharded_db", "sharding_user")
+      expect(parameters["process_id"]).not_to be_nil
+
+      pgcat_socket.send_query_message("SELECT 1")
+      messages = pgcat_socket.read_from_server
+      expect(messages.map { |message| message[:code] }).to eq(["T", "D", "C", "Z"])
+    end
+
+    context "with reject_gss_encryption" do
+      before do
+        new_configs = processes.pgcat.current_config
+        new_configs["general"]["reject_gss_encryption"] = true
+        processes.pgcat.update_config(new_configs)
+        processes.pgcat.reload_config
+      end
+
+      it "disconnects the client with an error" do
+        expect(pgcat_socket.send_gssenc_request).to eq("E")
+        sleep 0.1
+        expect(processes.pgcat.logs).to include("GSSAPI encryption requested")
+      end
+    end
+  end
+
   context "Simple message" do
     let(:sequence) {
       [[:send_query_message, "SELECT 1"]]
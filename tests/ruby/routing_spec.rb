@@ -78,4 +78,105 @@ describe "Routing" do
       end
     end
   end
+
+  describe "target_session_attrs" do
+    def connect(target_session_attrs)
+      PG.connect("#{processes.pgcat.connection_string("sharded_db", "sharding_user")}?options=-c%20target_session_attrs%3D#{target_session_attrs}")
+    end
+
+    context "read-write" do
+      it "routes queries only to the primary" do
+        conn = connect("read-write")
+        30.times { conn.async_exec("SELECT 1 + 2") }
+
+        processes.replicas.map(&:count_select_1_plus_2).each do |instance_share|
+          expect(instance_share).to eq(0)
+        end
+        expect(processes.primary.count_select_1_plus_2).to eq(30)
+        conn.close
+      end
+    end
+
+    context "read-only" do
+      it "routes queries only to replicas" do
+        conn = connect("read-only")
+        30.times { conn.async_exec("SELECT 1 + 2") }
+
+        expect(processes.replicas.map(&:count_select_1_plus_2).sum).to eq(30)
+        expect(processes.primary.count_select_1_plus_2).to eq(0)
+        expect(conn.async_exec("SHOW SERVER ROLE")[0]["server role"]).to eq("replica")
+        conn.close
+      end
+    end
+
+    context "prefer-standby" do
+      it "routes queries to replicas, or the primary if there are none" do
+        conn = connect("prefer-standby")
+        30.times { conn.async_exec("SELECT 1 + 2") }
+        expect(processes.replicas.map(&:count_select_1_plus_2).sum).to eq(30)
+        conn.close
+
+        current_configs = processes.pgcat.current_config
+        current_configs["pools"]["sharded_db"]["shards"]["0"]["servers"].select! { |server| server[2] == "primary" }
+        processes.pgcat.update_config(current_configs)
+        processes.pgcat.reload_config
+
+        conn = connect("prefer-standby")
+        30.times { conn.async_exec("SELECT 1 + 2") }
+        expect(processes.primary.count_select_1_plus_2).to eq(30)
+        conn.close
+      end
+    end
+
+    context "invalid value" do
+      it "rejects the client" do
+        expect { connect("read-mostly").async_exec("SELECT 1") }.to raise_error(PG::Error, /target_session_attrs/)
+      end
+    end
+  end
+
+  describe "Transactions" do
+    let(:processes) do
+      Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "info", {
+        "default_role" => "replica",
+        "query_parser_enabled" => true,
+        "query_parser_read_write_splitting" => true,
+      })
+    end
+
+    it "keeps reads after a write on the primary until the transaction ends" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("BEGIN")
+      conn.async_exec("CREATE TEMP TABLE sticky_primary (id INT)")
+      conn.async_exec("INSERT INTO sticky_primary VALUES (1)")
+      conn.async_exec("SELECT 1 + 2")
+      conn.async_exec("COMMIT")
+
+      expect(processes.primary.count_select_1_plus_2).to eq(1)
+      expect(processes.replicas.map(&:count_select_1_plus_2).sum).to eq(0)
+
+      # Reads outside of a transaction go to the replicas again.
+      conn.async_exec("SELECT 1 + 2")
+      expect(processes.primary.count_select_1_plus_2).to eq(1)
+
+      conn.close
+    end
+
+    it "serves read-only transactions from the replicas when disabled" do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["sticky_primary_in_txn"] = false
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("BEGIN READ ONLY")
+      conn.async_exec("SELECT 1 + 2")
+      conn.async_exec("COMMIT")
+
+      expect(processes.primary.count_select_1_plus_2).to eq(0)
+      expect(processes.replicas.map(&:count_select_1_plus_2).sum).to eq(1)
+
+      conn.close
+    end
+  end
 end
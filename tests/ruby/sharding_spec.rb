@@ -50,6 +50,109 @@ describe "Sharding" do
     end
   end
 
+  describe "automatic routing of WHERE clauses" do
+    it "routes equality and IN predicates on the sharding key" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      18.times do |i|
+        expect(conn.exec("SELECT * FROM data WHERE id = #{i + 1}").ntuples).to eq(1)
+        expect(conn.exec("SELECT * FROM data WHERE id IN (#{i + 1}) AND value IS NOT NULL").ntuples).to eq(1)
+      end
+    end
+
+    it "rejects queries spanning shards when configured to" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["reject_cross_shard_queries"] = true
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect { conn.exec("SELECT * FROM data WHERE id IN (1, 2, 3, 4, 5, 6)") }.to raise_error(PG::SystemError, /query spans multiple shards/)
+
+      # Queries without a sharding key still go to the default shard.
+      expect(conn.exec("SELECT * FROM data WHERE value = 'value_1'").ntuples).to be <= 1
+    end
+  end
+
+  describe "scatter-gather" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["enable_scatter_gather"] = true
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "returns the rows of all shards for reads without a sharding key" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      result = conn.exec("SELECT id FROM data")
+      expect(result.ntuples).to eq(18)
+      expect(result.map { |row| row["id"].to_i }.sort).to eq((1..18).to_a)
+      expect(result.cmd_status).to eq("SELECT 18")
+
+      # Reads with a sharding key go to one shard.
+      expect(conn.exec("SELECT id FROM data WHERE id = 1").ntuples).to eq(1)
+    end
+
+    it "errors out when shards return different columns" do
+      setup_conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      3.times do |i|
+        setup_conn.exec("SET SHARD TO '#{i}'")
+        setup_conn.exec("DROP TABLE IF EXISTS scatter_gather_test")
+        setup_conn.exec("CREATE TABLE scatter_gather_test (value #{i == 1 ? 'TEXT' : 'INTEGER'})")
+      end
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect { conn.exec("SELECT value FROM scatter_gather_test") }.to raise_error(PG::SystemError, /shard 1 returned different columns/)
+
+      3.times do |i|
+        setup_conn.exec("SET SHARD TO '#{i}'")
+        setup_conn.exec("DROP TABLE scatter_gather_test")
+      end
+    end
+  end
+
+  describe "catalog queries" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["catalog_shard"] = 1
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "answers them from the catalog shard whatever the session's shard is" do
+      setup_conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      setup_conn.exec("SET SHARD TO '1'")
+      setup_conn.exec("DROP TABLE IF EXISTS only_on_catalog_shard")
+      setup_conn.exec("CREATE TABLE only_on_catalog_shard (id BIGINT)")
+
+      # What psql runs for \dt
+      describe_tables = <<~SQL
+        SELECT n.nspname AS "Schema", c.relname AS "Name"
+        FROM pg_catalog.pg_class c
+        LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+        WHERE c.relkind IN ('r', 'p', '')
+          AND n.nspname <> 'pg_catalog'
+          AND n.nspname !~ '^pg_toast'
+          AND n.nspname <> 'information_schema'
+          AND pg_catalog.pg_table_is_visible(c.oid)
+        ORDER BY 1, 2
+      SQL
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      results = 3.times.map do |i|
+        conn.exec("SET SHARD TO '#{i}'")
+        conn.exec(describe_tables).map { |row| row["Name"] }
+      end
+
+      expect(results.uniq.count).to eq(1)
+      expect(results.first).to include("only_on_catalog_shard")
+
+      setup_conn.exec("SET SHARD TO '1'")
+      setup_conn.exec("DROP TABLE only_on_catalog_shard")
+    end
+  end
+
   describe "no_shard_specified_behavior config" do
     context "when default shard number is invalid" do
       it "prevents config reload" do
@@ -193,4 +296,121 @@ describe "Sharding" do
       end
     end
   end
+
+  describe "cross-shard transactions" do
+    let(:cross_shard_transactions) { "reject" }
+
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["cross_shard_transactions"] = cross_shard_transactions
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    # Two new sharding keys, on different shards.
+    def keys_on_different_shards(conn)
+      shards = (100..120).to_h do |key|
+        conn.exec("SET SHARDING KEY TO '#{key}'")
+        [key, conn.exec("SHOW SHARD").getvalue(0, 0)]
+      end
+      first = shards.keys.first
+      [first, shards.keys.find { |key| shards[key] != shards[first] }]
+    end
+
+    def count(conn, key)
+      conn.exec("SELECT count(*) FROM data WHERE id = #{key}").getvalue(0, 0).to_i
+    end
+
+    context "when set to reject" do
+      it "aborts the transaction" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        first, second = keys_on_different_shards(conn)
+
+        conn.exec("SET SHARDING KEY TO '#{first}'")
+        conn.exec("BEGIN")
+        conn.exec("INSERT INTO data (id, value) VALUES (#{first}, 'first')")
+        expect { conn.exec("INSERT INTO data (id, value) VALUES (#{second}, 'second')") }.to raise_error(PG::FeatureNotSupported, /cross-shard transaction/)
+        expect(conn.exec("COMMIT").cmd_status).to eq("ROLLBACK")
+
+        expect(count(conn, first)).to eq(0)
+        expect(count(conn, second)).to eq(0)
+      end
+
+      it "aborts the transaction without a shard picked before BEGIN" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        first, second = keys_on_different_shards(conn)
+        conn.close
+
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.exec("BEGIN")
+        conn.exec("INSERT INTO data (id, value) VALUES (#{first}, 'first')")
+        expect { conn.exec("INSERT INTO data (id, value) VALUES (#{second}, 'second')") }.to raise_error(PG::FeatureNotSupported, /cross-shard transaction/)
+        expect(conn.exec("COMMIT").cmd_status).to eq("ROLLBACK")
+
+        expect(count(conn, first)).to eq(0)
+        expect(count(conn, second)).to eq(0)
+      end
+    end
+
+    context "when set to pin_first" do
+      let(:cross_shard_transactions) { "pin_first" }
+
+      it "fails the statement and keeps the transaction on its first shard" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        first, second = keys_on_different_shards(conn)
+
+        conn.exec("SET SHARDING KEY TO '#{first}'")
+        conn.exec("BEGIN")
+        conn.exec("INSERT INTO data (id, value) VALUES (#{first}, 'first')")
+        expect { conn.exec("INSERT INTO data (id, value) VALUES (#{second}, 'second')") }.to raise_error(PG::FeatureNotSupported, /cross-shard transaction/)
+        expect(conn.transaction_status).to eq(PG::PQTRANS_INTRANS)
+        expect(conn.exec("COMMIT").cmd_status).to eq("COMMIT")
+
+        expect(count(conn, first)).to eq(1)
+        expect(count(conn, second)).to eq(0)
+      end
+
+      it "pins the transaction to the shard of its first statement without a shard picked before BEGIN" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        first, second = keys_on_different_shards(conn)
+        conn.close
+
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.exec("BEGIN")
+        conn.exec("INSERT INTO data (id, value) VALUES (#{second}, 'second')")
+        expect { conn.exec("INSERT INTO data (id, value) VALUES (#{first}, 'first')") }.to raise_error(PG::FeatureNotSupported, /cross-shard transaction/)
+        expect(conn.exec("COMMIT").cmd_status).to eq("COMMIT")
+
+        expect(count(conn, second)).to eq(1)
+        expect(count(conn, first)).to eq(0)
+      end
+    end
+  end
+
+  describe "allowed_shards" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["users"]["0"]["allowed_shards"] = [0]
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "denies queries to shards the user is not allowed on" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      shards = (1..18).to_h do |key|
+        conn.exec("SET SHARDING KEY TO '#{key}'")
+        [key, conn.exec("SHOW SHARD").getvalue(0, 0)]
+      end
+      allowed = shards.keys.find { |key| shards[key] == "0" }
+      denied = shards.keys.find { |key| shards[key] != "0" }
+
+      expect(conn.exec("SELECT * FROM data WHERE id = #{allowed}").ntuples).to eq(1)
+      expect { conn.exec("SELECT * FROM data WHERE id = #{denied}") }.to raise_error(PG::InsufficientPrivilege, /not in the allowed_shards/)
+
+      expect { conn.exec("SET SHARD TO '1'") }.to raise_error(PG::InsufficientPrivilege, /not in the allowed_shards/)
+      expect(conn.exec("/* shard: 0 */ SELECT 1").getvalue(0, 0)).to eq("1")
+      expect { conn.exec("/* shard: 2 */ SELECT 1") }.to raise_error(PG::InsufficientPrivilege)
+    end
+  end
 end
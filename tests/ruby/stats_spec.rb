@@ -33,6 +33,82 @@ describe "Stats" do
         expect(results["avg_wait_time"].to_i).to_not eq(0)
       end
     end
+
+    context "clients fail to authenticate and queries fail" do
+      it "updates the error counters" do
+        expect { PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", "wrong")) }.to raise_error(PG::ConnectionBad)
+
+        conn = PG::connect(pgcat_conn_str)
+        2.times { expect { conn.async_exec("SELECT 1/0") }.to raise_error(PG::DivisionByZero) }
+        conn.close
+
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        results = admin_conn.async_exec("SHOW STATS")[0]
+        admin_conn.close
+        expect(results["total_query_errors"].to_i).to eq(2)
+        expect(results["total_client_auth_failures"].to_i).to eq(1)
+        expect(results["total_server_connect_errors"].to_i).to eq(0)
+        expect(results["total_pool_timeouts"].to_i).to eq(0)
+      end
+    end
+  end
+
+  describe "RESET STATS" do
+    it "zeroes the counters but not the gauges" do
+      expect { PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", "wrong")) }.to raise_error(PG::ConnectionBad)
+
+      conn = PG::connect(pgcat_conn_str)
+      3.times { conn.async_exec("SELECT 1") }
+      expect { conn.async_exec("SELECT 1/0") }.to raise_error(PG::DivisionByZero)
+      conn.async_exec("BEGIN")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      expect(admin_conn.async_exec("SHOW STATS")[0]["total_query_count"].to_i).to be >= 4
+      uptime = admin_conn.async_exec("SHOW POOLS")[0]["uptime"].to_i
+
+      expect(admin_conn.async_exec("RESET STATS").cmd_status).to eq("RESET STATS")
+
+      results = admin_conn.async_exec("SHOW STATS")[0]
+      %w[total_xact_count total_query_count total_received total_sent total_query_time total_errors
+         total_query_errors total_client_auth_failures].each do |s|
+        expect(results[s]).to eq("0"), "#{s} was #{results[s]}"
+      end
+
+      # The pool and its connections are untouched.
+      results = admin_conn.async_exec("SHOW POOLS")[0]
+      expect(results["sv_active"]).to eq("1")
+      expect(results["cl_active"]).to eq("1")
+      expect(results["uptime"].to_i).to be >= uptime
+
+      # Counting starts over.
+      conn.async_exec("COMMIT")
+      expect(admin_conn.async_exec("SHOW STATS")[0]["total_query_count"].to_i).to eq(1)
+
+      [conn, admin_conn].map(&:close)
+    end
+  end
+
+  describe "SHOW LATENCY" do
+    it "reports query latency percentiles of the last stats period" do
+      conn = PG::connect("#{pgcat_conn_str}?application_name=latency")
+      10.times { conn.async_exec("SELECT pg_sleep(0.05)") }
+      conn.close
+
+      # wait for the stats period to end
+      sleep(15.5)
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      results = admin_conn.async_exec("SHOW LATENCY")[0]
+      expect(results["query_count"].to_i).to eq(10)
+      expect(results["server_p50_us"].to_i).to be_within(15_000).of(50_000)
+      expect(results["server_max_us"].to_i).to be >= results["server_p99_us"].to_i
+      expect(results["client_p99_us"].to_i).to be >= results["server_p50_us"].to_i
+
+      # the next period has no queries
+      sleep(15)
+      results = admin_conn.async_exec("SHOW LATENCY")[0]
+      expect(results["query_count"].to_i).to eq(0)
+      admin_conn.close
+    end
   end
 
   describe "SHOW POOLS" do
@@ -53,6 +129,47 @@ describe "Stats" do
       end
     end
 
+    context "after a reload recreating the pool" do
+      it "keeps its uptime" do
+        sleep(2)
+        new_configs = processes.pgcat.current_config
+        new_configs["pools"]["sharded_db"]["idle_timeout"] = 5001
+        processes.pgcat.update_config(new_configs)
+        processes.pgcat.reload_config
+
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        expect(admin_conn.async_exec("SHOW POOLS")[0]["uptime"].to_i).to be >= 2
+        admin_conn.close
+      end
+    end
+
+    context "with min_pool_size" do
+      it "keeps idle server connections open" do
+        new_configs = processes.pgcat.current_config
+        new_configs["general"]["min_pool_size"] = 3
+        # General settings apply to pools created by the reload.
+        new_configs["pools"]["sharded_db"]["idle_timeout"] = 5001
+        processes.pgcat.update_config(new_configs)
+        processes.pgcat.reload_config
+
+        sleep(1)
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        results = admin_conn.async_exec("SHOW POOLS")[0]
+        expect(results["min_pool_size"]).to eq("3")
+        expect(results["sv_idle"]).to eq("3")
+
+        # Used connections are replaced in the background.
+        conn = PG::connect(pgcat_conn_str)
+        conn.async_exec("BEGIN")
+        sleep(1)
+        results = admin_conn.async_exec("SHOW POOLS")[0]
+        expect(results["sv_active"]).to eq("1")
+        expect(results["sv_idle"].to_i).to be >= 2
+
+        [conn, admin_conn].map(&:close)
+      end
+    end
+
     context "bad database name" do
       it "does not change any stats" do
         bad_db_url = URI(pgcat_conn_str)
@@ -304,6 +421,31 @@ describe "Stats" do
       admin_conn.close
     end
 
+    it "reports the client address and the shard and role of its server" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("BEGIN")
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      sleep(1) # Wait for stats to be updated
+
+      client = admin_conn.async_exec("SHOW CLIENTS").reject { |r| r["database"] == "pgcat" }.first
+      expect(client["state"]).to eq("active")
+      expect(client["addr"]).to match(/:\d+$/)
+      expect(client["shard"]).to eq("0")
+      expect(%w[primary replica]).to include(client["role"])
+
+      conn.async_exec("COMMIT")
+      sleep(1)
+
+      client = admin_conn.async_exec("SHOW CLIENTS").reject { |r| r["database"] == "pgcat" }.first
+      expect(client["state"]).to eq("idle")
+      expect(client["shard"]).to eq("")
+
+      conn.close
+      admin_conn.close
+    end
+
     it "reports correct number of queries and transactions" do
       conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user")
 
@@ -368,6 +510,61 @@ describe "Stats" do
     end
   end
 
+  describe "SHOW SERVERS" do
+    it "reports the backend and the client linked to each server" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+
+      conn.async_exec("BEGIN")
+      backend_pid = conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]
+
+      client = admin_conn.async_exec("SHOW CLIENTS").find { |c| c["database"] == "sharded_db" }
+      server = admin_conn.async_exec("SHOW SERVERS").find { |s| s["backend_pid"] == backend_pid }
+      expect(server["state"]).to eq("active")
+      expect(server["client_id"]).to eq(client["client_id"])
+      expect(server["host"]).to eq("localhost")
+      expect(server["role"]).to eq("primary")
+      expect(server["shard"]).to eq("0")
+
+      conn.async_exec("COMMIT")
+      server = admin_conn.async_exec("SHOW SERVERS").find { |s| s["backend_pid"] == backend_pid }
+      expect(server["state"]).to eq("idle")
+      expect(server["client_id"]).to eq("")
+
+      conn.close
+      admin_conn.close
+    end
+  end
+
+  describe "SHOW APPLICATIONS" do
+    it "groups clients by application_name" do
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user")
+      connections = Array.new(6) { |i| PG::connect("#{conn_str}?application_name=app#{i % 2}") }
+      connections.each { |c| c.async_exec("SELECT 1") }
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      sleep(1) # Wait for stats to be updated
+
+      results = admin_conn.async_exec("SHOW APPLICATIONS").select { |r| r["database"] == "sharded_db" }
+      expect(results.map { |r| r["application_name"] }).to eq(["app0", "app1"])
+
+      app0 = results.find { |r| r["application_name"] == "app0" }
+      expect(app0["cl_idle"]).to eq("3")
+      expect(app0["query_count"]).to eq("3")
+
+      connections.map(&:close)
+      admin_conn.close
+    end
+
+    it "forwards the application_name to the server" do
+      conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user")
+
+      conn = PG::connect("#{conn_str}?application_name=forwarded_app")
+      expect(conn.async_exec("SELECT current_setting('application_name')")[0]["current_setting"]).to eq("forwarded_app")
+      conn.close
+    end
+  end
+
 
   describe "Query Storm" do
     context "when the proxy receives overwhelmingly large number of short quick queries" do
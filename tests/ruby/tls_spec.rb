@@ -0,0 +1,170 @@
+# frozen_string_literal: true
+require 'openssl'
+require 'tmpdir'
+require_relative 'spec_helper'
+
+describe "Client certificates" do
+  let(:processes) { Helpers::Pgcat.single_instance_setup("sharded_db", 5) }
+  let(:certs_dir) { Dir.mktmpdir }
+
+  def generate_ca(dir, name)
+    key = OpenSSL::PKey::RSA.new(2048)
+    cert = OpenSSL::X509::Certificate.new
+    cert.version = 2
+    cert.serial = 1
+    cert.subject = OpenSSL::X509::Name.parse("/CN=#{name}")
+    cert.issuer = cert.subject
+    cert.public_key = key.public_key
+    cert.not_before = Time.now - 60
+    cert.not_after = Time.now + 3600
+
+    extensions = OpenSSL::X509::ExtensionFactory.new
+    extensions.subject_certificate = cert
+    extensions.issuer_certificate = cert
+    cert.add_extension(extensions.create_extension("basicConstraints", "CA:TRUE", true))
+    cert.add_extension(extensions.create_extension("keyUsage", "keyCertSign, cRLSign", true))
+    cert.sign(key, OpenSSL::Digest::SHA256.new)
+
+    File.write(File.join(dir, "#{name}.crt"), cert.to_pem)
+    [cert, key]
+  end
+
+  def generate_client_cert(dir, name, ca_cert, ca_key)
+    key = OpenSSL::PKey::RSA.new(2048)
+    cert = OpenSSL::X509::Certificate.new
+    cert.version = 2
+    cert.serial = 2
+    cert.subject = OpenSSL::X509::Name.parse("/CN=sharding_user")
+    cert.issuer = ca_cert.subject
+    cert.public_key = key.public_key
+    cert.not_before = Time.now - 60
+    cert.not_after = Time.now + 3600
+
+    extensions = OpenSSL::X509::ExtensionFactory.new
+    extensions.subject_certificate = cert
+    extensions.issuer_certificate = ca_cert
+    cert.add_extension(extensions.create_extension("extendedKeyUsage", "clientAuth", false))
+    cert.sign(ca_key, OpenSSL::Digest::SHA256.new)
+
+    File.write(File.join(dir, "#{name}.crt"), cert.to_pem)
+    File.write(File.join(dir, "#{name}.key"), key.to_pem)
+    File.chmod(0600, File.join(dir, "#{name}.key"))
+  end
+
+  def client_connection_string(name)
+    processes.pgcat.connection_string(
+      "sharded_db",
+      "sharding_user",
+      parameters: {
+        "sslmode" => "require",
+        "sslcert" => File.join(certs_dir, "#{name}.crt"),
+        "sslkey" => File.join(certs_dir, "#{name}.key"),
+      }
+    )
+  end
+
+  before do
+    ca_cert, ca_key = generate_ca(certs_dir, "client_ca")
+    generate_client_cert(certs_dir, "trusted", ca_cert, ca_key)
+
+    untrusted_ca_cert, untrusted_ca_key = generate_ca(certs_dir, "untrusted_ca")
+    generate_client_cert(certs_dir, "untrusted", untrusted_ca_cert, untrusted_ca_key)
+
+    new_configs = processes.pgcat.current_config
+    new_configs["general"]["tls_certificate"] = "../../.circleci/server.cert"
+    new_configs["general"]["tls_private_key"] = "../../.circleci/server.key"
+    new_configs["general"]["client_ca_certificate"] = File.join(certs_dir, "client_ca.crt")
+    new_configs["general"]["require_client_certificate"] = true
+    processes.pgcat.update_config(new_configs)
+    processes.pgcat.stop
+    processes.pgcat.start
+    processes.pgcat.wait_until_ready(client_connection_string("trusted"))
+  end
+
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+    FileUtils.remove_entry(certs_dir)
+  end
+
+  it "accepts clients with a certificate signed by the client CA" do
+    conn = PG::connect(client_connection_string("trusted"))
+    expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+    conn.close
+  end
+
+  it "rejects clients with an untrusted certificate" do
+    expect { PG::connect(client_connection_string("untrusted")) }.to raise_error(PG::ConnectionBad)
+  end
+
+  it "rejects clients without a certificate" do
+    conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "sslmode" => "require" })
+    expect { PG::connect(conn_str) }.to raise_error(PG::ConnectionBad)
+  end
+
+  it "rejects plain connections" do
+    conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "sslmode" => "disable" })
+    expect { PG::connect(conn_str) }.to raise_error(PG::ConnectionBad, /client certificate is required/)
+  end
+end
+
+describe "SSL negotiation" do
+  let(:processes) { Helpers::Pgcat.single_instance_setup("sharded_db", 5) }
+
+  def connect(sslmode)
+    PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "sslmode" => sslmode }))
+  end
+
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  context "when TLS is configured" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["general"]["tls_certificate"] = "../../.circleci/server.cert"
+      new_configs["general"]["tls_private_key"] = "../../.circleci/server.key"
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.stop
+      processes.pgcat.start
+      processes.pgcat.wait_until_ready
+    end
+
+    ["require", "prefer"].each do |sslmode|
+      it "uses TLS with sslmode=#{sslmode}" do
+        conn = connect(sslmode)
+        expect(conn.ssl_in_use?).to be(true)
+        expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+        conn.close
+      end
+    end
+
+    it "accepts plain connections with sslmode=disable" do
+      conn = connect("disable")
+      expect(conn.ssl_in_use?).to be(false)
+      expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+      conn.close
+    end
+  end
+
+  context "when TLS is not configured" do
+    it "rejects clients with sslmode=require" do
+      expect { connect("require") }.to raise_error(PG::ConnectionBad, /server does not support SSL/)
+
+      # pgcat is still fine.
+      conn = connect("disable")
+      expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+      conn.close
+    end
+
+    ["prefer", "disable"].each do |sslmode|
+      it "falls back to a plain connection with sslmode=#{sslmode}" do
+        conn = connect(sslmode)
+        expect(conn.ssl_in_use?).to be(false)
+        expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+        conn.close
+      end
+    end
+  end
+end